@@ -7,6 +7,7 @@
 // Media Patent License 1.0 was not distributed with this source code in the
 // PATENTS file, you can obtain it at www.aomedia.org/license/patent.
 
+mod denoise;
 mod dist;
 mod mc;
 mod plane;
@@ -207,6 +208,7 @@ criterion_group! {
 }
 
 criterion_group!(ec, ec_bench);
+criterion_group!(denoise, denoise::denoise);
 
 criterion_main!(
   write_block,
@@ -219,5 +221,6 @@ criterion_main!(
   ec,
   rdo,
   plane,
+  denoise,
   mc::mc
 );
@@ -0,0 +1,43 @@
+// Copyright (c) 2018-2022, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+use criterion::*;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaChaRng;
+use rav1e::bench::denoise::*;
+use rav1e::bench::frame::*;
+use rav1e::prelude::ChromaSampling;
+
+fn init_frames(width: usize, height: usize, nframes: usize) -> Vec<Frame<u8>> {
+  let mut ra = ChaChaRng::from_seed([0; 32]);
+  (0..nframes)
+    .map(|_| {
+      let mut frame =
+        Frame::new_with_padding(width, height, ChromaSampling::Cs420, 0);
+      for plane in frame.planes.iter_mut() {
+        for pixel in plane.data.iter_mut() {
+          *pixel = 96 + ra.gen::<u8>() / 4;
+        }
+      }
+      frame
+    })
+    .collect()
+}
+
+pub fn denoise(c: &mut Criterion) {
+  for temporal_radius in [0, 1] {
+    let frames = init_frames(640, 360, 2 * temporal_radius + 1);
+    let denoiser = DctDenoiser::new(10, temporal_radius as u8);
+    let n = format!("denoise(640x360, radius {})", temporal_radius);
+    c.bench_function(&n, |b| {
+      let frames = frames.iter().collect::<Vec<_>>();
+      b.iter(|| denoiser.denoise(&frames, temporal_radius, 8, 3))
+    });
+  }
+}
@@ -0,0 +1,210 @@
+// Copyright (c) 2018-2022, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+#![deny(missing_docs)]
+
+use crate::api::internal::InterConfig;
+use crate::api::lookahead::compute_motion_vectors;
+use crate::api::{
+  EncoderConfig, EncoderStatus, FrameType, PartitionRange, PixelRange,
+};
+use crate::color::ChromaSampling::Cs400;
+use crate::encoder::*;
+use crate::frame::*;
+use crate::partition::BlockSize;
+use crate::rate::QuantizerParameters;
+use crate::util::Pixel;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/// Encoder of the alpha auxiliary stream.
+///
+/// The alpha planes are coded as a monochrome stream which mirrors the frame
+///  structure of the main one: each of its frames copies the frame type,
+///  references and reference updates of the main frame coded along with it,
+///  so that both streams hold the same frames in every packet.
+pub(crate) struct AlphaStream<T: Pixel> {
+  /// Configuration of the alpha stream, derived from the main one.
+  config: Arc<EncoderConfig>,
+  seq: Arc<Sequence>,
+  /// Base quantizer index of every alpha frame.
+  quantizer: u8,
+  /// Maps *`input_frameno`* to the alpha planes received, as monochrome
+  ///  frames.
+  frame_q: BTreeMap<u64, Arc<Frame<T>>>,
+  /// Fully opaque frame, coded for the frames sent without an alpha plane.
+  opaque_frame: Arc<Frame<T>>,
+  /// Reference frames of the alpha stream after the last coded frame.
+  rec_buffer: ReferenceFramesSet<T>,
+  /// Alpha stream data of the packet being built.
+  packet_data: Vec<u8>,
+  /// Reconstruction of the alpha plane shown by the packet being built.
+  rec: Option<Arc<Frame<T>>>,
+}
+
+impl<T: Pixel> AlphaStream<T> {
+  pub fn new(enc: &EncoderConfig, quantizer: usize) -> Self {
+    let mut config = EncoderConfig {
+      chroma_sampling: Cs400,
+      pixel_range: PixelRange::Full,
+      color_description: None,
+      mastering_display: None,
+      content_light: None,
+      quantizer,
+      tune: Tune::Psnr,
+      film_grain_params: None,
+      denoise_noise_level: 0,
+      zones: None,
+      ..enc.clone()
+    };
+    if quantizer == 0 {
+      // The loop filters are off in lossless frames.
+      config.speed_settings.cdef = false;
+      config.speed_settings.lrf = false;
+    }
+    let seq = Arc::new(Sequence::new(&config));
+
+    let mut opaque_frame = Frame::new(enc.width, enc.height, Cs400);
+    let max_value = (1u16 << enc.bit_depth) - 1;
+    opaque_frame.planes[0].data.fill(T::cast_from(max_value));
+
+    AlphaStream {
+      config: Arc::new(config),
+      seq,
+      quantizer: quantizer as u8,
+      frame_q: BTreeMap::new(),
+      opaque_frame: Arc::new(opaque_frame),
+      rec_buffer: ReferenceFramesSet::new(),
+      packet_data: TEMPORAL_DELIMITER.to_vec(),
+      rec: None,
+    }
+  }
+
+  pub fn sequence(&self) -> &Sequence {
+    &self.seq
+  }
+
+  /// Queues the alpha plane of the input frame `input_frameno`.
+  pub fn send_plane(
+    &mut self, input_frameno: u64, alpha: AlphaPlane,
+  ) -> Result<(), EncoderStatus> {
+    let EncoderConfig { width, height, .. } = *self.config;
+    let mut plane = alpha.into_plane::<T>().ok_or(EncoderStatus::Failure)?;
+    if plane.cfg != self.opaque_frame.planes[0].cfg {
+      return Err(EncoderStatus::Failure);
+    }
+    plane.pad(width, height);
+
+    let mut frame = Frame::new(width, height, Cs400);
+    frame.planes[0] = plane;
+    self.frame_q.insert(input_frameno, Arc::new(frame));
+    Ok(())
+  }
+
+  /// Codes the alpha plane of the frame coded with `fi` in the main stream.
+  pub fn encode_frame(
+    &mut self, output_frameno: u64, fi: &FrameInvariants<T>,
+    inter_cfg: &InterConfig,
+  ) {
+    let mut alpha_fi = self.frame_invariants(fi);
+    alpha_fi.coded_frame_data = Some(CodedFrameData::new(&alpha_fi));
+    let mut qps = QuantizerParameters::new_flat(
+      self.quantizer,
+      self.seq.bit_depth,
+      Cs400,
+      fi.get_frame_subtype(),
+      0,
+    );
+    if self.quantizer == 0 {
+      qps.dc_qi = [0; 3];
+      qps.ac_qi = [0; 3];
+    }
+    alpha_fi.set_quantizers(&qps);
+
+    let frame = self
+      .frame_q
+      .remove(&fi.input_frameno)
+      .unwrap_or_else(|| self.opaque_frame.clone());
+    let mut fs = FrameState::new_with_frame(&alpha_fi, frame);
+    if alpha_fi.frame_type.has_inter() {
+      compute_motion_vectors(&mut alpha_fi, &mut fs, inter_cfg);
+    }
+    let data = encode_frame(&alpha_fi, &mut fs, inter_cfg);
+    self.packet_data.extend(data);
+
+    Arc::get_mut(&mut fs.rec).unwrap().pad(fi.width, fi.height, 1);
+    if alpha_fi.show_frame {
+      self.rec = Some(fs.rec.clone());
+    }
+    update_rec_buffer(output_frameno, &mut alpha_fi, &fs);
+    self.rec_buffer = alpha_fi.rec_buffer;
+  }
+
+  /// Shows an existing alpha frame, as the main stream does with `fi`.
+  pub fn encode_show_existing_frame(
+    &mut self, fi: &FrameInvariants<T>, inter_cfg: &InterConfig,
+  ) {
+    let alpha_fi = self.frame_invariants(fi);
+    let mut fs = FrameState::new(&alpha_fi);
+    let data = encode_show_existing_frame(&alpha_fi, &mut fs, inter_cfg);
+    self.packet_data.extend(data);
+
+    if alpha_fi.frame_type == FrameType::KEY {
+      // Showing a forward keyframe loads it into every slot.
+      self.rec_buffer.load_key_frame(alpha_fi.frame_to_show_map_idx as usize);
+    }
+    if alpha_fi.show_frame {
+      self.rec = Some(fs.rec);
+    }
+  }
+
+  /// Forgets the alpha plane of an input frame which is not coded.
+  pub fn skip_frame(&mut self, input_frameno: u64) {
+    self.frame_q.remove(&input_frameno);
+  }
+
+  /// Returns the alpha stream data and reconstruction of the finished
+  ///  packet, and starts the next one.
+  pub fn take_packet(&mut self) -> (Vec<u8>, Option<Arc<Frame<T>>>) {
+    let data =
+      std::mem::replace(&mut self.packet_data, TEMPORAL_DELIMITER.to_vec());
+    (data, self.rec.take())
+  }
+
+  /// Derives the `FrameInvariants` of the alpha frame mirroring the main
+  ///  frame `fi`.
+  fn frame_invariants(&self, fi: &FrameInvariants<T>) -> FrameInvariants<T> {
+    let mut alpha_fi = fi.clone_without_coded_data();
+    alpha_fi.sequence = self.seq.clone();
+    // Zones may change the speed settings of the main frame.
+    alpha_fi.config = if fi.config.speed_settings == self.config.speed_settings
+    {
+      self.config.clone()
+    } else {
+      Arc::new(EncoderConfig {
+        speed_settings: fi.config.speed_settings,
+        ..(*self.config).clone()
+      })
+    };
+    alpha_fi.use_tx_domain_distortion =
+      fi.config.speed_settings.transform.tx_domain_distortion;
+    alpha_fi.enable_segmentation = false;
+    if self.quantizer == 0 {
+      // Lossless frames only have 4x4 transforms, which are reached with 4x4
+      //  blocks.
+      alpha_fi.partition_range =
+        PartitionRange::new(BlockSize::BLOCK_4X4, BlockSize::BLOCK_4X4);
+      alpha_fi.tx_mode_select = false;
+    }
+    alpha_fi.t35_metadata = Box::new([]);
+    alpha_fi.timecode = None;
+    alpha_fi.rec_buffer = self.rec_buffer.clone();
+    alpha_fi.set_ref_frame_sign_bias();
+    alpha_fi
+  }
+}
@@ -0,0 +1,438 @@
+// Copyright (c) 2018-2022, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+#![deny(missing_docs)]
+
+use crate::api::color::*;
+use crate::api::config::EncoderConfig;
+use crate::api::context::Context;
+use crate::api::util::Packet;
+use crate::encoder::TEMPORAL_DELIMITER;
+use crate::isobmff::*;
+use crate::util::Pixel;
+
+use thiserror::Error;
+
+/// Auxiliary type of the alpha plane of an image.
+const ALPHA_URN: &[u8] = b"urn:mpeg:mpegB:cicp:systems:auxiliary:alpha\0";
+
+/// A coded picture stored in an AVIF image, either the whole image or a tile
+/// of its grid.
+#[derive(Clone, Copy, Debug)]
+pub struct AvifTile<'a> {
+  /// The coded still picture, as in [`Packet::data`].
+  ///
+  /// [`Packet::data`]: struct.Packet.html#structfield.data
+  pub data: &'a [u8],
+  /// The coded alpha plane, as in [`Packet::alpha_data`].
+  ///
+  /// [`Packet::alpha_data`]: struct.Packet.html#structfield.alpha_data
+  pub alpha_data: Option<&'a [u8]>,
+}
+
+impl<'a, T: Pixel> From<&'a Packet<T>> for AvifTile<'a> {
+  fn from(packet: &'a Packet<T>) -> Self {
+    AvifTile { data: &packet.data, alpha_data: packet.alpha_data.as_deref() }
+  }
+}
+
+/// Layout of an image split in tiles coded separately, for pictures too
+/// large to be coded at once.
+///
+/// Every tile has the size of the encoded frames. The tiles of the last
+/// column and row may extend past the image, and are cropped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ImageGrid {
+  /// Number of rows of tiles, between 1 and 256.
+  pub rows: usize,
+  /// Number of columns of tiles, between 1 and 256.
+  pub columns: usize,
+  /// Width of the image.
+  pub width: usize,
+  /// Height of the image.
+  pub height: usize,
+}
+
+/// An error returned when the tiles of an AVIF image do not match its
+/// layout.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Error)]
+#[non_exhaustive]
+pub enum AvifError {
+  /// The grid has too few or too many rows or columns.
+  #[error(
+    "invalid grid of {rows}x{columns} tiles (expected between 1 and 256)"
+  )]
+  InvalidGridLayout {
+    /// The number of rows.
+    rows: usize,
+    /// The number of columns.
+    columns: usize,
+  },
+  /// The size of the image is not covered by the grid of tiles, or the last
+  /// tiles of the grid are outside of it.
+  #[error(
+    "invalid image size {width}x{height} for a grid of {rows}x{columns} tiles of {tile_width}x{tile_height}"
+  )]
+  InvalidGridSize {
+    /// The width of the image.
+    width: usize,
+    /// The height of the image.
+    height: usize,
+    /// The number of rows.
+    rows: usize,
+    /// The number of columns.
+    columns: usize,
+    /// The width of the tiles.
+    tile_width: usize,
+    /// The height of the tiles.
+    tile_height: usize,
+  },
+  /// The number of tiles does not match the grid.
+  #[error("invalid tile count {actual} (expected {expected})")]
+  InvalidTileCount {
+    /// The actual value.
+    actual: usize,
+    /// The expected value.
+    expected: usize,
+  },
+  /// Only some tiles have an alpha plane, or they have one without the
+  /// configuration record of the alpha stream.
+  #[error("the tiles do not all match the alpha configuration of the image")]
+  AlphaMismatch,
+}
+
+fn ispe(width: usize, height: usize) -> Vec<u8> {
+  let mut out = Vec::new();
+  write_full_box(&mut out, b"ispe", 0, 0, |out| {
+    out.extend((width as u32).to_be_bytes());
+    out.extend((height as u32).to_be_bytes());
+  });
+  out
+}
+
+fn pixi(channels: usize, bit_depth: usize) -> Vec<u8> {
+  let mut out = Vec::new();
+  write_full_box(&mut out, b"pixi", 0, 0, |out| {
+    out.push(channels as u8);
+    out.extend(std::iter::repeat(bit_depth as u8).take(channels));
+  });
+  out
+}
+
+fn av1c_box(record: &[u8]) -> Vec<u8> {
+  let mut out = Vec::new();
+  write_box(&mut out, b"av1C", |out| out.extend(record));
+  out
+}
+
+/// Returns the color properties of the image: its `colr`, `mdcv` and `clli`
+/// boxes, if they are not all unspecified.
+fn color_properties(config: &EncoderConfig) -> Vec<Vec<u8>> {
+  [write_colr_box, write_mdcv_box, write_clli_box]
+    .into_iter()
+    .map(|write| {
+      let mut out = Vec::new();
+      write(&mut out, config);
+      out
+    })
+    .filter(|property| !property.is_empty())
+    .collect()
+}
+
+/// Returns the OBUs of a coded picture, without the temporal delimiter which
+/// should not be in the items.
+fn item_data(data: &[u8]) -> &[u8] {
+  data.strip_prefix(&TEMPORAL_DELIMITER[..]).unwrap_or(data)
+}
+
+struct Item<'a> {
+  item_type: &'a [u8; 4],
+  data: &'a [u8],
+  /// Indices of the properties of the item, and whether they are essential.
+  properties: Vec<(usize, bool)>,
+}
+
+/// Produces an AVIF image holding pictures coded as set by `config`.
+///
+/// `av1c` is the codec configuration record of the stream, as produced by
+/// [`Context::container_sequence_header`], and `alpha_av1c` the one of the
+/// alpha auxiliary stream, if the tiles have an alpha plane.
+///
+/// Without `grid`, the image is made of a single tile.
+///
+/// [`Context::container_sequence_header`]: struct.Context.html#method.container_sequence_header
+///
+/// # Errors
+///
+/// Returns an `AvifError` if the number of tiles does not match the grid, if
+/// the grid does not match the size of the tiles, or if only some tiles have
+/// an alpha plane.
+///
+/// # Panics
+///
+/// Panics if the image is larger than 4 GiB.
+pub fn avif_image(
+  config: &EncoderConfig, av1c: &[u8], alpha_av1c: Option<&[u8]>,
+  tiles: &[AvifTile], grid: Option<&ImageGrid>,
+) -> Result<Vec<u8>, AvifError> {
+  let (rows, columns) = grid.map_or((1, 1), |grid| (grid.rows, grid.columns));
+  if !(1..=256).contains(&rows) || !(1..=256).contains(&columns) {
+    return Err(AvifError::InvalidGridLayout { rows, columns });
+  }
+  if tiles.len() != rows * columns {
+    return Err(AvifError::InvalidTileCount {
+      actual: tiles.len(),
+      expected: rows * columns,
+    });
+  }
+  if let Some(grid) = grid {
+    if (columns - 1) * config.width >= grid.width
+      || grid.width > columns * config.width
+      || (rows - 1) * config.height >= grid.height
+      || grid.height > rows * config.height
+    {
+      return Err(AvifError::InvalidGridSize {
+        width: grid.width,
+        height: grid.height,
+        rows,
+        columns,
+        tile_width: config.width,
+        tile_height: config.height,
+      });
+    }
+  }
+  let has_alpha = alpha_av1c.is_some();
+  if tiles.iter().any(|tile| tile.alpha_data.is_some() != has_alpha) {
+    return Err(AvifError::AlphaMismatch);
+  }
+
+  let grid_descriptor = grid.map(|grid| {
+    let large =
+      grid.width > u16::MAX as usize || grid.height > u16::MAX as usize;
+    let mut out = vec![0, u8::from(large)]; // version, flags
+    out.push((grid.rows - 1) as u8);
+    out.push((grid.columns - 1) as u8);
+    if large {
+      out.extend((grid.width as u32).to_be_bytes());
+      out.extend((grid.height as u32).to_be_bytes());
+    } else {
+      out.extend((grid.width as u16).to_be_bytes());
+      out.extend((grid.height as u16).to_be_bytes());
+    }
+    out
+  });
+
+  let mut properties = Vec::new();
+  let mut add_property = |property: Vec<u8>| {
+    properties.push(property);
+    properties.len()
+  };
+  let channels =
+    if config.chroma_sampling == ChromaSampling::Cs400 { 1 } else { 3 };
+  let tile_ispe = add_property(ispe(config.width, config.height));
+  let color_pixi = add_property(pixi(channels, config.bit_depth));
+  let color_av1c = add_property(av1c_box(av1c));
+  let colors: Vec<_> =
+    color_properties(config).into_iter().map(&mut add_property).collect();
+  let alpha_properties = alpha_av1c.map(|alpha_av1c| {
+    let mut auxc = Vec::new();
+    write_full_box(&mut auxc, b"auxC", 0, 0, |out| out.extend(ALPHA_URN));
+    (
+      add_property(pixi(1, config.bit_depth)),
+      add_property(av1c_box(alpha_av1c)),
+      add_property(auxc),
+    )
+  });
+  let grid_ispe = grid.map(|grid| add_property(ispe(grid.width, grid.height)));
+
+  // The primary item is the image, or its grid. It is followed by the tiles
+  // of the grid, then by the same items for the alpha plane.
+  let mut items = Vec::new();
+  let mut references = Vec::new();
+  let color_properties =
+    colors.iter().map(|&index| (index, false)).collect::<Vec<_>>();
+  if let (Some(descriptor), Some(grid_ispe)) = (&grid_descriptor, grid_ispe) {
+    let mut properties = vec![(grid_ispe, false), (color_pixi, false)];
+    properties.extend(&color_properties);
+    items.push(Item { item_type: b"grid", data: descriptor, properties });
+    let tile_ids = (2..).take(tiles.len()).collect::<Vec<u16>>();
+    references.push((b"dimg", 1, tile_ids));
+  }
+  for tile in tiles {
+    let mut properties =
+      vec![(tile_ispe, false), (color_pixi, false), (color_av1c, true)];
+    properties.extend(&color_properties);
+    items.push(Item {
+      item_type: b"av01",
+      data: item_data(tile.data),
+      properties,
+    });
+  }
+  if let Some((alpha_pixi, alpha_av1c, auxc)) = alpha_properties {
+    let alpha_id = items.len() as u16 + 1;
+    references.push((b"auxl", alpha_id, vec![1]));
+    if let (Some(descriptor), Some(grid_ispe)) = (&grid_descriptor, grid_ispe)
+    {
+      items.push(Item {
+        item_type: b"grid",
+        data: descriptor,
+        properties: vec![
+          (grid_ispe, false),
+          (alpha_pixi, false),
+          (auxc, true),
+        ],
+      });
+      let tile_ids = (alpha_id + 1..).take(tiles.len()).collect::<Vec<u16>>();
+      references.push((b"dimg", alpha_id, tile_ids));
+    }
+    for tile in tiles {
+      let mut properties =
+        vec![(tile_ispe, false), (alpha_pixi, false), (alpha_av1c, true)];
+      if grid.is_none() {
+        properties.push((auxc, true));
+      }
+      items.push(Item {
+        item_type: b"av01",
+        data: item_data(tile.alpha_data.unwrap()),
+        properties,
+      });
+    }
+  }
+
+  let mut ftyp = Vec::new();
+  write_box(&mut ftyp, b"ftyp", |out| {
+    out.extend(b"avif"); // major_brand
+    out.extend(0u32.to_be_bytes()); // minor_version
+    for brand in [b"avif", b"mif1", b"miaf"] {
+      out.extend(brand);
+    }
+  });
+
+  let write_meta = |out: &mut Vec<u8>, data_offset: u64| {
+    write_full_box(out, b"meta", 0, 0, |out| {
+      write_full_box(out, b"hdlr", 0, 0, |out| {
+        out.extend([0; 4]); // pre_defined
+        out.extend(b"pict"); // handler_type
+        out.extend([0; 12]); // reserved
+        out.push(0); // name
+      });
+      write_full_box(out, b"pitm", 0, 0, |out| {
+        out.extend(1u16.to_be_bytes()); // item_ID
+      });
+      write_full_box(out, b"iloc", 0, 0, |out| {
+        out.push(0x44); // offset_size, length_size
+        out.push(0); // base_offset_size, reserved
+        out.extend((items.len() as u16).to_be_bytes());
+        let mut offset = data_offset;
+        for (id, item) in (1u16..).zip(items.iter()) {
+          out.extend(id.to_be_bytes());
+          out.extend(0u16.to_be_bytes()); // data_reference_index
+          out.extend(1u16.to_be_bytes()); // extent_count
+          let extent_offset = u32::try_from(offset).expect("image too large");
+          out.extend(extent_offset.to_be_bytes());
+          out.extend((item.data.len() as u32).to_be_bytes());
+          offset += item.data.len() as u64;
+        }
+      });
+      write_full_box(out, b"iinf", 0, 0, |out| {
+        out.extend((items.len() as u16).to_be_bytes());
+        for (id, item) in (1u16..).zip(items.iter()) {
+          write_full_box(out, b"infe", 2, 0, |out| {
+            out.extend(id.to_be_bytes());
+            out.extend(0u16.to_be_bytes()); // item_protection_index
+            out.extend(item.item_type);
+            out.push(0); // item_name
+          });
+        }
+      });
+      if !references.is_empty() {
+        write_full_box(out, b"iref", 0, 0, |out| {
+          for (reference_type, from_id, to_ids) in references.iter() {
+            write_box(out, reference_type, |out| {
+              out.extend(from_id.to_be_bytes());
+              out.extend((to_ids.len() as u16).to_be_bytes());
+              for to_id in to_ids {
+                out.extend(to_id.to_be_bytes());
+              }
+            });
+          }
+        });
+      }
+      write_box(out, b"iprp", |out| {
+        write_box(out, b"ipco", |out| {
+          for property in properties.iter() {
+            out.extend(property);
+          }
+        });
+        write_full_box(out, b"ipma", 0, 0, |out| {
+          out.extend((items.len() as u32).to_be_bytes());
+          for (id, item) in (1u16..).zip(items.iter()) {
+            out.extend(id.to_be_bytes());
+            out.push(item.properties.len() as u8);
+            for &(index, essential) in item.properties.iter() {
+              out.push(u8::from(essential) << 7 | index as u8);
+            }
+          }
+        });
+      });
+    });
+  };
+
+  // The offsets are written on a fixed size, so the size of the meta box
+  // does not depend on them.
+  let mut meta = Vec::new();
+  write_meta(&mut meta, 0);
+  let data_len: usize = items.iter().map(|item| item.data.len()).sum();
+  let large_mdat = data_len + 8 > u32::MAX as usize;
+  let mdat_header_len = if large_mdat { 16 } else { 8 };
+  let data_offset = (ftyp.len() + meta.len() + mdat_header_len) as u64;
+  meta.clear();
+  write_meta(&mut meta, data_offset);
+
+  let mut out = ftyp;
+  out.extend(meta);
+  if large_mdat {
+    out.extend(1u32.to_be_bytes());
+    out.extend(b"mdat");
+    out.extend((data_len as u64 + 16).to_be_bytes());
+  } else {
+    out.extend((data_len as u32 + 8).to_be_bytes());
+    out.extend(b"mdat");
+  }
+  for item in items.iter() {
+    out.extend(item.data);
+  }
+  Ok(out)
+}
+
+impl<T: Pixel> Context<T> {
+  /// Produces an AVIF image holding still pictures coded by this context,
+  /// including their alpha planes if enabled.
+  ///
+  /// Without `grid`, the image is made of the single picture of `packets`.
+  /// See [`avif_image`] for the image made of a grid of tiles.
+  ///
+  /// [`avif_image`]: fn.avif_image.html
+  ///
+  /// # Errors
+  ///
+  /// Returns an `AvifError` if the number of packets does not match the grid,
+  /// or if the grid does not match the size of the frames.
+  pub fn avif_image(
+    &self, packets: &[Packet<T>], grid: Option<&ImageGrid>,
+  ) -> Result<Vec<u8>, AvifError> {
+    let tiles = packets.iter().map(AvifTile::from).collect::<Vec<_>>();
+    avif_image(
+      &self.config,
+      &self.container_sequence_header(),
+      self.alpha_container_sequence_header().as_deref(),
+      &tiles,
+      grid,
+    )
+  }
+}
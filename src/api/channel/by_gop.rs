@@ -77,6 +77,7 @@ impl<T: Pixel> SceneChange<T> {
     if new_gop {
       self.last_keyframe = self.processed;
     }
+    self.detector.forget_scene_labels_before(self.processed);
 
     if self.frames > self.pyramid_size {
       self.frames -= self.pyramid_size + 1;
@@ -327,6 +328,13 @@ impl Config {
       return Err(InvalidConfig::RateControlConfigurationMismatch);
     }
 
+    // The GOPs are encoded from their frames alone, without their parameters.
+    if self.enc.alpha_quantizer.is_some() {
+      return Err(InvalidConfig::InvalidOptionWithParallelGops(
+        "alpha_quantizer",
+      ));
+    }
+
     self.validate()?;
 
     // TODO: make it user-settable
@@ -355,7 +363,13 @@ impl Config {
     }
 
     let channel = (
-      FrameSender::new(frame_limit, send_frame, Arc::new(self.enc.clone())),
+      FrameSender::new(
+        frame_limit,
+        send_frame,
+        Arc::new(self.enc.clone()),
+        None,
+        false,
+      ),
       PacketReceiver {
         receiver: receive_packet,
         config: Arc::new(self.enc.clone()),
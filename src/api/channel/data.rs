@@ -8,18 +8,17 @@
 // PATENTS file, you can obtain it at www.aomedia.org/license/patent.
 
 use crate::api::color::*;
-use crate::api::config::EncoderConfig;
-use crate::api::context::RcData;
+use crate::api::config::{EncoderConfig, InvalidConfig};
+use crate::api::context::{sequence_header_inner, RcData};
 use crate::api::util::*;
 use crate::encoder::*;
 use crate::frame::*;
 use crate::util::Pixel;
 
-use bitstream_io::*;
 use crossbeam::channel::{Receiver, Sender};
 use thiserror::Error;
 
-use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 
 /// An error returned from the `send` methods.
@@ -153,7 +152,11 @@ impl RcDataSender {
 }
 
 /// Endpoint to receive current-pass statistics data
-pub struct RcDataReceiver(pub(crate) Receiver<RcData>);
+pub struct RcDataReceiver(
+  pub(crate) Receiver<RcData>,
+  /// The number of keyframes placed so far by the encoder.
+  pub(crate) Arc<AtomicUsize>,
+);
 
 impl RcDataReceiver {
   /// Attempts to receive a message from the channel without blocking.
@@ -198,8 +201,16 @@ impl RcDataReceiver {
     self.0.iter()
   }
 
-  pub const fn summary_size(&self) -> usize {
+  /// Size of the summary, as [`Context::rc_summary_size`].
+  ///
+  /// The summary lists the keyframes placed so far, so its size is only
+  /// final once the summary is received. Space reserved for it in front of
+  /// the frame data may have to be resized, as the `rav1e-ch` binary does.
+  ///
+  /// [`Context::rc_summary_size`]: crate::Context::rc_summary_size
+  pub fn summary_size(&self) -> usize {
     crate::rate::TWOPASS_HEADER_SZ
+      + self.1.load(Ordering::Relaxed) * crate::rate::TWOPASS_KEYFRAME_SZ
   }
 }
 
@@ -207,12 +218,18 @@ pub type PassDataChannel = (RcDataSender, RcDataReceiver);
 
 pub type FrameInput<T> = (Option<Arc<Frame<T>>>, Option<FrameParameters>);
 
+/// A new encoder configuration, along with the number of frames sent before
+/// it.
+pub(crate) type Reconfiguration = (u64, EncoderConfig);
+
 /// Endpoint to send frames
 pub struct FrameSender<T: Pixel> {
   sender: Sender<FrameInput<T>>,
   config: Arc<EncoderConfig>,
   limit: u64,
   count: u64,
+  reconfigure: Option<Sender<Reconfiguration>>,
+  multipass: bool,
 }
 
 // Proxy the crossbeam Sender
@@ -221,8 +238,9 @@ pub struct FrameSender<T: Pixel> {
 impl<T: Pixel> FrameSender<T> {
   pub(crate) fn new(
     limit: u64, sender: Sender<FrameInput<T>>, config: Arc<EncoderConfig>,
+    reconfigure: Option<Sender<Reconfiguration>>, multipass: bool,
   ) -> FrameSender<T> {
-    Self { sender, config, limit, count: 0 }
+    Self { sender, config, limit, count: 0, reconfigure, multipass }
   }
 
   /// # Errors
@@ -267,6 +285,36 @@ impl<T: Pixel> FrameSender<T> {
     self.sender.is_empty()
   }
   // TODO: proxy more methods
+
+  /// Changes the rate control and keyframe placement settings, starting
+  /// with the frames sent after this call.
+  ///
+  /// The settings taken from `enc` are the ones described in
+  /// [`Context::reconfigure`].
+  ///
+  /// [`Context::reconfigure`]: crate::Context::reconfigure
+  ///
+  /// # Errors
+  ///
+  /// Returns the errors of [`Context::reconfigure`], and
+  /// `InvalidConfig::InvalidReconfiguration` with parallel GOP encoding.
+  pub fn reconfigure(
+    &mut self, enc: EncoderConfig,
+  ) -> Result<(), InvalidConfig> {
+    let Some(reconfigure) = self.reconfigure.as_ref() else {
+      return Err(InvalidConfig::InvalidReconfiguration(
+        "parallel GOP encoding",
+      ));
+    };
+    self.config.validate_reconfiguration(&enc, self.multipass)?;
+    let mut config = (*self.config).clone();
+    config.reconfigure(&enc);
+    self.config = Arc::new(config);
+    // The encoder stopped on its own only if it failed, in which case the
+    // next frame sent reports it.
+    let _ = reconfigure.send((self.count, enc));
+    Ok(())
+  }
 }
 
 // Frame factory
@@ -280,6 +328,18 @@ impl<T: Pixel> FrameSender<T> {
       self.config.chroma_sampling,
     )
   }
+
+  /// Helper to create a new alpha plane, the size of the luma plane, to send
+  /// as the [`FrameParameters::alpha`] of a frame.
+  ///
+  /// [`FrameParameters::alpha`]: crate::data::FrameParameters::alpha
+  #[inline]
+  pub fn new_alpha_plane(&self) -> Plane<T> {
+    let [plane, ..] =
+      Frame::new(self.config.width, self.config.height, ChromaSampling::Cs400)
+        .planes;
+    plane
+  }
 }
 
 /// Endpoint to receive packets
@@ -347,31 +407,6 @@ impl<T: Pixel> PacketReceiver<T> {
   /// and usually indicates the system is out of memory.
   #[inline]
   pub fn container_sequence_header(&self) -> Vec<u8> {
-    fn sequence_header_inner(seq: &Sequence) -> io::Result<Vec<u8>> {
-      let mut buf = Vec::new();
-
-      {
-        let mut bw = BitWriter::endian(&mut buf, BigEndian);
-        bw.write_bit(true)?; // marker
-        bw.write(7, 1)?; // version
-        bw.write(3, seq.profile)?;
-        bw.write(5, 31)?; // level
-        bw.write_bit(false)?; // tier
-        bw.write_bit(seq.bit_depth > 8)?; // high_bitdepth
-        bw.write_bit(seq.bit_depth == 12)?; // twelve_bit
-        bw.write_bit(seq.chroma_sampling == ChromaSampling::Cs400)?; // monochrome
-        bw.write_bit(seq.chroma_sampling != ChromaSampling::Cs444)?; // chroma_subsampling_x
-        bw.write_bit(seq.chroma_sampling == ChromaSampling::Cs420)?; // chroma_subsampling_y
-        bw.write(2, 0)?; // chroma_sample_position
-        bw.write(3, 0)?; // reserved
-        bw.write_bit(false)?; // initial_presentation_delay_present
-
-        bw.write(4, 0)?; // reserved
-      }
-
-      Ok(buf)
-    }
-
     let seq = Sequence::new(&self.config);
 
     sequence_header_inner(&seq).unwrap()
@@ -19,9 +19,12 @@ use crate::rate::RCState;
 use crate::util::Pixel;
 
 use rayon::ThreadPool;
+use std::collections::{BTreeSet, VecDeque};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 
 mod data;
+use data::Reconfiguration;
 pub use data::{
   FrameInput, FrameSender, PacketReceiver, PassDataChannel, RcDataReceiver,
   RcDataSender, RecvError, SendError, TryRecvError, TrySendError,
@@ -177,7 +180,9 @@ impl Config {
 
 trait RcFirstPass {
   fn send_pass_data(&mut self, rc_state: &mut RCState);
-  fn send_pass_summary(&mut self, rc_state: &mut RCState);
+  fn send_pass_summary(
+    &mut self, rc_state: &mut RCState, keyframes: &BTreeSet<u64>,
+  );
 }
 
 trait RcSecondPass {
@@ -198,8 +203,10 @@ impl RcFirstPass for Sender<RcData> {
       );
     }
   }
-  fn send_pass_summary(&mut self, rc_state: &mut RCState) {
-    let data = rc_state.emit_summary();
+  fn send_pass_summary(
+    &mut self, rc_state: &mut RCState, keyframes: &BTreeSet<u64>,
+  ) {
+    let data = rc_state.emit_summary(keyframes);
     let data = data.to_vec().into_boxed_slice();
     self.send(RcData::Summary(data)).unwrap();
   }
@@ -211,9 +218,11 @@ impl RcFirstPass for Option<Sender<RcData>> {
       s.send_pass_data(rc_state)
     }
   }
-  fn send_pass_summary(&mut self, rc_state: &mut RCState) {
+  fn send_pass_summary(
+    &mut self, rc_state: &mut RCState, keyframes: &BTreeSet<u64>,
+  ) {
     if let Some(s) = self.as_mut() {
-      s.send_pass_summary(rc_state)
+      s.send_pass_summary(rc_state, keyframes)
     }
   }
 }
@@ -250,6 +259,29 @@ impl RcSecondPass for Option<Receiver<RcData>> {
   }
 }
 
+/// The reconfigurations received from the `FrameSender`, applied in order
+/// with the frames.
+struct Reconfigurations {
+  receiver: Receiver<Reconfiguration>,
+  pending: VecDeque<Reconfiguration>,
+}
+
+impl Reconfigurations {
+  /// Applies the reconfigurations sent before the next frame to be received
+  /// by `inner`.
+  fn apply<T: Pixel>(&mut self, inner: &mut ContextInner<T>) {
+    self.pending.extend(self.receiver.try_iter());
+    while self
+      .pending
+      .front()
+      .is_some_and(|&(sent_at, _)| sent_at <= inner.frame_count)
+    {
+      let (_, enc) = self.pending.pop_front().unwrap();
+      inner.reconfigure(&enc);
+    }
+  }
+}
+
 impl Config {
   #[allow(clippy::type_complexity)]
   fn new_channel_internal<T: Pixel>(
@@ -269,9 +301,13 @@ impl Config {
 
     let rc = &self.rate_control;
 
+    let keyframe_count = Arc::new(AtomicUsize::new(0));
     let (mut send_rc_pass1, rc_data_receiver) = if rc.emit_pass_data {
       let (send_rc_pass1, receive_rc_pass1) = unbounded();
-      (Some(send_rc_pass1), Some(RcDataReceiver(receive_rc_pass1)))
+      (
+        Some(send_rc_pass1),
+        Some(RcDataReceiver(receive_rc_pass1, keyframe_count.clone())),
+      )
     } else {
       (None, None)
     };
@@ -296,10 +332,22 @@ impl Config {
       (None, None, i32::MAX as u64)
     };
 
+    let (send_reconfigure, receive_reconfigure) = unbounded();
+    let mut reconfigurations = Reconfigurations {
+      receiver: receive_reconfigure,
+      pending: VecDeque::new(),
+    };
+
     let config = Arc::new(self.enc.clone());
 
     let channel = (
-      FrameSender::new(frame_limit, send_frame, config.clone()),
+      FrameSender::new(
+        frame_limit,
+        send_frame,
+        config.clone(),
+        Some(send_reconfigure),
+        rc.emit_pass_data || rc.summary.is_some(),
+      ),
       PacketReceiver { receiver: receive_packet, config },
     );
 
@@ -308,6 +356,7 @@ impl Config {
     let run = move || {
       for f in receive_frame.iter() {
         // info!("frame in {}", inner.frame_count);
+        reconfigurations.apply(&mut inner);
         while !inner.needs_more_fi_lookahead() {
           receive_rc_pass2.feed_pass_data(&mut inner).unwrap();
           // needs_more_fi_lookahead() should guard for missing output_frameno
@@ -324,6 +373,7 @@ impl Config {
             _ => unreachable!(),
           };
           if has_pass_data {
+            keyframe_count.store(inner.keyframes.len(), Ordering::Relaxed);
             send_rc_pass1.send_pass_data(&mut inner.rc_state);
           }
         }
@@ -332,6 +382,7 @@ impl Config {
         let _ = inner.send_frame(frame, params); // TODO make sure it cannot fail.
       }
 
+      reconfigurations.apply(&mut inner);
       inner.limit = Some(inner.frame_count);
       let _ = inner.send_frame(None, None);
 
@@ -351,11 +402,13 @@ impl Config {
         };
 
         if has_pass_data {
+          keyframe_count.store(inner.keyframes.len(), Ordering::Relaxed);
           send_rc_pass1.send_pass_data(&mut inner.rc_state);
         }
       }
 
-      send_rc_pass1.send_pass_summary(&mut inner.rc_state);
+      keyframe_count.store(inner.keyframes.len(), Ordering::Relaxed);
+      send_rc_pass1.send_pass_summary(&mut inner.rc_state, &inner.keyframes);
     };
 
     if let Some(pool) = pool {
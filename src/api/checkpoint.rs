@@ -0,0 +1,71 @@
+// Copyright (c) 2018-2022, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+#![deny(missing_docs)]
+
+use crate::rate::{QuantizerParameters, RCState};
+use crate::scenechange::SceneDetectorHistory;
+use crate::serialize::{Deserialize, Serialize};
+
+/// The state of an encode before the keyframe starting one of its GOPs,
+/// from which an interrupted encode can be resumed.
+///
+/// A checkpoint is returned by [`Context::checkpoint`], and with the
+/// `serialize` feature it can be saved to a file. An encode resumed with
+/// [`Config::new_context_from_checkpoint`] produces the same packets as
+/// the original one from the keyframe on.
+///
+/// [`Context::checkpoint`]: struct.Context.html#method.checkpoint
+/// [`Config::new_context_from_checkpoint`]:
+///   struct.Config.html#method.new_context_from_checkpoint
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Checkpoint {
+  /// The input frame of the keyframe.
+  pub(crate) input_frameno: u64,
+  /// The number of input frames the encoder had received.
+  pub(crate) frame_count: u64,
+  /// Whether the encoder had been flushed.
+  pub(crate) flushed: bool,
+  pub(crate) frames_processed: u64,
+  pub(crate) keyframes: Vec<u64>,
+  pub(crate) keyframes_forced: Vec<u64>,
+  pub(crate) keyframes_replay_end: u64,
+  pub(crate) max_key_frame_intervals: Vec<(u64, u64)>,
+  pub(crate) next_lookahead_frame: u64,
+  pub(crate) maybe_prev_log_base_q: Option<i64>,
+  pub(crate) lookahead_qps: Vec<(u64, QuantizerParameters)>,
+  pub(crate) rc_state: RCState,
+  pub(crate) scene_detector: SceneDetectorHistory,
+}
+
+impl Checkpoint {
+  /// Returns the number of the input frame of the keyframe, the first frame
+  /// encoded by a resumed encode.
+  pub const fn input_frameno(&self) -> u64 {
+    self.input_frameno
+  }
+
+  /// Returns the number of input frames the encoder had received.
+  ///
+  /// The frames from [`input_frameno`] up to this number must be sent to a
+  /// resumed encoder before receiving its first packet.
+  ///
+  /// [`input_frameno`]: #method.input_frameno
+  pub const fn frame_count(&self) -> u64 {
+    self.frame_count
+  }
+
+  /// Returns whether the encoder had been flushed, in which case a resumed
+  /// encoder must be flushed as well after sending it the frames up to
+  /// [`frame_count`].
+  ///
+  /// [`frame_count`]: #method.frame_count
+  pub const fn is_flushed(&self) -> bool {
+    self.flushed
+  }
+}
@@ -10,7 +10,7 @@
 use itertools::*;
 
 use crate::api::color::*;
-use crate::api::config::GrainTableSegment;
+use crate::api::config::{GrainTableSegment, Zone};
 use crate::api::{Rational, SpeedSettings};
 use crate::encoder::Tune;
 use crate::serialize::{Deserialize, Serialize};
@@ -21,6 +21,9 @@ use std::fmt;
 pub(crate) const MAX_RDO_LOOKAHEAD_FRAMES: usize = usize::MAX - 1;
 // Due to the math in RCState::new() regarding the reservoir frame delay.
 pub(crate) const MAX_MAX_KEY_FRAME_INTERVAL: u64 = i32::MAX as u64 / 3;
+// Matches the range of aomenc's --denoise-noise-level.
+pub(crate) const MAX_DENOISE_NOISE_LEVEL: u8 = 50;
+pub(crate) const MAX_DENOISE_TEMPORAL_RADIUS: u8 = 3;
 
 /// Encoder settings which impact the produced bitstream.
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -74,6 +77,15 @@ pub struct EncoderConfig {
   pub min_key_frame_interval: u64,
   /// The *maximum* interval between two keyframes
   pub max_key_frame_interval: u64,
+  /// Flag to enable open GOPs.
+  ///
+  /// Keyframes placed at least `max_key_frame_interval` frames after the
+  /// previous keyframe, or forced, stay keyframes. When they end a
+  /// re-ordering group, they are coded ahead as forward keyframes, which
+  /// the frames preceding them in display order may reference. The others
+  /// are coded as intra-only frames. Requires frame re-ordering, i.e. not
+  /// low latency mode.
+  pub open_gop: bool,
   /// The number of temporal units over which to distribute the reservoir
   /// usage.
   pub reservoir_frame_delay: Option<i32>,
@@ -85,12 +97,55 @@ pub struct EncoderConfig {
   pub quantizer: usize,
   /// The minimum allowed base quantizer to use in bitrate mode.
   pub min_quantizer: u8,
+  /// The base quantizer of the alpha auxiliary stream, or `None` to not
+  /// encode one.
+  ///
+  /// The alpha planes given in the [`FrameParameters`] are coded as a
+  /// monochrome stream with the same frame structure as the main one, carried
+  /// by the same packets. Quantizer 0 codes them losslessly.
+  ///
+  /// [`FrameParameters`]: ../struct.FrameParameters.html
+  pub alpha_quantizer: Option<usize>,
   /// The target bitrate for the bitrate mode.
   pub bitrate: i32,
+  /// Allow dropping frames to stay within the target bitrate.
+  ///
+  /// Only available in single-pass low latency mode with a target bitrate.
+  /// A dropped frame is replaced by a repeat of the previously shown frame.
+  pub drop_frames: bool,
   /// Metric to tune the quality for.
   pub tune: Tune,
   /// Parameters for grain synthesis.
   pub film_grain_params: Option<Vec<GrainTableSegment>>,
+  /// Standard deviation of the noise removed from the source frames, on the
+  /// 8-bit scale, or 0 to not denoise them.
+  ///
+  /// The denoised frames are the ones encoded.
+  pub denoise_noise_level: u8,
+  /// Number of frames on each side of a source frame whose matching blocks
+  /// are blended into it when denoising, or 0 to denoise each frame on its
+  /// own.
+  ///
+  /// Each frame is only queued for encoding once the following frames it
+  /// needs were received.
+  pub denoise_temporal_radius: u8,
+  /// Whether the grain synthesis parameters are estimated from the noise
+  /// removed by denoising, for each GOP. [`film_grain_params`] must then not
+  /// be set.
+  ///
+  /// [`film_grain_params`]: #structfield.film_grain_params
+  pub denoise_grain_synthesis: bool,
+  /// Input frames to encode as keyframes, e.g. chapter points.
+  ///
+  /// These are placed regardless of the minimum keyframe interval, in
+  /// addition to the ones placed by scene detection.
+  pub forced_keyframes: Option<Vec<u64>>,
+  /// Frame ranges encoded with their own settings.
+  ///
+  /// Zones must be sorted by [`start_frame`] and must not overlap.
+  ///
+  /// [`start_frame`]: struct.Zone.html#structfield.start_frame
+  pub zones: Option<Vec<Zone>>,
   /// Number of tiles horizontally. Must be a power of two.
   ///
   /// Overridden by [`tiles`], if present.
@@ -160,13 +215,21 @@ impl EncoderConfig {
 
       min_key_frame_interval: 12,
       max_key_frame_interval: 240,
+      open_gop: false,
       min_quantizer: 0,
       reservoir_frame_delay: None,
       low_latency: false,
       quantizer: 100,
+      alpha_quantizer: None,
       bitrate: 0,
+      drop_frames: false,
       tune: Tune::default(),
       film_grain_params: None,
+      denoise_noise_level: 0,
+      denoise_temporal_radius: 1,
+      denoise_grain_synthesis: true,
+      forced_keyframes: None,
+      zones: None,
       tile_cols: 0,
       tile_rows: 0,
       tiles: 0,
@@ -238,6 +301,29 @@ impl EncoderConfig {
       .unwrap_or(false)
   }
 
+  /// Returns the zone covering the input frame `input_frameno`, if any.
+  pub fn zone_at(&self, input_frameno: u64) -> Option<&Zone> {
+    self
+      .zones
+      .as_ref()
+      .and_then(|zones| zones.iter().find(|zone| zone.contains(input_frameno)))
+  }
+
+  /// Returns the input frames forced to be keyframes, by
+  /// [`forced_keyframes`] or by zones.
+  ///
+  /// [`forced_keyframes`]: #structfield.forced_keyframes
+  pub(crate) fn forced_keyframes(&self) -> impl Iterator<Item = u64> + '_ {
+    self.forced_keyframes.iter().flatten().copied().chain(
+      self
+        .zones
+        .iter()
+        .flatten()
+        .filter(|zone| zone.force_keyframe)
+        .map(|zone| zone.start_frame),
+    )
+  }
+
   pub(crate) fn get_film_grain_at(
     &self, timestamp: u64,
   ) -> Option<&GrainTableSegment> {
@@ -264,11 +350,20 @@ impl fmt::Display for EncoderConfig {
     let pairs = [
       ("keyint_min", self.min_key_frame_interval.to_string()),
       ("keyint_max", self.max_key_frame_interval.to_string()),
+      ("open_gop", self.open_gop.to_string()),
       ("quantizer", self.quantizer.to_string()),
       ("bitrate", self.bitrate.to_string()),
       ("min_quantizer", self.min_quantizer.to_string()),
+      (
+        "alpha_quantizer",
+        self.alpha_quantizer.map_or("none".to_string(), |q| q.to_string()),
+      ),
       ("low_latency", self.low_latency.to_string()),
+      ("drop_frames", self.drop_frames.to_string()),
       ("tune", self.tune.to_string()),
+      ("denoise_noise_level", self.denoise_noise_level.to_string()),
+      ("denoise_temporal_radius", self.denoise_temporal_radius.to_string()),
+      ("denoise_grain_synthesis", self.denoise_grain_synthesis.to_string()),
       (
         "rdo_lookahead_frames",
         self.speed_settings.rdo_lookahead_frames.to_string(),
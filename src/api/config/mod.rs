@@ -11,8 +11,12 @@ use thiserror::Error;
 
 use rayon::{ThreadPool, ThreadPoolBuilder};
 use std::sync::Arc;
+use std::{mem, ptr};
 
-use crate::api::{ChromaSampling, Context, ContextInner, PixelRange};
+use crate::api::{
+  Checkpoint, ChromaSampling, Context, ContextInner, PixelRange,
+};
+use crate::encoder::TIMESTAMP_BASE_UNIT;
 use crate::util::Pixel;
 
 mod encoder;
@@ -29,6 +33,9 @@ pub use rate::{RateControlConfig, RateControlSummary};
 mod speedsettings;
 pub use speedsettings::*;
 
+mod zone;
+pub use zone::*;
+
 pub use crate::tiling::TilingInfo;
 
 /// Enumeration of possible invalid configuration errors.
@@ -73,6 +80,14 @@ pub enum InvalidConfig {
     /// The maximal supported value.
     max: u64,
   },
+  /// Minimal keyframe interval is greater than the maximal one.
+  #[error("invalid min keyframe interval {min} (expected <= {max})")]
+  InvalidKeyFrameInterval {
+    /// The minimal keyframe interval.
+    min: u64,
+    /// The maximal keyframe interval.
+    max: u64,
+  },
   /// Tile columns is invalid.
   #[error("invalid tile cols {0} (expected power of 2)")]
   InvalidTileCols(usize),
@@ -108,10 +123,57 @@ pub enum InvalidConfig {
   #[error("invalid option {0} specified with still picture mode")]
   InvalidOptionWithStillPicture(&'static str),
 
+  /// An option unsupported by parallel GOP encoding was enabled along with it.
+  #[error("invalid option {0} specified with parallel GOP encoding")]
+  InvalidOptionWithParallelGops(&'static str),
+
   /// The rate control needs a target bitrate in order to produce results
   #[error("The rate control requires a target bitrate")]
   TargetBitrateNeeded,
 
+  /// Frame dropping was enabled outside of single-pass low latency bitrate
+  /// mode.
+  #[error(
+    "frame dropping requires single-pass low latency mode with a target bitrate"
+  )]
+  InvalidFrameDropping,
+
+  /// The quantizer of the alpha auxiliary stream is invalid.
+  #[error("invalid alpha quantizer {0} (expected <= 255)")]
+  InvalidAlphaQuantizer(usize),
+
+  /// Open GOPs were enabled in low latency mode.
+  #[error("open GOPs require frame re-ordering (no low latency mode)")]
+  InvalidOpenGop,
+
+  /// The denoising noise level is invalid.
+  #[error("invalid denoise noise level {actual} (expected <= {max})")]
+  InvalidDenoiseNoiseLevel {
+    /// The actual value.
+    actual: u8,
+    /// The maximal supported value.
+    max: u8,
+  },
+
+  /// The temporal radius of the denoising is invalid.
+  #[error("invalid denoise temporal radius {actual} (expected <= {max})")]
+  InvalidDenoiseTemporalRadius {
+    /// The actual value.
+    actual: u8,
+    /// The maximal supported value.
+    max: u8,
+  },
+
+  /// The film grain was set to be estimated from the denoised source along
+  /// with user grain parameters,
+  /// which would conflict with the estimated ones.
+  #[error("denoising estimates the film grain, which cannot also be given")]
+  InvalidDenoiseWithFilmGrain,
+
+  /// A setting fixed for the whole encode was changed by a reconfiguration.
+  #[error("{0} cannot be reconfigured during an encode")]
+  InvalidReconfiguration(&'static str),
+
   /// The configuration
   #[error("Mismatch in the rate control configuration")]
   RateControlConfigurationMismatch,
@@ -120,6 +182,26 @@ pub enum InvalidConfig {
   #[error("Mismatch in the color configuration")]
   ColorConfigurationMismatch,
 
+  /// A zone covers an empty frame range or overlaps the previous zone.
+  #[error(
+    "invalid zone range {start}..{end} (expected non-empty, sorted and not overlapping)"
+  )]
+  InvalidZoneRange {
+    /// The first frame of the zone.
+    start: u64,
+    /// The frame following the last one of the zone.
+    end: u64,
+  },
+  /// The quantizer of a zone is invalid.
+  #[error("invalid zone quantizer {0} (expected <= 255)")]
+  InvalidZoneQuantizer(usize),
+  /// The bitrate multiplier of the zone starting at the given frame is
+  /// invalid.
+  #[error(
+    "invalid bitrate multiplier for zone starting at {0} (expected >= 0.01, <= 100)"
+  )]
+  InvalidZoneBitrateMultiplier(u64),
+
   /// The specified level is undefined in the current version of AV1.
   #[error("Specified level is undefined")]
   LevelUndefined,
@@ -178,10 +260,27 @@ impl Config {
   ///
   /// The default configuration is single pass
   pub const fn with_rate_control(
-    mut self, rate_control: RateControlConfig,
+    self, rate_control: RateControlConfig,
   ) -> Self {
-    self.rate_control = rate_control;
-    self
+    // SAFETY: The fields are moved out of the configuration, which is then
+    // forgotten instead of dropped.
+    let enc = unsafe { ptr::read(&self.enc) };
+    // SAFETY: As above.
+    let pool = unsafe { ptr::read(&self.pool) };
+    let threads = self.threads;
+    #[cfg(feature = "unstable")]
+    let slots = self.slots;
+    // The destructor of a rate control summary cannot run in a const fn.
+    // Only a summary replaced by another one is not freed.
+    mem::forget(self);
+    Self {
+      enc,
+      rate_control,
+      threads,
+      pool,
+      #[cfg(feature = "unstable")]
+      slots,
+    }
   }
 
   #[cfg(feature = "unstable")]
@@ -230,12 +329,33 @@ impl Config {
       config.max_key_frame_interval,
     );
 
-    // FIXME: inter unsupported with 4:2:2 and 4:4:4 chroma sampling
-    let chroma_sampling = config.chroma_sampling;
+    if let Some(ref mut zones) = config.zones {
+      let base_speed_settings = config.speed_settings;
+      for zone in zones.iter_mut() {
+        zone.speed_settings = zone.merged_speed_settings(&base_speed_settings);
+      }
 
-    // FIXME: tx partition for intra not supported for chroma 422
-    if chroma_sampling == ChromaSampling::Cs422 {
-      config.speed_settings.transform.rdo_tx_decision = false;
+      // Zone grain parameters take precedence over the ones of the grain
+      // table, as the first segment matching a timestamp is the one used.
+      let time_base = config.time_base;
+      let frame_timestamp = |input_frameno: u64| {
+        input_frameno * TIMESTAMP_BASE_UNIT * time_base.num / time_base.den
+      };
+      let mut grain_segments: Vec<_> = zones
+        .iter()
+        .filter_map(|zone| {
+          zone.film_grain_params.as_ref().map(|params| GrainTableSegment {
+            start_time: frame_timestamp(zone.start_frame),
+            end_time: frame_timestamp(zone.end_frame),
+            ..params.clone()
+          })
+        })
+        .collect();
+      if !grain_segments.is_empty() {
+        grain_segments
+          .extend(config.film_grain_params.take().into_iter().flatten());
+        config.film_grain_params = Some(grain_segments);
+      }
     }
 
     let mut inner = ContextInner::new(&config);
@@ -243,13 +363,14 @@ impl Config {
     if let Some(ref s) = self.rate_control.summary {
       inner.rc_state.init_second_pass();
       inner.rc_state.setup_second_pass(s);
+      inner.replay_first_pass_keyframes(&s.keyframes, s.ntus as u64);
     }
 
     // First-pass parameters depend on whether second-pass is in effect.
     // So `init_first_pass` must follow `init_second_pass`.
     if self.rate_control.emit_pass_data {
       let maybe_pass1_log_base_q = (self.rate_control.summary.is_none())
-        .then(|| inner.rc_state.select_pass1_log_base_q(&inner, 0));
+        .then(|| inner.rc_state.select_pass1_log_base_q(&inner, 0, 0));
       inner.rc_state.init_first_pass(maybe_pass1_log_base_q);
     }
 
@@ -297,6 +418,33 @@ impl Config {
     Ok(Context { is_flushing: false, inner, pool, config })
   }
 
+  /// Creates a [`Context`] resuming the encode saved in `checkpoint`.
+  ///
+  /// This configuration, including the rate control, must be the one of
+  /// the interrupted encode. The frames from
+  /// [`Checkpoint::input_frameno`] up to [`Checkpoint::frame_count`] must
+  /// then be sent to the context, and the context flushed if
+  /// [`Checkpoint::is_flushed`], before receiving its first packet. With
+  /// pass data, the pass data following the one sent before the checkpoint
+  /// is expected.
+  ///
+  /// # Errors
+  ///
+  /// Returns `InvalidConfig` if the config is invalid.
+  ///
+  /// [`Context`]: struct.Context.html
+  /// [`Checkpoint::input_frameno`]:
+  ///   struct.Checkpoint.html#method.input_frameno
+  /// [`Checkpoint::frame_count`]: struct.Checkpoint.html#method.frame_count
+  /// [`Checkpoint::is_flushed`]: struct.Checkpoint.html#method.is_flushed
+  pub fn new_context_from_checkpoint<T: Pixel>(
+    &self, checkpoint: &Checkpoint,
+  ) -> Result<Context<T>, InvalidConfig> {
+    let mut ctx = self.new_context()?;
+    ctx.inner.restore(checkpoint);
+    Ok(ctx)
+  }
+
   /// Validates the configuration.
   ///
   /// # Errors
@@ -385,6 +533,40 @@ impl Config {
       return Err(InvalidSwitchFrameInterval(config.switch_frame_interval));
     }
 
+    if config.open_gop && config.low_latency {
+      return Err(InvalidOpenGop);
+    }
+
+    if let Some(quantizer) = config.alpha_quantizer {
+      if quantizer > 255 {
+        return Err(InvalidAlphaQuantizer(quantizer));
+      }
+    }
+
+    if config.denoise_noise_level > MAX_DENOISE_NOISE_LEVEL {
+      return Err(InvalidDenoiseNoiseLevel {
+        actual: config.denoise_noise_level,
+        max: MAX_DENOISE_NOISE_LEVEL,
+      });
+    }
+    if config.denoise_temporal_radius > MAX_DENOISE_TEMPORAL_RADIUS {
+      return Err(InvalidDenoiseTemporalRadius {
+        actual: config.denoise_temporal_radius,
+        max: MAX_DENOISE_TEMPORAL_RADIUS,
+      });
+    }
+    if config.denoise_noise_level > 0
+      && config.denoise_grain_synthesis
+      && (config.film_grain_params.is_some()
+        || config
+          .zones
+          .iter()
+          .flatten()
+          .any(|zone| zone.film_grain_params.is_some()))
+    {
+      return Err(InvalidDenoiseWithFilmGrain);
+    }
+
     if config.enable_timing_info && config.still_picture {
       return Err(InvalidOptionWithStillPicture("enable_timing_info"));
     }
@@ -403,6 +585,31 @@ impl Config {
       }
     }
 
+    if let Some(ref zones) = config.zones {
+      let mut prev_end = 0;
+      for zone in zones {
+        if zone.start_frame >= zone.end_frame || zone.start_frame < prev_end {
+          return Err(InvalidZoneRange {
+            start: zone.start_frame,
+            end: zone.end_frame,
+          });
+        }
+        prev_end = zone.end_frame;
+        if let Some(quantizer) = zone.quantizer {
+          if quantizer > 255 {
+            return Err(InvalidZoneQuantizer(quantizer));
+          }
+        }
+        if let Some(multiplier) = zone.bitrate_multiplier {
+          if !(MIN_ZONE_BITRATE_MULTIPLIER..=MAX_ZONE_BITRATE_MULTIPLIER)
+            .contains(&multiplier)
+          {
+            return Err(InvalidZoneBitrateMultiplier(zone.start_frame));
+          }
+        }
+      }
+    }
+
     if let Some(level_idx) = config.level_idx {
       if level_idx > 31 {
         return Err(LevelUndefined);
@@ -440,6 +647,15 @@ impl Config {
       return Err(TargetBitrateNeeded);
     }
 
+    if config.drop_frames
+      && (!config.low_latency
+        || config.bitrate <= 0
+        || rc.emit_pass_data
+        || rc.summary.is_some())
+    {
+      return Err(InvalidFrameDropping);
+    }
+
     Ok(())
   }
 
@@ -458,3 +674,102 @@ impl Config {
     Ok(seq.tiling)
   }
 }
+
+impl EncoderConfig {
+  /// Checks that the settings of `enc` which may change during an encode
+  /// are valid and that the others are left unchanged.
+  pub(crate) fn validate_reconfiguration(
+    &self, enc: &EncoderConfig, multipass: bool,
+  ) -> Result<(), InvalidConfig> {
+    use InvalidConfig::*;
+
+    if enc.width != self.width {
+      return Err(InvalidReconfiguration("width"));
+    }
+    if enc.height != self.height {
+      return Err(InvalidReconfiguration("height"));
+    }
+    if enc.bit_depth != self.bit_depth {
+      return Err(InvalidReconfiguration("bit_depth"));
+    }
+    if enc.chroma_sampling != self.chroma_sampling {
+      return Err(InvalidReconfiguration("chroma_sampling"));
+    }
+    if enc.time_base.num != self.time_base.num
+      || enc.time_base.den != self.time_base.den
+    {
+      return Err(InvalidReconfiguration("time_base"));
+    }
+    if enc.low_latency != self.low_latency {
+      return Err(InvalidReconfiguration("low_latency"));
+    }
+    if enc.still_picture != self.still_picture {
+      return Err(InvalidReconfiguration("still_picture"));
+    }
+    if enc.open_gop != self.open_gop {
+      return Err(InvalidReconfiguration("open_gop"));
+    }
+    if enc.denoise_noise_level != self.denoise_noise_level {
+      return Err(InvalidReconfiguration("denoise_noise_level"));
+    }
+    if enc.denoise_temporal_radius != self.denoise_temporal_radius {
+      return Err(InvalidReconfiguration("denoise_temporal_radius"));
+    }
+    if enc.denoise_grain_synthesis != self.denoise_grain_synthesis {
+      return Err(InvalidReconfiguration("denoise_grain_synthesis"));
+    }
+    if enc.alpha_quantizer != self.alpha_quantizer {
+      return Err(InvalidReconfiguration("alpha_quantizer"));
+    }
+
+    if enc.max_key_frame_interval > MAX_MAX_KEY_FRAME_INTERVAL {
+      return Err(InvalidMaxKeyFrameInterval {
+        actual: enc.max_key_frame_interval,
+        max: MAX_MAX_KEY_FRAME_INTERVAL,
+      });
+    }
+    // A maximal interval of 0 is infinite.
+    if enc.max_key_frame_interval != 0
+      && enc.min_key_frame_interval > enc.max_key_frame_interval
+    {
+      return Err(InvalidKeyFrameInterval {
+        min: enc.min_key_frame_interval,
+        max: enc.max_key_frame_interval,
+      });
+    }
+    if let Some(delay) = enc.reservoir_frame_delay {
+      if !(12..=131_072).contains(&delay) {
+        return Err(InvalidReservoirFrameDelay(delay));
+      }
+    }
+
+    // The rate targets of a multi-pass encode are set by its pass data.
+    if multipass
+      && (enc.bitrate != self.bitrate
+        || enc.quantizer != self.quantizer
+        || enc.min_quantizer != self.min_quantizer
+        || enc.reservoir_frame_delay != self.reservoir_frame_delay
+        || enc.drop_frames != self.drop_frames)
+    {
+      return Err(RateControlConfigurationMismatch);
+    }
+    if enc.drop_frames && (!enc.low_latency || enc.bitrate <= 0) {
+      return Err(InvalidFrameDropping);
+    }
+
+    Ok(())
+  }
+
+  /// Takes the settings of `enc` which may change during an encode.
+  pub(crate) fn reconfigure(&mut self, enc: &EncoderConfig) {
+    self.bitrate = enc.bitrate;
+    self.quantizer = enc.quantizer;
+    self.min_quantizer = enc.min_quantizer;
+    self.reservoir_frame_delay = enc.reservoir_frame_delay;
+    self.drop_frames = enc.drop_frames;
+    self.set_key_frame_interval(
+      enc.min_key_frame_interval,
+      enc.max_key_frame_interval,
+    );
+  }
+}
@@ -9,6 +9,8 @@
 
 use thiserror::Error;
 
+use std::mem;
+
 use crate::rate::*;
 
 /// Rate control errors
@@ -35,9 +37,26 @@ impl RateControlSummary {
   /// Deserializes a byte slice into a `RateControlSummary`
   pub(crate) fn from_slice(bytes: &[u8]) -> Result<Self, Error> {
     let mut de = RCDeserialize::default();
-    let _ = de.buffer_fill(bytes, 0, TWOPASS_HEADER_SZ);
+    let mut consumed = de.buffer_fill(bytes, 0, TWOPASS_HEADER_SZ);
+
+    let mut summary = de.parse_summary().map_err(Error::CorruptedSummary)?;
+    summary.keyframes.reserve_exact(summary.nkeyframes);
+    for _ in 0..summary.nkeyframes {
+      de.pass2_buffer_fill = 0;
+      de.pass2_buffer_pos = 0;
+      consumed = de.buffer_fill(bytes, consumed, TWOPASS_KEYFRAME_SZ);
+      if de.pass2_buffer_fill < TWOPASS_KEYFRAME_SZ {
+        return Err(Error::CorruptedSummary(
+          "Truncated keyframe list".to_string(),
+        ));
+      }
+      let keyframe = de
+        .parse_keyframe(summary.keyframes.last().copied(), summary.ntus)
+        .map_err(Error::CorruptedSummary)?;
+      summary.keyframes.push(keyframe);
+    }
 
-    de.parse_summary().map_err(Error::CorruptedSummary)
+    Ok(summary)
   }
 }
 
@@ -63,14 +82,22 @@ impl RateControlConfig {
   /// Set a rate control summary
   ///
   /// Enable the second pass encoding mode
-  pub const fn with_summary(mut self, summary: RateControlSummary) -> Self {
-    self.summary = Some(summary);
-    self
+  pub const fn with_summary(self, summary: RateControlSummary) -> Self {
+    let emit_pass_data = self.emit_pass_data;
+    // A summary owns its keyframe list, whose destructor cannot run in a
+    // const fn. Only a summary set twice is not freed.
+    mem::forget(self);
+    Self { emit_pass_data, summary: Some(summary) }
   }
 
   /// Emit the current pass data
   ///
-  /// The pass data will be used in a second pass encoding session
+  /// The pass data will be used in a second pass encoding session.
+  ///
+  /// Combined with a summary, the pass data of the second pass is emitted
+  /// in turn and can be used for a third pass, and so on.
+  /// From the third pass on, the quantizer of each frame is refined from its
+  /// size in the previous pass, bringing the total size closer to the target.
   pub const fn with_emit_data(mut self, emit: bool) -> Self {
     self.emit_pass_data = emit;
     self
@@ -16,7 +16,7 @@ use std::fmt;
 
 // NOTE: Add Structures at the end.
 /// Contains the speed settings.
-#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
 #[non_exhaustive]
 pub struct SpeedSettings {
   /// Enables inter-frames to have multiple reference frames.
@@ -194,7 +194,7 @@ impl SpeedSettings {
   }
 }
 
-#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
 #[cfg_attr(test, derive(Default))]
 /// Speed settings related to transform size and type decision
 pub struct TransformSpeedSettings {
@@ -222,7 +222,7 @@ pub struct TransformSpeedSettings {
   pub enable_inter_tx_split: bool,
 }
 
-#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
 #[cfg_attr(test, derive(Default))]
 /// Speed settings related to partition decision
 pub struct PartitionSpeedSettings {
@@ -241,7 +241,7 @@ pub struct PartitionSpeedSettings {
   pub partition_range: PartitionRange,
 }
 
-#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
 #[cfg_attr(test, derive(Default))]
 /// Speed settings related to motion estimation and motion vector selection
 pub struct MotionSpeedSettings {
@@ -260,7 +260,7 @@ pub struct MotionSpeedSettings {
   pub me_allow_full_search: bool,
 }
 
-#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
 #[cfg_attr(test, derive(Default))]
 /// Speed settings related to intra prediction mode selection
 pub struct PredictionSpeedSettings {
@@ -274,7 +274,7 @@ pub struct PredictionSpeedSettings {
 }
 
 /// Range of block sizes to use.
-#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
 pub struct PartitionRange {
   pub(crate) min: BlockSize,
   pub(crate) max: BlockSize,
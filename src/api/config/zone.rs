@@ -0,0 +1,89 @@
+// Copyright (c) 2020-2022, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+use crate::api::config::GrainTableSegment;
+use crate::api::SpeedSettings;
+use crate::encoder::Tune;
+use crate::serialize::{Deserialize, Serialize};
+
+/// Smallest accepted value for [`Zone::bitrate_multiplier`].
+pub(crate) const MIN_ZONE_BITRATE_MULTIPLIER: f64 = 0.01;
+/// Largest accepted value for [`Zone::bitrate_multiplier`].
+pub(crate) const MAX_ZONE_BITRATE_MULTIPLIER: f64 = 100.0;
+
+/// A range of input frames encoded with settings overriding the ones of the
+/// enclosing [`EncoderConfig`].
+///
+/// Frame numbers count the frames sent to the encoder, starting at 0.
+/// Every field left to `None` inherits the value of the base configuration.
+///
+/// [`EncoderConfig`]: struct.EncoderConfig.html
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Zone {
+  /// First input frame of the zone.
+  pub start_frame: u64,
+  /// Input frame following the last one of the zone (exclusive).
+  pub end_frame: u64,
+  /// The base quantizer to use within the zone.
+  ///
+  /// Takes precedence over the bitrate target, i.e. frames of the zone are
+  /// coded at this quantizer even in bitrate mode.
+  pub quantizer: Option<usize>,
+  /// Scales the rate allocated to the frames of the zone in bitrate mode,
+  /// e.g. `0.5` asks for roughly half the bits.
+  ///
+  /// Must be in the range 0.01–100. Ignored in quantizer mode.
+  pub bitrate_multiplier: Option<f64>,
+  /// Settings which affect the encoding speed vs. quality trade-off.
+  ///
+  /// Settings which apply to the whole sequence (`multiref`, `cdef`, `lrf`,
+  /// `rdo_lookahead_frames`, `scene_detection_mode` and
+  /// `tx_domain_distortion`) are always taken from the base configuration.
+  pub speed_settings: Option<SpeedSettings>,
+  /// Metric to tune the quality for.
+  pub tune: Option<Tune>,
+  /// Force a keyframe on the first frame of the zone.
+  pub force_keyframe: bool,
+  /// Parameters for grain synthesis within the zone.
+  ///
+  /// The `start_time` and `end_time` of the segment are ignored and derived
+  /// from the frame range of the zone instead.
+  pub film_grain_params: Option<GrainTableSegment>,
+}
+
+impl Zone {
+  /// Creates a zone covering the input frames `start_frame..end_frame`
+  /// without any override.
+  pub fn new(start_frame: u64, end_frame: u64) -> Self {
+    Zone { start_frame, end_frame, ..Default::default() }
+  }
+
+  /// Returns whether `input_frameno` is covered by the zone.
+  #[inline]
+  pub const fn contains(&self, input_frameno: u64) -> bool {
+    input_frameno >= self.start_frame && input_frameno < self.end_frame
+  }
+
+  /// Returns the zone speed settings merged with the settings of `base`
+  /// that cannot change within a sequence.
+  pub(crate) fn merged_speed_settings(
+    &self, base: &SpeedSettings,
+  ) -> Option<SpeedSettings> {
+    self.speed_settings.map(|mut speed_settings| {
+      speed_settings.multiref = base.multiref;
+      speed_settings.cdef = base.cdef;
+      speed_settings.lrf = base.lrf;
+      speed_settings.rdo_lookahead_frames = base.rdo_lookahead_frames;
+      speed_settings.scene_detection_mode = base.scene_detection_mode;
+      speed_settings.transform.tx_domain_distortion =
+        base.transform.tx_domain_distortion;
+      speed_settings
+    })
+  }
+}
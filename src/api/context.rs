@@ -14,6 +14,7 @@ use std::sync::Arc;
 
 use bitstream_io::*;
 
+use crate::api::checkpoint::Checkpoint;
 use crate::api::color::*;
 use crate::api::config::*;
 use crate::api::internal::*;
@@ -56,6 +57,43 @@ impl<T: Pixel> Context<T> {
     )
   }
 
+  /// Allocates and returns a new alpha plane, the size of the luma plane.
+  ///
+  /// Once filled, it is sent along with its frame as the
+  /// [`FrameParameters::alpha`] of an encoder configured with an
+  /// `alpha_quantizer`.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use rav1e::prelude::*;
+  ///
+  /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+  /// let mut enc = EncoderConfig::default();
+  /// enc.alpha_quantizer = Some(50);
+  /// let cfg = Config::new().with_encoder_config(enc);
+  /// let mut ctx: Context<u8> = cfg.new_context()?;
+  /// let frame = ctx.new_frame();
+  /// let mut alpha = ctx.new_alpha_plane();
+  /// alpha.data.fill(128);
+  /// let info = FrameParameters {
+  ///   alpha: Some(AlphaPlane::new(alpha)),
+  ///   ..Default::default()
+  /// };
+  /// ctx.send_frame((frame, info))?;
+  /// # Ok(())
+  /// # }
+  /// ```
+  ///
+  /// [`FrameParameters::alpha`]: struct.FrameParameters.html#structfield.alpha
+  #[inline]
+  pub fn new_alpha_plane(&self) -> Plane<T> {
+    let [plane, ..] =
+      Frame::new(self.config.width, self.config.height, ChromaSampling::Cs400)
+        .planes;
+    plane
+  }
+
   /// Sends the frame for encoding.
   ///
   /// This method adds the frame into the frame queue and runs the first passes
@@ -144,8 +182,10 @@ impl<T: Pixel> Context<T> {
   /// [`receive_packet`], if any (i.e., [`EncoderStatus::Encoded`] was
   /// returned).  It needs to be called once more after
   /// [`EncoderStatus::LimitReached`] is returned, to retrieve the header that
-  /// should be written to the front of the stats file (overwriting the
-  /// placeholder header that was emitted at the start of encoding).
+  /// should be written to the front of the stats file (replacing the
+  /// placeholder header that was emitted at the start of encoding). The
+  /// header lists the keyframes placed during the encode, so it is usually
+  /// larger than the placeholder.
   ///
   /// It is still safe to call this function when [`receive_packet`] returns
   /// any other error. It will return `None` instead of returning a duplicate
@@ -157,7 +197,8 @@ impl<T: Pixel> Context<T> {
   /// enum.EncoderStatus.html#variant.LimitReached
   #[inline]
   pub fn twopass_out(&mut self) -> Option<&[u8]> {
-    self.inner.rc_state.twopass_out(self.inner.done_processing())
+    let done_processing = self.inner.done_processing();
+    self.inner.rc_state.twopass_out(done_processing, &self.inner.keyframes)
   }
 
   /// Returns the number of bytes of the stats file needed before the next
@@ -184,15 +225,29 @@ impl<T: Pixel> Context<T> {
   /// (including the very first one) until no bytes are consumed, or until
   /// [`twopass_bytes_needed`] returns `0`.
   ///
+  /// The keyframes placed in the first pass are replayed once the stats
+  /// header has been read, so it should be provided before the first call to
+  /// [`send_frame`].
+  ///
   /// [`receive_packet`]: #method.receive_packet
   /// [`twopass_bytes_needed`]: #method.twopass_bytes_needed
+  /// [`send_frame`]: #method.send_frame
   ///
   /// # Errors
   ///
   /// Returns `Err(EncoderStatus::Failure)` if the two-pass data is invalid.
   #[inline]
   pub fn twopass_in(&mut self, buf: &[u8]) -> Result<usize, EncoderStatus> {
-    self.inner.rc_state.twopass_in(Some(buf)).or(Err(EncoderStatus::Failure))
+    let consumed = self
+      .inner
+      .rc_state
+      .twopass_in(Some(buf))
+      .or(Err(EncoderStatus::Failure))?;
+    if let Some(keyframes) = self.inner.rc_state.take_first_pass_keyframes() {
+      let nframes = self.inner.rc_state.ntus_total() as u64;
+      self.inner.replay_first_pass_keyframes(&keyframes, nframes);
+    }
+    Ok(consumed)
   }
 
   /// Encodes the next frame and returns the encoded data.
@@ -324,6 +379,77 @@ impl<T: Pixel> Context<T> {
     self.send_frame(None).unwrap();
   }
 
+  /// Changes the rate control and keyframe placement settings between
+  /// frames, without restarting the encoder.
+  ///
+  /// The `bitrate`, `quantizer`, `min_quantizer`, `reservoir_frame_delay`
+  /// and `drop_frames` fields of `enc` apply from the next frame to be
+  /// encoded. The `min_key_frame_interval` and `max_key_frame_interval`
+  /// fields apply to the frames not yet analyzed by the lookahead, the
+  /// current GOP keeps its maximum length.
+  /// The other fields are ignored.
+  ///
+  /// # Errors
+  ///
+  /// Returns [`InvalidConfig::InvalidReconfiguration`] if `enc` changes a
+  /// setting fixed for the whole encode, such as the frame size or the bit
+  /// depth, and [`InvalidConfig::RateControlConfigurationMismatch`] if it
+  /// changes the rate targets of an encode using pass data,
+  /// [`InvalidConfig::InvalidKeyFrameInterval`] if `min_key_frame_interval`
+  /// is greater than a non-zero `max_key_frame_interval`. The matching error
+  /// is returned if one of the other new values is invalid.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use rav1e::prelude::*;
+  ///
+  /// # fn main() -> Result<(), InvalidConfig> {
+  /// let mut enc = EncoderConfig::default();
+  /// enc.low_latency = true;
+  /// enc.bitrate = 500_000;
+  /// let cfg = Config::new().with_encoder_config(enc.clone());
+  /// let mut ctx: Context<u8> = cfg.new_context()?;
+  ///
+  /// enc.bitrate = 300_000;
+  /// ctx.reconfigure(enc.clone())?;
+  ///
+  /// enc.width *= 2;
+  /// assert_eq!(
+  ///   ctx.reconfigure(enc),
+  ///   Err(InvalidConfig::InvalidReconfiguration("width"))
+  /// );
+  /// # Ok(())
+  /// # }
+  /// ```
+  pub fn reconfigure(
+    &mut self, enc: EncoderConfig,
+  ) -> Result<(), InvalidConfig> {
+    self
+      .config
+      .validate_reconfiguration(&enc, self.inner.rc_state.is_multipass())?;
+    self.config.reconfigure(&enc);
+    self.inner.reconfigure(&enc);
+    Ok(())
+  }
+
+  /// Returns a checkpoint of the encode if the next call to
+  /// [`receive_packet`] encodes the keyframe starting a GOP, or `None`.
+  ///
+  /// Encodes with open GOPs, denoising or an alpha stream carry state
+  /// across keyframes, so they have no checkpoints.
+  ///
+  /// An interrupted encode is resumed with
+  /// [`Config::new_context_from_checkpoint`], using the configuration in
+  /// effect at the checkpoint.
+  ///
+  /// [`receive_packet`]: #method.receive_packet
+  /// [`Config::new_context_from_checkpoint`]:
+  ///   struct.Config.html#method.new_context_from_checkpoint
+  pub fn checkpoint(&self) -> Option<Checkpoint> {
+    self.inner.checkpoint()
+  }
+
   /// Produces a sequence header matching the current encoding context.
   ///
   /// Its format is compatible with the AV1 Matroska and ISOBMFF specification.
@@ -339,35 +465,60 @@ impl<T: Pixel> Context<T> {
   /// and usually indicates the system is out of memory.
   #[inline]
   pub fn container_sequence_header(&self) -> Vec<u8> {
-    fn sequence_header_inner(seq: &Sequence) -> io::Result<Vec<u8>> {
-      let mut buf = Vec::new();
+    let seq = Sequence::new(&self.config);
 
-      {
-        let mut bw = BitWriter::endian(&mut buf, BigEndian);
-        bw.write_bit(true)?; // marker
-        bw.write(7, 1)?; // version
-        bw.write(3, seq.profile)?;
-        bw.write(5, 31)?; // level
-        bw.write_bit(false)?; // tier
-        bw.write_bit(seq.bit_depth > 8)?; // high_bitdepth
-        bw.write_bit(seq.bit_depth == 12)?; // twelve_bit
-        bw.write_bit(seq.chroma_sampling == ChromaSampling::Cs400)?; // monochrome
-        bw.write_bit(seq.chroma_sampling != ChromaSampling::Cs444)?; // chroma_subsampling_x
-        bw.write_bit(seq.chroma_sampling == ChromaSampling::Cs420)?; // chroma_subsampling_y
-        bw.write(2, 0)?; // chroma_sample_position
-        bw.write(3, 0)?; // reserved
-        bw.write_bit(false)?; // initial_presentation_delay_present
+    sequence_header_inner(&seq).unwrap()
+  }
 
-        bw.write(4, 0)?; // reserved
-      }
+  /// Produces the sequence header of the alpha auxiliary stream, in the same
+  /// format as [`container_sequence_header`], if the stream is enabled.
+  ///
+  /// [`container_sequence_header`]: #method.container_sequence_header
+  ///
+  /// # Panics
+  ///
+  /// Panics if the header cannot be written in memory. This is unrecoverable,
+  /// and usually indicates the system is out of memory.
+  #[inline]
+  pub fn alpha_container_sequence_header(&self) -> Option<Vec<u8>> {
+    self.inner.alpha_sequence().map(|seq| sequence_header_inner(seq).unwrap())
+  }
+}
 
-      Ok(buf)
-    }
+/// Writes the AV1 codec configuration record of a sequence.
+pub(crate) fn sequence_header_inner(seq: &Sequence) -> io::Result<Vec<u8>> {
+  let mut buf = Vec::new();
 
-    let seq = Sequence::new(&self.config);
+  {
+    let mut bw = BitWriter::endian(&mut buf, BigEndian);
+    bw.write_bit(true)?; // marker
+    bw.write(7, 1)?; // version
+    bw.write(3, seq.profile)?;
+    bw.write(5, seq.level_idx[0])?; // seq_level_idx_0
+    bw.write_bit(seq.tier[0] != 0)?; // seq_tier_0
+    bw.write_bit(seq.bit_depth > 8)?; // high_bitdepth
+    bw.write_bit(seq.bit_depth == 12)?; // twelve_bit
+    bw.write_bit(seq.chroma_sampling == ChromaSampling::Cs400)?; // monochrome
+    bw.write_bit(seq.chroma_sampling != ChromaSampling::Cs444)?; // chroma_subsampling_x
+    bw.write_bit(matches!(
+      seq.chroma_sampling,
+      ChromaSampling::Cs420 | ChromaSampling::Cs400
+    ))?; // chroma_subsampling_y
+    bw.write(
+      2,
+      if seq.chroma_sampling == ChromaSampling::Cs420 {
+        seq.chroma_sample_position as u32
+      } else {
+        0
+      },
+    )?; // chroma_sample_position
+    bw.write(3, 0)?; // reserved
+    bw.write_bit(false)?; // initial_presentation_delay_present
 
-    sequence_header_inner(&seq).unwrap()
+    bw.write(4, 0)?; // reserved
   }
+
+  Ok(buf)
 }
 
 /// Rate Control Data
@@ -391,10 +542,14 @@ pub enum RcData {
 impl<T: Pixel> Context<T> {
   /// Return the Rate Control Summary Packet size
   ///
-  /// It is useful mainly to preserve space when saving
-  /// both Rate Control Summary and Frame Packets in a single file.
+  /// The summary lists the keyframes placed so far, so its size is only
+  /// final once the encoder is flushed. It used to be constant: space
+  /// reserved for the summary in front of the frame data with the size
+  /// returned before the end of the encode may have to be resized, as the
+  /// `rav1e` binary does.
   pub fn rc_summary_size(&self) -> usize {
     crate::rate::TWOPASS_HEADER_SZ
+      + self.inner.keyframes.len() * crate::rate::TWOPASS_KEYFRAME_SZ
   }
 
   /// Return the first pass data
@@ -411,7 +566,7 @@ impl<T: Pixel> Context<T> {
   pub fn rc_receive_pass_data(&mut self) -> Option<RcData> {
     if self.inner.done_processing() && self.inner.rc_state.pass1_data_retrieved
     {
-      let data = self.inner.rc_state.emit_summary();
+      let data = self.inner.rc_state.emit_summary(&self.inner.keyframes);
       Some(RcData::Summary(data.to_vec().into_boxed_slice()))
     } else if self.inner.rc_state.pass1_data_retrieved {
       None
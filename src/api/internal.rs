@@ -9,19 +9,25 @@
 #![deny(missing_docs)]
 
 use crate::activity::ActivityMask;
+use crate::api::alpha::AlphaStream;
+use crate::api::config::{GrainTableSegment, DEFAULT_GRAIN_SEED};
 use crate::api::lookahead::*;
 use crate::api::{
-  EncoderConfig, EncoderStatus, FrameType, Opaque, Packet, T35,
+  Checkpoint, EncoderConfig, EncoderStatus, FrameType, Opaque, Packet,
+  SceneLabel, Timecode, T35,
 };
 use crate::color::ChromaSampling::Cs400;
 use crate::cpu_features::CpuFeatureLevel;
+use crate::denoise::{estimate_film_grain, DctDenoiser};
 use crate::dist::get_satd;
 use crate::encoder::*;
 use crate::frame::*;
+use crate::header::ALL_REF_FRAMES_MASK;
+use crate::lrf::RestorationState;
 use crate::partition::*;
 use crate::rate::{
-  RCState, FRAME_NSUBTYPES, FRAME_SUBTYPE_I, FRAME_SUBTYPE_P,
-  FRAME_SUBTYPE_SEF,
+  scene_label_log_q_offset, QuantizerParameters, RCFrameStats, RCState,
+  FRAME_NSUBTYPES, FRAME_SUBTYPE_I, FRAME_SUBTYPE_P, FRAME_SUBTYPE_SEF,
 };
 use crate::scenechange::SceneChangeDetector;
 use crate::stats::EncoderStats;
@@ -32,6 +38,7 @@ use std::cmp;
 use std::collections::{BTreeMap, BTreeSet};
 use std::env;
 use std::fs;
+use std::io;
 use std::path::PathBuf;
 use std::sync::Arc;
 
@@ -56,6 +63,8 @@ pub struct InterConfig {
   /// Keyframes reset this interval.
   /// This MUST be a multiple of `group_input_len`.
   pub(crate) switch_frame_interval: u64,
+  /// Whether GOPs may start with forward keyframes or intra-only frames.
+  pub(crate) open_gop: bool,
 }
 
 impl InterConfig {
@@ -88,6 +97,7 @@ impl InterConfig {
       group_input_len,
       group_output_len,
       switch_frame_interval,
+      open_gop: enc_config.open_gop,
     }
   }
 
@@ -155,6 +165,14 @@ impl InterConfig {
     }
   }
 
+  /// Get the slots refreshed by an intra-only frame starting a GOP.
+  /// Unlike keyframes, intra-only frames may not refresh every slot, so the
+  ///  last one, which is never used by the re-ordering pyramid, is kept.
+  pub(crate) const fn get_intra_only_refresh_frame_flags(&self) -> u32 {
+    debug_assert!(3 + self.pyramid_depth < REF_FRAMES as u64 - 1);
+    ALL_REF_FRAMES_MASK & !(1 << (REF_FRAMES - 1))
+  }
+
   pub(crate) const fn get_show_frame(&self, idx_in_group_output: u64) -> bool {
     idx_in_group_output >= self.pyramid_depth
   }
@@ -236,21 +254,39 @@ pub(crate) struct ContextInner<T: Pixel> {
   /// A list of the `input_frameno` for keyframes in this encode.
   /// Needed so that we don't need to keep all of the `frame_invariants` in
   ///  memory for the whole life of the encode.
-  // TODO: Is this needed at all?
-  keyframes: BTreeSet<u64>,
-  // TODO: Is this needed at all?
+  pub(crate) keyframes: BTreeSet<u64>,
   keyframes_forced: BTreeSet<u64>,
+  /// Keyframes of input frames before this one are replayed from the first
+  /// pass, so scene detection is skipped for them.
+  keyframes_replay_end: u64,
+  /// Maps the first input frame analyzed with a maximum keyframe interval
+  /// to its value, as it may be reconfigured during the encode.
+  max_key_frame_intervals: BTreeMap<u64, u64>,
   /// A storage space for reordered frames.
   packet_data: Vec<u8>,
   /// Maps `output_frameno` to `gop_output_frameno_start`.
   gop_output_frameno_start: BTreeMap<u64, u64>,
   /// Maps `output_frameno` to `gop_input_frameno_start`.
   pub(crate) gop_input_frameno_start: BTreeMap<u64, u64>,
+  /// Maps `output_frameno` to the order hint of the frame starting its GOP,
+  ///  which is only non-zero for a GOP started by a forward keyframe.
+  gop_order_hint_start: BTreeMap<u64, u32>,
+  /// The `input_frameno` of the forward keyframes coded but not shown yet.
+  forward_keyframes: BTreeSet<u64>,
+  /// The `input_frameno` of the last GOP started by a keyframe rather than
+  ///  an intra-only frame.
+  last_keyframe_input_frameno: u64,
+  /// For each slot a non-reference flash did not refresh, the slot of the
+  ///  frame to refer to instead of its stale content.
+  flash_skipped_slots: [Option<u8>; REF_FRAMES],
   keyframe_detector: SceneChangeDetector<T>,
   pub(crate) config: Arc<EncoderConfig>,
   seq: Arc<Sequence>,
   pub(crate) rc_state: RCState,
   maybe_prev_log_base_q: Option<i64>,
+  /// The quantizers the lookahead motion search of each input frame was run
+  ///  with, which depend on the rate control state at the time.
+  lookahead_qps: BTreeMap<u64, QuantizerParameters>,
   /// The next `input_frameno` to be processed by lookahead.
   next_lookahead_frame: u64,
   /// The next `output_frameno` to be computed by lookahead.
@@ -259,14 +295,34 @@ pub(crate) struct ContextInner<T: Pixel> {
   opaque_q: BTreeMap<u64, Opaque>,
   /// Optional T35 metadata per frame
   t35_q: BTreeMap<u64, Box<[T35]>>,
+  timecode_q: BTreeMap<u64, Timecode>,
+  /// Denoiser of the source frames.
+  denoiser: Option<DctDenoiser>,
+  /// Source frames before denoising, kept until they are no longer needed
+  ///  to denoise the following frames or to estimate the film grain of
+  ///  their GOP.
+  noisy_frame_q: BTreeMap<u64, Arc<Frame<T>>>,
+  /// The next `input_frameno` to be denoised.
+  next_denoise_frame: u64,
+  /// The first `input_frameno` which may start a GOP whose film grain is
+  ///  not estimated yet.
+  next_grain_estimation_frame: u64,
+  /// Encoder of the alpha auxiliary stream, if enabled.
+  alpha: Option<AlphaStream<T>>,
 }
 
 impl<T: Pixel> ContextInner<T> {
+  /// Returns the sequence of the alpha auxiliary stream, if enabled.
+  pub(crate) fn alpha_sequence(&self) -> Option<&Sequence> {
+    self.alpha.as_ref().map(AlphaStream::sequence)
+  }
+
   pub fn new(enc: &EncoderConfig) -> Self {
     // initialize with temporal delimiter
     let packet_data = TEMPORAL_DELIMITER.to_vec();
     let mut keyframes = BTreeSet::new();
     keyframes.insert(0);
+    let keyframes_forced = enc.forced_keyframes().collect();
 
     let maybe_ac_qi_max =
       if enc.quantizer < 255 { Some(enc.quantizer as u8) } else { None };
@@ -284,10 +340,19 @@ impl<T: Pixel> ContextInner<T> {
       frame_q: BTreeMap::new(),
       frame_data: BTreeMap::new(),
       keyframes,
-      keyframes_forced: BTreeSet::new(),
+      keyframes_forced,
+      keyframes_replay_end: 0,
+      max_key_frame_intervals: BTreeMap::from([(
+        0,
+        enc.max_key_frame_interval,
+      )]),
       packet_data,
       gop_output_frameno_start: BTreeMap::new(),
       gop_input_frameno_start: BTreeMap::new(),
+      gop_order_hint_start: BTreeMap::new(),
+      forward_keyframes: BTreeSet::new(),
+      last_keyframe_input_frameno: 0,
+      flash_skipped_slots: [None; REF_FRAMES],
       keyframe_detector: SceneChangeDetector::new(
         enc.clone(),
         CpuFeatureLevel::default(),
@@ -306,20 +371,179 @@ impl<T: Pixel> ContextInner<T> {
         enc.min_quantizer,
         enc.max_key_frame_interval as i32,
         enc.reservoir_frame_delay,
+        enc.drop_frames,
       ),
       maybe_prev_log_base_q: None,
+      lookahead_qps: BTreeMap::new(),
       next_lookahead_frame: 1,
       next_lookahead_output_frameno: 0,
       opaque_q: BTreeMap::new(),
       t35_q: BTreeMap::new(),
+      timecode_q: BTreeMap::new(),
+      denoiser: (enc.denoise_noise_level > 0).then(|| {
+        DctDenoiser::new(enc.denoise_noise_level, enc.denoise_temporal_radius)
+      }),
+      noisy_frame_q: BTreeMap::new(),
+      next_denoise_frame: 0,
+      next_grain_estimation_frame: 0,
+      alpha: enc
+        .alpha_quantizer
+        .map(|quantizer| AlphaStream::new(enc, quantizer)),
+    }
+  }
+
+  /// Applies the settings of `enc` which may change during an encode,
+  /// starting with the next frame to be encoded.
+  ///
+  /// The keyframe intervals only apply to the frames not analyzed by the
+  /// lookahead yet, a GOP already started keeps its maximum length.
+  pub(crate) fn reconfigure(&mut self, enc: &EncoderConfig) {
+    let mut config = (*self.config).clone();
+    config.reconfigure(enc);
+
+    let maybe_ac_qi_max =
+      if config.quantizer < 255 { Some(config.quantizer as u8) } else { None };
+    // The rate targets of a multi-pass encode cannot change.
+    if !self.rc_state.is_multipass() {
+      self.rc_state.reconfigure(
+        config.time_base.den as i64,
+        config.time_base.num as i64,
+        config.bitrate,
+        maybe_ac_qi_max,
+        config.min_quantizer,
+        config.max_key_frame_interval as i32,
+        config.reservoir_frame_delay,
+        config.drop_frames,
+      );
+    }
+    self.keyframe_detector.set_key_frame_intervals(
+      config.min_key_frame_interval,
+      config.max_key_frame_interval,
+    );
+    if config.max_key_frame_interval != self.config.max_key_frame_interval {
+      self
+        .max_key_frame_intervals
+        .insert(self.next_lookahead_frame, config.max_key_frame_interval);
+    }
+    self.config = Arc::new(config);
+  }
+
+  /// Places keyframes exactly where the first pass placed them, for the
+  /// `nframes` input frames it encoded.
+  pub(crate) fn replay_first_pass_keyframes(
+    &mut self, keyframes: &[u64], nframes: u64,
+  ) {
+    self.keyframes_forced.extend(keyframes);
+    self.keyframes_replay_end = nframes;
+  }
+
+  /// Returns a checkpoint of the encode if the next call to
+  /// `receive_packet` encodes the keyframe starting a GOP.
+  ///
+  /// The GOPs of an open GOP encode, the temporal denoiser and the alpha
+  /// stream carry state across keyframes, so such encodes have none.
+  pub(crate) fn checkpoint(&self) -> Option<Checkpoint> {
+    if self.inter_cfg.open_gop
+      || self.denoiser.is_some()
+      || self.alpha.is_some()
+      || self.done_processing()
+      || self.needs_more_fi_lookahead()
+    {
+      return None;
     }
+    let fi = &self
+      .frame_data
+      .range(self.output_frameno..)
+      .find_map(|(_, data)| data.as_ref())?
+      .fi;
+    if fi.frame_type != FrameType::KEY
+      || !fi.show_frame
+      || fi.is_show_existing_frame()
+      || !self.needs_more_frames(fi.input_frameno)
+    {
+      return None;
+    }
+
+    Some(Checkpoint {
+      input_frameno: fi.input_frameno,
+      frame_count: self.frame_count,
+      flushed: self.limit.is_some(),
+      frames_processed: self.frames_processed,
+      keyframes: self.keyframes.iter().copied().collect(),
+      keyframes_forced: self.keyframes_forced.iter().copied().collect(),
+      keyframes_replay_end: self.keyframes_replay_end,
+      max_key_frame_intervals: self
+        .max_key_frame_intervals
+        .iter()
+        .map(|(&input_frameno, &interval)| (input_frameno, interval))
+        .collect(),
+      next_lookahead_frame: self.next_lookahead_frame,
+      maybe_prev_log_base_q: self.maybe_prev_log_base_q,
+      lookahead_qps: self
+        .lookahead_qps
+        .range(fi.input_frameno..)
+        .map(|(&input_frameno, qps)| (input_frameno, qps.clone()))
+        .collect(),
+      rc_state: self.rc_state.clone(),
+      scene_detector: self.keyframe_detector.history(),
+    })
+  }
+
+  /// Restores the state of the encode saved in `checkpoint`, so the frames
+  /// from its keyframe on are encoded as they were before.
+  pub(crate) fn restore(&mut self, checkpoint: &Checkpoint) {
+    let input_frameno = checkpoint.input_frameno;
+    self.frame_count = input_frameno;
+    self.frames_processed = checkpoint.frames_processed;
+    self.keyframes = checkpoint.keyframes.iter().copied().collect();
+    self.keyframes_forced =
+      checkpoint.keyframes_forced.iter().copied().collect();
+    self.keyframes_replay_end = checkpoint.keyframes_replay_end;
+    self.max_key_frame_intervals =
+      checkpoint.max_key_frame_intervals.iter().copied().collect();
+    self.last_keyframe_input_frameno = input_frameno;
+    self.next_lookahead_frame = checkpoint.next_lookahead_frame;
+    self.next_denoise_frame = input_frameno;
+    self.next_grain_estimation_frame = input_frameno;
+    self.maybe_prev_log_base_q = checkpoint.maybe_prev_log_base_q;
+    self.lookahead_qps = checkpoint.lookahead_qps.iter().cloned().collect();
+    self.rc_state = checkpoint.rc_state.clone();
+    self.keyframe_detector.restore_history(&checkpoint.scene_detector);
   }
 
   #[profiling::function]
   pub fn send_frame(
     &mut self, mut frame: Option<Arc<Frame<T>>>,
-    params: Option<FrameParameters>,
+    mut params: Option<FrameParameters>,
   ) -> Result<(), EncoderStatus> {
+    // Check and serialize the typed metadata before any state change, as it
+    // may be invalid.
+    if let Some(params) = params.as_mut() {
+      if params.timecode.is_some_and(|timecode| !timecode.is_valid()) {
+        return Err(EncoderStatus::Failure);
+      }
+      let hdr10plus = params.hdr10plus.take().map(|m| m.to_t35());
+      let dolby_vision_rpu =
+        params.dolby_vision_rpu.take().map(|m| m.to_t35());
+      let dynamic_metadata = hdr10plus
+        .into_iter()
+        .chain(dolby_vision_rpu)
+        .collect::<io::Result<Vec<_>>>()
+        .map_err(|_| EncoderStatus::Failure)?;
+      if !dynamic_metadata.is_empty() {
+        params.t35_metadata = dynamic_metadata
+          .into_iter()
+          .chain(std::mem::take(&mut params.t35_metadata).into_vec())
+          .collect();
+      }
+    }
+    let alpha = params.as_mut().and_then(|params| params.alpha.take());
+    if let (Some(stream), Some(alpha), Some(_)) =
+      (self.alpha.as_mut(), alpha, frame.as_ref())
+    {
+      stream.send_plane(self.frame_count, alpha)?;
+    }
+
     if let Some(ref mut frame) = frame {
       use crate::api::color::ChromaSampling;
       let EncoderConfig { width, height, chroma_sampling, .. } = *self.config;
@@ -345,7 +569,18 @@ impl<T: Pixel> ContextInner<T> {
     if !is_flushing {
       self.frame_count += 1;
     }
-    self.frame_q.insert(input_frameno, frame);
+    if self.denoiser.is_some() {
+      // The denoised frames are queued instead, once denoised.
+      if let Some(frame) = frame {
+        self.noisy_frame_q.insert(input_frameno, frame);
+      }
+      self.denoise_frames(is_flushing);
+      if is_flushing {
+        self.frame_q.insert(input_frameno, None);
+      }
+    } else {
+      self.frame_q.insert(input_frameno, frame);
+    }
 
     if let Some(params) = params {
       if params.frame_type_override == FrameTypeOverride::Key {
@@ -355,6 +590,9 @@ impl<T: Pixel> ContextInner<T> {
         self.opaque_q.insert(input_frameno, op);
       }
       self.t35_q.insert(input_frameno, params.t35_metadata);
+      if let Some(timecode) = params.timecode {
+        self.timecode_q.insert(input_frameno, timecode);
+      }
     }
 
     if !self.needs_more_frame_q_lookahead(self.next_lookahead_frame) {
@@ -378,6 +616,7 @@ impl<T: Pixel> ContextInner<T> {
           Self::compute_keyframe_placement(
             cur_lookahead_frames,
             &self.keyframes_forced,
+            self.keyframes_replay_end,
             &mut self.keyframe_detector,
             &mut self.next_lookahead_frame,
             &mut self.keyframes,
@@ -387,6 +626,7 @@ impl<T: Pixel> ContextInner<T> {
         Self::compute_keyframe_placement(
           &lookahead_frames,
           &self.keyframes_forced,
+          self.keyframes_replay_end,
           &mut self.keyframe_detector,
           &mut self.next_lookahead_frame,
           &mut self.keyframes,
@@ -394,11 +634,129 @@ impl<T: Pixel> ContextInner<T> {
       }
     }
 
+    if self.denoiser.is_some() {
+      if self.config.denoise_grain_synthesis {
+        self.estimate_film_grain();
+      }
+      self.prune_noisy_frames();
+    }
     self.compute_frame_invariants();
 
     Ok(())
   }
 
+  /// Denoises the source frames whose following frames needed by the
+  ///  temporal filter were all received, or all the remaining ones when
+  ///  flushing, and queues them for encoding.
+  fn denoise_frames(&mut self, is_flushing: bool) {
+    let Some(denoiser) = self.denoiser.as_ref() else {
+      return;
+    };
+    let radius = denoiser.temporal_radius() as u64;
+    let EncoderConfig { width, height, chroma_sampling, bit_depth, .. } =
+      *self.config;
+    let planes = if chroma_sampling == Cs400 { 1 } else { 3 };
+
+    while self.next_denoise_frame < self.frame_count
+      && (is_flushing || self.next_denoise_frame + radius < self.frame_count)
+    {
+      let input_frameno = self.next_denoise_frame;
+      let first = input_frameno.saturating_sub(radius);
+      let frames = self
+        .noisy_frame_q
+        .range(first..=input_frameno + radius)
+        .map(|(_, frame)| &**frame)
+        .collect::<Vec<_>>();
+      let mut denoised = denoiser.denoise(
+        &frames,
+        (input_frameno - first) as usize,
+        bit_depth,
+        planes,
+      );
+      for plane in denoised.planes[..planes].iter_mut() {
+        plane.pad(width, height);
+      }
+      self.frame_q.insert(input_frameno, Some(Arc::new(denoised)));
+      self.next_denoise_frame += 1;
+    }
+  }
+
+  /// Drops the source frames which are no longer needed to denoise the
+  ///  following frames or to estimate the film grain.
+  fn prune_noisy_frames(&mut self) {
+    let Some(denoiser) = self.denoiser.as_ref() else {
+      return;
+    };
+    let mut first_needed = self
+      .next_denoise_frame
+      .saturating_sub(denoiser.temporal_radius() as u64);
+    if self.config.denoise_grain_synthesis {
+      first_needed = first_needed.min(self.next_grain_estimation_frame);
+    }
+    self.noisy_frame_q = self.noisy_frame_q.split_off(&first_needed);
+  }
+
+  /// Estimates the film grain removed by denoising for the GOPs starting at
+  ///  the keyframes placed since the last call, from the first frames of
+  ///  each GOP available in the lookahead.
+  ///
+  /// Each GOP gets a grain table segment starting at its keyframe, which
+  ///  ends the segment of the previous one.
+  fn estimate_film_grain(&mut self) {
+    const MAX_ESTIMATION_FRAMES: usize = 8;
+
+    let mut next_frame = self.next_lookahead_frame;
+    let keyframes = self
+      .keyframes
+      .range(self.next_grain_estimation_frame..self.next_lookahead_frame)
+      .copied()
+      .collect::<Vec<_>>();
+    for (i, &keyframe) in keyframes.iter().enumerate() {
+      // The frame invariants of the keyframe are not built before then.
+      if self.needs_more_frame_q_lookahead(keyframe) {
+        next_frame = keyframe;
+        break;
+      }
+
+      let gop_end = keyframes.get(i + 1).copied().unwrap_or(u64::MAX);
+      let segment = estimate_film_grain(
+        self
+          .noisy_frame_q
+          .range(keyframe..gop_end)
+          .filter_map(|(input_frameno, source)| {
+            let denoised = self.frame_q.get(input_frameno)?.as_ref()?;
+            Some((&**source, &**denoised))
+          })
+          .take(MAX_ESTIMATION_FRAMES),
+        self.config.bit_depth,
+        self.config.chroma_sampling,
+        self.config.time_base,
+      );
+
+      let time_base = self.config.time_base;
+      let start_time =
+        keyframe * TIMESTAMP_BASE_UNIT * time_base.num / time_base.den;
+      let segments = Arc::make_mut(&mut self.config)
+        .film_grain_params
+        .get_or_insert_with(Vec::new);
+      if let Some(last) =
+        segments.last_mut().filter(|last| last.end_time == u64::MAX)
+      {
+        last.end_time = start_time;
+      }
+      if let Some(segment) = segment {
+        segments.push(GrainTableSegment {
+          start_time,
+          end_time: u64::MAX,
+          random_seed: DEFAULT_GRAIN_SEED,
+          ..segment
+        });
+      }
+    }
+
+    self.next_grain_estimation_frame = next_frame;
+  }
+
   /// Indicates whether more frames need to be read into the frame queue
   /// in order for frame queue lookahead to be full.
   fn needs_more_frame_q_lookahead(&self, input_frameno: u64) -> bool {
@@ -442,8 +800,8 @@ impl<T: Pixel> ContextInner<T> {
       .iter()
       .find(|&&input_frameno| input_frameno > gop_input_frameno_start)
       .cloned();
-    let mut next_limit =
-      gop_input_frameno_start + self.config.max_key_frame_interval;
+    let mut next_limit = gop_input_frameno_start
+      + self.max_key_frame_interval(gop_input_frameno_start);
     if !ignore_limit && self.limit.is_some() {
       next_limit = next_limit.min(self.limit.unwrap());
     }
@@ -453,10 +811,51 @@ impl<T: Pixel> ContextInner<T> {
     cmp::min(next_detected.unwrap(), next_limit)
   }
 
+  /// Returns the maximum keyframe interval of the GOP starting at
+  ///  `gop_input_frameno_start`.
+  fn max_key_frame_interval(&self, gop_input_frameno_start: u64) -> u64 {
+    // A GOP keeps the maximum interval in effect when it started.
+    let (_, &max_key_frame_interval) = self
+      .max_key_frame_intervals
+      .range(..=gop_input_frameno_start)
+      .next_back()
+      .unwrap();
+    max_key_frame_interval
+  }
+
+  /// Returns whether the GOP starting at `input_frameno` after the one
+  ///  starting at `prev_gop_input_frameno_start` starts with an intra-only
+  ///  frame rather than a keyframe.
+  fn is_intra_only_gop_start(
+    &self, prev_gop_input_frameno_start: u64, input_frameno: u64,
+  ) -> bool {
+    self.inter_cfg.open_gop
+      && input_frameno > 0
+      && input_frameno - self.last_keyframe_input_frameno
+        < self.max_key_frame_interval(prev_gop_input_frameno_start)
+      && !self
+        .config
+        .forced_keyframes()
+        .any(|frameno| frameno == input_frameno)
+  }
+
   fn set_frame_properties(
     &mut self, output_frameno: u64,
   ) -> Result<(), EncoderStatus> {
-    let fi = self.build_frame_properties(output_frameno)?;
+    let mut fi = self.build_frame_properties(output_frameno)?;
+    if let Some(fi) = fi.as_mut() {
+      self.apply_scene_label(fi);
+      if fi.show_frame {
+        self.t35_q.remove(&fi.input_frameno);
+      }
+      // The frame invariants may be derived from the previous ones, which
+      // already had a timecode.
+      fi.timecode = if fi.show_frame {
+        self.timecode_q.remove(&fi.input_frameno)
+      } else {
+        None
+      };
+    }
 
     self.frame_data.insert(
       output_frameno,
@@ -475,6 +874,36 @@ impl<T: Pixel> ContextInner<T> {
     Ok(())
   }
 
+  /// Adjusts the references of the frame to the content transition
+  ///  detected at it.
+  fn apply_scene_label(&mut self, fi: &mut FrameInvariants<T>) {
+    if fi.frame_type == FrameType::INTER && !fi.is_show_existing_frame() {
+      for ref_frame in fi.ref_frames.iter_mut() {
+        if let Some(slot) = self.flash_skipped_slots[*ref_frame as usize] {
+          *ref_frame = slot;
+        }
+      }
+      match self.keyframe_detector.scene_label(fi.input_frameno) {
+        // Nothing refers to a flash, the following frames are predicted
+        //  from the frames around it instead.
+        SceneLabel::Flash if fi.show_frame => {
+          fi.refresh_frame_flags = 0;
+          self.flash_skipped_slots[fi.frame_to_show_map_idx as usize] =
+            Some(fi.ref_frames[RefType::LAST_FRAME.to_index()]);
+        }
+        label if label.is_gradual() => fi.use_nearest_ref_frames(),
+        _ => {}
+      }
+      fi.set_ref_frame_sign_bias();
+    }
+
+    for (slot, skipped) in self.flash_skipped_slots.iter_mut().enumerate() {
+      if (fi.refresh_frame_flags & (1 << slot)) != 0 {
+        *skipped = None;
+      }
+    }
+  }
+
   #[allow(unused)]
   pub fn build_dump_properties() -> PathBuf {
     let mut data_location = PathBuf::new();
@@ -491,15 +920,20 @@ impl<T: Pixel> ContextInner<T> {
   fn build_frame_properties(
     &mut self, output_frameno: u64,
   ) -> Result<Option<FrameInvariants<T>>, EncoderStatus> {
-    let (prev_gop_output_frameno_start, prev_gop_input_frameno_start) =
-      if output_frameno == 0 {
-        (0, 0)
-      } else {
-        (
-          self.gop_output_frameno_start[&(output_frameno - 1)],
-          self.gop_input_frameno_start[&(output_frameno - 1)],
-        )
-      };
+    let (
+      prev_gop_output_frameno_start,
+      prev_gop_input_frameno_start,
+      prev_gop_order_hint_start,
+    ) = if output_frameno == 0 {
+      // A resumed encode starts with the keyframe of its checkpoint.
+      (0, self.last_keyframe_input_frameno, 0)
+    } else {
+      (
+        self.gop_output_frameno_start[&(output_frameno - 1)],
+        self.gop_input_frameno_start[&(output_frameno - 1)],
+        self.gop_order_hint_start[&(output_frameno - 1)],
+      )
+    };
 
     self
       .gop_output_frameno_start
@@ -507,6 +941,9 @@ impl<T: Pixel> ContextInner<T> {
     self
       .gop_input_frameno_start
       .insert(output_frameno, prev_gop_input_frameno_start);
+    self
+      .gop_order_hint_start
+      .insert(output_frameno, prev_gop_order_hint_start);
 
     let output_frameno_in_gop =
       output_frameno - self.gop_output_frameno_start[&output_frameno];
@@ -519,11 +956,9 @@ impl<T: Pixel> ContextInner<T> {
       return Err(EncoderStatus::NeedMoreData);
     }
 
-    let t35_metadata = if let Some(t35) = self.t35_q.remove(&input_frameno) {
-      t35
-    } else {
-      Box::new([])
-    };
+    // The metadata is only taken once the frame is shown.
+    let mut t35_metadata =
+      self.t35_q.get(&input_frameno).cloned().unwrap_or_default();
 
     if output_frameno_in_gop > 0 {
       let next_keyframe_input_frameno = self.next_keyframe_input_frameno(
@@ -547,16 +982,47 @@ impl<T: Pixel> ContextInner<T> {
               return Err(EncoderStatus::NeedMoreData);
             }
           }
+          t35_metadata =
+            self.t35_q.get(&input_frameno).cloned().unwrap_or_default();
 
           *self.gop_output_frameno_start.get_mut(&output_frameno).unwrap() =
             output_frameno;
           *self.gop_input_frameno_start.get_mut(&output_frameno).unwrap() =
             next_keyframe_input_frameno;
+          *self.gop_order_hint_start.get_mut(&output_frameno).unwrap() = 0;
+        } else if self.forward_keyframes.contains(&input_frameno) {
+          return Ok(Some(self.build_forward_keyframe_shown(
+            output_frameno,
+            output_frameno_in_gop,
+            input_frameno,
+            t35_metadata,
+          )));
+        } else if self.inter_cfg.open_gop
+          && input_frameno == next_keyframe_input_frameno
+          && self.limit.map_or(true, |limit| input_frameno < limit)
+          && self.inter_cfg.get_idx_in_group_output(output_frameno_in_gop) == 0
+          && !self.is_intra_only_gop_start(
+            self.gop_input_frameno_start[&output_frameno],
+            input_frameno,
+          )
+        {
+          match self.frame_q.get(&input_frameno) {
+            Some(Some(_)) => {}
+            _ => {
+              return Err(EncoderStatus::NeedMoreData);
+            }
+          }
+          return Ok(Some(self.build_forward_keyframe(
+            output_frameno,
+            output_frameno_in_gop,
+            input_frameno,
+          )));
         } else {
           let fi = FrameInvariants::new_inter_frame(
             self.get_previous_coded_fi(output_frameno),
             &self.inter_cfg,
             self.gop_input_frameno_start[&output_frameno],
+            self.gop_order_hint_start[&output_frameno],
             output_frameno_in_gop,
             next_keyframe_input_frameno,
             self.config.error_resilient,
@@ -586,37 +1052,133 @@ impl<T: Pixel> ContextInner<T> {
         output_frameno;
       *self.gop_input_frameno_start.get_mut(&output_frameno).unwrap() =
         input_frameno;
+      *self.gop_order_hint_start.get_mut(&output_frameno).unwrap() = 0;
     }
 
     let output_frameno_in_gop =
       output_frameno - self.gop_output_frameno_start[&output_frameno];
     if output_frameno_in_gop == 0 {
-      let fi = FrameInvariants::new_key_frame(
+      let mut fi = FrameInvariants::new_key_frame(
         self.config.clone(),
         self.seq.clone(),
         self.gop_input_frameno_start[&output_frameno],
         t35_metadata,
       );
+      if self
+        .is_intra_only_gop_start(prev_gop_input_frameno_start, input_frameno)
+      {
+        fi.frame_type = FrameType::INTRA_ONLY;
+        fi.refresh_frame_flags =
+          self.inter_cfg.get_intra_only_refresh_frame_flags();
+      } else {
+        self.last_keyframe_input_frameno = input_frameno;
+      }
+      fi.apply_zone(&self.config);
       Ok(Some(fi))
     } else {
-      let next_keyframe_input_frameno = self.next_keyframe_input_frameno(
-        self.gop_input_frameno_start[&output_frameno],
-        false,
-      );
-      let fi = FrameInvariants::new_inter_frame(
-        self.get_previous_coded_fi(output_frameno),
+      let gop_input_frameno_start =
+        self.gop_input_frameno_start[&output_frameno];
+      let gop_order_hint_start = self.gop_order_hint_start[&output_frameno];
+      let next_keyframe_input_frameno =
+        self.next_keyframe_input_frameno(gop_input_frameno_start, false);
+      let previous_coded_fi = self.get_previous_coded_fi(output_frameno);
+      // The first frame coded after showing a forward keyframe finds it in
+      //  every slot.
+      let shown_keyframe_slot = (gop_order_hint_start > 0
+        && previous_coded_fi.input_frameno < gop_input_frameno_start)
+        .then(|| self.inter_cfg.get_slot_idx(0, gop_order_hint_start));
+      let mut fi = FrameInvariants::new_inter_frame(
+        previous_coded_fi,
         &self.inter_cfg,
-        self.gop_input_frameno_start[&output_frameno],
+        gop_input_frameno_start,
+        gop_order_hint_start,
         output_frameno_in_gop,
         next_keyframe_input_frameno,
         self.config.error_resilient,
         t35_metadata,
       );
       assert!(fi.is_some());
+      if let Some(ref mut fi) = fi {
+        if let Some(slot) = shown_keyframe_slot {
+          fi.rec_buffer.load_key_frame(slot as usize);
+          if let Some(coded_data) = fi.coded_frame_data.as_mut() {
+            coded_data.lookahead_rec_buffer.load_key_frame(slot as usize);
+          }
+          fi.set_ref_frame_sign_bias();
+        }
+        fi.apply_zone(&self.config);
+      }
       Ok(fi)
     }
   }
 
+  /// Builds the forward keyframe at `input_frameno`, coded hidden at the
+  ///  start of the last re-ordering group of the GOP so that the frames of
+  ///  the group may reference it.
+  fn build_forward_keyframe(
+    &mut self, output_frameno: u64, output_frameno_in_gop: u64,
+    input_frameno: u64,
+  ) -> FrameInvariants<T> {
+    let previous_coded_fi = self.get_previous_coded_fi(output_frameno);
+    let mut fi = FrameInvariants::new_key_frame(
+      self.config.clone(),
+      self.seq.clone(),
+      input_frameno,
+      Box::new([]),
+    );
+    fi.order_hint = self.gop_order_hint_start[&output_frameno]
+      + self.inter_cfg.get_order_hint(output_frameno_in_gop, 0);
+    let slot_idx = self.inter_cfg.get_slot_idx(0, fi.order_hint);
+    fi.show_frame = false;
+    fi.frame_to_show_map_idx = slot_idx;
+    fi.refresh_frame_flags = 1 << slot_idx;
+    // The other slots still hold the references of the current GOP.
+    fi.rec_buffer = previous_coded_fi.rec_buffer.clone();
+    if let (Some(coded_data), Some(previous_coded_data)) = (
+      fi.coded_frame_data.as_mut(),
+      previous_coded_fi.coded_frame_data.as_ref(),
+    ) {
+      coded_data
+        .lookahead_rec_buffer
+        .clone_from(&previous_coded_data.lookahead_rec_buffer);
+    }
+    fi.apply_zone(&self.config);
+    self.forward_keyframes.insert(input_frameno);
+    self.last_keyframe_input_frameno = input_frameno;
+    fi
+  }
+
+  /// Builds the Show Existing Frame of a forward keyframe, which starts the
+  ///  next GOP.
+  fn build_forward_keyframe_shown(
+    &mut self, output_frameno: u64, output_frameno_in_gop: u64,
+    input_frameno: u64, t35_metadata: Box<[T35]>,
+  ) -> FrameInvariants<T> {
+    let mut fi = FrameInvariants::new_inter_frame(
+      self.get_previous_coded_fi(output_frameno),
+      &self.inter_cfg,
+      self.gop_input_frameno_start[&output_frameno],
+      self.gop_order_hint_start[&output_frameno],
+      output_frameno_in_gop,
+      input_frameno + 1,
+      self.config.error_resilient,
+      t35_metadata,
+    )
+    .unwrap();
+    debug_assert!(fi.is_show_existing_frame());
+    // Showing a keyframe refreshes every slot with it.
+    fi.frame_type = FrameType::KEY;
+    fi.refresh_frame_flags = ALL_REF_FRAMES_MASK;
+    self.forward_keyframes.remove(&input_frameno);
+    *self.gop_output_frameno_start.get_mut(&output_frameno).unwrap() =
+      output_frameno;
+    *self.gop_input_frameno_start.get_mut(&output_frameno).unwrap() =
+      input_frameno;
+    *self.gop_order_hint_start.get_mut(&output_frameno).unwrap() =
+      fi.order_hint;
+    fi
+  }
+
   fn get_previous_fi(&self, output_frameno: u64) -> &FrameInvariants<T> {
     let res = self
       .frame_data
@@ -662,15 +1224,22 @@ impl<T: Pixel> ContextInner<T> {
       return;
     }
 
-    let qps = {
-      let fti = frame_data.as_ref().unwrap().fi.get_frame_subtype();
-      self.rc_state.select_qi(
-        self,
-        output_frameno,
-        fti,
-        self.maybe_prev_log_base_q,
-        0,
-      )
+    // A resumed encode runs the motion search of the frames the encode it
+    // resumes had received with the same quantizers.
+    let input_frameno = frame_data.as_ref().unwrap().fi.input_frameno;
+    let qps = match self.lookahead_qps.get(&input_frameno) {
+      Some(qps) => qps.clone(),
+      None => {
+        let fi = &frame_data.as_ref().unwrap().fi;
+        self.rc_state.select_qi(
+          self,
+          output_frameno,
+          fi.input_frameno,
+          fi.get_frame_subtype(),
+          self.maybe_prev_log_base_q,
+          0,
+        )
+      }
     };
 
     let frame_data =
@@ -742,6 +1311,7 @@ impl<T: Pixel> ContextInner<T> {
 
     // Estimate lambda with rate-control dry-run
     fi.set_quantizers(&qps);
+    self.lookahead_qps.insert(input_frameno, qps);
 
     // TODO: as in the encoding code, key frames will have no references.
     // However, for block importance purposes we want key frames to act as
@@ -863,16 +1433,24 @@ impl<T: Pixel> ContextInner<T> {
   #[profiling::function]
   pub fn compute_keyframe_placement(
     lookahead_frames: &[&Arc<Frame<T>>], keyframes_forced: &BTreeSet<u64>,
-    keyframe_detector: &mut SceneChangeDetector<T>,
+    keyframes_replay_end: u64, keyframe_detector: &mut SceneChangeDetector<T>,
     next_lookahead_frame: &mut u64, keyframes: &mut BTreeSet<u64>,
   ) {
-    if keyframes_forced.contains(next_lookahead_frame)
-      || keyframe_detector.analyze_next_frame(
+    let is_keyframe = if keyframes_forced.contains(next_lookahead_frame)
+      || *next_lookahead_frame < keyframes_replay_end
+    {
+      // The keyframe placement is already decided, only label the frame.
+      keyframe_detector
+        .label_next_frame(lookahead_frames, *next_lookahead_frame);
+      keyframes_forced.contains(next_lookahead_frame)
+    } else {
+      keyframe_detector.analyze_next_frame(
         lookahead_frames,
         *next_lookahead_frame,
         *keyframes.iter().last().unwrap(),
       )
-    {
+    };
+    if is_keyframe {
       keyframes.insert(*next_lookahead_frame);
     }
 
@@ -885,7 +1463,8 @@ impl<T: Pixel> ContextInner<T> {
     {
       self
         .compute_lookahead_motion_vectors(self.next_lookahead_output_frameno);
-      if self.config.temporal_rdo() {
+      // The intra costs are also summarized in the pass data.
+      if self.config.temporal_rdo() || self.rc_state.emits_pass_data() {
         self.compute_lookahead_intra_costs(self.next_lookahead_output_frameno);
       }
       self.next_lookahead_output_frameno += 1;
@@ -1054,6 +1633,42 @@ impl<T: Pixel> ContextInner<T> {
       });
   }
 
+  /// Summarizes the lookahead costs and motion of a frame for the rate
+  /// control pass data.
+  fn compute_rc_frame_stats(
+    fi: &FrameInvariants<T>, fs: &FrameState<T>,
+  ) -> RCFrameStats {
+    let Some(coded_data) = fi.coded_frame_data.as_ref() else {
+      return RCFrameStats::default();
+    };
+    let nblocks = coded_data.lookahead_intra_costs.len().max(1) as u64;
+    let intra_cost = (coded_data
+      .lookahead_intra_costs
+      .iter()
+      .map(|&cost| cost as u64)
+      .sum::<u64>()
+      / nblocks) as u32;
+    if fi.intra_only {
+      return RCFrameStats { intra_cost, inter_cost: intra_cost, motion: 0 };
+    }
+
+    // The lookahead search of the closest previous frame is kept in the
+    // first slot.
+    let me_stats = &fs.frame_me_stats.read().expect("poisoned lock")[0];
+    let nstats = (me_stats.cols * me_stats.rows).max(1) as u64;
+    let (sad_sum, motion_sum) = me_stats.rows_iter().flatten().fold(
+      (0u64, 0f64),
+      |(sad_sum, motion_sum), stat| {
+        let (row, col) = (stat.mv.row as f64, stat.mv.col as f64);
+        (sad_sum + stat.normalized_sad as u64, motion_sum + row.hypot(col))
+      },
+    );
+    // The SAD is normalized to a 128x128 block, rescale it to 8x8.
+    let inter_cost = (sad_sum / nstats / (128 * 128 / (8 * 8))) as u32;
+    let motion = (motion_sum / nstats as f64) as u32;
+    RCFrameStats { intra_cost, inter_cost, motion }
+  }
+
   /// Computes the block importances for the current output frame.
   #[profiling::function]
   fn compute_block_importances(&mut self) {
@@ -1110,7 +1725,7 @@ impl<T: Pixel> ContextInner<T> {
         .unwrap()
         .fi
         .frame_type
-        == FrameType::KEY
+        .all_intra()
       {
         continue;
       }
@@ -1290,6 +1905,18 @@ impl<T: Pixel> ContextInner<T> {
       &mut frame_data.fs,
       &self.inter_cfg,
     );
+    if let Some(alpha) = self.alpha.as_mut() {
+      alpha.encode_show_existing_frame(&frame_data.fi, &self.inter_cfg);
+    }
+    if frame_data.fi.frame_type == FrameType::KEY {
+      // Showing a forward keyframe loads it into every slot.
+      let slot = frame_data.fi.frame_to_show_map_idx as usize;
+      frame_data.fi.rec_buffer.load_key_frame(slot);
+      let rec_buffer = frame_data.fi.rec_buffer.clone();
+      self.propagate_rec_buffer(cur_output_frameno, &rec_buffer);
+    }
+    let frame_data =
+      self.frame_data.get_mut(&cur_output_frameno).unwrap().as_mut().unwrap();
     let bits = (sef_data.len() * 8) as i64;
     self.packet_data.extend(sef_data);
     self.rc_state.update_state(
@@ -1325,7 +1952,7 @@ impl<T: Pixel> ContextInner<T> {
     let mut log_isqrt_mean_scale = 0i64;
 
     if let Some(coded_data) = frame_data.fi.coded_frame_data.as_mut() {
-      if self.config.tune == Tune::Psychovisual {
+      if frame_data.fi.config.tune == Tune::Psychovisual {
         let frame =
           self.frame_q[&frame_data.fi.input_frameno].as_ref().unwrap();
         coded_data.activity_mask = ActivityMask::from_plane(&frame.planes[0]);
@@ -1342,7 +1969,7 @@ impl<T: Pixel> ContextInner<T> {
       {
         use crate::encoder::Scales::*;
         let input_frameno = frame_data.fi.input_frameno;
-        if self.config.tune == Tune::Psychovisual {
+        if frame_data.fi.config.tune == Tune::Psychovisual {
           coded_data.dump_scales(
             Self::build_dump_properties(),
             ActivityScales,
@@ -1362,15 +1989,33 @@ impl<T: Pixel> ContextInner<T> {
       }
     }
 
+    log_isqrt_mean_scale += scene_label_log_q_offset(
+      self.keyframe_detector.scene_label(frame_data.fi.input_frameno),
+      frame_data.fi.refresh_frame_flags != 0,
+    );
+
     let fti = frame_data.fi.get_frame_subtype();
     let qps = self.rc_state.select_qi(
       self,
       cur_output_frameno,
+      frame_data.fi.input_frameno,
       fti,
       self.maybe_prev_log_base_q,
       log_isqrt_mean_scale,
     );
     frame_data.fi.set_quantizers(&qps);
+    // The restoration units were sized for the quantizer the frame
+    // invariants inherited from the frame before them, which depends on
+    // when they were built, so they are sized again for the selected one.
+    frame_data.fs.restoration =
+      RestorationState::new(&frame_data.fi, &frame_data.fs.input);
+
+    // Collect the lookahead statistics before any encode overwrites the
+    // motion search results.
+    self.rc_state.set_frame_stats(Self::compute_rc_frame_stats(
+      &frame_data.fi,
+      &frame_data.fs,
+    ));
 
     if self.rc_state.needs_trial_encode(fti) {
       let mut trial_fs = frame_data.fs.clone();
@@ -1386,11 +2031,14 @@ impl<T: Pixel> ContextInner<T> {
       let qps = self.rc_state.select_qi(
         self,
         cur_output_frameno,
+        frame_data.fi.input_frameno,
         fti,
         self.maybe_prev_log_base_q,
         log_isqrt_mean_scale,
       );
       frame_data.fi.set_quantizers(&qps);
+      frame_data.fs.restoration =
+        RestorationState::new(&frame_data.fi, &frame_data.fs.input);
     }
 
     let data =
@@ -1401,18 +2049,55 @@ impl<T: Pixel> ContextInner<T> {
       let data_location = Self::build_dump_properties();
       frame_data.fs.segmentation.dump_threshold(data_location, input_frameno);
     }
-    let enc_stats = frame_data.fs.enc_stats.clone();
-    self.maybe_prev_log_base_q = Some(qps.log_base_q);
-    // TODO: Add support for dropping frames.
-    self.rc_state.update_state(
+    let mut enc_stats = frame_data.fs.enc_stats.clone();
+    // Frames with a zone quantizer bypass rate control, so they must not
+    // limit the quantizer change of the following frames.
+    if self
+      .config
+      .zone_at(frame_data.fi.input_frameno)
+      .and_then(|zone| zone.quantizer)
+      .is_none()
+    {
+      self.maybe_prev_log_base_q = Some(qps.log_base_q);
+    }
+    let maybe_drop_slot =
+      self.droppable_frame_slot(cur_output_frameno, &frame_data.fi);
+    let dropped = self.rc_state.update_state(
       (data.len() * 8) as i64,
       fti,
       frame_data.fi.show_frame,
       qps.log_target_q,
       false,
-      false,
+      maybe_drop_slot.is_some(),
     );
-    self.packet_data.extend(data);
+    let maybe_drop_slot = maybe_drop_slot.filter(|_| dropped);
+    if let Some(slot) = maybe_drop_slot {
+      // Show the previous frame again instead, leaving the reference frames
+      // untouched.
+      let mut sef_fi = frame_data.fi.clone_without_coded_data();
+      sef_fi.frame_to_show_map_idx = slot;
+      sef_fi.refresh_frame_flags = 0;
+      let sef_data = encode_show_existing_frame(
+        &sef_fi,
+        &mut frame_data.fs,
+        &self.inter_cfg,
+      );
+      self.packet_data.extend(sef_data);
+      enc_stats = EncoderStats::default();
+      if let Some(alpha) = self.alpha.as_mut() {
+        alpha.skip_frame(sef_fi.input_frameno);
+        alpha.encode_show_existing_frame(&sef_fi, &self.inter_cfg);
+      }
+    } else {
+      self.packet_data.extend(data);
+      if let Some(alpha) = self.alpha.as_mut() {
+        alpha.encode_frame(
+          cur_output_frameno,
+          &frame_data.fi,
+          &self.inter_cfg,
+        );
+      }
+    }
 
     let planes =
       if frame_data.fi.sequence.chroma_sampling == Cs400 { 1 } else { 3 };
@@ -1429,10 +2114,43 @@ impl<T: Pixel> ContextInner<T> {
       (None, None)
     };
 
-    update_rec_buffer(cur_output_frameno, &mut frame_data.fi, &frame_data.fs);
+    if maybe_drop_slot.is_none() {
+      update_rec_buffer(
+        cur_output_frameno,
+        &mut frame_data.fi,
+        &frame_data.fs,
+      );
+    }
+
+    self.propagate_rec_buffer(cur_output_frameno, &frame_data.fi.rec_buffer);
+
+    self.frame_data.insert(cur_output_frameno, Some(frame_data));
+    let frame_data =
+      self.frame_data.get(&cur_output_frameno).unwrap().as_ref().unwrap();
+    let fi = &frame_data.fi;
+
+    self.output_frameno += 1;
 
-    // Copy persistent fields into subsequent FrameInvariants.
-    let rec_buffer = frame_data.fi.rec_buffer.clone();
+    if fi.show_frame {
+      let input_frameno = fi.input_frameno;
+      let frame_type = fi.frame_type;
+      let qp = fi.base_q_idx;
+      self
+        .finalize_packet(rec, source, input_frameno, frame_type, qp, enc_stats)
+        .map(|mut pkt| {
+          pkt.dropped = maybe_drop_slot.is_some();
+          pkt
+        })
+    } else {
+      Err(EncoderStatus::Encoded)
+    }
+  }
+
+  /// Copies the persistent fields into the `FrameInvariants` following
+  ///  `cur_output_frameno`.
+  fn propagate_rec_buffer(
+    &mut self, cur_output_frameno: u64, rec_buffer: &ReferenceFramesSet<T>,
+  ) {
     for subsequent_fi in self
       .frame_data
       .iter_mut()
@@ -1443,7 +2161,12 @@ impl<T: Pixel> ContextInner<T> {
       // for correct encoding, but it's needed for the reconstruction to
       // work correctly.
       .filter_map(|(_, frame_data)| frame_data.as_mut().map(|fd| &mut fd.fi))
-      .take_while(|fi| fi.frame_type != FrameType::KEY)
+      // A keyframe coded as shown does not use the previous references.
+      .take_while(|fi| {
+        fi.frame_type != FrameType::KEY
+          || !fi.show_frame
+          || fi.is_show_existing_frame()
+      })
     {
       subsequent_fi.rec_buffer = rec_buffer.clone();
       subsequent_fi.set_ref_frame_sign_bias();
@@ -1453,29 +2176,37 @@ impl<T: Pixel> ContextInner<T> {
         break;
       }
     }
+  }
 
-    self.frame_data.insert(cur_output_frameno, Some(frame_data));
-    let frame_data =
-      self.frame_data.get(&cur_output_frameno).unwrap().as_ref().unwrap();
-    let fi = &frame_data.fi;
-
-    self.output_frameno += 1;
-
-    if fi.show_frame {
-      let input_frameno = fi.input_frameno;
-      let frame_type = fi.frame_type;
-      let qp = fi.base_q_idx;
-      self.finalize_packet(
-        rec,
-        source,
-        input_frameno,
-        frame_type,
-        qp,
-        enc_stats,
-      )
-    } else {
-      Err(EncoderStatus::Encoded)
+  /// Returns the reference slot holding the previously shown frame if the
+  /// frame at `output_frameno` may be dropped, i.e. replaced by a Show
+  /// Existing Frame of it.
+  fn droppable_frame_slot(
+    &self, output_frameno: u64, fi: &FrameInvariants<T>,
+  ) -> Option<u32> {
+    // A reconfiguration applies to the frames already in the lookahead.
+    if !self.config.drop_frames
+      || !fi.show_frame
+      || fi.frame_type != FrameType::INTER
+    {
+      return None;
+    }
+    // Without reordering the most recently coded frame is the one shown
+    // last.
+    let (slot, last_output_frameno) = fi
+      .rec_buffer
+      .frames
+      .iter()
+      .enumerate()
+      .filter_map(|(slot, frame)| {
+        frame.as_ref().map(|frame| (slot as u32, frame.output_frameno))
+      })
+      .max_by_key(|&(_, output_frameno)| output_frameno)?;
+    // A shown keyframe cannot be shown again.
+    if last_output_frameno == self.gop_output_frameno_start[&output_frameno] {
+      return None;
     }
+    Some(slot)
   }
 
   #[profiling::function]
@@ -1531,6 +2262,9 @@ impl<T: Pixel> ContextInner<T> {
       return Err(EncoderStatus::Failure);
     }
 
+    let (alpha_data, alpha_rec) =
+      self.alpha.as_mut().map(AlphaStream::take_packet).unzip();
+
     self.frames_processed += 1;
     Ok(Packet {
       data,
@@ -1538,9 +2272,13 @@ impl<T: Pixel> ContextInner<T> {
       source,
       input_frameno,
       frame_type,
+      scene_label: self.keyframe_detector.scene_label(input_frameno),
       qp,
       enc_stats,
       opaque: None,
+      dropped: false,
+      alpha_data,
+      alpha_rec: alpha_rec.flatten(),
     })
   }
 
@@ -1553,6 +2291,8 @@ impl<T: Pixel> ContextInner<T> {
     for i in frame_q_start..cur_input_frameno {
       self.frame_q.remove(&i);
     }
+    self.keyframe_detector.forget_scene_labels_before(cur_input_frameno);
+    self.lookahead_qps.retain(|&i, _| i >= cur_input_frameno);
 
     if self.output_frameno < 2 {
       return;
@@ -1562,6 +2302,7 @@ impl<T: Pixel> ContextInner<T> {
       self.frame_data.remove(&i);
       self.gop_output_frameno_start.remove(&i);
       self.gop_input_frameno_start.remove(&i);
+      self.gop_order_hint_start.remove(&i);
     }
   }
 
@@ -1625,11 +2366,10 @@ impl<T: Pixel> ContextInner<T> {
         output_frameno - prev_keyframe_output_frameno;
       let is_kf =
         if let Some(Some(frame_data)) = self.frame_data.get(&output_frameno) {
-          if frame_data.fi.frame_type == FrameType::KEY {
+          // A forward keyframe starts its GOP when it is shown, which always
+          //  ends the current TU (thus we always increment ntus below).
+          if frame_data.fi.frame_type.all_intra() && frame_data.fi.show_frame {
             prev_keyframe_input_frameno = frame_data.fi.input_frameno;
-            // We do not currently use forward keyframes, so they should always
-            //  end the current TU (thus we always increment ntus below).
-            debug_assert!(frame_data.fi.show_frame);
             true
           } else {
             false
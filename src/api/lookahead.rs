@@ -196,6 +196,7 @@ pub(crate) fn estimate_inter_costs<T: Pixel>(
     &last_fi,
     &inter_cfg,
     0,
+    0,
     1,
     2,
     false,
@@ -0,0 +1,538 @@
+// Copyright (c) 2018-2022, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+#![deny(missing_docs)]
+
+use crate::api::T35;
+
+use bitstream_io::{BigEndian, BitRead, BitReader, BitWrite, BitWriter};
+use std::io;
+
+/// ITU-T T.35 country code of the United States.
+const T35_COUNTRY_CODE_US: u8 = 0xB5;
+/// ITU-T T.35 terminal provider code of SMPTE, used by HDR10+.
+const T35_PROVIDER_CODE_SMPTE: u16 = 0x003C;
+/// ITU-T T.35 terminal provider code of Dolby.
+const T35_PROVIDER_CODE_DOLBY: u16 = 0x003B;
+/// Terminal provider oriented code of the HDR10+ payloads.
+const HDR10PLUS_PROVIDER_ORIENTED_CODE: u16 = 0x0001;
+/// Application identifier of SMPTE ST 2094-40.
+const HDR10PLUS_APPLICATION_IDENTIFIER: u8 = 4;
+/// Terminal provider oriented code of the Dolby Vision payloads.
+const DOLBY_VISION_PROVIDER_ORIENTED_CODE: u32 = 0x0000_0800;
+/// The fixed EMDF container header wrapping a Dolby Vision RPU:
+/// `emdf_version` 0, `key_id` 6, `emdf_payload_id` 31 extended to 256,
+/// no optional fields and `discard_unknown_payload` set.
+const DOLBY_VISION_EMDF_HEADER: u32 = 0x01BE_6841;
+/// Largest number of rows or columns of a peak luminance matrix.
+const MAX_LUMINANCE_MATRIX_SIZE: usize = 25;
+
+/// HDR10+ dynamic metadata of a frame, as defined by SMPTE ST 2094-40 and
+/// carried in an ITU-T T.35 metadata OBU.
+///
+/// The luminances are in units of 1 cd/m², the `maxscl`, `average_maxrgb`
+/// and percentile values in units of 0.00001 of the peak luminance of the
+/// mastering display, and the other values in the fixed-point units of the
+/// specification.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Hdr10Plus {
+  /// Version of the application, 0 or 1.
+  pub application_version: u8,
+  /// Processing windows of the frame, between 1 and 3. The first one covers
+  /// the whole frame.
+  pub windows: Vec<Hdr10PlusWindow>,
+  /// Maximum luminance of the targeted display, up to 10000.
+  pub targeted_system_display_maximum_luminance: u32,
+  /// Peak luminance of the targeted display over regions of the frame, as
+  /// rows of 4-bit values.
+  pub targeted_system_display_actual_peak_luminance: Option<Vec<Vec<u8>>>,
+  /// Peak luminance of the mastering display over regions of the frame, as
+  /// rows of 4-bit values.
+  pub mastering_display_actual_peak_luminance: Option<Vec<Vec<u8>>>,
+}
+
+/// A processing window of HDR10+ dynamic metadata.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Hdr10PlusWindow {
+  /// Area of the window, which is only given for the windows after the
+  /// first one.
+  pub geometry: Option<Hdr10PlusWindowGeometry>,
+  /// Maximum of each of the red, green and blue components of the window,
+  /// on 17 bits.
+  pub maxscl: [u32; 3],
+  /// Average of the maximum component of each pixel, on 17 bits.
+  pub average_maxrgb: u32,
+  /// Distribution of the maximum component of each pixel, as at most 15
+  /// percentiles.
+  pub distribution_maxrgb: Vec<Hdr10PlusPercentile>,
+  /// Fraction of the pixels brighter than the highest percentile, on 10
+  /// bits.
+  pub fraction_bright_pixels: u16,
+  /// Tone mapping curve of the window.
+  pub tone_mapping: Option<Hdr10PlusToneMapping>,
+  /// Saturation weight of the window, on 6 bits.
+  pub color_saturation_weight: Option<u8>,
+}
+
+/// Area of an HDR10+ processing window, an ellipse within a rectangle.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Hdr10PlusWindowGeometry {
+  /// Horizontal position of the top-left corner of the window.
+  pub upper_left_corner_x: u16,
+  /// Vertical position of the top-left corner of the window.
+  pub upper_left_corner_y: u16,
+  /// Horizontal position of the bottom-right corner of the window.
+  pub lower_right_corner_x: u16,
+  /// Vertical position of the bottom-right corner of the window.
+  pub lower_right_corner_y: u16,
+  /// Horizontal position of the center of the ellipse.
+  pub center_of_ellipse_x: u16,
+  /// Vertical position of the center of the ellipse.
+  pub center_of_ellipse_y: u16,
+  /// Clockwise rotation of the ellipse, up to 180 degrees.
+  pub rotation_angle: u8,
+  /// Semi-major axis of the inner ellipse.
+  pub semimajor_axis_internal_ellipse: u16,
+  /// Semi-major axis of the outer ellipse.
+  pub semimajor_axis_external_ellipse: u16,
+  /// Semi-minor axis of the outer ellipse.
+  pub semiminor_axis_external_ellipse: u16,
+  /// Whether the overlapping windows are blended rather than layered.
+  pub overlap_process_option: bool,
+}
+
+/// A percentile of the distribution of the maximum component of the pixels.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Hdr10PlusPercentile {
+  /// Percentage of the pixels, on 7 bits.
+  pub percentage: u8,
+  /// Value of the maximum component below which this percentage of pixels
+  /// falls, on 17 bits.
+  pub percentile: u32,
+}
+
+/// Bézier tone mapping curve of an HDR10+ processing window.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Hdr10PlusToneMapping {
+  /// Horizontal position of the knee point, on 12 bits.
+  pub knee_point_x: u16,
+  /// Vertical position of the knee point, on 12 bits.
+  pub knee_point_y: u16,
+  /// At most 15 anchors of the curve above the knee point, on 10 bits.
+  pub bezier_curve_anchors: Vec<u16>,
+}
+
+/// A Dolby Vision RPU (reference processing unit) of a frame, passed
+/// through in an ITU-T T.35 metadata OBU.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DolbyVisionRpu {
+  /// The RPU, starting with its `rpu_nal_prefix`, without any NAL unit
+  /// header or emulation prevention bytes.
+  pub data: Box<[u8]>,
+}
+
+fn invalid_input(msg: &'static str) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+fn write_luminance_matrix<W: io::Write>(
+  bw: &mut BitWriter<W, BigEndian>, matrix: &Option<Vec<Vec<u8>>>,
+) -> io::Result<()> {
+  bw.write_bit(matrix.is_some())?;
+  if let Some(rows) = matrix {
+    let cols = rows.first().map_or(0, Vec::len);
+    if rows.len() > MAX_LUMINANCE_MATRIX_SIZE
+      || cols > MAX_LUMINANCE_MATRIX_SIZE
+      || rows.iter().any(|row| row.len() != cols)
+    {
+      return Err(invalid_input("invalid peak luminance matrix size"));
+    }
+    bw.write(5, rows.len() as u8)?;
+    bw.write(5, cols as u8)?;
+    for &value in rows.iter().flatten() {
+      bw.write(4, value)?;
+    }
+  }
+  Ok(())
+}
+
+fn read_luminance_matrix<R: io::Read>(
+  br: &mut BitReader<R, BigEndian>,
+) -> io::Result<Option<Vec<Vec<u8>>>> {
+  if !br.read_bit()? {
+    return Ok(None);
+  }
+  let num_rows = br.read::<u8>(5)? as usize;
+  let num_cols = br.read::<u8>(5)? as usize;
+  if num_rows > MAX_LUMINANCE_MATRIX_SIZE
+    || num_cols > MAX_LUMINANCE_MATRIX_SIZE
+  {
+    return Err(invalid_input("invalid peak luminance matrix size"));
+  }
+  let mut rows = Vec::with_capacity(num_rows);
+  for _ in 0..num_rows {
+    rows.push((0..num_cols).map(|_| br.read(4)).collect::<io::Result<_>>()?);
+  }
+  Ok(Some(rows))
+}
+
+/// Writes `value` as the `variable_bits(n)` of an EMDF container.
+fn write_variable_bits<W: io::Write>(
+  bw: &mut BitWriter<W, BigEndian>, n: u32, mut value: u32,
+) -> io::Result<()> {
+  let mask = (1 << n) - 1;
+  let mut chunks = vec![value & mask];
+  value >>= n;
+  while value > 0 {
+    value -= 1;
+    chunks.push(value & mask);
+    value >>= n;
+  }
+  for (i, &chunk) in chunks.iter().rev().enumerate() {
+    bw.write(n, chunk)?;
+    bw.write_bit(i + 1 < chunks.len())?; // read_more
+  }
+  Ok(())
+}
+
+/// Reads the `variable_bits(n)` of an EMDF container.
+fn read_variable_bits<R: io::Read>(
+  br: &mut BitReader<R, BigEndian>, n: u32,
+) -> io::Result<u64> {
+  let mut value = 0u64;
+  for _ in 0..4 {
+    value += br.read::<u64>(n)?;
+    if !br.read_bit()? {
+      return Ok(value);
+    }
+    value = (value + 1) << n;
+  }
+  Err(invalid_input("EMDF variable bits overflow"))
+}
+
+/// Reads the country and provider codes of a T.35 payload of the United
+/// States, returning the reader positioned after them.
+fn us_provider_payload(
+  t35: &T35, provider_code: u16,
+) -> Option<BitReader<&[u8], BigEndian>> {
+  if t35.country_code != T35_COUNTRY_CODE_US {
+    return None;
+  }
+  let mut br = BitReader::endian(&t35.data[..], BigEndian);
+  (br.read::<u16>(16).ok()? == provider_code).then_some(br)
+}
+
+impl Hdr10Plus {
+  /// Serializes the metadata into a T.35 payload.
+  ///
+  /// # Errors
+  ///
+  /// Returns an `InvalidInput` error if a value does not fit in its field
+  /// or the windows do not follow the layout of SMPTE ST 2094-40.
+  pub fn to_t35(&self) -> io::Result<T35> {
+    let num_windows = self.windows.len();
+    if !(1..=3).contains(&num_windows)
+      || self.windows[0].geometry.is_some()
+      || self.windows[1..].iter().any(|window| window.geometry.is_none())
+    {
+      return Err(invalid_input("invalid HDR10+ processing windows"));
+    }
+
+    let mut data = Vec::new();
+    let mut bw = BitWriter::endian(&mut data, BigEndian);
+    bw.write(16, T35_PROVIDER_CODE_SMPTE)?;
+    bw.write(16, HDR10PLUS_PROVIDER_ORIENTED_CODE)?;
+    bw.write(8, HDR10PLUS_APPLICATION_IDENTIFIER)?;
+    bw.write(8, self.application_version)?;
+    bw.write(2, num_windows as u8)?;
+    for geometry in self.windows[1..].iter().filter_map(|w| w.geometry) {
+      bw.write(16, geometry.upper_left_corner_x)?;
+      bw.write(16, geometry.upper_left_corner_y)?;
+      bw.write(16, geometry.lower_right_corner_x)?;
+      bw.write(16, geometry.lower_right_corner_y)?;
+      bw.write(16, geometry.center_of_ellipse_x)?;
+      bw.write(16, geometry.center_of_ellipse_y)?;
+      bw.write(8, geometry.rotation_angle)?;
+      bw.write(16, geometry.semimajor_axis_internal_ellipse)?;
+      bw.write(16, geometry.semimajor_axis_external_ellipse)?;
+      bw.write(16, geometry.semiminor_axis_external_ellipse)?;
+      bw.write_bit(geometry.overlap_process_option)?;
+    }
+    bw.write(27, self.targeted_system_display_maximum_luminance)?;
+    write_luminance_matrix(
+      &mut bw,
+      &self.targeted_system_display_actual_peak_luminance,
+    )?;
+    for window in self.windows.iter() {
+      for &maxscl in window.maxscl.iter() {
+        bw.write(17, maxscl)?;
+      }
+      bw.write(17, window.average_maxrgb)?;
+      if window.distribution_maxrgb.len() > 15 {
+        return Err(invalid_input("too many HDR10+ maxrgb percentiles"));
+      }
+      bw.write(4, window.distribution_maxrgb.len() as u8)?;
+      for percentile in window.distribution_maxrgb.iter() {
+        bw.write(7, percentile.percentage)?;
+        bw.write(17, percentile.percentile)?;
+      }
+      bw.write(10, window.fraction_bright_pixels)?;
+    }
+    write_luminance_matrix(
+      &mut bw,
+      &self.mastering_display_actual_peak_luminance,
+    )?;
+    for window in self.windows.iter() {
+      bw.write_bit(window.tone_mapping.is_some())?;
+      if let Some(tone_mapping) = &window.tone_mapping {
+        bw.write(12, tone_mapping.knee_point_x)?;
+        bw.write(12, tone_mapping.knee_point_y)?;
+        if tone_mapping.bezier_curve_anchors.len() > 15 {
+          return Err(invalid_input("too many HDR10+ Bézier curve anchors"));
+        }
+        bw.write(4, tone_mapping.bezier_curve_anchors.len() as u8)?;
+        for &anchor in tone_mapping.bezier_curve_anchors.iter() {
+          bw.write(10, anchor)?;
+        }
+      }
+      bw.write_bit(window.color_saturation_weight.is_some())?;
+      if let Some(weight) = window.color_saturation_weight {
+        bw.write(6, weight)?;
+      }
+    }
+    bw.byte_align()?;
+
+    Ok(T35 {
+      country_code: T35_COUNTRY_CODE_US,
+      country_code_extension_byte: 0,
+      data: data.into_boxed_slice(),
+    })
+  }
+
+  /// Parses the metadata from a T.35 payload, returning `None` if it is not
+  /// a valid HDR10+ one.
+  pub fn from_t35(t35: &T35) -> Option<Self> {
+    let mut br = us_provider_payload(t35, T35_PROVIDER_CODE_SMPTE)?;
+    if br.read::<u16>(16).ok()? != HDR10PLUS_PROVIDER_ORIENTED_CODE
+      || br.read::<u8>(8).ok()? != HDR10PLUS_APPLICATION_IDENTIFIER
+    {
+      return None;
+    }
+    Self::read(&mut br).ok()
+  }
+
+  fn read<R: io::Read>(br: &mut BitReader<R, BigEndian>) -> io::Result<Self> {
+    let application_version = br.read(8)?;
+    let num_windows = br.read::<u8>(2)? as usize;
+    if num_windows == 0 {
+      return Err(invalid_input("invalid HDR10+ processing windows"));
+    }
+    let mut windows = vec![Hdr10PlusWindow::default(); num_windows];
+    for window in windows[1..].iter_mut() {
+      window.geometry = Some(Hdr10PlusWindowGeometry {
+        upper_left_corner_x: br.read(16)?,
+        upper_left_corner_y: br.read(16)?,
+        lower_right_corner_x: br.read(16)?,
+        lower_right_corner_y: br.read(16)?,
+        center_of_ellipse_x: br.read(16)?,
+        center_of_ellipse_y: br.read(16)?,
+        rotation_angle: br.read(8)?,
+        semimajor_axis_internal_ellipse: br.read(16)?,
+        semimajor_axis_external_ellipse: br.read(16)?,
+        semiminor_axis_external_ellipse: br.read(16)?,
+        overlap_process_option: br.read_bit()?,
+      });
+    }
+    let targeted_system_display_maximum_luminance = br.read(27)?;
+    let targeted_system_display_actual_peak_luminance =
+      read_luminance_matrix(br)?;
+    for window in windows.iter_mut() {
+      for maxscl in window.maxscl.iter_mut() {
+        *maxscl = br.read(17)?;
+      }
+      window.average_maxrgb = br.read(17)?;
+      let num_percentiles = br.read::<u8>(4)?;
+      for _ in 0..num_percentiles {
+        window.distribution_maxrgb.push(Hdr10PlusPercentile {
+          percentage: br.read(7)?,
+          percentile: br.read(17)?,
+        });
+      }
+      window.fraction_bright_pixels = br.read(10)?;
+    }
+    let mastering_display_actual_peak_luminance = read_luminance_matrix(br)?;
+    for window in windows.iter_mut() {
+      if br.read_bit()? {
+        let knee_point_x = br.read(12)?;
+        let knee_point_y = br.read(12)?;
+        let num_anchors = br.read::<u8>(4)?;
+        window.tone_mapping = Some(Hdr10PlusToneMapping {
+          knee_point_x,
+          knee_point_y,
+          bezier_curve_anchors: (0..num_anchors)
+            .map(|_| br.read(10))
+            .collect::<io::Result<_>>()?,
+        });
+      }
+      if br.read_bit()? {
+        window.color_saturation_weight = Some(br.read(6)?);
+      }
+    }
+
+    Ok(Hdr10Plus {
+      application_version,
+      windows,
+      targeted_system_display_maximum_luminance,
+      targeted_system_display_actual_peak_luminance,
+      mastering_display_actual_peak_luminance,
+    })
+  }
+}
+
+impl DolbyVisionRpu {
+  /// Serializes the RPU into a T.35 payload, wrapped in an EMDF container.
+  ///
+  /// # Errors
+  ///
+  /// Returns an `InvalidInput` error if the RPU is empty.
+  pub fn to_t35(&self) -> io::Result<T35> {
+    if self.data.is_empty() {
+      return Err(invalid_input("empty Dolby Vision RPU"));
+    }
+
+    let mut data = Vec::with_capacity(self.data.len() + 16);
+    let mut bw = BitWriter::endian(&mut data, BigEndian);
+    bw.write(16, T35_PROVIDER_CODE_DOLBY)?;
+    bw.write(32, DOLBY_VISION_PROVIDER_ORIENTED_CODE)?;
+    bw.write(27, DOLBY_VISION_EMDF_HEADER)?;
+    write_variable_bits(&mut bw, 8, self.data.len() as u32)?; // emdf_payload_size
+    bw.write_bytes(&self.data)?;
+    // emdf_payload_id: end of the payloads
+    bw.write(5, 0u8)?;
+    // emdf_protection: protection_length_primary 1,
+    //  protection_length_secondary 0 and 8 protection_bits_primary
+    bw.write(12, 0x400u16)?;
+    bw.byte_align()?;
+
+    Ok(T35 {
+      country_code: T35_COUNTRY_CODE_US,
+      country_code_extension_byte: 0,
+      data: data.into_boxed_slice(),
+    })
+  }
+
+  /// Parses the RPU from a T.35 payload, returning `None` if it is not a
+  /// valid Dolby Vision one.
+  pub fn from_t35(t35: &T35) -> Option<Self> {
+    let mut br = us_provider_payload(t35, T35_PROVIDER_CODE_DOLBY)?;
+    if br.read::<u32>(32).ok()? != DOLBY_VISION_PROVIDER_ORIENTED_CODE
+      || br.read::<u32>(27).ok()? != DOLBY_VISION_EMDF_HEADER
+    {
+      return None;
+    }
+    let size = read_variable_bits(&mut br, 8).ok()? as usize;
+    if size == 0 || size > t35.data.len() {
+      return None;
+    }
+    let mut data = vec![0; size];
+    br.read_bytes(&mut data).ok()?;
+    Some(DolbyVisionRpu { data: data.into_boxed_slice() })
+  }
+}
+
+/// The `counting_type` of timecodes dropping the frame numbers 0 and 1 at
+/// the start of every minute which is not a multiple of 10, as in the NTSC
+/// drop-frame timecode.
+pub const TIMECODE_COUNTING_DROP_FRAME: u8 = 4;
+
+/// Timecode of a frame, written in a timecode metadata OBU as defined by
+/// the AV1 specification (section 6.7.7), after SMPTE ST 12-1.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Timecode {
+  /// Method of dropping frame numbers, from 0 (no dropping) to 6, as
+  /// defined by the `counting_type` of ITU-T H.264.
+  pub counting_type: u8,
+  /// Whether the seconds, minutes and hours are written. Otherwise only the
+  /// frame number is, and the other units are those of the previous
+  /// timecode.
+  pub full_timestamp: bool,
+  /// Whether the timecode does not follow the previous one.
+  pub discontinuity: bool,
+  /// Whether frame numbers were dropped right before this frame, as
+  /// specified by `counting_type`.
+  pub drop_frame: bool,
+  /// Hours, up to 23.
+  pub hours: u8,
+  /// Minutes, up to 59.
+  pub minutes: u8,
+  /// Seconds, up to 59.
+  pub seconds: u8,
+  /// Frame number within the second, on 9 bits.
+  pub frames: u16,
+  /// Offset of the frame from the timecode, on 31 bits, in units of the
+  /// `time_scale` of the timing info.
+  pub time_offset: u32,
+}
+
+impl Timecode {
+  /// Returns whether every value is within the range of its field.
+  pub fn is_valid(&self) -> bool {
+    self.counting_type <= 6
+      && self.hours < 24
+      && self.minutes < 60
+      && self.seconds < 60
+      && self.frames < 512
+      && self.time_offset < 1 << 31
+  }
+
+  /// Returns the timecode of the frame coming `count` frames after this one,
+  /// counting `frame_rate` frames per second, rounded to an integer.
+  ///
+  /// Frame numbers are dropped with the `TIMECODE_COUNTING_DROP_FRAME`
+  /// counting type, which is meant for a frame rate of 30000/1001.
+  #[must_use]
+  pub fn add_frames(&self, count: u64, frame_rate: u32) -> Self {
+    let fps = u64::from(frame_rate.max(1));
+    let dropped =
+      if self.counting_type == TIMECODE_COUNTING_DROP_FRAME { 2 } else { 0 };
+    let frames_per_minute = fps * 60 - dropped;
+    let frames_per_10_minutes = fps * 600 - dropped * 9;
+    let frames_per_day = frames_per_10_minutes * 144;
+
+    let minutes = u64::from(self.hours) * 60 + u64::from(self.minutes);
+    let frameno = (minutes * 60 + u64::from(self.seconds)) * fps
+      + u64::from(self.frames)
+      - dropped * (minutes - minutes / 10);
+    let mut frameno = (frameno + count) % frames_per_day;
+
+    // Skip the frame numbers dropped before the frame.
+    let (tens_of_minutes, rest) =
+      (frameno / frames_per_10_minutes, frameno % frames_per_10_minutes);
+    frameno += dropped * 9 * tens_of_minutes;
+    if rest > dropped {
+      frameno += dropped * ((rest - dropped) / frames_per_minute);
+    }
+
+    let seconds = frameno / fps;
+    let timecode = Timecode {
+      discontinuity: false,
+      hours: (seconds / 3600 % 24) as u8,
+      minutes: (seconds / 60 % 60) as u8,
+      seconds: (seconds % 60) as u8,
+      frames: (frameno % fps) as u16,
+      ..*self
+    };
+    Timecode {
+      drop_frame: dropped > 0
+        && timecode.seconds == 0
+        && timecode.minutes % 10 != 0
+        && u64::from(timecode.frames) == dropped,
+      ..timecode
+    }
+  }
+}
@@ -8,9 +8,15 @@
 // PATENTS file, you can obtain it at www.aomedia.org/license/patent.
 #![deny(missing_docs)]
 
+/// Alpha auxiliary stream
+pub(crate) mod alpha;
+/// AVIF still images
+mod avif;
 /// Channel-based encoder
 #[cfg(all(feature = "channel-api", feature = "unstable"))]
 pub mod channel;
+/// Checkpoints to resume an interrupted encode
+mod checkpoint;
 /// Color model information
 pub mod color;
 /// Encoder Configuration
@@ -21,16 +27,21 @@ pub mod context;
 pub(crate) mod internal;
 /// Lookahead-specific methods
 pub(crate) mod lookahead;
+/// Per-frame metadata, such as HDR10+ and timecodes
+mod metadata;
 
 mod util;
 
 #[cfg(test)]
 mod test;
 
+pub use avif::*;
 #[cfg(all(feature = "channel-api", feature = "unstable"))]
 pub use channel::*;
+pub use checkpoint::*;
 pub use color::*;
 pub use config::*;
 pub use context::*;
 pub(crate) use internal::*;
+pub use metadata::*;
 pub use util::*;
@@ -145,6 +145,230 @@ mod channel {
 
     assert_eq!(limit, count);
   }
+
+  #[test]
+  fn reconfigure_key_frame_interval() {
+    let cfg = setup_config(
+      64,
+      80,
+      10,
+      100,
+      8,
+      ChromaSampling::Cs420,
+      0,
+      200,
+      0,
+      true,
+      0,
+      true,
+      10,
+      None,
+    );
+    let mut enc = cfg.enc.clone();
+
+    let limit = 40;
+
+    let (mut sf, rp) = cfg.new_channel::<u8>().unwrap();
+
+    for i in 0..limit {
+      if i == 10 {
+        enc.max_key_frame_interval = 5;
+        sf.reconfigure(enc.clone()).unwrap();
+      }
+      let input = sf.new_frame();
+      let _ = sf.send(input);
+    }
+
+    enc.width = 32;
+    assert_eq!(
+      sf.reconfigure(enc),
+      Err(InvalidConfig::InvalidReconfiguration("width"))
+    );
+
+    drop(sf);
+
+    let keyframes: Vec<_> = rp
+      .iter()
+      .filter(|pkt| pkt.frame_type == FrameType::KEY)
+      .map(|pkt| pkt.input_frameno)
+      .collect();
+    assert_eq!(keyframes[0], 0);
+    assert!(keyframes.windows(2).skip(1).all(|w| w[1] - w[0] == 5));
+    assert!(keyframes.last().unwrap() >= &(limit - 5));
+  }
+
+  #[test]
+  fn alpha_channel() {
+    let mut cfg = setup_config(
+      64,
+      80,
+      10,
+      100,
+      8,
+      ChromaSampling::Cs420,
+      150,
+      200,
+      0,
+      false,
+      0,
+      true,
+      10,
+      None,
+    );
+    cfg.enc.alpha_quantizer = Some(50);
+
+    let limit = 10;
+
+    let (mut sf, rp) = cfg.new_channel::<u8>().unwrap();
+
+    for _ in 0..limit {
+      let input = sf.new_frame();
+      let mut alpha = sf.new_alpha_plane();
+      alpha.data.fill(128);
+      let params = FrameParameters {
+        alpha: Some(AlphaPlane::new(alpha)),
+        ..Default::default()
+      };
+      let _ = sf.send((Arc::new(input), params));
+    }
+
+    drop(sf);
+
+    let packets: Vec<_> = rp.iter().collect();
+    assert_eq!(packets.len(), limit);
+    assert!(packets.iter().all(|pkt| pkt.alpha_data.is_some()));
+
+    assert!(matches!(
+      cfg.with_parallel_gops(2).new_channel::<u8>(),
+      Err(InvalidConfig::InvalidOptionWithParallelGops("alpha_quantizer"))
+    ));
+  }
+
+  #[test]
+  fn firstpass_channel_summary_size() {
+    let cfg = setup_config(
+      64,
+      80,
+      10,
+      100,
+      8,
+      ChromaSampling::Cs420,
+      0,
+      5,
+      1000,
+      true,
+      0,
+      true,
+      10,
+      None,
+    );
+
+    let limit = 12;
+
+    let ((mut sf, rp), rcr) = cfg
+      .with_rate_control(RateControlConfig::new().with_emit_data(true))
+      .new_firstpass_channel::<u8>()
+      .unwrap();
+    assert_eq!(rcr.summary_size(), crate::rate::TWOPASS_HEADER_SZ);
+
+    for _ in 0..limit {
+      let input = sf.new_frame();
+      let _ = sf.send(input);
+    }
+
+    drop(sf);
+
+    assert_eq!(rp.iter().count(), limit);
+    let summary = rcr
+      .iter()
+      .find_map(|data| match data {
+        RcData::Summary(data) => Some(data),
+        RcData::Frame(_) => None,
+      })
+      .unwrap();
+    // The keyframes at 0, 5 and 10 are listed.
+    assert_eq!(
+      summary.len(),
+      crate::rate::TWOPASS_HEADER_SZ + 3 * crate::rate::TWOPASS_KEYFRAME_SZ
+    );
+    assert_eq!(summary.len(), rcr.summary_size());
+  }
+}
+
+#[cfg(feature = "scenechange")]
+mod scene_detection {
+  use super::*;
+  use crate::scenechange::*;
+
+  fn detect_scenes(
+    config: SceneDetectionConfig, limit: u64, scene_change_at: u64,
+  ) -> Vec<SceneDecision> {
+    let mut detector = SceneDetector::<u8>::new(config).unwrap();
+    let mut decisions = Vec::new();
+    for i in 0..limit {
+      let mut frame = detector.new_frame();
+      fill_frame_const(
+        &mut frame,
+        if i < scene_change_at { u8::MIN } else { u8::MAX },
+      );
+      detector.send_frame(Arc::new(frame)).unwrap();
+      while let Ok(decision) = detector.receive_decision() {
+        decisions.push(decision);
+      }
+    }
+    assert_eq!(detector.receive_decision(), Err(EncoderStatus::NeedMoreData));
+    detector.flush();
+    loop {
+      match detector.receive_decision() {
+        Ok(decision) => decisions.push(decision),
+        Err(status) => {
+          assert_eq!(status, EncoderStatus::LimitReached);
+          break;
+        }
+      }
+    }
+    assert_eq!(
+      detector.send_frame(Arc::new(detector.new_frame())),
+      Err(EncoderStatus::EnoughData)
+    );
+    decisions
+  }
+
+  #[interpolate_test(standard, SceneDetectionSpeed::Standard)]
+  #[interpolate_test(fast, SceneDetectionSpeed::Fast)]
+  fn scene_cuts(speed: SceneDetectionSpeed) {
+    let config = SceneDetectionConfig {
+      width: 64,
+      height: 80,
+      speed,
+      min_scene_len: 0,
+      max_scene_len: 15,
+      ..Default::default()
+    };
+    let decisions = detect_scenes(config, 30, 10);
+
+    assert!(decisions
+      .iter()
+      .enumerate()
+      .all(|(i, decision)| decision.input_frameno == i as u64));
+    let scene_cuts = decisions
+      .iter()
+      .filter(|decision| decision.scene_cut)
+      .map(|decision| decision.input_frameno)
+      .collect::<Vec<_>>();
+    assert_eq!(&scene_cuts[..], &[0, 10, 25][..]);
+    assert_eq!(decisions[10].label, SceneLabel::SceneCut);
+    let score = decisions[10].score.unwrap();
+    assert!(score.forward_adjusted_cost >= score.threshold);
+    assert!(decisions[9].score.is_some());
+  }
+
+  #[test]
+  fn invalid_config() {
+    let config = SceneDetectionConfig { width: 8, ..Default::default() };
+    assert_eq!(config.validate(), Err(InvalidConfig::InvalidWidth(8)));
+    assert!(SceneDetector::<u8>::new(config).is_err());
+  }
 }
 
 #[interpolate_test(low_latency_no_scene_change, true, true)]
@@ -1316,6 +1540,7 @@ fn send_frame_kf<T: Pixel>(ctx: &mut Context<T>, keyframe: bool) {
     frame_type_override,
     opaque,
     t35_metadata: Box::new([]),
+    ..Default::default()
   };
 
   let _ = ctx.send_frame((input, fp));
@@ -1370,7 +1595,12 @@ fn send_frame_t35<T: Pixel>(ctx: &mut Context<T>) {
     data: Box::new(*b"AYAYA"),
   }]);
 
-  let fp = FrameParameters { frame_type_override, opaque, t35_metadata };
+  let fp = FrameParameters {
+    frame_type_override,
+    opaque,
+    t35_metadata,
+    ..Default::default()
+  };
 
   let _ = ctx.send_frame((input, fp));
 }
@@ -1791,6 +2021,65 @@ struct LookaheadTestExpectations {
   post_receive_fi_lens: [usize; 60],
 }
 
+#[test]
+fn flash_is_labeled_and_not_referenced() {
+  let mut ctx = setup_encoder::<u8>(
+    64,
+    80,
+    10,
+    100,
+    8,
+    ChromaSampling::Cs420,
+    0,
+    30,
+    0,
+    true,
+    0,
+    false,
+    10,
+    None,
+  );
+
+  let limit = 6;
+  for i in 0..limit {
+    if i == 2 {
+      send_test_frame(&mut ctx, u8::MIN);
+    } else {
+      send_test_frame(&mut ctx, u8::MAX);
+    }
+  }
+  ctx.flush();
+
+  // The flash does not replace any reference of the following frames.
+  let refresh = ctx
+    .inner
+    .frame_data
+    .values()
+    .flatten()
+    .map(|fd| (fd.fi.input_frameno, fd.fi.refresh_frame_flags))
+    .collect::<Vec<_>>();
+  assert!(refresh.contains(&(2, 0)));
+  assert!(refresh.iter().all(|&(frameno, flags)| frameno == 2 || flags != 0));
+
+  let mut labels = Vec::new();
+  while let Ok(pkt) = ctx.receive_packet() {
+    labels.push((pkt.input_frameno, pkt.frame_type, pkt.scene_label));
+  }
+  use FrameType::*;
+  use SceneLabel::*;
+  assert_eq!(
+    &labels[..],
+    &[
+      (0, KEY, Normal),
+      (1, INTER, Normal),
+      (2, INTER, Flash),
+      (3, INTER, Normal),
+      (4, INTER, Normal),
+      (5, INTER, Normal),
+    ][..]
+  );
+}
+
 #[test]
 fn lookahead_size_properly_bounded_8() {
   const LOOKAHEAD_SIZE: usize = 8;
@@ -2120,13 +2409,21 @@ fn log_q_exp_overflow() {
     time_base: Rational { num: 1, den: 25 },
     min_key_frame_interval: 12,
     max_key_frame_interval: 240,
+    open_gop: false,
     reservoir_frame_delay: None,
     low_latency: false,
+    drop_frames: false,
     quantizer: 100,
     min_quantizer: 64,
+    alpha_quantizer: None,
     bitrate: 1,
     tune: Tune::Psychovisual,
     film_grain_params: None,
+    denoise_noise_level: 0,
+    denoise_temporal_radius: 1,
+    denoise_grain_synthesis: true,
+    forced_keyframes: None,
+    zones: None,
     tile_cols: 0,
     tile_rows: 0,
     tiles: 0,
@@ -2197,13 +2494,21 @@ fn guess_frame_subtypes_assert() {
     time_base: Rational { num: 1, den: 25 },
     min_key_frame_interval: 0,
     max_key_frame_interval: 1,
+    open_gop: false,
     reservoir_frame_delay: None,
     low_latency: false,
+    drop_frames: false,
     quantizer: 100,
     min_quantizer: 0,
+    alpha_quantizer: None,
     bitrate: 16384,
     tune: Tune::Psychovisual,
     film_grain_params: None,
+    denoise_noise_level: 0,
+    denoise_temporal_radius: 1,
+    denoise_grain_synthesis: true,
+    forced_keyframes: None,
+    zones: None,
     tile_cols: 0,
     tile_rows: 0,
     tiles: 0,
@@ -2379,3 +2684,1804 @@ fn max_quantizer_bounds_correctly() {
     }
   }
 }
+
+#[test]
+fn zone_quantizer_overrides_rate_control() {
+  let mut cfg = setup_config(
+    64,
+    80,
+    10,
+    255,
+    8,
+    ChromaSampling::Cs420,
+    25,
+    25,
+    25000,
+    true,
+    0,
+    true,
+    1,
+    None,
+  );
+  cfg.enc.zones =
+    Some(vec![Zone { quantizer: Some(200), ..Zone::new(10, 20) }]);
+  let mut ctx: Context<u8> = cfg.new_context().unwrap();
+
+  let limit = 25;
+  send_frames(&mut ctx, limit, 0);
+  ctx.flush();
+
+  for i in 0..limit {
+    ctx.inner.encode_packet(i).unwrap();
+    let frame_data = ctx.inner.frame_data.get(&i).unwrap().as_ref().unwrap();
+    if (10..20).contains(&frame_data.fi.input_frameno) {
+      assert_eq!(200, frame_data.fi.config.quantizer);
+      assert!(frame_data.fi.base_q_idx > 150);
+    } else {
+      assert_eq!(255, frame_data.fi.config.quantizer);
+      assert!(frame_data.fi.base_q_idx < 150);
+    }
+  }
+}
+
+#[test]
+fn zone_overrides_frame_settings() {
+  let mut cfg = setup_config(
+    64,
+    80,
+    6,
+    100,
+    8,
+    ChromaSampling::Cs420,
+    0,
+    200,
+    0,
+    true,
+    0,
+    true,
+    1,
+    None,
+  );
+  let zone_speed_settings = SpeedSettings::from_preset(10);
+  cfg.enc.zones = Some(vec![Zone {
+    speed_settings: Some(zone_speed_settings),
+    tune: Some(Tune::Psnr),
+    force_keyframe: true,
+    ..Zone::new(7, 12)
+  }]);
+  let mut ctx: Context<u8> = cfg.new_context().unwrap();
+
+  let limit = 15;
+  send_frames(&mut ctx, limit, 0);
+  ctx.flush();
+
+  for fi in get_frame_invariants(ctx).flatten() {
+    let in_zone = (7..12).contains(&fi.input_frameno);
+    assert_eq!(
+      fi.frame_type == FrameType::KEY,
+      fi.input_frameno == 0 || fi.input_frameno == 7
+    );
+    assert_eq!(fi.config.tune == Tune::Psnr, in_zone);
+    assert_eq!(
+      fi.partition_range,
+      if in_zone {
+        zone_speed_settings.partition.partition_range
+      } else {
+        cfg.enc.speed_settings.partition.partition_range
+      }
+    );
+    // Sequence-wide settings are kept from the base configuration.
+    assert_eq!(
+      fi.config.speed_settings.rdo_lookahead_frames,
+      cfg.enc.speed_settings.rdo_lookahead_frames
+    );
+  }
+}
+
+#[test]
+fn invalid_zones() {
+  let mut enc = EncoderConfig {
+    zones: Some(vec![Zone::new(0, 10), Zone::new(5, 15)]),
+    ..Default::default()
+  };
+  let config = Config::new().with_encoder_config(enc.clone());
+  assert_eq!(
+    config.validate(),
+    Err(InvalidConfig::InvalidZoneRange { start: 5, end: 15 })
+  );
+
+  enc.zones = Some(vec![Zone::new(10, 10)]);
+  let config = Config::new().with_encoder_config(enc.clone());
+  assert_eq!(
+    config.validate(),
+    Err(InvalidConfig::InvalidZoneRange { start: 10, end: 10 })
+  );
+
+  enc.zones =
+    Some(vec![Zone { bitrate_multiplier: Some(0.0), ..Zone::new(3, 10) }]);
+  let config = Config::new().with_encoder_config(enc);
+  assert_eq!(
+    config.validate(),
+    Err(InvalidConfig::InvalidZoneBitrateMultiplier(3))
+  );
+}
+
+#[test]
+fn second_pass_replays_first_pass_keyframes() {
+  let cfg = setup_config(
+    64,
+    80,
+    10,
+    100,
+    8,
+    ChromaSampling::Cs420,
+    0,
+    200,
+    1000,
+    true,
+    0,
+    true,
+    1,
+    None,
+  );
+  let limit = 12;
+  let kf_at = 7;
+
+  let mut ctx: Context<u8> = cfg
+    .clone()
+    .with_rate_control(RateControlConfig::new().with_emit_data(true))
+    .new_context()
+    .unwrap();
+  for i in 0..limit {
+    send_frame_kf(&mut ctx, i == kf_at);
+  }
+  ctx.flush();
+
+  let mut pass_data = Vec::new();
+  let mut summary = None;
+  loop {
+    let status = ctx.receive_packet();
+    match ctx.rc_receive_pass_data() {
+      Some(RcData::Frame(data)) => pass_data.push(data),
+      Some(RcData::Summary(data)) => summary = Some(data),
+      None => {}
+    }
+    if let Err(EncoderStatus::LimitReached) = status {
+      break;
+    }
+  }
+  let summary = summary.unwrap();
+  assert_eq!(summary.len(), ctx.rc_summary_size());
+
+  let rc = RateControlConfig::from_summary_slice(&summary).unwrap();
+  let mut ctx: Context<u8> = cfg.with_rate_control(rc).new_context().unwrap();
+  send_frames(&mut ctx, limit, 0);
+  ctx.flush();
+
+  let mut pass_data = pass_data.into_iter();
+  loop {
+    for _ in 0..ctx.rc_second_pass_data_required() {
+      ctx.rc_send_pass_data(&pass_data.next().unwrap()).unwrap();
+    }
+    match ctx.receive_packet() {
+      Ok(pkt) => assert_eq!(
+        pkt.frame_type == FrameType::KEY,
+        pkt.input_frameno == 0 || pkt.input_frameno == kf_at
+      ),
+      Err(EncoderStatus::LimitReached) => break,
+      Err(EncoderStatus::Encoded) => {}
+      Err(e) => panic!("Unexpected status {e:?}"),
+    }
+  }
+}
+
+#[test]
+fn forced_keyframes() {
+  let mut cfg = setup_config(
+    64,
+    80,
+    10,
+    100,
+    8,
+    ChromaSampling::Cs420,
+    0,
+    200,
+    0,
+    false,
+    0,
+    true,
+    10,
+    None,
+  );
+  cfg.enc.forced_keyframes = Some(vec![5, 9]);
+  let mut ctx: Context<u8> = cfg.new_context().unwrap();
+
+  let limit = 15;
+  send_frames(&mut ctx, limit, 0);
+  ctx.flush();
+
+  for fi in get_frame_invariants(ctx).flatten() {
+    assert_eq!(
+      fi.frame_type == FrameType::KEY,
+      [0, 5, 9].contains(&fi.input_frameno)
+    );
+  }
+}
+
+#[test]
+fn open_gop_forward_keyframes() {
+  let mut cfg = setup_config(
+    64,
+    80,
+    10,
+    100,
+    8,
+    ChromaSampling::Cs420,
+    0,
+    8,
+    0,
+    false,
+    0,
+    true,
+    10,
+    None,
+  );
+  cfg.enc.open_gop = true;
+  let mut ctx: Context<u8> = cfg.new_context().unwrap();
+
+  let limit = 13;
+  send_frames(&mut ctx, limit, 0);
+  ctx.flush();
+
+  // data[output_frameno] = (input_frameno, frame_type, show_frame, is_sef)
+  let fis = get_frame_invariants(ctx).flatten().collect::<Vec<_>>();
+  // The order hints keep counting across the forward keyframe.
+  for fi in fis.iter() {
+    assert_eq!(fi.order_hint as u64, fi.input_frameno);
+  }
+  let data = fis
+    .iter()
+    .map(|fi| {
+      (
+        fi.input_frameno,
+        fi.frame_type,
+        fi.show_frame,
+        fi.is_show_existing_frame(),
+      )
+    })
+    .collect::<Vec<_>>();
+
+  use FrameType::*;
+  assert_eq!(
+    &data[..],
+    &[
+      (0, KEY, true, false),
+      (4, INTER, false, false),
+      (2, INTER, false, false),
+      (1, INTER, true, false),
+      (2, INTER, true, true),
+      (3, INTER, true, false),
+      (4, INTER, true, true),
+      // The keyframe is coded ahead of the frames referencing it...
+      (8, KEY, false, false),
+      (6, INTER, false, false),
+      (5, INTER, true, false),
+      (6, INTER, true, true),
+      (7, INTER, true, false),
+      // ...and shown later, starting the next GOP.
+      (8, KEY, true, true),
+      (12, INTER, false, false),
+      (10, INTER, false, false),
+      (9, INTER, true, false),
+      (10, INTER, true, true),
+      (11, INTER, true, false),
+      (12, INTER, true, true),
+    ][..]
+  );
+}
+
+#[test]
+fn open_gop_intra_only_frames() {
+  let mut cfg = setup_config(
+    64,
+    80,
+    10,
+    100,
+    8,
+    ChromaSampling::Cs420,
+    0,
+    200,
+    0,
+    false,
+    0,
+    false,
+    10,
+    None,
+  );
+  cfg.enc.open_gop = true;
+  cfg.enc.forced_keyframes = Some(vec![11]);
+  let mut ctx: Context<u8> = cfg.new_context().unwrap();
+
+  let limit = 15;
+  send_frames(&mut ctx, limit, 5);
+  ctx.flush();
+
+  let gop_starts = get_frame_invariants(ctx)
+    .flatten()
+    .filter(|fi| fi.frame_type.all_intra())
+    .map(|fi| (fi.input_frameno, fi.frame_type, fi.refresh_frame_flags))
+    .collect::<Vec<_>>();
+  // The scene change within the maximum keyframe interval is coded as an
+  // intra-only frame, unlike the forced keyframe.
+  assert_eq!(
+    &gop_starts[..],
+    &[
+      (0, FrameType::KEY, 0xff),
+      (5, FrameType::INTRA_ONLY, 0x7f),
+      (11, FrameType::KEY, 0xff)
+    ][..]
+  );
+}
+
+#[test]
+fn invalid_open_gop() {
+  let enc =
+    EncoderConfig { open_gop: true, low_latency: true, ..Default::default() };
+  let config = Config::new().with_encoder_config(enc.clone());
+  assert_eq!(config.validate(), Err(InvalidConfig::InvalidOpenGop));
+
+  let enc = EncoderConfig { low_latency: false, ..enc };
+  let config = Config::new().with_encoder_config(enc);
+  assert_eq!(config.validate(), Ok(()));
+}
+
+// Send frames of noise over a pattern moving by a few pixels each frame.
+fn send_moving_frames<T: Pixel>(ctx: &mut Context<T>, limit: u64) {
+  let mut seed = 1u32;
+  for i in 0..limit as usize {
+    let mut input = ctx.new_frame();
+    for plane in input.planes.iter_mut() {
+      let stride = plane.cfg.stride;
+      for (y, row) in plane.data.chunks_mut(stride).enumerate() {
+        for (x, pixel) in row.iter_mut().enumerate() {
+          seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+          let v = ((x + 3 * i) / 4 + y / 4) % 2 * 128 + (seed >> 27) as usize;
+          *pixel = T::cast_from(v as u8);
+        }
+      }
+    }
+    let _ = ctx.send_frame(Arc::new(input));
+  }
+}
+
+// Run a rate control pass over `limit` frames, feeding it the frame data
+// of the previous pass if any, and return the pass data it emits.
+fn run_rate_control_pass(
+  cfg: &Config, rc: RateControlConfig, limit: u64, pass_data: &[Box<[u8]>],
+) -> (Vec<Box<[u8]>>, Box<[u8]>) {
+  let mut ctx: Context<u8> = cfg
+    .clone()
+    .with_rate_control(rc.with_emit_data(true))
+    .new_context()
+    .unwrap();
+  send_moving_frames(&mut ctx, limit);
+  ctx.flush();
+
+  let second_pass = !pass_data.is_empty();
+  let mut pass_data = pass_data.iter();
+  let mut frame_data = Vec::new();
+  loop {
+    while second_pass && ctx.rc_second_pass_data_required() > 0 {
+      ctx.rc_send_pass_data(pass_data.next().unwrap()).unwrap();
+    }
+    let status = ctx.receive_packet();
+    match ctx.rc_receive_pass_data() {
+      Some(RcData::Frame(data)) => frame_data.push(data),
+      Some(RcData::Summary(data)) => return (frame_data, data),
+      None => {}
+    }
+    match status {
+      Ok(_) | Err(EncoderStatus::Encoded) => {}
+      Err(e) => panic!("Unexpected status {e:?}"),
+    }
+  }
+}
+
+#[test]
+fn n_pass_summaries() {
+  let cfg = setup_config(
+    64,
+    80,
+    10,
+    255,
+    8,
+    ChromaSampling::Cs420,
+    0,
+    200,
+    100_000,
+    true,
+    0,
+    true,
+    1,
+    None,
+  );
+  let limit = 10;
+
+  let (pass1_data, pass1_summary) =
+    run_rate_control_pass(&cfg, RateControlConfig::new(), limit, &[]);
+  assert!(pass1_data
+    .iter()
+    .all(|data| data.len() == crate::rate::TWOPASS_PACKET_SZ));
+  let s1 = RateControlSummary::from_slice(&pass1_summary).unwrap();
+  assert_eq!(s1.pass, 1);
+  // The first pass uses a fixed quantizer, it has no bit target.
+  assert_eq!(s1.target_bits, 0);
+  assert!(s1.bits[crate::rate::FRAME_SUBTYPE_I] > 0);
+
+  let (pass2_data, pass2_summary) = run_rate_control_pass(
+    &cfg,
+    RateControlConfig::from_summary_slice(&pass1_summary).unwrap(),
+    limit,
+    &pass1_data,
+  );
+  assert_eq!(pass2_data.len(), pass1_data.len());
+  let s2 = RateControlSummary::from_slice(&pass2_summary).unwrap();
+  assert_eq!(s2.pass, 2);
+  assert_eq!(s2.ntus, s1.ntus);
+  assert!(s2.target_bits > 0);
+
+  let (_, pass3_summary) = run_rate_control_pass(
+    &cfg,
+    RateControlConfig::from_summary_slice(&pass2_summary).unwrap(),
+    limit,
+    &pass2_data,
+  );
+  let s3 = RateControlSummary::from_slice(&pass3_summary).unwrap();
+  assert_eq!(s3.pass, 3);
+  // The quantizers are refined rather than the target.
+  assert_eq!(s3.target_bits, s2.target_bits);
+}
+
+#[test]
+fn pass_data_frame_statistics() {
+  let cfg = setup_config(
+    64,
+    80,
+    10,
+    255,
+    8,
+    ChromaSampling::Cs420,
+    0,
+    200,
+    100_000,
+    true,
+    0,
+    true,
+    1,
+    None,
+  );
+  let limit = 10;
+
+  let (pass1_data, pass1_summary) =
+    run_rate_control_pass(&cfg, RateControlConfig::new(), limit, &[]);
+  let (pass2_data, _) = run_rate_control_pass(
+    &cfg,
+    RateControlConfig::from_summary_slice(&pass1_summary).unwrap(),
+    limit,
+    &pass1_data,
+  );
+
+  // The statistics are read back from the frame data of every pass.
+  for data in [pass1_data, pass2_data] {
+    let metrics = data
+      .iter()
+      .map(|data| {
+        let mut des = crate::rate::RCDeserialize::default();
+        des.buffer_fill(data, 0, crate::rate::TWOPASS_PACKET_SZ);
+        des.parse_metrics().unwrap()
+      })
+      .collect::<Vec<_>>();
+    assert!(metrics.iter().all(|m| m.stats.intra_cost > 0));
+    // The keyframe has no motion, and its inter cost is its intra cost.
+    let key = metrics[0].stats;
+    assert_eq!((key.inter_cost, key.motion), (key.intra_cost, 0));
+    assert!(metrics[1..]
+      .iter()
+      .any(|m| m.stats.inter_cost != m.stats.intra_cost));
+  }
+}
+
+#[test]
+fn n_pass_size_converges() {
+  let cfg = setup_config(
+    64,
+    80,
+    10,
+    255,
+    8,
+    ChromaSampling::Cs420,
+    0,
+    200,
+    200_000,
+    true,
+    0,
+    true,
+    1,
+    None,
+  );
+  let limit = 30;
+
+  let (mut pass_data, mut summary) =
+    run_rate_control_pass(&cfg, RateControlConfig::new(), limit, &[]);
+  // The relative size errors of passes 2 to 5.
+  let mut errors = Vec::new();
+  for _ in 0..4 {
+    (pass_data, summary) = run_rate_control_pass(
+      &cfg,
+      RateControlConfig::from_summary_slice(&summary).unwrap(),
+      limit,
+      &pass_data,
+    );
+    let s = RateControlSummary::from_slice(&summary).unwrap();
+    let bits = s.bits.iter().sum::<i64>();
+    errors.push((bits - s.target_bits).abs() as f64 / s.target_bits as f64);
+  }
+  // Each pass refined from the previous one gets closer to the target.
+  assert!(errors.windows(2).all(|e| e[1] < e[0]), "{errors:?}");
+  assert!(errors[1] < 0.01, "{errors:?}");
+}
+
+#[test]
+fn drop_frames_under_rate_pressure() {
+  let mut cfg = setup_config(
+    64,
+    80,
+    10,
+    100,
+    8,
+    ChromaSampling::Cs420,
+    0,
+    200,
+    1000,
+    true,
+    0,
+    true,
+    1,
+    None,
+  );
+  cfg.enc.drop_frames = true;
+  let mut ctx: Context<u8> = cfg.new_context().unwrap();
+
+  let limit = 20;
+  for i in 0..limit {
+    send_test_frame(&mut ctx, (i * 97 % 256) as u8);
+  }
+  ctx.flush();
+
+  let mut packets = Vec::new();
+  loop {
+    match ctx.receive_packet() {
+      Ok(pkt) => packets.push(pkt),
+      Err(EncoderStatus::Encoded) => {}
+      Err(EncoderStatus::LimitReached) => break,
+      Err(e) => panic!("Unexpected status {e:?}"),
+    }
+  }
+  assert_eq!(packets.len(), limit as usize);
+  // The keyframe cannot be replaced.
+  assert!(!packets[0].dropped);
+  assert!(packets.iter().any(|pkt| pkt.dropped));
+  for pkt in packets.iter().filter(|pkt| pkt.dropped) {
+    assert_eq!(pkt.frame_type, FrameType::INTER);
+    // A Show Existing Frame header only.
+    assert!(pkt.data.len() < 8, "{} bytes", pkt.data.len());
+  }
+}
+
+#[test]
+fn reconfigure_frame_dropping() {
+  let cfg = setup_config(
+    64,
+    80,
+    10,
+    100,
+    8,
+    ChromaSampling::Cs420,
+    0,
+    200,
+    1000,
+    true,
+    0,
+    true,
+    20,
+    None,
+  );
+  let mut enc = cfg.enc.clone();
+  let mut ctx: Context<u8> = cfg.new_context().unwrap();
+
+  let limit = 20;
+  for i in 0..limit {
+    send_test_frame(&mut ctx, (i * 97 % 256) as u8);
+  }
+  ctx.flush();
+
+  let mut packets = Vec::new();
+  loop {
+    match ctx.receive_packet() {
+      Ok(pkt) => {
+        // The following frames are already in the lookahead.
+        if packets.is_empty() {
+          enc.drop_frames = true;
+          ctx.reconfigure(enc.clone()).unwrap();
+        }
+        packets.push(pkt);
+      }
+      Err(EncoderStatus::Encoded) => {}
+      Err(EncoderStatus::LimitReached) => break,
+      Err(e) => panic!("Unexpected status {e:?}"),
+    }
+  }
+  assert_eq!(packets.len(), limit as usize);
+  assert!(packets.iter().any(|pkt| pkt.dropped));
+}
+
+#[test]
+fn invalid_frame_dropping() {
+  let enc = EncoderConfig { drop_frames: true, ..Default::default() };
+  let config = Config::new().with_encoder_config(enc.clone());
+  assert_eq!(config.validate(), Err(InvalidConfig::InvalidFrameDropping));
+
+  let enc = EncoderConfig { low_latency: true, bitrate: 1000, ..enc };
+  let config = Config::new().with_encoder_config(enc.clone());
+  assert_eq!(config.validate(), Ok(()));
+
+  let config = Config::new()
+    .with_encoder_config(enc)
+    .with_rate_control(RateControlConfig::new().with_emit_data(true));
+  assert_eq!(config.validate(), Err(InvalidConfig::InvalidFrameDropping));
+}
+
+#[test]
+fn reconfigure_bitrate() {
+  let cfg = setup_config(
+    64,
+    80,
+    10,
+    255,
+    8,
+    ChromaSampling::Cs420,
+    0,
+    200,
+    1_000_000,
+    true,
+    0,
+    true,
+    1,
+    None,
+  );
+  let mut enc = cfg.enc.clone();
+  let mut ctx: Context<u8> = cfg.new_context().unwrap();
+
+  let limit = 40;
+  let mut qps = Vec::new();
+  for i in 0..limit {
+    if i == limit / 2 {
+      enc.bitrate = 50_000;
+      ctx.reconfigure(enc.clone()).unwrap();
+    }
+    let mut input = ctx.new_frame();
+    for plane in input.planes.iter_mut() {
+      let stride = plane.cfg.stride;
+      for (y, row) in plane.data.chunks_mut(stride).enumerate() {
+        for (x, pixel) in row.iter_mut().enumerate() {
+          *pixel = ((x * y + x * i) % 256) as u8;
+        }
+      }
+    }
+    ctx.send_frame(input).unwrap();
+    while let Ok(pkt) = ctx.receive_packet() {
+      qps.push(pkt.qp as u32);
+    }
+  }
+  ctx.flush();
+  while let Ok(pkt) = ctx.receive_packet() {
+    qps.push(pkt.qp as u32);
+  }
+  assert_eq!(qps.len(), limit);
+
+  let (before, after) = qps.split_at(qps.len() / 2);
+  let mean = |qps: &[u32]| qps.iter().sum::<u32>() / qps.len() as u32;
+  assert!(mean(before) < mean(after), "{qps:?}");
+}
+
+fn send_moving_frame(ctx: &mut Context<u8>, i: usize) {
+  let mut input = ctx.new_frame();
+  for plane in input.planes.iter_mut() {
+    let stride = plane.cfg.stride;
+    for (y, row) in plane.data.chunks_mut(stride).enumerate() {
+      for (x, pixel) in row.iter_mut().enumerate() {
+        // A horizontal pan, then a vertical one after a scene cut.
+        *pixel =
+          if i < 20 { (x + i) * 4 + y } else { (y + i) * 3 + x * 2 + 128 }
+            as u8;
+      }
+    }
+  }
+  ctx.send_frame(input).unwrap();
+}
+
+/// Encodes the frames from `next` to `limit`, returning the input frame
+/// number and data of the packets along with the checkpoints.
+fn encode_with_checkpoints(
+  ctx: &mut Context<u8>, mut next: usize, limit: usize,
+) -> (Vec<(u64, Vec<u8>)>, Vec<Checkpoint>) {
+  let mut packets = Vec::new();
+  let mut checkpoints = Vec::new();
+  loop {
+    checkpoints.extend(ctx.checkpoint());
+    match ctx.receive_packet() {
+      Ok(pkt) => packets.push((pkt.input_frameno, pkt.data)),
+      Err(EncoderStatus::NeedMoreData) if next < limit => {
+        send_moving_frame(ctx, next);
+        next += 1;
+      }
+      Err(EncoderStatus::NeedMoreData) => ctx.flush(),
+      Err(EncoderStatus::Encoded) => {}
+      Err(EncoderStatus::LimitReached) => break,
+      Err(e) => panic!("Unexpected status {e:?}"),
+    }
+  }
+  (packets, checkpoints)
+}
+
+#[test]
+fn resume_from_checkpoint() {
+  let cfg = setup_config(
+    64,
+    80,
+    6,
+    255,
+    8,
+    ChromaSampling::Cs420,
+    0,
+    12,
+    200_000,
+    false,
+    0,
+    false,
+    5,
+    None,
+  );
+  let limit = 40;
+  let mut ctx: Context<u8> = cfg.new_context().unwrap();
+  let (packets, checkpoints) = encode_with_checkpoints(&mut ctx, 0, limit);
+  let keyframes =
+    checkpoints.iter().map(Checkpoint::input_frameno).collect::<Vec<_>>();
+  assert_eq!(keyframes, [0, 12, 20, 32]);
+
+  for checkpoint in &checkpoints[1..] {
+    let mut ctx: Context<u8> =
+      cfg.new_context_from_checkpoint(checkpoint).unwrap();
+    for i in checkpoint.input_frameno()..checkpoint.frame_count() {
+      send_moving_frame(&mut ctx, i as usize);
+    }
+    if checkpoint.is_flushed() {
+      ctx.flush();
+    }
+    let (resumed, _) = encode_with_checkpoints(
+      &mut ctx,
+      checkpoint.frame_count() as usize,
+      limit,
+    );
+    let start = packets
+      .iter()
+      .position(|&(input_frameno, _)| {
+        input_frameno == checkpoint.input_frameno()
+      })
+      .unwrap();
+    assert_eq!(resumed, packets[start..]);
+  }
+}
+
+#[test]
+fn invalid_reconfiguration() {
+  let enc = EncoderConfig {
+    low_latency: true,
+    bitrate: 100_000,
+    ..Default::default()
+  };
+  let mut ctx: Context<u8> =
+    Config::new().with_encoder_config(enc.clone()).new_context().unwrap();
+
+  assert_eq!(
+    ctx.reconfigure(EncoderConfig { height: 32, ..enc.clone() }),
+    Err(InvalidConfig::InvalidReconfiguration("height"))
+  );
+  assert_eq!(
+    ctx.reconfigure(EncoderConfig { bit_depth: 10, ..enc.clone() }),
+    Err(InvalidConfig::InvalidReconfiguration("bit_depth"))
+  );
+  assert_eq!(
+    ctx.reconfigure(EncoderConfig {
+      reservoir_frame_delay: Some(4),
+      ..enc.clone()
+    }),
+    Err(InvalidConfig::InvalidReservoirFrameDelay(4))
+  );
+  assert_eq!(
+    ctx.reconfigure(EncoderConfig { open_gop: true, ..enc.clone() }),
+    Err(InvalidConfig::InvalidReconfiguration("open_gop"))
+  );
+  assert_eq!(
+    ctx.reconfigure(EncoderConfig {
+      min_key_frame_interval: 30,
+      max_key_frame_interval: 20,
+      ..enc.clone()
+    }),
+    Err(InvalidConfig::InvalidKeyFrameInterval { min: 30, max: 20 })
+  );
+  assert_eq!(
+    ctx.reconfigure(EncoderConfig {
+      min_key_frame_interval: 30,
+      max_key_frame_interval: 0,
+      ..enc.clone()
+    }),
+    Ok(())
+  );
+  assert_eq!(
+    ctx.reconfigure(EncoderConfig { drop_frames: true, bitrate: 0, ..enc }),
+    Err(InvalidConfig::InvalidFrameDropping)
+  );
+
+  let enc = EncoderConfig { bitrate: 100_000, ..Default::default() };
+  let mut ctx: Context<u8> = Config::new()
+    .with_encoder_config(enc.clone())
+    .with_rate_control(RateControlConfig::new().with_emit_data(true))
+    .new_context()
+    .unwrap();
+  assert_eq!(
+    ctx.reconfigure(EncoderConfig { bitrate: 50_000, ..enc.clone() }),
+    Err(InvalidConfig::RateControlConfigurationMismatch)
+  );
+  assert_eq!(
+    ctx.reconfigure(EncoderConfig { max_key_frame_interval: 30, ..enc }),
+    Ok(())
+  );
+}
+
+fn send_noisy_frame(ctx: &mut Context<u8>, seed: &mut u32) {
+  let mut input = ctx.new_frame();
+  for plane in input.planes.iter_mut() {
+    let stride = plane.cfg.stride;
+    for row in plane.data.chunks_mut(stride) {
+      for pixel in row {
+        *seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+        *pixel = 120 + (*seed >> 28) as u8;
+      }
+    }
+  }
+  ctx.send_frame(input).unwrap();
+}
+
+#[test]
+fn denoise_estimates_film_grain() {
+  let mut cfg = setup_config(
+    64,
+    80,
+    10,
+    100,
+    8,
+    ChromaSampling::Cs420,
+    0,
+    5,
+    0,
+    false,
+    0,
+    true,
+    10,
+    None,
+  );
+  cfg.enc.denoise_noise_level = 4;
+  let mut ctx: Context<u8> = cfg.new_context().unwrap();
+
+  let limit = 10;
+  let mut seed = 1u32;
+  for _ in 0..limit {
+    send_noisy_frame(&mut ctx, &mut seed);
+  }
+  ctx.flush();
+
+  // Each GOP gets its own grain table segment.
+  let time_base = ctx.inner.config.time_base;
+  let timestamp = |input_frameno: u64| {
+    input_frameno * 10_000_000 * time_base.num / time_base.den
+  };
+  let segments = ctx.inner.config.film_grain_params.clone().unwrap();
+  let times = segments
+    .iter()
+    .map(|segment| (segment.start_time, segment.end_time))
+    .collect::<Vec<_>>();
+  assert_eq!(&times[..], &[(0, timestamp(5)), (timestamp(5), u64::MAX)][..]);
+
+  for fi in get_frame_invariants(ctx).flatten() {
+    assert!(fi.sequence.film_grain_params_present);
+    assert_eq!(
+      fi.film_grain_params().is_some(),
+      fi.show_frame || fi.showable_frame
+    );
+  }
+}
+
+#[test]
+fn denoise_temporal_without_grain_synthesis() {
+  let mut cfg = setup_config(
+    64,
+    80,
+    10,
+    100,
+    8,
+    ChromaSampling::Cs420,
+    0,
+    5,
+    0,
+    false,
+    0,
+    true,
+    10,
+    None,
+  );
+  let photon_noise = generate_photon_noise_params(
+    0,
+    u64::MAX,
+    NoiseGenArgs {
+      iso_setting: 800,
+      width: 64,
+      height: 80,
+      transfer_function: TransferFunction::BT1886,
+      chroma_grain: false,
+      random_seed: None,
+    },
+  );
+  cfg.enc.denoise_noise_level = 4;
+  cfg.enc.denoise_temporal_radius = 2;
+  cfg.enc.denoise_grain_synthesis = false;
+  cfg.enc.film_grain_params = Some(vec![photon_noise.clone()]);
+  let mut ctx: Context<u8> = cfg.new_context().unwrap();
+
+  // Frames are only queued once the two following ones are received.
+  let mut seed = 1u32;
+  for _ in 0..3 {
+    send_noisy_frame(&mut ctx, &mut seed);
+  }
+  assert_eq!(ctx.inner.frame_q.keys().copied().collect::<Vec<_>>(), [0]);
+
+  let limit = 10;
+  for _ in 3..limit {
+    send_noisy_frame(&mut ctx, &mut seed);
+  }
+  ctx.flush();
+
+  let mut shown_frames = 0;
+  loop {
+    match ctx.receive_packet() {
+      Ok(packet) => {
+        assert_eq!(packet.input_frameno, shown_frames);
+        shown_frames += 1;
+      }
+      Err(EncoderStatus::Encoded) => {}
+      Err(EncoderStatus::LimitReached) => break,
+      Err(e) => panic!("unexpected status {e:?}"),
+    }
+  }
+  assert_eq!(shown_frames, limit);
+
+  // The given grain parameters are kept as they are.
+  assert_eq!(ctx.inner.config.film_grain_params, Some(vec![photon_noise]));
+}
+
+#[test]
+fn invalid_denoise_noise_level() {
+  let enc = EncoderConfig { denoise_noise_level: 51, ..Default::default() };
+  let config = Config::new().with_encoder_config(enc.clone());
+  assert_eq!(
+    config.validate(),
+    Err(InvalidConfig::InvalidDenoiseNoiseLevel { actual: 51, max: 50 })
+  );
+
+  let enc = EncoderConfig {
+    denoise_noise_level: 10,
+    film_grain_params: Some(vec![generate_photon_noise_params(
+      0,
+      u64::MAX,
+      NoiseGenArgs {
+        iso_setting: 800,
+        width: 640,
+        height: 480,
+        transfer_function: TransferFunction::BT1886,
+        chroma_grain: false,
+        random_seed: None,
+      },
+    )]),
+    ..enc
+  };
+  let config = Config::new().with_encoder_config(enc.clone());
+  assert_eq!(
+    config.validate(),
+    Err(InvalidConfig::InvalidDenoiseWithFilmGrain)
+  );
+
+  let enc = EncoderConfig { denoise_grain_synthesis: false, ..enc };
+  let config = Config::new().with_encoder_config(enc.clone());
+  assert_eq!(config.validate(), Ok(()));
+
+  let enc = EncoderConfig { denoise_temporal_radius: 4, ..enc };
+  let config = Config::new().with_encoder_config(enc);
+  assert_eq!(
+    config.validate(),
+    Err(InvalidConfig::InvalidDenoiseTemporalRadius { actual: 4, max: 3 })
+  );
+}
+
+#[test]
+fn container_sequence_header_chroma_sampling() {
+  for (chroma_sampling, flags) in [
+    (ChromaSampling::Cs400, 0b111),
+    (ChromaSampling::Cs420, 0b011),
+    (ChromaSampling::Cs422, 0b010),
+    (ChromaSampling::Cs444, 0b000),
+  ] {
+    let config = Config::new().with_encoder_config(EncoderConfig {
+      chroma_sampling,
+      ..Default::default()
+    });
+    let ctx: Context<u8> = config.new_context().unwrap();
+    let av1c = ctx.container_sequence_header();
+    // monochrome, chroma_subsampling_x and chroma_subsampling_y
+    assert_eq!((av1c[2] >> 2) & 0b111, flags, "{chroma_sampling}");
+  }
+}
+
+#[test]
+fn container_sequence_header_level() {
+  let config = Config::new().with_encoder_config(EncoderConfig {
+    level_idx: Some(8),
+    chroma_sample_position: ChromaSamplePosition::Colocated,
+    ..Default::default()
+  });
+  let ctx: Context<u8> = config.new_context().unwrap();
+  let av1c = ctx.container_sequence_header();
+  assert_eq!(av1c[0], 0x81); // marker and version
+  assert_eq!(av1c[1] & 0x1f, 8); // seq_level_idx_0
+  assert_eq!(av1c[2] & 0b11, 2); // chroma_sample_position
+}
+
+#[test]
+fn monochrome_denoise_estimates_film_grain() {
+  let mut cfg = setup_config(
+    64,
+    80,
+    10,
+    100,
+    8,
+    ChromaSampling::Cs400,
+    0,
+    5,
+    0,
+    false,
+    0,
+    true,
+    10,
+    None,
+  );
+  cfg.enc.denoise_noise_level = 4;
+  let mut ctx: Context<u8> = cfg.new_context().unwrap();
+
+  let mut seed = 1u32;
+  for _ in 0..10 {
+    send_noisy_frame(&mut ctx, &mut seed);
+  }
+  ctx.flush();
+
+  let segments = ctx.inner.config.film_grain_params.clone().unwrap();
+  assert!(!segments.is_empty());
+  for segment in segments {
+    assert!(!segment.scaling_points_y.is_empty());
+    assert!(segment.scaling_points_cb.is_empty());
+    assert!(segment.scaling_points_cr.is_empty());
+    assert!(!segment.chroma_scaling_from_luma);
+  }
+}
+
+#[test]
+fn alpha_stream_follows_frame_structure() {
+  let mut cfg = setup_config(
+    64,
+    80,
+    10,
+    100,
+    8,
+    ChromaSampling::Cs420,
+    0,
+    5,
+    0,
+    false,
+    0,
+    true,
+    10,
+    None,
+  );
+  cfg.enc.alpha_quantizer = Some(50);
+  let mut ctx: Context<u8> = cfg.new_context().unwrap();
+
+  let limit = 10;
+  for i in 0..limit {
+    let mut input = ctx.new_frame();
+    fill_frame_const(&mut input, 128);
+    let mut alpha = ctx.new_alpha_plane();
+    alpha.data.fill(64);
+    // Frames sent without an alpha plane are fully opaque.
+    let params = FrameParameters {
+      alpha: (i % 3 != 2).then(|| AlphaPlane::new(alpha)),
+      ..Default::default()
+    };
+    ctx.send_frame((input, params)).unwrap();
+  }
+  ctx.flush();
+
+  let mut packets = 0;
+  loop {
+    match ctx.receive_packet() {
+      Ok(pkt) => {
+        let alpha_data = pkt.alpha_data.unwrap();
+        // Each packet is a temporal unit of its own in the alpha stream.
+        assert_eq!(alpha_data[..2], crate::encoder::TEMPORAL_DELIMITER);
+        let alpha_rec = pkt.alpha_rec.unwrap();
+        let expected = if pkt.input_frameno % 3 != 2 { 64 } else { 255 };
+        for row in alpha_rec.planes[0].rows_iter().take(80) {
+          assert!(row[..64].iter().all(|&v| v.abs_diff(expected) <= 2));
+        }
+        packets += 1;
+      }
+      Err(EncoderStatus::Encoded) => {}
+      Err(EncoderStatus::LimitReached) => break,
+      Err(e) => panic!("Unexpected status {e:?}"),
+    }
+  }
+  assert_eq!(packets, limit);
+}
+
+#[test]
+fn lossless_alpha_stream() {
+  let mut cfg = setup_config(
+    66,
+    50,
+    10,
+    100,
+    10,
+    ChromaSampling::Cs420,
+    0,
+    5,
+    0,
+    false,
+    0,
+    true,
+    10,
+    None,
+  );
+  cfg.enc.alpha_quantizer = Some(0);
+  let mut ctx: Context<u16> = cfg.new_context().unwrap();
+
+  let limit = 6;
+  let mut seed = 1u32;
+  let mut alphas = Vec::new();
+  for _ in 0..limit {
+    let mut input = ctx.new_frame();
+    fill_frame_const(&mut input, 512);
+    let mut alpha = ctx.new_alpha_plane();
+    for pixel in alpha.data.iter_mut() {
+      seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+      *pixel = (seed >> 22) as u16;
+    }
+    let params = FrameParameters {
+      alpha: Some(AlphaPlane::new(alpha.clone())),
+      ..Default::default()
+    };
+    alphas.push(alpha);
+    ctx.send_frame((input, params)).unwrap();
+  }
+  ctx.flush();
+
+  let mut packets = 0;
+  loop {
+    match ctx.receive_packet() {
+      Ok(pkt) => {
+        let alpha_rec = pkt.alpha_rec.unwrap();
+        let alpha = &alphas[pkt.input_frameno as usize];
+        for (rec, input) in
+          alpha_rec.planes[0].rows_iter().zip(alpha.rows_iter()).take(50)
+        {
+          assert_eq!(rec[..66], input[..66]);
+        }
+        packets += 1;
+      }
+      Err(EncoderStatus::Encoded) => {}
+      Err(EncoderStatus::LimitReached) => break,
+      Err(e) => panic!("Unexpected status {e:?}"),
+    }
+  }
+  assert_eq!(packets, limit);
+}
+
+#[test]
+fn invalid_alpha_quantizer() {
+  for (quantizer, valid) in [(0, true), (255, true), (256, false)] {
+    let config = Config::new().with_encoder_config(EncoderConfig {
+      alpha_quantizer: Some(quantizer),
+      ..Default::default()
+    });
+    let expected = if valid {
+      Ok(())
+    } else {
+      Err(InvalidConfig::InvalidAlphaQuantizer(quantizer))
+    };
+    assert_eq!(config.validate(), expected);
+  }
+}
+
+fn test_hdr10plus() -> Hdr10Plus {
+  Hdr10Plus {
+    application_version: 1,
+    windows: vec![
+      Hdr10PlusWindow {
+        maxscl: [100_000, 50_000, 1],
+        average_maxrgb: 20_000,
+        distribution_maxrgb: vec![
+          Hdr10PlusPercentile { percentage: 1, percentile: 10 },
+          Hdr10PlusPercentile { percentage: 99, percentile: 99_000 },
+        ],
+        fraction_bright_pixels: 1023,
+        tone_mapping: Some(Hdr10PlusToneMapping {
+          knee_point_x: 4095,
+          knee_point_y: 17,
+          bezier_curve_anchors: vec![0, 500, 1023],
+        }),
+        color_saturation_weight: Some(63),
+        ..Default::default()
+      },
+      Hdr10PlusWindow {
+        geometry: Some(Hdr10PlusWindowGeometry {
+          upper_left_corner_x: 1,
+          upper_left_corner_y: 2,
+          lower_right_corner_x: 1919,
+          lower_right_corner_y: 1079,
+          center_of_ellipse_x: 960,
+          center_of_ellipse_y: 540,
+          rotation_angle: 180,
+          semimajor_axis_internal_ellipse: 100,
+          semimajor_axis_external_ellipse: 200,
+          semiminor_axis_external_ellipse: 150,
+          overlap_process_option: true,
+        }),
+        maxscl: [3, 2, 1],
+        ..Default::default()
+      },
+    ],
+    targeted_system_display_maximum_luminance: 10000,
+    targeted_system_display_actual_peak_luminance: Some(vec![
+      vec![1, 2, 3],
+      vec![15, 14, 13],
+    ]),
+    mastering_display_actual_peak_luminance: Some(vec![vec![7; 25]; 25]),
+  }
+}
+
+#[test]
+fn hdr10plus_t35_roundtrip() {
+  let hdr10plus = test_hdr10plus();
+  let t35 = hdr10plus.to_t35().unwrap();
+  assert_eq!(t35.country_code, 0xB5);
+  // Provider code, provider oriented code, application identifier and
+  // version.
+  assert_eq!(t35.data[..6], [0x00, 0x3C, 0x00, 0x01, 0x04, 0x01]);
+  assert_eq!(Hdr10Plus::from_t35(&t35), Some(hdr10plus));
+  assert_eq!(DolbyVisionRpu::from_t35(&t35), None);
+
+  let minimal = Hdr10Plus {
+    windows: vec![Hdr10PlusWindow::default()],
+    ..Default::default()
+  };
+  let t35 = minimal.to_t35().unwrap();
+  assert_eq!(Hdr10Plus::from_t35(&t35), Some(minimal));
+}
+
+#[test]
+fn invalid_hdr10plus() {
+  let mut invalid = Vec::new();
+  let mut hdr10plus = test_hdr10plus();
+  hdr10plus.windows.clear();
+  invalid.push(hdr10plus);
+  let mut hdr10plus = test_hdr10plus();
+  hdr10plus.windows[0].geometry = hdr10plus.windows[1].geometry;
+  invalid.push(hdr10plus);
+  let mut hdr10plus = test_hdr10plus();
+  hdr10plus.windows[1].geometry = None;
+  invalid.push(hdr10plus);
+  let mut hdr10plus = test_hdr10plus();
+  hdr10plus.windows[0].maxscl[0] = 1 << 17;
+  invalid.push(hdr10plus);
+  let mut hdr10plus = test_hdr10plus();
+  hdr10plus.windows[1].distribution_maxrgb =
+    vec![Hdr10PlusPercentile::default(); 16];
+  invalid.push(hdr10plus);
+  let mut hdr10plus = test_hdr10plus();
+  hdr10plus.targeted_system_display_actual_peak_luminance =
+    Some(vec![vec![1, 2], vec![3]]);
+  invalid.push(hdr10plus);
+  let mut hdr10plus = test_hdr10plus();
+  hdr10plus.mastering_display_actual_peak_luminance = Some(vec![vec![16]]);
+  invalid.push(hdr10plus);
+
+  for hdr10plus in invalid {
+    assert!(hdr10plus.to_t35().is_err(), "{hdr10plus:?}");
+  }
+}
+
+#[test]
+fn dolby_vision_rpu_t35_roundtrip() {
+  for size in [1, 25, 255, 256, 300, 70000] {
+    let rpu =
+      DolbyVisionRpu { data: (0..size).map(|i| (i * 7) as u8).collect() };
+    let t35 = rpu.to_t35().unwrap();
+    assert_eq!(t35.country_code, 0xB5);
+    assert_eq!(t35.data[..6], [0x00, 0x3B, 0x00, 0x00, 0x08, 0x00]);
+    assert_eq!(DolbyVisionRpu::from_t35(&t35), Some(rpu));
+    assert_eq!(Hdr10Plus::from_t35(&t35), None);
+  }
+
+  assert!(DolbyVisionRpu::default().to_t35().is_err());
+}
+
+/// Returns the payloads of the metadata OBUs of type `metadata_type` in a
+/// packet, including their trailing bits.
+fn metadata_obus(mut data: &[u8], metadata_type: usize) -> Vec<&[u8]> {
+  fn leb128(data: &mut &[u8]) -> usize {
+    let mut value = 0;
+    for i in 0.. {
+      let byte = data[0];
+      *data = &data[1..];
+      value |= ((byte & 0x7f) as usize) << (i * 7);
+      if byte & 0x80 == 0 {
+        break;
+      }
+    }
+    value
+  }
+
+  let mut metadata = Vec::new();
+  while !data.is_empty() {
+    let header = data[0];
+    data = &data[1 + ((header >> 2) & 1) as usize..];
+    assert!(header & 0x02 != 0, "OBU without a size");
+    let size = leb128(&mut data);
+    let (mut payload, rest) = data.split_at(size);
+    data = rest;
+    // OBU_METADATA
+    if (header >> 3) & 0xf == 5 && leb128(&mut payload) == metadata_type {
+      metadata.push(payload);
+    }
+  }
+  metadata
+}
+
+/// Returns the ITU-T T.35 payloads of the metadata OBUs of a packet.
+fn t35_metadata_obus(data: &[u8]) -> Vec<T35> {
+  metadata_obus(data, 4)
+    .into_iter()
+    .map(|payload| {
+      let country_code = payload[0];
+      let start = if country_code == 0xFF { 2 } else { 1 };
+      let (&trailing_bits, payload) = payload.split_last().unwrap();
+      assert_eq!(trailing_bits, 0x80);
+      T35 {
+        country_code,
+        country_code_extension_byte: if start == 2 { payload[1] } else { 0 },
+        data: payload[start..].into(),
+      }
+    })
+    .collect()
+}
+
+#[interpolate_test(low_latency, true)]
+#[interpolate_test(reorder, false)]
+fn dynamic_metadata_in_shown_frame_packet(low_latency: bool) {
+  let mut ctx = setup_encoder::<u8>(
+    64,
+    80,
+    10,
+    100,
+    8,
+    ChromaSampling::Cs420,
+    0,
+    5,
+    0,
+    low_latency,
+    0,
+    true,
+    10,
+    None,
+  );
+
+  let limit = 10;
+  for i in 0..limit {
+    let hdr10plus = Hdr10Plus {
+      windows: vec![Hdr10PlusWindow::default()],
+      targeted_system_display_maximum_luminance: 100 + i as u32,
+      ..Default::default()
+    };
+    let params = FrameParameters {
+      hdr10plus: Some(Box::new(hdr10plus)),
+      dolby_vision_rpu: (i % 2 == 0)
+        .then(|| DolbyVisionRpu { data: Box::new([i as u8; 3]) }),
+      t35_metadata: Box::new([T35 {
+        country_code: 0xFF,
+        country_code_extension_byte: 0x01,
+        data: Box::new([i as u8]),
+      }]),
+      ..Default::default()
+    };
+    ctx.send_frame((ctx.new_frame(), params)).unwrap();
+  }
+  ctx.flush();
+
+  let mut packets = 0;
+  loop {
+    match ctx.receive_packet() {
+      Ok(pkt) => {
+        // Each packet carries the metadata of the frame it shows, and only
+        // it, with the typed metadata first.
+        let i = pkt.input_frameno;
+        let metadata = t35_metadata_obus(&pkt.data);
+        let hdr10plus = Hdr10Plus::from_t35(&metadata[0]).unwrap();
+        assert_eq!(
+          hdr10plus.targeted_system_display_maximum_luminance,
+          100 + i as u32
+        );
+        let mut rest = &metadata[1..];
+        if i % 2 == 0 {
+          let rpu = DolbyVisionRpu::from_t35(&rest[0]).unwrap();
+          assert_eq!(rpu.data[..], [i as u8; 3]);
+          rest = &rest[1..];
+        }
+        assert_eq!(rest.len(), 1);
+        assert_eq!(rest[0].country_code_extension_byte, 0x01);
+        assert_eq!(rest[0].data[..], [i as u8]);
+        packets += 1;
+      }
+      Err(EncoderStatus::Encoded) => {}
+      Err(EncoderStatus::LimitReached) => break,
+      Err(e) => panic!("Unexpected status {e:?}"),
+    }
+  }
+  assert_eq!(packets, limit);
+}
+
+#[test]
+fn send_frame_invalid_dynamic_metadata() {
+  let mut ctx = setup_encoder::<u8>(
+    64,
+    80,
+    10,
+    100,
+    8,
+    ChromaSampling::Cs420,
+    0,
+    5,
+    0,
+    false,
+    0,
+    true,
+    10,
+    None,
+  );
+
+  let params =
+    FrameParameters { hdr10plus: Some(Box::default()), ..Default::default() };
+  assert_eq!(
+    ctx.send_frame((ctx.new_frame(), params)),
+    Err(EncoderStatus::Failure)
+  );
+  // The frame is not queued.
+  ctx.flush();
+  assert_eq!(ctx.receive_packet(), Err(EncoderStatus::LimitReached));
+}
+
+#[test]
+fn timecode_add_frames() {
+  let timecode = Timecode {
+    full_timestamp: true,
+    hours: 23,
+    minutes: 59,
+    seconds: 59,
+    frames: 24,
+    ..Default::default()
+  };
+  let next = timecode.add_frames(1, 25);
+  assert_eq!(
+    (next.hours, next.minutes, next.seconds, next.frames),
+    (0, 0, 0, 0)
+  );
+  let next = timecode.add_frames(25 * 3600 + 2, 25);
+  assert_eq!(
+    (next.hours, next.minutes, next.seconds, next.frames),
+    (1, 0, 0, 1)
+  );
+
+  // Frame numbers 0 and 1 are dropped at the start of every minute, except
+  // the tens of minutes.
+  let start = Timecode {
+    counting_type: TIMECODE_COUNTING_DROP_FRAME,
+    full_timestamp: true,
+    ..Default::default()
+  };
+  let mut timecode = start;
+  let mut dropped = 0;
+  for count in 1..=2 * 17982 {
+    let next = timecode.add_frames(1, 30);
+    assert_eq!(next, start.add_frames(count, 30));
+    if next.drop_frame {
+      assert_eq!((next.seconds, next.frames), (0, 2));
+      assert_ne!(next.minutes % 10, 0);
+      dropped += 1;
+    } else {
+      assert!(next.frames >= 2 || next.seconds != 0 || next.minutes % 10 == 0);
+    }
+    timecode = next;
+  }
+  assert_eq!(dropped, 18);
+  assert_eq!(
+    (timecode.hours, timecode.minutes, timecode.seconds, timecode.frames),
+    (0, 20, 0, 0)
+  );
+}
+
+#[interpolate_test(low_latency, true)]
+#[interpolate_test(reorder, false)]
+fn timecode_in_shown_frame_packet(low_latency: bool) {
+  use bitstream_io::{BigEndian, BitRead, BitReader};
+
+  let mut ctx = setup_encoder::<u8>(
+    64,
+    80,
+    10,
+    100,
+    8,
+    ChromaSampling::Cs420,
+    0,
+    5,
+    0,
+    low_latency,
+    0,
+    true,
+    10,
+    None,
+  );
+
+  let start = Timecode {
+    full_timestamp: true,
+    hours: 1,
+    minutes: 2,
+    seconds: 3,
+    frames: 20,
+    ..Default::default()
+  };
+  let limit = 10;
+  for i in 0..limit {
+    let params = FrameParameters {
+      timecode: Some(Timecode {
+        // Frames without a timestamp only carry their frame number.
+        full_timestamp: i % 3 != 1,
+        time_offset: i as u32 * 100,
+        ..start.add_frames(i, 24)
+      }),
+      ..Default::default()
+    };
+    ctx.send_frame((ctx.new_frame(), params)).unwrap();
+  }
+  ctx.flush();
+
+  let mut packets = 0;
+  loop {
+    match ctx.receive_packet() {
+      Ok(pkt) => {
+        let i = pkt.input_frameno;
+        let expected = start.add_frames(i, 24);
+        let metadata = metadata_obus(&pkt.data, 5);
+        assert_eq!(metadata.len(), 1);
+        let mut br = BitReader::endian(metadata[0], BigEndian);
+        assert_eq!(br.read::<u8>(5).unwrap(), 0); // counting_type
+        let full_timestamp = br.read_bit().unwrap();
+        assert_eq!(full_timestamp, i % 3 != 1);
+        assert!(!br.read_bit().unwrap()); // discontinuity_flag
+        assert!(!br.read_bit().unwrap()); // cnt_dropped_flag
+        assert_eq!(br.read::<u16>(9).unwrap(), expected.frames);
+        if full_timestamp {
+          assert_eq!(br.read::<u8>(6).unwrap(), expected.seconds);
+          assert_eq!(br.read::<u8>(6).unwrap(), expected.minutes);
+          assert_eq!(br.read::<u8>(5).unwrap(), expected.hours);
+        } else {
+          assert!(!br.read_bit().unwrap()); // seconds_flag
+        }
+        let time_offset_length = br.read::<u32>(5).unwrap();
+        let time_offset = if time_offset_length > 0 {
+          br.read::<u32>(time_offset_length).unwrap()
+        } else {
+          0
+        };
+        assert_eq!(time_offset, i as u32 * 100);
+        // Trailing bits
+        assert!(br.read_bit().unwrap());
+        while !br.byte_aligned() {
+          assert!(!br.read_bit().unwrap());
+        }
+        assert!(br.read_bit().is_err());
+        packets += 1;
+      }
+      Err(EncoderStatus::Encoded) => {}
+      Err(EncoderStatus::LimitReached) => break,
+      Err(e) => panic!("Unexpected status {e:?}"),
+    }
+  }
+  assert_eq!(packets, limit);
+}
+
+#[test]
+fn send_frame_invalid_timecode() {
+  let mut ctx = setup_encoder::<u8>(
+    64,
+    80,
+    10,
+    100,
+    8,
+    ChromaSampling::Cs420,
+    0,
+    5,
+    0,
+    false,
+    0,
+    true,
+    10,
+    None,
+  );
+
+  let params = FrameParameters {
+    timecode: Some(Timecode { minutes: 60, ..Default::default() }),
+    ..Default::default()
+  };
+  assert_eq!(
+    ctx.send_frame((ctx.new_frame(), params)),
+    Err(EncoderStatus::Failure)
+  );
+}
+
+fn isobmff_boxes(mut data: &[u8]) -> Vec<([u8; 4], &[u8])> {
+  let mut boxes = Vec::new();
+  while !data.is_empty() {
+    let size = u32::from_be_bytes(data[..4].try_into().unwrap()) as usize;
+    boxes.push((data[4..8].try_into().unwrap(), &data[8..size]));
+    data = &data[size..];
+  }
+  boxes
+}
+
+/// Returns the type and data of each item of an AVIF image.
+fn avif_items(avif: &[u8]) -> Vec<([u8; 4], &[u8])> {
+  let boxes = isobmff_boxes(avif);
+  assert_eq!(
+    boxes.iter().map(|b| &b.0).collect::<Vec<_>>(),
+    [b"ftyp", b"meta", b"mdat"]
+  );
+  let meta = isobmff_boxes(&boxes[1].1[4..]);
+  let child =
+    |box_type: &[u8; 4]| meta.iter().find(|b| &b.0 == box_type).unwrap().1;
+  let item_types = isobmff_boxes(&child(b"iinf")[6..])
+    .into_iter()
+    .map(|infe| infe.1[8..12].try_into().unwrap());
+  let iloc = child(b"iloc");
+  let extents = iloc[8..].chunks(14).map(|entry| {
+    let offset = u32::from_be_bytes(entry[6..10].try_into().unwrap());
+    let length = u32::from_be_bytes(entry[10..14].try_into().unwrap());
+    &avif[offset as usize..(offset + length) as usize]
+  });
+  item_types.zip(extents).collect()
+}
+
+fn encode_still_picture(value: u8) -> (Context<u8>, Packet<u8>) {
+  let config = Config::new().with_encoder_config(EncoderConfig {
+    width: 64,
+    height: 48,
+    still_picture: true,
+    alpha_quantizer: Some(50),
+    speed_settings: SpeedSettings::from_preset(10),
+    ..Default::default()
+  });
+  let mut ctx: Context<u8> = config.new_context().unwrap();
+  let mut input = ctx.new_frame();
+  fill_frame_const(&mut input, value);
+  let mut alpha = ctx.new_alpha_plane();
+  alpha.data.fill(value);
+  let params = FrameParameters {
+    alpha: Some(AlphaPlane::new(alpha)),
+    ..Default::default()
+  };
+  ctx.send_frame((input, params)).unwrap();
+  ctx.flush();
+  let packet = ctx.receive_packet().unwrap();
+  (ctx, packet)
+}
+
+#[test]
+fn avif_image_with_alpha() {
+  let (ctx, packet) = encode_still_picture(128);
+  let packets = [packet];
+  let avif = ctx.avif_image(&packets, None).unwrap();
+  let [packet] = &packets;
+
+  // The items hold the temporal units without their temporal delimiters.
+  let items = avif_items(&avif);
+  assert_eq!(items.len(), 2);
+  assert_eq!(&items[0].0, b"av01");
+  assert_eq!(items[0].1, &packet.data[2..]);
+  assert_eq!(&items[1].0, b"av01");
+  assert_eq!(items[1].1, &packet.alpha_data.as_ref().unwrap()[2..]);
+  assert!(avif.windows(4).any(|w| w == b"auxl"));
+  assert!(avif.windows(7).any(|w| w == b"urn:mpe"));
+}
+
+#[test]
+fn avif_image_grid() {
+  let (ctx, left) = encode_still_picture(64);
+  let (_, right) = encode_still_picture(192);
+  let grid = ImageGrid { rows: 1, columns: 2, width: 100, height: 48 };
+  let packets = [left, right];
+  let avif = ctx.avif_image(&packets, Some(&grid)).unwrap();
+  let [left, right] = &packets;
+
+  let items = avif_items(&avif);
+  let item_types = items.iter().map(|item| &item.0).collect::<Vec<_>>();
+  assert_eq!(
+    item_types,
+    [b"grid", b"av01", b"av01", b"grid", b"av01", b"av01"]
+  );
+  // version, flags, rows_minus_one, columns_minus_one, output size
+  assert_eq!(items[0].1, [0, 0, 0, 1, 0, 100, 0, 48]);
+  assert_eq!(items[1].1, &left.data[2..]);
+  assert_eq!(items[2].1, &right.data[2..]);
+  assert_eq!(items[3].1, items[0].1);
+  assert_eq!(items[5].1, &right.alpha_data.as_ref().unwrap()[2..]);
+}
+
+#[test]
+fn invalid_avif_image_grid() {
+  let (ctx, packet) = encode_still_picture(128);
+  let packets = [packet];
+
+  let grid = ImageGrid { rows: 0, columns: 1, width: 64, height: 48 };
+  assert_eq!(
+    ctx.avif_image(&packets, Some(&grid)),
+    Err(AvifError::InvalidGridLayout { rows: 0, columns: 1 })
+  );
+  let grid = ImageGrid { rows: 1, columns: 2, width: 100, height: 48 };
+  assert_eq!(
+    ctx.avif_image(&packets, Some(&grid)),
+    Err(AvifError::InvalidTileCount { actual: 1, expected: 2 })
+  );
+  let grid = ImageGrid { rows: 1, columns: 1, width: 65, height: 48 };
+  assert!(matches!(
+    ctx.avif_image(&packets, Some(&grid)),
+    Err(AvifError::InvalidGridSize { width: 65, .. })
+  ));
+
+  let [mut packet] = packets;
+  packet.alpha_data = None;
+  assert_eq!(ctx.avif_image(&[packet], None), Err(AvifError::AlphaMismatch));
+}
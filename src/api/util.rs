@@ -137,6 +137,52 @@ impl fmt::Display for FrameType {
   }
 }
 
+/// The kind of content transition detected at a frame by the lookahead.
+#[derive(
+  Debug, Default, Eq, PartialEq, Clone, Copy, Serialize, Deserialize,
+)]
+#[repr(C)]
+pub enum SceneLabel {
+  /// Nothing particular was detected.
+  #[default]
+  Normal,
+  /// The frame starts a new scene.
+  SceneCut,
+  /// The frame is part of a short flash, after which the scene continues.
+  Flash,
+  /// The frame is part of a fade from a flat color.
+  FadeIn,
+  /// The frame is part of a fade to a flat color.
+  FadeOut,
+  /// The frame is part of a gradual blend between two scenes.
+  CrossDissolve,
+}
+
+impl SceneLabel {
+  /// Returns whether the frame is part of a gradual transition.
+  #[inline]
+  pub fn is_gradual(self) -> bool {
+    matches!(
+      self,
+      SceneLabel::FadeIn | SceneLabel::FadeOut | SceneLabel::CrossDissolve
+    )
+  }
+}
+
+impl fmt::Display for SceneLabel {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    use self::SceneLabel::*;
+    match self {
+      Normal => write!(f, "Normal"),
+      SceneCut => write!(f, "Scene cut"),
+      Flash => write!(f, "Flash"),
+      FadeIn => write!(f, "Fade-in"),
+      FadeOut => write!(f, "Fade-out"),
+      CrossDissolve => write!(f, "Cross-dissolve"),
+    }
+  }
+}
+
 /// A single T.35 metadata packet.
 #[derive(Clone, Debug, Default)]
 pub struct T35 {
@@ -215,18 +261,31 @@ pub struct Packet<T: Pixel> {
   pub input_frameno: u64,
   /// Type of the shown frame.
   pub frame_type: FrameType,
+  /// Content transition detected at the shown frame by the lookahead.
+  pub scene_label: SceneLabel,
   /// QP selected for the frame.
   pub qp: u8,
   /// Block-level encoding stats for the frame
   pub enc_stats: EncoderStats,
+  /// Whether the frame was dropped by the rate control, in which case the
+  /// packet shows the previous frame again.
+  pub dropped: bool,
   /// Optional user-provided opaque data
   #[cfg_attr(feature = "serialize", serde(skip))]
   pub opaque: Option<Opaque>,
+  /// The data of the alpha auxiliary stream holding the same frames, if
+  /// enabled by `EncoderConfig::alpha_quantizer`.
+  pub alpha_data: Option<Vec<u8>>,
+  /// The reconstruction of the shown alpha plane, in the luma plane of a
+  /// monochrome frame.
+  #[cfg_attr(feature = "serialize", serde(skip))]
+  pub alpha_rec: Option<Arc<Frame<T>>>,
 }
 
 impl<T: Pixel> PartialEq for Packet<T> {
   fn eq(&self, other: &Self) -> bool {
     self.data == other.data
+      && self.alpha_data == other.alpha_data
       && self.input_frameno == other.input_frameno
       && self.frame_type == other.frame_type
       && self.qp == other.qp
@@ -0,0 +1,243 @@
+// Copyright (c) 2017-2022, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+//! Checkpoints saved before the keyframes of an encode, from which it can be
+//! resumed once interrupted: the rest of the output is then identical to the
+//! one of an uninterrupted encode.
+
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use rav1e::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::common::*;
+use crate::decoder::{Decoder, InputDecoder};
+use crate::error::*;
+use crate::muxer::*;
+use crate::stats::*;
+use crate::verify::Verifier;
+use crate::{finish_encode, process_frame, report_frames, Source};
+
+/// The contents of a checkpoint file.
+#[derive(Serialize, Deserialize)]
+struct CheckpointFile {
+  /// The encoder configuration, which a resumed encode must keep.
+  settings: String,
+  /// The length of the output before the keyframe.
+  output_len: u64,
+  /// The length of the pass data written before the keyframe, following the
+  /// summary at the start of the file, which the end of an interrupted encode
+  /// replaces with one of a different length.
+  pass1_len: Option<u64>,
+  /// The position of the pass data to be read for the keyframe.
+  pass2_pos: Option<u64>,
+  encoder: Checkpoint,
+}
+
+fn load(path: &Path) -> Result<CheckpointFile, CliError> {
+  let mut checkpoint = String::new();
+  File::open(path)
+    .and_then(|mut f| f.read_to_string(&mut checkpoint))
+    .map_err(|e| e.context("Cannot open the checkpoint file"))?;
+  toml::from_str(&checkpoint).map_err(|e| e.context("Invalid checkpoint file"))
+}
+
+/// Replaces the checkpoint file, so that a valid checkpoint remains if the
+/// encode is interrupted while saving it.
+fn save(path: &Path, checkpoint: &CheckpointFile) -> Result<(), CliError> {
+  let data = toml::to_string(checkpoint)
+    .map_err(|e| e.context("Cannot save the checkpoint"))?;
+  let mut tmp_path = OsString::from(path);
+  tmp_path.push(".tmp");
+  fs::write(&tmp_path, data)
+    .and_then(|()| fs::rename(&tmp_path, path))
+    .map_err(|e| e.context("Cannot write the checkpoint file"))
+}
+
+fn stream_position(file: Option<&mut File>) -> Result<Option<u64>, CliError> {
+  file
+    .map(Seek::stream_position)
+    .transpose()
+    .map_err(|e| e.context("Unable to seek in the two-pass data file."))
+}
+
+/// Returns the position of the frame data following the summary at the start
+/// of a first pass data file.
+fn pass1_data_start(passfile: &mut File) -> Result<u64, CliError> {
+  let mut buflen = [0u8; 8];
+  passfile
+    .rewind()
+    .and_then(|()| passfile.read_exact(&mut buflen))
+    .map_err(|e| e.context("Unable to read the two-pass data file."))?;
+  Ok(8 + u64::from_be_bytes(buflen))
+}
+
+/// Returns whether the encode stops before the end of its input.
+fn stopped<D: Decoder>(source: &Source<D>) -> bool {
+  source.exit_requested()
+    || (source.limit != 0 && source.count == source.limit)
+}
+
+/// Encodes the input like `do_encode`, saving a checkpoint before every
+/// keyframe, and removes the checkpoint file once the whole input is
+/// encoded. An encode stopped by a signal or by the frame limit keeps it, to
+/// be resumed.
+///
+/// A resumed encode skips the input frames before the keyframe of its
+/// checkpoint, and truncates the output and the pass data written after it.
+pub fn do_encode_resumable<T: Pixel>(
+  cfg: Config, enc: &EncoderConfig, verbose: Verboseness,
+  mut progress: ProgressInfo, output: &mut dyn Muxer,
+  mut source: Source<InputDecoder>, mut pass1file: Option<File>,
+  mut pass2file: Option<File>, mut verifier: Option<Verifier>,
+  metrics_enabled: MetricsEnabled, options: CheckpointOptions,
+) -> Result<(), CliError> {
+  let settings = format!("{enc:?}");
+  let mut pass1_start = 0;
+
+  let mut ctx: Context<T> = if options.resume {
+    let resumed = load(&options.path)?;
+    if resumed.settings != settings {
+      return Err(CliError::new(
+        "The encoder settings differ from the ones of the interrupted encode",
+      ));
+    }
+    if resumed.pass1_len.is_some() != pass1file.is_some()
+      || resumed.pass2_pos.is_some() != pass2file.is_some()
+    {
+      return Err(CliError::new(
+        "The passes differ from the ones of the interrupted encode",
+      ));
+    }
+
+    // The files are appended to, from their length at the checkpoint.
+    File::options()
+      .write(true)
+      .open(&options.output)
+      .and_then(|f| f.set_len(resumed.output_len))
+      .map_err(|e| e.context("Cannot truncate the output file"))?;
+    if let (Some(passfile), Some(len)) =
+      (pass1file.as_mut(), resumed.pass1_len)
+    {
+      pass1_start = pass1_data_start(passfile)?;
+      passfile
+        .set_len(pass1_start + len)
+        .and_then(|()| passfile.seek(SeekFrom::End(0)))
+        .map_err(|e| {
+          e.context("Unable to truncate the two-pass data file.")
+        })?;
+    }
+    if let (Some(passfile), Some(pos)) =
+      (pass2file.as_mut(), resumed.pass2_pos)
+    {
+      passfile
+        .seek(SeekFrom::Start(pos))
+        .map_err(|e| e.context("Unable to seek in the two-pass data file."))?;
+    }
+
+    let checkpoint = resumed.encoder;
+    if verbose != Verboseness::Quiet {
+      info!("Resuming the encode from frame {}", checkpoint.input_frameno());
+    }
+    for _ in 0..checkpoint.input_frameno() {
+      source.input.skip_frame().map_err(|_| {
+        CliError::new("The input ends before the frame of the checkpoint")
+      })?;
+    }
+    source.count = checkpoint.input_frameno() as usize;
+
+    let mut ctx = cfg
+      .new_context_from_checkpoint(&checkpoint)
+      .map_err(|e| e.context("Invalid encoder settings"))?;
+    // The encoder had received the frames following the keyframe as well.
+    for _ in checkpoint.input_frameno()..checkpoint.frame_count() {
+      let Some((frame, params)) = source.next_frame(&ctx)? else {
+        return Err(CliError::new(
+          "The input ends before the frame of the checkpoint",
+        ));
+      };
+      let _ = ctx.send_frame((Arc::new(frame), params));
+    }
+    if checkpoint.is_flushed() {
+      ctx.flush();
+    }
+    ctx
+  } else {
+    let ctx =
+      cfg.new_context().map_err(|e| e.context("Invalid encoder settings"))?;
+    output.write_header(enc, &ctx.container_sequence_header());
+    if let Some(av1c) = ctx.alpha_container_sequence_header() {
+      output.write_alpha_header(&av1c);
+    }
+
+    // Let's write down a placeholder.
+    if let Some(passfile) = pass1file.as_mut() {
+      let len = ctx.rc_summary_size();
+      pass1_start = 8 + len as u64;
+      passfile
+        .write_all(&(len as u64).to_be_bytes())
+        .and_then(|()| passfile.write_all(&vec![0u8; len]))
+        .map_err(|e| e.context("Unable to write to two-pass data file."))?;
+    }
+    ctx
+  };
+
+  loop {
+    // The encode stopped by a signal or by the frame limit is flushed early,
+    // which changes the frames following the last checkpoint.
+    if let Some(checkpoint) = ctx
+      .checkpoint()
+      .filter(|checkpoint| !(checkpoint.is_flushed() && stopped(&source)))
+    {
+      output.flush().map_err(|e| e.context("Unable to write the output"))?;
+      let output_len = fs::metadata(&options.output)
+        .map_err(|e| e.context("Unable to write the output"))?
+        .len();
+      let checkpoint = CheckpointFile {
+        settings: settings.clone(),
+        output_len,
+        pass1_len: stream_position(pass1file.as_mut())?
+          .map(|pos| pos - pass1_start),
+        pass2_pos: stream_position(pass2file.as_mut())?,
+        encoder: checkpoint,
+      };
+      save(&options.path, &checkpoint)?;
+    }
+
+    let Some(frame_info) = process_frame(
+      &mut ctx,
+      &mut *output,
+      &mut source,
+      pass1file.as_mut(),
+      pass2file.as_mut(),
+      None,
+      verifier.as_mut(),
+      metrics_enabled,
+    )?
+    else {
+      break;
+    };
+    report_frames(frame_info, verbose, &mut progress, None, &mut *output)?;
+  }
+  finish_encode(verbose, progress, output, None)?;
+
+  if source.exit_requested() {
+    info!("The encode was interrupted, continue it with --resume");
+    Ok(())
+  } else if stopped(&source) {
+    Ok(())
+  } else {
+    fs::remove_file(&options.path)
+      .map_err(|e| e.context("Cannot remove the checkpoint file"))
+  }
+}
@@ -0,0 +1,417 @@
+// Copyright (c) 2017-2022, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+//! Chunked parallel encoding: the input is split at scene changes into
+//! chunks starting with a keyframe, which are encoded concurrently by
+//! independent encoders, and their frames are written in order as if a
+//! single encoder produced them.
+
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::io::Write;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use rav1e::prelude::*;
+use rav1e::scenechange::{SceneDetectionConfig, SceneDetector};
+
+use crate::common::*;
+use crate::decoder::{Decoder, FrameBuilder, VideoDetails};
+use crate::error::*;
+use crate::muxer::*;
+use crate::stats::*;
+use crate::verify::Verifier;
+use crate::{finish_encode, report_frames, Source};
+
+/// The unit of the timestamps of grain table segments, in 1/s.
+const GRAIN_TIMESTAMP_UNIT: u64 = 10_000_000;
+
+impl<T: Pixel> FrameBuilder<T> for SceneDetector<T> {
+  fn new_frame(&self) -> Frame<T> {
+    SceneDetector::new_frame(self)
+  }
+}
+
+type ChunkFrame<T> = (Arc<Frame<T>>, Option<FrameParameters>);
+
+/// A chunk of the input, whose frames are received while it is encoded.
+struct Chunk<T: Pixel> {
+  index: usize,
+  /// The input frame number of the first frame of the chunk.
+  start: u64,
+  frames: Receiver<ChunkFrame<T>>,
+}
+
+/// A frame encoded by the encoder of a chunk, numbered from the start of the
+/// input.
+struct EncodedFrame<T: Pixel> {
+  data: Vec<u8>,
+  rec: Option<Arc<Frame<T>>>,
+  summary: FrameSummary,
+}
+
+/// The data emitted by the first pass of a chunk.
+#[derive(Default)]
+struct PassData {
+  frames: Vec<Box<[u8]>>,
+  summary: Option<Box<[u8]>>,
+}
+
+/// Returns the scene detection settings placing keyframes as the encoder
+/// would.
+fn scene_detection_config(enc: &EncoderConfig) -> SceneDetectionConfig {
+  let speed = match enc.speed_settings.scene_detection_mode {
+    // The encoder always uses fast detection in low latency mode.
+    speed if enc.low_latency && speed != SceneDetectionSpeed::None => {
+      SceneDetectionSpeed::Fast
+    }
+    speed => speed,
+  };
+  SceneDetectionConfig {
+    width: enc.width,
+    height: enc.height,
+    bit_depth: enc.bit_depth,
+    chroma_sampling: enc.chroma_sampling,
+    speed,
+    min_scene_len: enc.min_key_frame_interval,
+    max_scene_len: enc.max_key_frame_interval,
+    // The keyframe lookahead of the encoder, which is shorter without frame
+    // reordering.
+    lookahead_frames: if enc.low_latency { 2 } else { 5 },
+  }
+}
+
+/// Returns the input frames forced to be keyframes, by `forced_keyframes` or
+/// by zones.
+fn forced_keyframes(enc: &EncoderConfig) -> BTreeSet<u64> {
+  let zones = enc.zones.iter().flatten();
+  enc
+    .forced_keyframes
+    .iter()
+    .flatten()
+    .copied()
+    .chain(
+      zones.filter(|zone| zone.force_keyframe).map(|zone| zone.start_frame),
+    )
+    .collect()
+}
+
+/// Returns the settings of the encoder of a chunk starting at the input
+/// frame `start`, whose zones and grain table are relative to the chunk.
+fn chunk_config(enc: &EncoderConfig, start: u64) -> EncoderConfig {
+  let mut enc = enc.clone();
+  // Chunks are split at the keyframes, so each one only starts with one.
+  enc.speed_settings.scene_detection_mode = SceneDetectionSpeed::None;
+  enc.forced_keyframes = None;
+
+  enc.zones = enc.zones.take().map(|zones| {
+    zones
+      .into_iter()
+      .filter(|zone| zone.end_frame > start)
+      .map(|zone| Zone {
+        start_frame: zone.start_frame.saturating_sub(start),
+        end_frame: zone.end_frame - start,
+        ..zone
+      })
+      .collect()
+  });
+
+  let start_time =
+    start * GRAIN_TIMESTAMP_UNIT * enc.time_base.num / enc.time_base.den;
+  enc.film_grain_params = enc.film_grain_params.take().map(|segments| {
+    segments
+      .into_iter()
+      .filter(|segment| segment.end_time > start_time)
+      .map(|segment| GrainTableSegment {
+        start_time: segment.start_time.saturating_sub(start_time),
+        end_time: segment.end_time - start_time,
+        ..segment
+      })
+      .collect()
+  });
+  enc
+}
+
+/// Runs an encoder over frames, sending it the pass data of a previous pass
+/// if any, and returns the pass data it emits if `emit_pass_data` is set.
+fn encode_pass<T: Pixel>(
+  cfg: &Config, mut frames: impl Iterator<Item = ChunkFrame<T>>,
+  pass_data: Option<&PassData>, emit_pass_data: bool,
+  mut on_packet: impl FnMut(Packet<T>),
+) -> Result<PassData, CliError> {
+  let mut ctx: Context<T> =
+    cfg.new_context().map_err(|e| e.context("Invalid encoder settings"))?;
+  let mut pass_data_in = pass_data.map(|pass_data| pass_data.frames.iter());
+  let mut pass_data = PassData::default();
+
+  loop {
+    if let Some(pass_data_in) = pass_data_in.as_mut() {
+      while ctx.rc_second_pass_data_required() > 0 {
+        let data = pass_data_in
+          .next()
+          .ok_or_else(|| CliError::new("Missing first pass data"))?;
+        ctx
+          .rc_send_pass_data(data)
+          .map_err(|e| e.context("Corrupted first pass data"))?;
+      }
+    }
+
+    let done = match ctx.receive_packet() {
+      Ok(pkt) => {
+        on_packet(pkt);
+        false
+      }
+      Err(EncoderStatus::NeedMoreData) => {
+        match frames.next() {
+          Some(frame) => {
+            let _ = ctx.send_frame(frame);
+          }
+          None => ctx.flush(),
+        }
+        continue;
+      }
+      Err(EncoderStatus::Encoded) => false,
+      Err(EncoderStatus::LimitReached) => true,
+      Err(e) => return Err(e.context("Failed to encode video")),
+    };
+
+    if emit_pass_data {
+      match ctx.rc_receive_pass_data() {
+        Some(RcData::Frame(data)) => pass_data.frames.push(data),
+        Some(RcData::Summary(data)) => pass_data.summary = Some(data),
+        None => {}
+      }
+    }
+    if done {
+      return Ok(pass_data);
+    }
+  }
+}
+
+/// Encodes a chunk, in two passes if requested, and returns its frames.
+fn encode_chunk<T: Pixel>(
+  cfg: &Config, enc: &EncoderConfig, passes: u8, chunk: Chunk<T>,
+  y4m_details: VideoDetails, metrics_cli: MetricsEnabled, keep_rec: bool,
+) -> Result<Vec<EncodedFrame<T>>, CliError> {
+  let cfg = cfg.clone().with_encoder_config(chunk_config(enc, chunk.start));
+  let mut encoded = Vec::new();
+  let on_packet = |mut pkt: Packet<T>| {
+    pkt.input_frameno += chunk.start;
+    let data = pkt.data.clone();
+    let rec = if keep_rec { pkt.rec.clone() } else { None };
+    let summary = build_frame_summary(
+      pkt,
+      y4m_details.bit_depth,
+      y4m_details.chroma_sampling,
+      metrics_cli,
+    );
+    encoded.push(EncodedFrame { data, rec, summary });
+  };
+
+  if passes == 1 {
+    encode_pass(&cfg, chunk.frames.iter(), None, false, on_packet)?;
+    return Ok(encoded);
+  }
+
+  // The parameters of the frames are only sent in the second pass, which
+  // produces the output.
+  let mut frames = Vec::new();
+  let first_pass = cfg
+    .clone()
+    .with_rate_control(RateControlConfig::new().with_emit_data(true));
+  let pass_data = encode_pass(
+    &first_pass,
+    chunk.frames.iter().map(|(frame, params)| {
+      frames.push((Arc::clone(&frame), params));
+      (frame, None)
+    }),
+    None,
+    true,
+    |_| {},
+  )?;
+  let summary = pass_data
+    .summary
+    .as_ref()
+    .ok_or_else(|| CliError::new("Missing first pass summary"))?;
+  let rc = RateControlConfig::from_summary_slice(summary)
+    .map_err(|e| e.context("Invalid summary"))?;
+  encode_pass(
+    &cfg.with_rate_control(rc),
+    frames.into_iter(),
+    Some(&pass_data),
+    false,
+    on_packet,
+  )?;
+  Ok(encoded)
+}
+
+/// Encodes the input in chunks, `workers` of them in parallel, and writes
+/// their frames in order.
+pub fn do_encode_chunks<T: Pixel, D: Decoder>(
+  cfg: Config, enc: &EncoderConfig, workers: usize, passes: u8,
+  verbose: Verboseness, mut progress: ProgressInfo, output: &mut dyn Muxer,
+  mut source: Source<D>,
+  mut y4m_enc: Option<y4m::Encoder<Box<dyn Write + Send>>>,
+  mut verifier: Option<Verifier>, metrics_enabled: MetricsEnabled,
+  mut stats_file: Option<StatsWriter>,
+) -> Result<(), CliError> {
+  let ctx: Context<T> =
+    cfg.new_context().map_err(|e| e.context("Invalid encoder settings"))?;
+  output.write_header(enc, &ctx.container_sequence_header());
+  drop(ctx);
+
+  let mut detector: SceneDetector<T> =
+    SceneDetector::new(scene_detection_config(enc))
+      .map_err(|e| e.context("Invalid encoder settings"))?;
+  let forced_keyframes = forced_keyframes(enc);
+  let y4m_details = source.video_info;
+  let keep_rec = y4m_enc.is_some() || verifier.is_some();
+
+  // Writes the frames of the chunks which are next in order.
+  let mut next_chunk = 0;
+  let mut pending = BTreeMap::new();
+  let mut write_chunks =
+    |pending: &mut BTreeMap<usize, Vec<EncodedFrame<T>>>| {
+      while let Some(frames) = pending.remove(&next_chunk) {
+        next_chunk += 1;
+        let mut summaries = Vec::with_capacity(frames.len());
+        for frame in frames {
+          let summary = frame.summary;
+          output.write_frame(
+            summary.input_frameno,
+            &frame.data,
+            summary.frame_type,
+          );
+          if let (Some(y4m_enc), Some(rec)) = (y4m_enc.as_mut(), &frame.rec) {
+            write_y4m_frame(y4m_enc, rec, y4m_details);
+          }
+          if let Some(verifier) = verifier.as_mut() {
+            verifier.verify(
+              summary.input_frameno,
+              &frame.data,
+              frame.rec.as_deref(),
+            )?;
+          }
+          summaries.push(summary);
+        }
+        report_frames(
+          summaries,
+          verbose,
+          &mut progress,
+          stats_file.as_mut(),
+          &mut *output,
+        )?;
+      }
+      Ok::<_, CliError>(())
+    };
+
+  // A chunk is only started once a worker is available to encode it, so the
+  // input is read no faster than it can be encoded.
+  let (chunk_send, chunk_recv) = mpsc::sync_channel::<Chunk<T>>(0);
+  let chunk_recv = Mutex::new(chunk_recv);
+  let (result_send, result_recv) = mpsc::channel();
+  thread::scope(|s| {
+    for _ in 0..workers {
+      let (cfg, chunk_recv, result_send) =
+        (&cfg, &chunk_recv, result_send.clone());
+      s.spawn(move || loop {
+        let chunk = chunk_recv.lock().unwrap().recv();
+        let Ok(chunk) = chunk else { break };
+        let index = chunk.index;
+        // A panic is raised again by the main thread, which would otherwise
+        // wait for the worker forever.
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+          encode_chunk(
+            cfg,
+            enc,
+            passes,
+            chunk,
+            y4m_details,
+            metrics_enabled,
+            keep_rec,
+          )
+        }));
+        if result_send.send((index, result)).is_err() {
+          break;
+        }
+      });
+    }
+    drop(result_send);
+
+    // Frames read from the input, waiting for the decision of the detector.
+    let mut frames = VecDeque::new();
+    let mut chunk = None;
+    let mut chunk_count = 0;
+    loop {
+      for (index, result) in result_recv.try_iter() {
+        pending
+          .insert(index, result.unwrap_or_else(|e| panic::resume_unwind(e))?);
+      }
+      write_chunks(&mut pending)?;
+
+      match detector.receive_decision() {
+        Ok(decision) => {
+          if decision.scene_cut
+            || forced_keyframes.contains(&decision.input_frameno)
+          {
+            // The previous chunk is ended before waiting for a worker, which
+            // may be the one encoding it.
+            drop(chunk.take());
+            let (frame_send, frame_recv) = mpsc::channel();
+            chunk_send
+              .send(Chunk {
+                index: chunk_count,
+                start: decision.input_frameno,
+                frames: frame_recv,
+              })
+              .unwrap();
+            chunk = Some(frame_send);
+            chunk_count += 1;
+          }
+          // A failed encoder reports its error with its result.
+          let _ = chunk.as_ref().unwrap().send(frames.pop_front().unwrap());
+        }
+        Err(EncoderStatus::NeedMoreData) => {
+          match source.next_frame(&detector)? {
+            Some((mut frame, params)) => {
+              // The frame is shared with the detector, so the encoder cannot
+              // pad it itself.
+              let planes = if enc.chroma_sampling == ChromaSampling::Cs400 {
+                1
+              } else {
+                3
+              };
+              for plane in frame.planes[..planes].iter_mut() {
+                plane.pad(enc.width, enc.height);
+              }
+              let frame = Arc::new(frame);
+              let _ = detector.send_frame(Arc::clone(&frame));
+              frames.push_back((frame, params));
+            }
+            None => detector.flush(),
+          }
+        }
+        Err(EncoderStatus::LimitReached) => break,
+        Err(e) => return Err(e.context("Failed to detect scene changes")),
+      }
+    }
+    drop(chunk);
+    drop(chunk_send);
+
+    for (index, result) in result_recv.iter() {
+      pending
+        .insert(index, result.unwrap_or_else(|e| panic::resume_unwind(e))?);
+      write_chunks(&mut pending)?;
+    }
+    Ok(())
+  })?;
+
+  finish_encode(verbose, progress, output, stats_file)
+}
@@ -7,12 +7,22 @@
 // Media Patent License 1.0 was not distributed with this source code in the
 // PATENTS file, you can obtain it at www.aomedia.org/license/patent.
 
+use crate::decoder::image::{is_image_extension, ImageSequenceDecoder};
+use crate::decoder::raw::{parse_pix_fmt, RawDecoder};
+use crate::decoder::{parse_timecode, Decoder, InputDecoder, VideoDetails};
 use crate::error::*;
-use crate::muxer::{create_muxer, Muxer};
+use crate::muxer::{
+  append_muxer, create_muxer, grid_tile_size, output_container, Container,
+  Muxer,
+};
+#[cfg(feature = "serialize")]
+use crate::settings::*;
 use crate::stats::MetricsEnabled;
 use clap::builder::styling::AnsiColor;
 use clap::builder::Styles;
-use clap::{CommandFactory, Parser as Clap, Subcommand};
+#[cfg(feature = "serialize")]
+use clap::parser::ValueSource;
+use clap::{CommandFactory, FromArgMatches, Parser as Clap, Subcommand};
 use clap_complete::{generate, Shell};
 use rav1e::prelude::*;
 use scan_fmt::scan_fmt;
@@ -39,15 +49,62 @@ pub mod built_info {
   long_about = None
 )]
 pub struct CliOptions {
-  /// Uncompressed YUV4MPEG2 video input
+  /// Uncompressed video input: YUV4MPEG2, raw YUV with --width and
+  /// --height, or a PNG or PNM image or sequence of images such as
+  /// frame%04d.png
   #[clap(value_parser, help_heading = "INPUT/OUTPUT")]
   pub input: PathBuf,
-  /// Compressed AV1 in IVF video output
+  /// Width of raw YUV input
+  #[clap(long, requires = "height", help_heading = "INPUT/OUTPUT")]
+  pub width: Option<usize>,
+  /// Height of raw YUV input
+  #[clap(long, requires = "width", help_heading = "INPUT/OUTPUT")]
+  pub height: Option<usize>,
+  /// Pixel format of raw YUV input, or to convert images to: gray, yuv420p,
+  /// yuv422p or yuv444p, with a 10le or 12le suffix for high bit depths,
+  /// e.g. yuv420p10le
+  #[clap(long, help_heading = "INPUT/OUTPUT")]
+  pub pix_fmt: Option<String>,
+  /// Frame rate of raw YUV and image sequence input, as NUM or NUM/DEN
+  #[clap(long, default_value = "30", help_heading = "INPUT/OUTPUT")]
+  pub fps: String,
+  /// Compressed AV1 video output, in IVF, MP4, Matroska, `WebM`, raw OBUs or
+  /// AVIF depending on the extension
   #[clap(long, short, value_parser, help_heading = "INPUT/OUTPUT")]
   pub output: PathBuf,
   /// Overwrite output file.
   #[clap(short = 'y', help_heading = "INPUT/OUTPUT")]
   pub overwrite: bool,
+  /// Container format of the output, instead of the one matching its
+  /// extension. Required for annex B output, and to stream other formats than
+  /// IVF to stdout
+  #[clap(long, value_parser, help_heading = "INPUT/OUTPUT")]
+  pub container: Option<Container>,
+  /// Write a fragmented MP4 output, with a fragment per keyframe
+  #[clap(long, help_heading = "INPUT/OUTPUT")]
+  pub fragmented: bool,
+  /// Split the input image in a grid of `COLUMNSxROWS` tiles, coded
+  /// separately and joined in the AVIF output, for images too large to be
+  /// coded at once
+  #[clap(long, value_parser, help_heading = "INPUT/OUTPUT")]
+  pub grid: Option<String>,
+  /// Save a checkpoint of the encode to this file before every keyframe, to
+  /// continue the encode with --resume if it is interrupted. Requires an IVF
+  /// or OBU output file
+  #[cfg(feature = "serialize")]
+  #[clap(
+    long,
+    value_parser,
+    conflicts_with_all = ["parallel_chunks", "reconstruction", "stats_file"],
+    help_heading = "INPUT/OUTPUT"
+  )]
+  pub checkpoint: Option<PathBuf>,
+  /// Resume the encode interrupted after saving the checkpoint given by
+  /// --checkpoint, with the same options. The output and pass data files are
+  /// truncated to their length at the checkpoint, then appended to
+  #[cfg(feature = "serialize")]
+  #[clap(long, requires = "checkpoint", help_heading = "INPUT/OUTPUT")]
+  pub resume: bool,
 
   /// Set the threadpool size. If 0, will use the number of logical CPUs.
   /// rav1e will use up to this many threads. Additional tiles may be needed to
@@ -74,6 +131,28 @@ pub struct CliOptions {
   #[cfg(feature = "unstable")]
   #[clap(long, value_parser, default_value_t = 0, help_heading = "THREADING")]
   pub slots: usize,
+  /// Split the input at scene changes into chunks starting with a keyframe,
+  /// and encode this many chunks in parallel with independent encoders,
+  /// each using the threadpool size given by --threads. 0 disables chunked
+  /// encoding.
+  #[clap(
+    long,
+    value_parser,
+    default_value_t = 0,
+    conflicts_with_all = ["first_pass", "second_pass"],
+    help_heading = "THREADING"
+  )]
+  pub parallel_chunks: usize,
+  /// Number of passes of each chunk of a chunked encode. A second pass
+  /// requires a target bitrate, and keeps the frames of each chunk in memory.
+  #[clap(
+    long,
+    value_parser = clap::value_parser!(u8).range(1..=2),
+    default_value_t = 1,
+    requires = "parallel_chunks",
+    help_heading = "THREADING"
+  )]
+  pub chunk_passes: u8,
 
   /// Perform the first pass of a two-pass encode,
   /// saving the pass data to the specified file for future passes
@@ -85,7 +164,9 @@ pub struct CliOptions {
   )]
   pub first_pass: Option<PathBuf>,
   /// Perform the second pass of a two-pass encode,
-  /// reading the pass data saved from a previous pass from the specified file
+  /// reading the pass data saved from a previous pass from the specified file.
+  /// Combined with --first-pass (using another file), the pass data of this
+  /// pass is saved as well, and each further pass refines the size.
   #[clap(
     long,
     value_parser,
@@ -154,6 +235,11 @@ pub struct CliOptions {
     help_heading = "ENCODE SETTINGS"
   )]
   pub switch_frame_interval: u64,
+  /// Use open GOPs: scene cuts within the maximum keyframe interval become
+  /// intra-only frames, and keyframes are coded ahead as forward keyframes
+  /// when possible. Incompatible with --low-latency
+  #[clap(long, help_heading = "ENCODE SETTINGS")]
+  pub open_gop: bool,
   /// "Number of frames over which rate control should distribute the reservoir
   /// [default: min(240, 1.5x keyint)]
   /// A minimum value of 12 is enforced.
@@ -163,6 +249,10 @@ pub struct CliOptions {
   /// Has a significant speed-to-quality trade-off
   #[clap(long, help_heading = "ENCODE SETTINGS")]
   pub low_latency: bool,
+  /// Drop frames that would exceed the target bitrate, showing the previous
+  /// frame again instead. Requires --low-latency and --bitrate
+  #[clap(long, help_heading = "ENCODE SETTINGS")]
+  pub drop_frames: bool,
   /// Disables scene detection entirely.
   /// Has a significant speed-to-quality trade-off in full encodes.
   /// Useful for chunked encoding.
@@ -178,6 +268,10 @@ pub struct CliOptions {
   /// Still picture mode
   #[clap(long, help_heading = "ENCODE SETTINGS")]
   pub still_picture: bool,
+  /// Code the alpha channel of the input with this quantizer (0-255) in the
+  /// AVIF output. Quantizer 0 codes it losslessly
+  #[clap(long, value_parser, help_heading = "ENCODE SETTINGS")]
+  pub alpha_quantizer: Option<u8>,
   /// Uses grain synthesis to add photon noise to the resulting encode.
   /// Takes a strength value 0-64.
   #[clap(
@@ -197,10 +291,63 @@ pub struct CliOptions {
     help_heading = "ENCODE SETTINGS"
   )]
   pub film_grain_table: Option<PathBuf>,
+  /// Denoises the source with the given noise level (standard deviation on
+  /// the 8-bit scale, 0-50), and synthesizes grain estimated from the removed
+  /// noise instead, unless --no-denoise-grain is given.
+  #[clap(
+    long,
+    value_parser = clap::value_parser!(u8).range(0..=50),
+    default_value_t = 0,
+    help_heading = "ENCODE SETTINGS"
+  )]
+  pub denoise_noise_level: u8,
+  /// Number of frames on each side of a frame blended into it when
+  /// denoising (0-3). 0 denoises each frame on its own.
+  #[clap(
+    long,
+    value_parser = clap::value_parser!(u8).range(0..=3),
+    default_value_t = 1,
+    help_heading = "ENCODE SETTINGS"
+  )]
+  pub denoise_temporal_radius: u8,
+  /// Only encodes the denoised source, without synthesizing grain in its
+  /// place. Allows giving grain parameters with --photon-noise or
+  /// --film-grain-table instead.
+  #[clap(
+    long,
+    requires = "denoise_noise_level",
+    help_heading = "ENCODE SETTINGS"
+  )]
+  pub no_denoise_grain: bool,
+  /// Frame ranges encoded with their own settings, in the form
+  /// <start>,<end>,<option>[,<option>...][/<start>,<end>,...].
+  /// Frame numbers are inclusive and count from the first encoded frame.
+  /// Options: q=<quantizer>, b=<bitrate multiplier>, speed=<0-10>,
+  /// tune=<Psnr|Psychovisual>, keyframe
+  #[clap(long, help_heading = "ENCODE SETTINGS")]
+  pub zones: Option<String>,
+  /// Comma-separated list of frames to encode as keyframes, e.g. chapter
+  /// points. Frame numbers count from the first encoded frame.
+  #[clap(long, help_heading = "ENCODE SETTINGS")]
+  pub keyframes: Option<String>,
+  /// Encodes only the luma plane of the input, as a monochrome (4:0:0)
+  /// stream. Monochrome Y4M input is always encoded this way.
+  #[clap(long, help_heading = "ENCODE SETTINGS")]
+  pub monochrome: bool,
   /// Force the high bitdepth codepath even for 8bit content.
   /// Mainly for debugging purposes.
   #[clap(long, help_heading = "ENCODE SETTINGS")]
   pub high_bitdepth: bool,
+  /// Set an encoder setting, overriding the options and the loaded
+  /// configuration, e.g. `partition.encode_bottomup=false`. May be repeated
+  #[cfg(feature = "serialize")]
+  #[clap(
+    long,
+    value_name = "KEY=VALUE",
+    help_heading = "ENCODE SETTINGS",
+    long_help = build_set_long_help()
+  )]
+  pub set: Vec<String>,
 
   /// Pixel range
   #[clap(long, value_parser, help_heading = "VIDEO METADATA")]
@@ -220,6 +367,11 @@ pub struct CliOptions {
   /// Content light level used to describe content luminosity (cll,fall)
   #[clap(long, help_heading = "VIDEO METADATA")]
   pub content_light: Option<String>,
+  /// Timecode of the first input frame as HH:MM:SS:FF, or HH:MM:SS;FF for
+  /// drop-frame counting, written with every frame. Overrides the XTIMECODE
+  /// of the Y4M input
+  #[clap(long, help_heading = "VIDEO METADATA")]
+  pub timecode: Option<String>,
   /// AV1 level to target in the form <major>.<minor>, e.g. 3.1.
   /// Specify "unconstrained" for no level constraints or "auto" to let
   /// the encoder choose (default)
@@ -252,9 +404,19 @@ pub struct CliOptions {
   /// Calculate and display several metrics including PSNR, SSIM, CIEDE2000 etc
   #[clap(long, conflicts_with = "psnr", help_heading = "DEBUGGING")]
   pub metrics: bool,
+  /// Writes statistics of every frame and of the whole encode to a file, as
+  /// CSV if its extension is csv and JSON Lines otherwise. The metrics are
+  /// included with --psnr or --metrics
+  #[clap(long, value_parser, help_heading = "DEBUGGING")]
+  pub stats_file: Option<PathBuf>,
   /// Outputs a Y4M file containing the output from the decoder
   #[clap(long, short, value_parser, help_heading = "DEBUGGING")]
   pub reconstruction: Option<PathBuf>,
+  /// Decodes every frame with dav1d and checks that it is identical to the
+  /// reconstruction of the encoder, failing at the first mismatch
+  #[cfg(feature = "decode_test_dav1d")]
+  #[clap(long, help_heading = "DEBUGGING")]
+  pub verify: bool,
 
   #[clap(subcommand)]
   pub command: Option<Commands>,
@@ -321,18 +483,31 @@ pub enum Commands {
     /// Save the current configuration in a toml file
     #[clap(long, short, value_parser)]
     save_config: Option<PathBuf>,
-    /// Load the encoder configuration from a toml file
-    #[clap(long, short, value_parser, conflicts_with = "save-config")]
+    /// Load the encoder configuration from a toml file, under the encoder
+    /// options given on the command line
+    #[clap(long, short, value_parser)]
     load_config: Option<PathBuf>,
   },
 }
 
 pub struct EncoderIO {
-  pub input: Box<dyn Read + Send>,
+  pub input: InputDecoder,
   pub output: Box<dyn Muxer + Send>,
   pub rec: Option<Box<dyn Write + Send>>,
 }
 
+/// Where the checkpoints of an encode are saved.
+#[cfg(feature = "serialize")]
+// rav1e-ch rejects checkpoints without reading their options.
+#[cfg_attr(feature = "channel-api", allow(dead_code))]
+pub struct CheckpointOptions {
+  pub path: PathBuf,
+  /// The output file, whose length is saved in the checkpoints.
+  pub output: PathBuf,
+  /// Whether the encode resumes from the saved checkpoint.
+  pub resume: bool,
+}
+
 #[derive(Clone, Copy, PartialEq, Eq)]
 pub enum Verboseness {
   Quiet,
@@ -351,6 +526,10 @@ pub struct ParsedCliOptions {
   pub benchmark: bool,
   pub threads: usize,
   pub metrics_enabled: MetricsEnabled,
+  pub stats_file_name: Option<PathBuf>,
+  /// Whether the encoded frames are decoded and compared to the
+  /// reconstruction.
+  pub verify: bool,
   pub pass1file_name: Option<PathBuf>,
   pub pass2file_name: Option<PathBuf>,
   #[cfg(feature = "serialize")]
@@ -358,7 +537,17 @@ pub struct ParsedCliOptions {
   pub photon_noise: u8,
   #[cfg(feature = "unstable")]
   pub slots: usize,
+  /// The number of chunks encoded in parallel and the number of passes of
+  /// each chunk, for a chunked encode.
+  pub chunks: Option<(usize, u8)>,
+  #[cfg(feature = "serialize")]
+  pub checkpoint: Option<CheckpointOptions>,
   pub force_highbitdepth: bool,
+  pub monochrome: bool,
+  pub timecode: Option<Timecode>,
+  /// The grid of tiles of an AVIF image coded in tiles, and the size of the
+  /// tiles.
+  pub grid: Option<(ImageGrid, (usize, usize))>,
 }
 
 #[cfg(feature = "serialize")]
@@ -384,7 +573,35 @@ fn build_speed_long_help() -> Option<&'static str> {
     )
   });
 
-  Some(&help)
+  Some(help)
+}
+
+#[cfg(feature = "serialize")]
+static SET_HELP_TEXT: OnceLock<String> = OnceLock::new();
+
+#[cfg(feature = "serialize")]
+fn build_set_long_help() -> Option<&'static str> {
+  let help = SET_HELP_TEXT.get_or_init(|| {
+    let settings = crate::settings::setting_pairs()
+      .into_iter()
+      .map(|(key, value)| {
+        let key = key.strip_prefix("speed_settings.").unwrap_or(&key);
+        format!("    {key} = {value}")
+      })
+      .collect::<Vec<String>>()
+      .join("\n");
+
+    format!(
+      "Set an encoder setting, overriding the options and the loaded\n\
+   configuration, e.g. partition.encode_bottomup=false. May be repeated.\n\
+   Values are TOML values, and None unsets an optional setting.\n\
+   Settings and their defaults:\n\
+{}",
+      settings
+    )
+  });
+
+  Some(help)
 }
 
 #[cfg(not(feature = "serialize"))]
@@ -400,11 +617,14 @@ fn build_speed_long_help() -> Option<&'static str> {
 /// Only call this once at the start of the app,
 /// otherwise bad things will happen.
 pub fn parse_cli() -> Result<ParsedCliOptions, CliError> {
-  let matches = CliOptions::parse();
+  let arg_matches = CliOptions::command().get_matches();
+  let matches =
+    CliOptions::from_arg_matches(&arg_matches).unwrap_or_else(|e| e.exit());
 
   #[cfg(feature = "serialize")]
   let mut save_config_path = None;
-  let mut enc = None;
+  #[cfg(feature = "serialize")]
+  let mut file_enc = None;
 
   if let Some(command) = matches.command.as_ref() {
     match command {
@@ -425,7 +645,10 @@ pub fn parse_cli() -> Result<ParsedCliOptions, CliError> {
               .and_then(|mut f| f.read_to_string(&mut config))
               .map_err(|e| e.context("Cannot open the configuration file"))?;
 
-            enc = Some(toml::from_str(&config).unwrap());
+            file_enc = Some(
+              toml::from_str(&config)
+                .map_err(|e| e.context("Invalid configuration file"))?,
+            );
           }
         }
         #[cfg(not(feature = "serialize"))]
@@ -450,20 +673,141 @@ pub fn parse_cli() -> Result<ParsedCliOptions, CliError> {
     None => None,
   };
 
-  let os_input = &matches.input;
+  let mut enc = parse_config(&matches)?;
+  #[cfg(feature = "serialize")]
+  let settings = matches
+    .set
+    .iter()
+    .map(|arg| Setting::parse(arg))
+    .collect::<Result<Vec<_>, _>>()?;
+  // The options given on the command line override the loaded
+  // configuration, and the settings override both.
+  #[cfg(feature = "serialize")]
+  let unset_enc = {
+    if let Some(file_enc) = file_enc.as_ref() {
+      enc = layer_config(file_enc, &enc, |option| {
+        arg_matches.value_source(option) == Some(ValueSource::CommandLine)
+      })?;
+    }
+    let set_enc = apply_settings(&enc, &settings)?;
+    std::mem::replace(&mut enc, set_enc)
+  };
+
+  let container = output_container(&matches.output, matches.container)?;
+
+  #[cfg(feature = "serialize")]
+  let checkpoint = matches.checkpoint.clone().map(|path| CheckpointOptions {
+    path,
+    output: matches.output.clone(),
+    resume: matches.resume,
+  });
+  #[cfg(feature = "serialize")]
+  if checkpoint.is_some()
+    && (matches.output.to_str() == Some("-")
+      || !matches!(
+        container,
+        Container::Ivf | Container::Obu | Container::AnnexB
+      ))
+  {
+    return Err(CliError::new(
+      "Checkpoints require an IVF or OBU output file",
+    ));
+  }
+  #[cfg(feature = "serialize")]
+  let resume = matches.resume;
+  #[cfg(not(feature = "serialize"))]
+  let resume = false;
+
+  #[cfg(feature = "decode_test_dav1d")]
+  let verify = matches.verify;
+  #[cfg(not(feature = "decode_test_dav1d"))]
+  let verify = false;
+
+  if enc.alpha_quantizer.is_some()
+    && (container != Container::Avif || matches.parallel_chunks > 0)
+  {
+    return Err(CliError::new(
+      "Alpha coding requires an AVIF output, without chunks",
+    ));
+  }
+  let input = open_input(&matches, &enc)?;
+  let grid = matches
+    .grid
+    .as_deref()
+    .map(|grid| {
+      let (columns, rows) =
+        scan_fmt!(grid, "{}x{}", usize, usize).map_err(|_| {
+          CliError::new("The grid must be in the form COLUMNSxROWS")
+        })?;
+      let details = input.get_video_details();
+      let grid = ImageGrid {
+        rows,
+        columns,
+        width: details.width,
+        height: details.height,
+      };
+      let chroma_sampling = if matches.monochrome {
+        ChromaSampling::Cs400
+      } else {
+        details.chroma_sampling
+      };
+      Ok((grid, grid_tile_size(&grid, chroma_sampling)?))
+    })
+    .transpose()?;
+  if grid.is_some()
+    && (container != Container::Avif
+      || rec.is_some()
+      || matches.parallel_chunks > 0
+      || verify)
+  {
+    return Err(CliError::new(
+      "A grid requires an AVIF output, without reconstruction, verification or chunks",
+    ));
+  }
+
+  #[cfg(feature = "serialize")]
+  let is_set = |key: &str| settings.iter().any(|s| s.key.starts_with(key));
+  #[cfg(not(feature = "serialize"))]
+  let is_set = |_: &str| false;
+  let override_time_base = matches.frame_rate.is_some() || is_set("time_base");
+  // MP4 stores the time base as a 32-bit timescale and sample duration.
+  let time_base = if override_time_base {
+    enc.time_base
+  } else {
+    input.get_video_details().time_base
+  };
+  if container == Container::Mp4
+    && (u32::try_from(time_base.num).is_err()
+      || u32::try_from(time_base.den).is_err())
+  {
+    return Err(CliError::new(
+      "An MP4 output requires a time base of at most 32 bits",
+    ));
+  }
+
   let io = EncoderIO {
-    input: match os_input.to_str() {
-      Some("-") => Box::new(io::stdin()) as Box<dyn Read + Send>,
-      _ => Box::new(
-        File::open(os_input)
-          .map_err(|e| e.context("Cannot open input file"))?,
-      ) as Box<dyn Read + Send>,
+    input,
+    output: if resume {
+      append_muxer(&matches.output, container)?
+    } else {
+      create_muxer(
+        &matches.output,
+        matches.overwrite,
+        container,
+        matches.fragmented,
+        grid.map(|(grid, _)| grid),
+      )?
     },
-    output: create_muxer(&matches.output, matches.overwrite)?,
     rec,
   };
-
-  let enc = enc.map_or_else(|| parse_config(&matches), Ok)?;
+  #[cfg(feature = "serialize")]
+  {
+    let mut details = io.input.get_video_details();
+    if matches.monochrome {
+      details.chroma_sampling = ChromaSampling::Cs400;
+    }
+    validate_settings(&unset_enc, &settings, &details)?;
+  }
 
   let verbose = if matches.quiet {
     Verboseness::Quiet
@@ -481,36 +825,164 @@ pub fn parse_cli() -> Result<ParsedCliOptions, CliError> {
     MetricsEnabled::None
   };
 
-  let limit = matches.limit;
+  let mut limit = matches.limit;
   if enc.still_picture && limit > 1 {
     panic!("A limit cannot be set above 1 in still picture mode");
   }
+  if container == Container::Avif {
+    if !enc.still_picture {
+      return Err(CliError::new("AVIF output requires --still-picture"));
+    }
+    // An AVIF image holds a single picture.
+    limit = 1;
+  }
 
   #[cfg(feature = "unstable")]
   let slots = matches.slots;
 
+  let timecode = matches
+    .timecode
+    .as_deref()
+    .map(|timecode| {
+      parse_timecode(timecode).ok_or_else(|| {
+        CliError::new(
+          "Timecode must be in the form HH:MM:SS:FF or HH:MM:SS;FF",
+        )
+      })
+    })
+    .transpose()?;
+
+  let color_range_specified = matches.range.is_some() || is_set("pixel_range");
+
   Ok(ParsedCliOptions {
     io,
     enc,
     limit,
-    color_range_specified: matches.range.is_some(),
-    override_time_base: matches.frame_rate.is_some(),
+    color_range_specified,
+    override_time_base,
     metrics_enabled,
     skip: matches.skip,
     benchmark: matches.benchmark,
     verbose,
     threads: matches.threads,
+    stats_file_name: matches.stats_file.clone(),
+    verify,
     pass1file_name: matches.first_pass.clone(),
     pass2file_name: matches.second_pass.clone(),
     #[cfg(feature = "serialize")]
     save_config: save_config_path,
     photon_noise: matches.photon_noise,
     force_highbitdepth: matches.high_bitdepth,
+    monochrome: matches.monochrome,
+    timecode,
+    grid,
     #[cfg(feature = "unstable")]
     slots,
+    chunks: (matches.parallel_chunks > 0)
+      .then_some((matches.parallel_chunks, matches.chunk_passes)),
+    #[cfg(feature = "serialize")]
+    checkpoint,
   })
 }
 
+/// Opens the input with the decoder of its format, found from its extension
+/// and the raw input options.
+fn open_input(
+  matches: &CliOptions, enc: &EncoderConfig,
+) -> Result<InputDecoder, CliError> {
+  let pix_fmt = matches
+    .pix_fmt
+    .as_deref()
+    .map(|pix_fmt| {
+      parse_pix_fmt(pix_fmt)
+        .ok_or_else(|| CliError::new("Unsupported pixel format"))
+    })
+    .transpose()?;
+  let (num, den) = matches.fps.split_once('/').unwrap_or((&matches.fps, "1"));
+  let time_base = match (num.parse(), den.parse()) {
+    (Ok(num), Ok(den)) if num > 0 && den > 0 => Rational::new(den, num),
+    _ => {
+      return Err(CliError::new(
+        "The frame rate must be in the form NUM or NUM/DEN",
+      ))
+    }
+  };
+
+  let os_input = &matches.input;
+  let extension =
+    os_input.extension().and_then(|e| e.to_str()).unwrap_or_default();
+  if is_image_extension(extension) {
+    let pattern = os_input
+      .to_str()
+      .ok_or_else(|| CliError::new("The input path is not valid UTF-8"))?;
+    let matrix = enc
+      .color_description
+      .map_or(MatrixCoefficients::Unspecified, |c| c.matrix_coefficients);
+    return ImageSequenceDecoder::open(
+      pattern,
+      pix_fmt,
+      time_base,
+      matrix,
+      enc.pixel_range,
+    )
+    .map(InputDecoder::Images);
+  }
+
+  let input = match os_input.to_str() {
+    Some("-") => Box::new(io::stdin()) as Box<dyn Read + Send>,
+    _ => Box::new(
+      File::open(os_input).map_err(|e| e.context("Cannot open input file"))?,
+    ) as Box<dyn Read + Send>,
+  };
+
+  if extension.eq_ignore_ascii_case("yuv") || matches.width.is_some() {
+    let (Some(width), Some(height)) = (matches.width, matches.height) else {
+      return Err(CliError::new(
+        "Raw YUV input requires --width and --height",
+      ));
+    };
+    let (chroma_sampling, bit_depth) =
+      pix_fmt.unwrap_or((ChromaSampling::Cs420, 8));
+    let details = VideoDetails {
+      width,
+      height,
+      bit_depth,
+      chroma_sampling,
+      chroma_sample_position: ChromaSamplePosition::Unknown,
+      time_base,
+      ..Default::default()
+    };
+    return Ok(InputDecoder::Raw(RawDecoder::new(input, details)));
+  }
+
+  // Maximum frame size by specification + maximum y4m header
+  let limit = y4m::Limits {
+    // Use saturating operations to gracefully handle 32-bit architectures
+    bytes: 64usize
+      .saturating_mul(64)
+      .saturating_mul(4096)
+      .saturating_mul(2304)
+      .saturating_add(1024),
+  };
+  match y4m::Decoder::new_with_limits(input, limit) {
+    Err(e) => Err(CliError::new(match e {
+      y4m::Error::ParseError(_) => {
+        "Could not parse input video. Is it a y4m file?"
+      }
+      y4m::Error::IoError(_) => {
+        "Could not read input file. Check that the path is correct and you have read permissions."
+      }
+      y4m::Error::UnknownColorspace => {
+        "Unknown colorspace or unsupported bit depth."
+      }
+      y4m::Error::OutOfMemory => "The video's frame size exceeds the limit.",
+      y4m::Error::EOF => "Unexpected end of input.",
+      y4m::Error::BadInput => "Bad y4m input parameters provided.",
+    })),
+    Ok(d) => Ok(InputDecoder::Y4m(d)),
+  }
+}
+
 fn parse_config(matches: &CliOptions) -> Result<EncoderConfig, CliError> {
   let maybe_quantizer = matches.quantizer;
   let maybe_bitrate = matches.bitrate;
@@ -579,6 +1051,7 @@ fn parse_config(matches: &CliOptions) -> Result<EncoderConfig, CliError> {
 
   cfg.set_key_frame_interval(min_interval, max_interval);
   cfg.switch_frame_interval = matches.switch_frame_interval;
+  cfg.open_gop = matches.open_gop;
 
   cfg.pixel_range = matches.range.unwrap_or_default();
   cfg.color_description = if color_primaries == ColorPrimaries::Unspecified
@@ -671,6 +1144,7 @@ fn parse_config(matches: &CliOptions) -> Result<EncoderConfig, CliError> {
     });
 
   cfg.still_picture = matches.still_picture;
+  cfg.alpha_quantizer = matches.alpha_quantizer.map(usize::from);
 
   cfg.quantizer = quantizer;
   cfg.min_quantizer = matches.min_quantizer.unwrap_or(0);
@@ -696,6 +1170,9 @@ fn parse_config(matches: &CliOptions) -> Result<EncoderConfig, CliError> {
     panic!("Tile columns and rows may not be greater than 64");
   }
 
+  cfg.denoise_noise_level = matches.denoise_noise_level;
+  cfg.denoise_temporal_radius = matches.denoise_temporal_radius;
+  cfg.denoise_grain_synthesis = !matches.no_denoise_grain;
   if let Some(table_file) = matches.film_grain_table.as_ref() {
     let contents = std::fs::read_to_string(table_file)
       .expect("Failed to read film grain table file");
@@ -711,10 +1188,140 @@ fn parse_config(matches: &CliOptions) -> Result<EncoderConfig, CliError> {
   }
 
   cfg.low_latency = matches.low_latency;
+  cfg.drop_frames = matches.drop_frames;
   // Disables scene_detection
   if matches.no_scene_detection {
     cfg.speed_settings.scene_detection_mode = SceneDetectionSpeed::None;
   }
 
+  if let Some(zones) = matches.zones.as_ref() {
+    cfg.zones = Some(parse_zones(zones)?);
+  }
+
+  if let Some(keyframes) = matches.keyframes.as_ref() {
+    cfg.forced_keyframes = Some(
+      keyframes
+        .split(',')
+        .map(|frame| {
+          frame
+            .trim()
+            .parse()
+            .map_err(|e: std::num::ParseIntError| e.context("keyframes"))
+        })
+        .collect::<Result<_, _>>()?,
+    );
+  }
+
   Ok(cfg)
 }
+
+fn parse_zones(zones: &str) -> Result<Vec<Zone>, CliError> {
+  zones
+    .split('/')
+    .map(|zone_str| {
+      let mut fields = zone_str.split(',');
+      let mut next_frame = || -> Result<u64, CliError> {
+        fields
+          .next()
+          .ok_or_else(|| CliError::new("Zones must start with <start>,<end>"))?
+          .trim()
+          .parse()
+          .map_err(|e: std::num::ParseIntError| e.context("zones"))
+      };
+      let start_frame = next_frame()?;
+      // The end frame is inclusive on the command line.
+      let end_frame = next_frame()? + 1;
+      let mut zone = Zone::new(start_frame, end_frame);
+      for option in fields.map(str::trim) {
+        match option.split_once('=') {
+          Some(("q", value)) => {
+            zone.quantizer = Some(
+              value
+                .parse()
+                .map_err(|e: std::num::ParseIntError| e.context("zones"))?,
+            );
+          }
+          Some(("b", value)) => {
+            zone.bitrate_multiplier = Some(value.parse().map_err(|_| {
+              CliError::new("Invalid zone bitrate multiplier")
+            })?);
+          }
+          Some(("speed", value)) => {
+            let speed: u8 = value
+              .parse()
+              .map_err(|e: std::num::ParseIntError| e.context("zones"))?;
+            if speed > 10 {
+              return Err(CliError::new("Zone speed must be between 0-10"));
+            }
+            zone.speed_settings = Some(SpeedSettings::from_preset(speed));
+          }
+          Some(("tune", value)) => {
+            zone.tune = Some(
+              value.parse().map_err(|_| CliError::new("Invalid zone tune"))?,
+            );
+          }
+          None if option == "keyframe" => zone.force_keyframe = true,
+          _ => {
+            return Err(CliError::new(&format!(
+              "Unknown zone option `{option}`"
+            )));
+          }
+        }
+      }
+      Ok(zone)
+    })
+    .collect()
+}
+
+/// Write the rate control summary in front of the first pass data.
+///
+/// The summary replaces the placeholder written at the start of the file,
+/// moving the frame data that follows it in place when the sizes differ.
+pub fn write_pass1_summary(
+  passfile: &mut File, summary: &[u8],
+) -> Result<(), CliError> {
+  let seek_err =
+    |e: io::Error| e.context("Unable to seek in the two-pass data file.");
+  let read_err =
+    |e: io::Error| e.context("Unable to read the two-pass data file.");
+  let write_err =
+    |e: io::Error| e.context("Unable to write to two-pass data file.");
+
+  passfile.rewind().map_err(seek_err)?;
+  let mut buflen = [0u8; 8];
+  passfile.read_exact(&mut buflen).map_err(read_err)?;
+  let old_start = 8 + u64::from_be_bytes(buflen);
+  let new_start = 8 + summary.len() as u64;
+
+  if old_start != new_start {
+    let data_len =
+      passfile.seek(io::SeekFrom::End(0)).map_err(seek_err)? - old_start;
+    // The data is moved by chunks, starting from its end when it moves
+    // forward so that no chunk overwrites one yet to be moved.
+    let mut buf = vec![0u8; 1 << 16];
+    let mut offsets: Vec<u64> = (0..data_len).step_by(buf.len()).collect();
+    if new_start > old_start {
+      offsets.reverse();
+    }
+    for offset in offsets {
+      let len = (data_len - offset).min(buf.len() as u64) as usize;
+      passfile
+        .seek(io::SeekFrom::Start(old_start + offset))
+        .map_err(seek_err)?;
+      passfile.read_exact(&mut buf[..len]).map_err(read_err)?;
+      passfile
+        .seek(io::SeekFrom::Start(new_start + offset))
+        .map_err(seek_err)?;
+      passfile.write_all(&buf[..len]).map_err(write_err)?;
+    }
+    passfile.set_len(new_start + data_len).map_err(write_err)?;
+  }
+
+  passfile.rewind().map_err(seek_err)?;
+  passfile
+    .write_all(&(summary.len() as u64).to_be_bytes())
+    .map_err(write_err)?;
+  passfile.write_all(summary).map_err(write_err)?;
+  passfile.seek(io::SeekFrom::End(0)).map_err(seek_err)?;
+  Ok(())
+}
@@ -0,0 +1,300 @@
+// Copyright (c) 2017-2022, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+//! PNG and PNM image sequence input, converted from RGB to YUV with the
+//! matrix coefficients and pixel range of the output.
+
+use std::io;
+
+use ::image::{DynamicImage, ImageError};
+
+use crate::color::ChromaSampling::Cs400;
+use crate::decoder::{DecodeError, Decoder, FrameBuilder, VideoDetails};
+use crate::error::*;
+use rav1e::prelude::*;
+
+/// Returns whether an extension is that of an image format that can be read.
+pub fn is_image_extension(extension: &str) -> bool {
+  matches!(
+    extension.to_ascii_lowercase().as_str(),
+    "png" | "pnm" | "pbm" | "pgm" | "ppm"
+  )
+}
+
+/// Splits a pattern such as `frame%04d.png` into the parts before and after
+/// its frame number, and the width to which the frame number is zero-padded.
+fn split_pattern(pattern: &str) -> Option<(&str, usize, &str)> {
+  let (prefix, spec) = pattern.split_once('%')?;
+  let (width, suffix) = spec.split_once('d')?;
+  let width = match width {
+    "" => 0,
+    _ if width.starts_with('0') => width.parse().ok()?,
+    _ => return None,
+  };
+  Some((prefix, width, suffix))
+}
+
+/// Returns the `(Kr, Kb)` luma coefficients of a matrix, if it is one of
+/// the matrices computed from them.
+fn luma_coefficients(matrix: MatrixCoefficients) -> Option<(f32, f32)> {
+  use MatrixCoefficients::*;
+  match matrix {
+    // Images carry no matrix, so BT.601 is assumed as by most decoders.
+    Unspecified | BT470BG | BT601 => Some((0.299, 0.114)),
+    BT709 => Some((0.2126, 0.0722)),
+    FCC => Some((0.30, 0.11)),
+    SMPTE240 => Some((0.212, 0.087)),
+    BT2020NCL => Some((0.2627, 0.0593)),
+    _ => None,
+  }
+}
+
+/// Copies samples of the given bit depth, in rows of `width`, to a plane.
+fn write_samples<T: Pixel>(
+  plane: &mut Plane<T>, samples: &[u16], width: usize, bit_depth: usize,
+) {
+  let bytes = if bit_depth > 8 { 2 } else { 1 };
+  let raw: Vec<u8> = if bytes == 2 {
+    samples.iter().flat_map(|s| s.to_le_bytes()).collect()
+  } else {
+    samples.iter().map(|&s| s as u8).collect()
+  };
+  plane.copy_from_raw_u8(&raw, width * bytes, bytes);
+}
+
+pub struct ImageSequenceDecoder {
+  /// The path of the images before their frame number, or the path of the
+  /// single image.
+  prefix: String,
+  /// The width of the zero-padded frame numbers, and the rest of the path.
+  numbering: Option<(usize, String)>,
+  next: u64,
+  /// The first image, read to find the size of the sequence.
+  first: Option<DynamicImage>,
+  details: VideoDetails,
+  matrix: MatrixCoefficients,
+  range: PixelRange,
+  /// The alpha samples of the last image read, at the output bit depth.
+  alpha: Option<Vec<u16>>,
+}
+
+impl ImageSequenceDecoder {
+  /// Opens a sequence of images numbered from 0 or 1, given by a path with a
+  /// `%d` or `%0Nd` in place of the frame number, or a single image.
+  ///
+  /// The output is 4:2:0 with the bit depth of the images, 10-bit for more
+  /// than 8 bits per channel, unless a pixel format is given.
+  pub fn open(
+    pattern: &str, pix_fmt: Option<(ChromaSampling, usize)>,
+    time_base: Rational, matrix: MatrixCoefficients, range: PixelRange,
+  ) -> Result<Self, CliError> {
+    let (prefix, numbering) = match split_pattern(pattern) {
+      Some((prefix, width, suffix)) => {
+        (prefix.to_owned(), Some((width, suffix.to_owned())))
+      }
+      None => (pattern.to_owned(), None),
+    };
+    let mut dec = ImageSequenceDecoder {
+      prefix,
+      numbering,
+      next: 0,
+      first: None,
+      details: VideoDetails::default(),
+      matrix,
+      range,
+      alpha: None,
+    };
+
+    let first = match dec.read_image() {
+      Err(ImageError::IoError(e))
+        if e.kind() == io::ErrorKind::NotFound && dec.numbering.is_some() =>
+      {
+        dec.next = 1;
+        dec.read_image()
+      }
+      first => first,
+    }
+    .map_err(|e| e.context("Cannot read input image"))?;
+
+    let high_bit_depth = first.color().bits_per_pixel()
+      > 8 * u16::from(first.color().channel_count());
+    let (chroma_sampling, bit_depth) = pix_fmt
+      .unwrap_or((ChromaSampling::Cs420, if high_bit_depth { 10 } else { 8 }));
+    if matrix == MatrixCoefficients::Identity {
+      if chroma_sampling != ChromaSampling::Cs444 {
+        return Err(CliError::new(
+          "The identity matrix requires a 4:4:4 pixel format",
+        ));
+      }
+    } else if luma_coefficients(matrix).is_none() {
+      return Err(CliError::new(
+        "Unsupported matrix coefficients for RGB input",
+      ));
+    }
+
+    dec.details = VideoDetails {
+      width: first.width() as usize,
+      height: first.height() as usize,
+      bit_depth,
+      chroma_sampling,
+      chroma_sample_position: ChromaSamplePosition::Unknown,
+      time_base,
+      alpha: first.color().has_alpha(),
+      ..Default::default()
+    };
+    dec.first = Some(first);
+    Ok(dec)
+  }
+
+  fn read_image(&mut self) -> Result<DynamicImage, ImageError> {
+    let path = match &self.numbering {
+      Some((width, suffix)) => {
+        format!("{}{:0width$}{}", self.prefix, self.next, suffix)
+      }
+      // A single image makes a sequence of one frame.
+      None if self.next > 0 => {
+        return Err(ImageError::IoError(io::ErrorKind::NotFound.into()))
+      }
+      None => self.prefix.clone(),
+    };
+    let image = ::image::open(path)?;
+    self.next += 1;
+    Ok(image)
+  }
+
+  /// Reads the next image of the sequence.
+  pub fn next_image(&mut self) -> Result<DynamicImage, DecodeError> {
+    if let Some(first) = self.first.take() {
+      return Ok(first);
+    }
+    let image = self.read_image().map_err(|e| match e {
+      ImageError::IoError(e) if e.kind() == io::ErrorKind::NotFound => {
+        DecodeError::EOF
+      }
+      ImageError::IoError(_) => DecodeError::IoError,
+      e => {
+        error!("Cannot read input image: {}", e);
+        DecodeError::ParseError
+      }
+    })?;
+    if image.width() as usize != self.details.width
+      || image.height() as usize != self.details.height
+    {
+      error!("The size of the input images changes");
+      return Err(DecodeError::BadInput);
+    }
+    Ok(image)
+  }
+
+  /// Converts normalized RGB to YUV samples of the output bit depth.
+  fn rgb_to_yuv(&self, [r, g, b]: [f32; 3]) -> [u16; 3] {
+    let (y, u, v) = match luma_coefficients(self.matrix) {
+      Some((kr, kb)) => {
+        let y = kr * r + (1.0 - kr - kb) * g + kb * b;
+        (y, (b - y) / (2.0 * (1.0 - kb)), (r - y) / (2.0 * (1.0 - kr)))
+      }
+      // The identity matrix stores GBR, scaled as luma.
+      None => (g, b, r),
+    };
+    let is_identity = self.matrix == MatrixCoefficients::Identity;
+    let scale = (1 << (self.details.bit_depth - 8)) as f32;
+    let max = ((1 << self.details.bit_depth) - 1) as f32;
+    let quantize = |value: f32, chroma: bool| {
+      let value = match (self.range, chroma && !is_identity) {
+        (PixelRange::Limited, false) => (16.0 + 219.0 * value) * scale,
+        (PixelRange::Limited, true) => (128.0 + 224.0 * value) * scale,
+        (PixelRange::Full, false) => max * value,
+        (PixelRange::Full, true) => max * value + 128.0 * scale,
+      };
+      value.round().clamp(0.0, max) as u16
+    };
+    [quantize(y, false), quantize(u, true), quantize(v, true)]
+  }
+}
+
+impl Decoder for ImageSequenceDecoder {
+  fn get_video_details(&self) -> VideoDetails {
+    self.details
+  }
+
+  fn read_frame<T: Pixel, F: FrameBuilder<T>>(
+    &mut self, ctx: &F, cfg: &VideoDetails,
+  ) -> Result<Frame<T>, DecodeError> {
+    let image = self.next_image()?;
+    let (width, height) = (self.details.width, self.details.height);
+    // The alpha channel is always full range.
+    self.alpha = image.color().has_alpha().then(|| {
+      let max = ((1 << self.details.bit_depth) - 1) as f32;
+      image
+        .to_luma_alpha16()
+        .pixels()
+        .map(|pixel| (f32::from(pixel.0[1]) / 65535.0 * max).round() as u16)
+        .collect()
+    });
+    let image = image.into_rgb16();
+    let pixel = |x: usize, y: usize| {
+      image.get_pixel(x as u32, y as u32).0.map(|c| f32::from(c) / 65535.0)
+    };
+
+    let mut f = ctx.new_frame();
+    let bit_depth = self.details.bit_depth;
+    let write_plane = |plane: &mut Plane<T>, samples: &[u16], width| {
+      write_samples(plane, samples, width, bit_depth);
+    };
+
+    let luma = (0..height)
+      .flat_map(|y| (0..width).map(move |x| (x, y)))
+      .map(|(x, y)| self.rgb_to_yuv(pixel(x, y))[0])
+      .collect::<Vec<_>>();
+    write_plane(&mut f.planes[0], &luma, width);
+
+    if cfg.chroma_sampling != Cs400 {
+      // The chroma of a subsampled block is that of its average color.
+      let (ss_x, ss_y) =
+        self.details.chroma_sampling.get_decimation().unwrap();
+      let (chroma_width, chroma_height) =
+        self.details.chroma_sampling.get_chroma_dimensions(width, height);
+      let mut u = Vec::with_capacity(chroma_width * chroma_height);
+      let mut v = Vec::with_capacity(chroma_width * chroma_height);
+      for cy in 0..chroma_height {
+        for cx in 0..chroma_width {
+          let mut sum = [0.0; 3];
+          let mut count = 0.0;
+          for y in (cy << ss_y)..((cy + 1) << ss_y).min(height) {
+            for x in (cx << ss_x)..((cx + 1) << ss_x).min(width) {
+              let rgb = pixel(x, y);
+              (0..3).for_each(|c| sum[c] += rgb[c]);
+              count += 1.0;
+            }
+          }
+          let yuv = self.rgb_to_yuv(sum.map(|c| c / count));
+          u.push(yuv[1]);
+          v.push(yuv[2]);
+        }
+      }
+      write_plane(&mut f.planes[1], &u, chroma_width);
+      write_plane(&mut f.planes[2], &v, chroma_width);
+    }
+    Ok(f)
+  }
+
+  fn read_alpha<T: Pixel, F: FrameBuilder<T>>(
+    &mut self, ctx: &F,
+  ) -> Option<Plane<T>> {
+    let alpha = self.alpha.take()?;
+    let mut plane = ctx.new_alpha_plane();
+    write_samples(
+      &mut plane,
+      &alpha,
+      self.details.width,
+      self.details.bit_depth,
+    );
+    Some(plane)
+  }
+}
@@ -8,12 +8,27 @@
 // Media Patent License 1.0 was not distributed with this source code in the
 // PATENTS file, you can obtain it at www.aomedia.org/license/patent.
 
+use std::io::Read;
+
 use rav1e::prelude::*;
 
+pub mod image;
+pub mod raw;
 pub mod y4m;
 
+use self::image::ImageSequenceDecoder;
+use self::raw::RawDecoder;
+
 pub trait FrameBuilder<T: Pixel> {
   fn new_frame(&self) -> Frame<T>;
+
+  /// Allocates an alpha plane, the size of the luma plane.
+  // rav1e-ch rejects alpha coding.
+  #[cfg_attr(feature = "channel-api", allow(dead_code))]
+  fn new_alpha_plane(&self) -> Plane<T> {
+    let [plane, ..] = self.new_frame().planes;
+    plane
+  }
 }
 
 pub trait Decoder: Send {
@@ -21,6 +36,83 @@ pub trait Decoder: Send {
   fn read_frame<T: Pixel, F: FrameBuilder<T>>(
     &mut self, ctx: &F, cfg: &VideoDetails,
   ) -> Result<Frame<T>, DecodeError>;
+
+  /// Returns the alpha channel of the last frame read, for the inputs which
+  /// have one.
+  // rav1e-ch rejects alpha coding.
+  #[cfg_attr(feature = "channel-api", allow(dead_code))]
+  fn read_alpha<T: Pixel, F: FrameBuilder<T>>(
+    &mut self, _ctx: &F,
+  ) -> Option<Plane<T>> {
+    None
+  }
+}
+
+/// The decoders of the input formats.
+pub enum InputDecoder {
+  Y4m(::y4m::Decoder<Box<dyn Read + Send>>),
+  Raw(RawDecoder),
+  Images(ImageSequenceDecoder),
+}
+
+impl InputDecoder {
+  pub const fn name(&self) -> &'static str {
+    match self {
+      InputDecoder::Y4m(_) => "y4m",
+      InputDecoder::Raw(_) => "raw YUV",
+      InputDecoder::Images(_) => "image sequence",
+    }
+  }
+
+  /// Returns the colorspace of a Y4M reconstruction of the input, if Y4M can
+  /// store it.
+  pub fn rec_colorspace(&self) -> Option<::y4m::Colorspace> {
+    match self {
+      InputDecoder::Y4m(dec) => Some(dec.get_colorspace()),
+      _ => {
+        let details = self.get_video_details();
+        y4m::y4m_colorspace(details.chroma_sampling, details.bit_depth)
+      }
+    }
+  }
+
+  /// Reads a frame of the input without converting it.
+  pub fn skip_frame(&mut self) -> Result<(), DecodeError> {
+    match self {
+      InputDecoder::Y4m(dec) => dec.read_frame().map(drop).map_err(Into::into),
+      InputDecoder::Raw(dec) => dec.read_raw_frame(),
+      InputDecoder::Images(dec) => dec.next_image().map(drop),
+    }
+  }
+}
+
+impl Decoder for InputDecoder {
+  fn get_video_details(&self) -> VideoDetails {
+    match self {
+      InputDecoder::Y4m(dec) => dec.get_video_details(),
+      InputDecoder::Raw(dec) => dec.get_video_details(),
+      InputDecoder::Images(dec) => dec.get_video_details(),
+    }
+  }
+
+  fn read_frame<T: Pixel, F: FrameBuilder<T>>(
+    &mut self, ctx: &F, cfg: &VideoDetails,
+  ) -> Result<Frame<T>, DecodeError> {
+    match self {
+      InputDecoder::Y4m(dec) => Decoder::read_frame(dec, ctx, cfg),
+      InputDecoder::Raw(dec) => dec.read_frame(ctx, cfg),
+      InputDecoder::Images(dec) => dec.read_frame(ctx, cfg),
+    }
+  }
+
+  fn read_alpha<T: Pixel, F: FrameBuilder<T>>(
+    &mut self, ctx: &F,
+  ) -> Option<Plane<T>> {
+    match self {
+      InputDecoder::Images(dec) => dec.read_alpha(ctx),
+      _ => None,
+    }
+  }
 }
 
 #[derive(Debug)]
@@ -33,6 +125,29 @@ pub enum DecodeError {
   MemoryLimitExceeded,
 }
 
+/// Parses a timecode in the form HH:MM:SS:FF, or HH:MM:SS;FF for drop-frame
+/// counting.
+pub fn parse_timecode(s: &str) -> Option<Timecode> {
+  let (hms, frames) = s.rsplit_once([':', ';'])?;
+  let drop_frame = s.as_bytes()[hms.len()] == b';';
+  let mut units = hms.split(':').map(|unit| unit.parse::<u8>().ok());
+  let (hours, minutes, seconds) =
+    (units.next()??, units.next()??, units.next()??);
+  if units.next().is_some() {
+    return None;
+  }
+  Some(Timecode {
+    counting_type: if drop_frame { TIMECODE_COUNTING_DROP_FRAME } else { 0 },
+    full_timestamp: true,
+    hours,
+    minutes,
+    seconds,
+    frames: frames.parse().ok()?,
+    ..Default::default()
+  })
+  .filter(Timecode::is_valid)
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct VideoDetails {
   pub width: usize,
@@ -42,6 +157,25 @@ pub struct VideoDetails {
   pub chroma_sampling: ChromaSampling,
   pub chroma_sample_position: ChromaSamplePosition,
   pub time_base: Rational,
+  /// Timecode of the first frame.
+  pub timecode: Option<Timecode>,
+  /// Whether the frames have an alpha channel, returned by
+  /// [`Decoder::read_alpha`].
+  // rav1e-ch rejects alpha coding.
+  #[cfg_attr(feature = "channel-api", allow(dead_code))]
+  pub alpha: bool,
+}
+
+impl VideoDetails {
+  /// Returns the timecode of the frame `frameno` of the input, counting
+  /// frames at the nominal frame rate.
+  pub fn frame_timecode(&self, frameno: u64) -> Option<Timecode> {
+    let Rational { num, den } = self.time_base;
+    let frame_rate = (den + num / 2) / num;
+    self
+      .timecode
+      .map(|timecode| timecode.add_frames(frameno, frame_rate as u32))
+  }
 }
 
 impl Default for VideoDetails {
@@ -54,6 +188,8 @@ impl Default for VideoDetails {
       chroma_sampling: ChromaSampling::Cs420,
       chroma_sample_position: ChromaSamplePosition::Unknown,
       time_base: Rational { num: 30, den: 1 },
+      timecode: None,
+      alpha: false,
     }
   }
 }
@@ -0,0 +1,100 @@
+// Copyright (c) 2017-2022, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+//! Headerless planar YUV input, whose frame size, pixel format and frame rate
+//! are given on the command line.
+
+use std::io::{self, Read};
+
+use crate::color::ChromaSampling::Cs400;
+use crate::decoder::{DecodeError, Decoder, FrameBuilder, VideoDetails};
+use rav1e::prelude::*;
+
+/// Parses a pixel format in the naming of `FFmpeg`, such as `yuv420p` or
+/// `yuv422p10le`, into its chroma sampling and bit depth.
+pub fn parse_pix_fmt(pix_fmt: &str) -> Option<(ChromaSampling, usize)> {
+  let (chroma_sampling, bit_depth) = match pix_fmt.strip_prefix("gray") {
+    Some(bit_depth) => (ChromaSampling::Cs400, bit_depth),
+    None => {
+      let (sampling, bit_depth) = pix_fmt.split_once('p')?;
+      let chroma_sampling = match sampling {
+        "yuv420" => ChromaSampling::Cs420,
+        "yuv422" => ChromaSampling::Cs422,
+        "yuv444" => ChromaSampling::Cs444,
+        _ => return None,
+      };
+      (chroma_sampling, bit_depth)
+    }
+  };
+  let bit_depth = match bit_depth {
+    "" => 8,
+    "10le" => 10,
+    "12le" => 12,
+    _ => return None,
+  };
+  Some((chroma_sampling, bit_depth))
+}
+
+pub struct RawDecoder {
+  input: Box<dyn Read + Send>,
+  details: VideoDetails,
+  buf: Vec<u8>,
+}
+
+impl RawDecoder {
+  pub fn new(input: Box<dyn Read + Send>, details: VideoDetails) -> Self {
+    let bytes = if details.bit_depth > 8 { 2 } else { 1 };
+    let (chroma_width, chroma_height) = details
+      .chroma_sampling
+      .get_chroma_dimensions(details.width, details.height);
+    let frame_size = (details.width * details.height
+      + 2 * chroma_width * chroma_height)
+      * bytes;
+    RawDecoder { input, details, buf: vec![0; frame_size] }
+  }
+
+  /// Reads the next frame of the input into the buffer.
+  pub fn read_raw_frame(&mut self) -> Result<(), DecodeError> {
+    self.input.read_exact(&mut self.buf).map_err(|e| match e.kind() {
+      io::ErrorKind::UnexpectedEof => DecodeError::EOF,
+      _ => DecodeError::IoError,
+    })
+  }
+}
+
+impl Decoder for RawDecoder {
+  fn get_video_details(&self) -> VideoDetails {
+    self.details
+  }
+
+  fn read_frame<T: Pixel, F: FrameBuilder<T>>(
+    &mut self, ctx: &F, cfg: &VideoDetails,
+  ) -> Result<Frame<T>, DecodeError> {
+    self.read_raw_frame()?;
+    let mut f = ctx.new_frame();
+
+    // The layout of the input follows its own pixel format, even when only
+    // its luma is encoded.
+    let bytes = if self.details.bit_depth > 8 { 2 } else { 1 };
+    let (chroma_width, chroma_height) = self
+      .details
+      .chroma_sampling
+      .get_chroma_dimensions(self.details.width, self.details.height);
+    let (luma, chroma) =
+      self.buf.split_at(self.details.width * self.details.height * bytes);
+    let (u, v) = chroma.split_at(chroma_width * chroma_height * bytes);
+
+    f.planes[0].copy_from_raw_u8(luma, self.details.width * bytes, bytes);
+    if cfg.chroma_sampling != Cs400 {
+      f.planes[1].copy_from_raw_u8(u, chroma_width * bytes, bytes);
+      f.planes[2].copy_from_raw_u8(v, chroma_width * bytes, bytes);
+    }
+    Ok(f)
+  }
+}
@@ -11,7 +11,9 @@
 use std::io::Read;
 
 use crate::color::ChromaSampling::Cs400;
-use crate::decoder::{DecodeError, Decoder, FrameBuilder, VideoDetails};
+use crate::decoder::{
+  parse_timecode, DecodeError, Decoder, FrameBuilder, VideoDetails,
+};
 use rav1e::prelude::*;
 
 impl Decoder for y4m::Decoder<Box<dyn Read + Send>> {
@@ -25,6 +27,20 @@ impl Decoder for y4m::Decoder<Box<dyn Read + Send>> {
       map_y4m_color_space(color_space);
     let framerate = self.get_framerate();
     let time_base = Rational::new(framerate.den as u64, framerate.num as u64);
+    // The timecode of the first frame may be given by an XTIMECODE=
+    // vendor extension.
+    let timecode = self
+      .get_raw_params()
+      .split(|&b| b == b' ')
+      .find_map(|param| param.strip_prefix(b"XTIMECODE="))
+      .and_then(|value| {
+        let timecode =
+          std::str::from_utf8(value).ok().and_then(parse_timecode);
+        if timecode.is_none() {
+          warn!("Ignoring the invalid Y4M timecode");
+        }
+        timecode
+      });
 
     VideoDetails {
       width,
@@ -38,6 +54,8 @@ impl Decoder for y4m::Decoder<Box<dyn Read + Send>> {
       chroma_sampling,
       chroma_sample_position,
       time_base,
+      timecode,
+      alpha: false,
     }
   }
 
@@ -107,3 +125,25 @@ pub const fn map_y4m_color_space(
     _ => unimplemented!(),
   }
 }
+
+/// Returns the Y4M colorspace of a chroma sampling and bit depth, if any.
+pub const fn y4m_colorspace(
+  chroma_sampling: ChromaSampling, bit_depth: usize,
+) -> Option<y4m::Colorspace> {
+  use crate::ChromaSampling::*;
+  use y4m::Colorspace::*;
+  Some(match (chroma_sampling, bit_depth) {
+    (Cs400, 8) => Cmono,
+    (Cs400, 12) => Cmono12,
+    (Cs420, 8) => C420,
+    (Cs420, 10) => C420p10,
+    (Cs420, 12) => C420p12,
+    (Cs422, 8) => C422,
+    (Cs422, 10) => C422p10,
+    (Cs422, 12) => C422p12,
+    (Cs444, 8) => C444,
+    (Cs444, 10) => C444p10,
+    (Cs444, 12) => C444p12,
+    _ => return None,
+  })
+}
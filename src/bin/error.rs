@@ -37,9 +37,14 @@ impl ToError for std::num::ParseIntError {
 }
 
 impl ToError for std::io::Error {}
+impl ToError for image::ImageError {}
 impl ToError for rav1e::InvalidConfig {}
 impl ToError for rav1e::EncoderStatus {}
 impl ToError for rav1e::config::RateControlError {}
+#[cfg(feature = "serialize")]
+impl ToError for toml::de::Error {}
+#[cfg(feature = "serialize")]
+impl ToError for toml::ser::Error {}
 
 pub fn print_error(e: &dyn std::error::Error) {
   error!("{}", e);
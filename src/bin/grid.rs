@@ -0,0 +1,124 @@
+// Copyright (c) 2017-2022, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+//! Encoding of an AVIF image as a grid of tiles, each coded as a separate
+//! still picture the size of the encoded frames.
+
+use std::sync::Arc;
+
+use rav1e::prelude::*;
+
+use crate::common::*;
+use crate::decoder::{Decoder, FrameBuilder, VideoDetails};
+use crate::error::*;
+use crate::muxer::*;
+use crate::stats::*;
+use crate::{finish_encode, report_frames, Source};
+
+/// Allocates the frames of the whole input image.
+struct ImageBuilder(VideoDetails);
+
+impl<T: Pixel> FrameBuilder<T> for ImageBuilder {
+  fn new_frame(&self) -> Frame<T> {
+    let VideoDetails { width, height, chroma_sampling, .. } = self.0;
+    Frame::new_with_padding(width, height, chroma_sampling, 0)
+  }
+}
+
+/// Copies the area of `src` at `(x, y)` to `dst`, repeating the last column
+/// and row of `src` past its edges.
+fn copy_tile<T: Pixel>(
+  dst: &mut Plane<T>, src: &Plane<T>, x: usize, y: usize,
+) {
+  let (src_width, src_height) = (src.cfg.width, src.cfg.height);
+  for (row, dst_row) in dst.rows_iter_mut().enumerate() {
+    let src_y = (y + row).min(src_height - 1);
+    for (col, pixel) in dst_row.iter_mut().enumerate() {
+      *pixel = src.p((x + col).min(src_width - 1), src_y);
+    }
+  }
+}
+
+/// Encodes the tiles of the input image in row-major order, each with its
+/// own encoder, and writes them as a grid.
+pub fn do_encode_grid<T: Pixel, D: Decoder>(
+  cfg: Config, enc: &EncoderConfig, grid: &ImageGrid, verbose: Verboseness,
+  mut progress: ProgressInfo, output: &mut dyn Muxer, mut source: Source<D>,
+  metrics_enabled: MetricsEnabled, mut stats_file: Option<StatsWriter>,
+) -> Result<(), CliError> {
+  let video_info = source.video_info;
+  let builder = ImageBuilder(video_info);
+  let image: Frame<T> = source
+    .input
+    .read_frame(&builder, &video_info)
+    .map_err(|_| CliError::new("Unable to read the input image"))?;
+  let alpha =
+    if video_info.alpha { source.input.read_alpha(&builder) } else { None };
+  let planes =
+    if enc.chroma_sampling == ChromaSampling::Cs400 { 1 } else { 3 };
+
+  for index in 0..grid.rows * grid.columns {
+    let mut ctx: Context<T> =
+      cfg.new_context().map_err(|e| e.context("Invalid encoder settings"))?;
+    if index == 0 {
+      output.write_header(enc, &ctx.container_sequence_header());
+      if let Some(av1c) = ctx.alpha_container_sequence_header() {
+        output.write_alpha_header(&av1c);
+      }
+    }
+
+    let x = index % grid.columns * enc.width;
+    let y = index / grid.columns * enc.height;
+    let mut tile = ctx.new_frame();
+    for (dst, src) in tile.planes.iter_mut().zip(&image.planes).take(planes) {
+      let (xdec, ydec) = (dst.cfg.xdec, dst.cfg.ydec);
+      copy_tile(dst, src, x >> xdec, y >> ydec);
+    }
+    let params = alpha.as_ref().map(|alpha| {
+      let mut plane = ctx.new_alpha_plane();
+      copy_tile(&mut plane, alpha, x, y);
+      FrameParameters {
+        alpha: Some(AlphaPlane::new(plane)),
+        ..Default::default()
+      }
+    });
+    ctx
+      .send_frame((Arc::new(tile), params))
+      .map_err(|e| e.context("Failed to encode image"))?;
+    ctx.flush();
+
+    loop {
+      match ctx.receive_packet() {
+        Ok(pkt) => {
+          output.write_frame(pkt.input_frameno, &pkt.data, pkt.frame_type);
+          if let Some(alpha_data) = &pkt.alpha_data {
+            output.write_alpha_frame(alpha_data);
+          }
+          let summary = build_frame_summary(
+            pkt,
+            enc.bit_depth,
+            enc.chroma_sampling,
+            metrics_enabled,
+          );
+          report_frames(
+            vec![summary],
+            verbose,
+            &mut progress,
+            stats_file.as_mut(),
+            &mut *output,
+          )?;
+        }
+        Err(EncoderStatus::Encoded) => {}
+        Err(EncoderStatus::LimitReached) => break,
+        Err(e) => return Err(e.context("Failed to encode image")),
+      }
+    }
+  }
+  finish_encode(verbose, progress, output, stats_file)
+}
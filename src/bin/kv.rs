@@ -5,7 +5,31 @@ use thiserror::*;
 use std::fmt;
 
 struct KVString {
-  output: String,
+  /// The key of the value being serialized, made of the names of the fields
+  /// of the nested structs holding it.
+  path: Vec<&'static str>,
+  pairs: Vec<(String, String)>,
+  /// The start of the values of each sequence being serialized, which are
+  /// joined into a single one at its end.
+  seqs: Vec<usize>,
+}
+
+impl KVString {
+  fn push_value(&mut self, value: String) -> Result<(), Error> {
+    self.pairs.push((self.path.join("."), value));
+    Ok(())
+  }
+
+  fn start_seq(&mut self) {
+    self.seqs.push(self.pairs.len());
+  }
+
+  fn end_seq(&mut self) -> Result<(), Error> {
+    let start = self.seqs.pop().ok_or(Error::Unsupported)?;
+    let values =
+      self.pairs.drain(start..).map(|(_, value)| value).collect::<Vec<_>>();
+    self.push_value(format!("[{}]", values.join(", ")))
+  }
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, Debug, Error)]
@@ -26,16 +50,38 @@ pub fn to_string<T>(value: &T) -> Result<String, ()>
 where
   T: Serialize,
 {
-  let mut serializer = KVString { output: String::new() };
+  let pairs = to_pairs(value)?
+    .into_iter()
+    .map(
+      |(key, value)| {
+        if key.is_empty() {
+          value
+        } else {
+          format!("{key} = {value}")
+        }
+      },
+    )
+    .collect::<Vec<_>>();
+  Ok(pairs.join(", "))
+}
+
+/// Serialize a configuration as a list of keys and values, the fields of
+/// nested structs having keys joined by dots such as `partition.min`.
+pub fn to_pairs<T>(value: &T) -> Result<Vec<(String, String)>, ()>
+where
+  T: Serialize,
+{
+  let mut serializer =
+    KVString { path: Vec::new(), pairs: Vec::new(), seqs: Vec::new() };
   value.serialize(&mut serializer).map_err(|_| ())?;
-  Ok(serializer.output)
+  Ok(serializer.pairs)
 }
 
 impl Serializer for &mut KVString {
   type Ok = ();
   type Error = Error;
-  type SerializeSeq = ser::Impossible<(), Self::Error>;
-  type SerializeTuple = ser::Impossible<(), Self::Error>;
+  type SerializeSeq = Self;
+  type SerializeTuple = Self;
   type SerializeTupleStruct = ser::Impossible<(), Self::Error>;
   type SerializeTupleVariant = ser::Impossible<(), Self::Error>;
   type SerializeMap = ser::Impossible<(), Self::Error>;
@@ -43,8 +89,7 @@ impl Serializer for &mut KVString {
   type SerializeStructVariant = ser::Impossible<(), Self::Error>;
 
   fn serialize_bool(self, v: bool) -> Result<Self::Ok, Self::Error> {
-    self.output += if v { "true" } else { "false" };
-    Ok(())
+    self.push_value(v.to_string())
   }
   fn serialize_i8(self, v: i8) -> Result<(), Self::Error> {
     self.serialize_i64(i64::from(v))
@@ -59,8 +104,7 @@ impl Serializer for &mut KVString {
   }
 
   fn serialize_i64(self, v: i64) -> Result<(), Self::Error> {
-    self.output += &v.to_string();
-    Ok(())
+    self.push_value(v.to_string())
   }
 
   fn serialize_u8(self, v: u8) -> Result<(), Self::Error> {
@@ -76,43 +120,36 @@ impl Serializer for &mut KVString {
   }
 
   fn serialize_u64(self, v: u64) -> Result<(), Self::Error> {
-    self.output += &v.to_string();
-    Ok(())
+    self.push_value(v.to_string())
   }
   fn serialize_f32(self, v: f32) -> Result<(), Self::Error> {
     self.serialize_f64(f64::from(v))
   }
 
   fn serialize_f64(self, v: f64) -> Result<(), Self::Error> {
-    self.output += &v.to_string();
-    Ok(())
+    self.push_value(v.to_string())
   }
   fn serialize_char(self, v: char) -> Result<Self::Ok, Self::Error> {
     unimplemented!()
   }
   fn serialize_str(self, v: &str) -> Result<Self::Ok, Self::Error> {
-    self.output += v;
-    Ok(())
+    self.push_value(v.to_owned())
   }
 
   fn serialize_bytes(self, v: &[u8]) -> Result<Self::Ok, Self::Error> {
     unimplemented!()
   }
   fn serialize_none(self) -> Result<Self::Ok, Self::Error> {
-    self.output += "None";
-    Ok(())
+    self.push_value("None".to_owned())
   }
-  fn serialize_some<T: ?Sized>(
-    self, value: &T,
-  ) -> Result<Self::Ok, Self::Error>
+  fn serialize_some<T>(self, value: &T) -> Result<Self::Ok, Self::Error>
   where
-    T: Serialize,
+    T: ?Sized + Serialize,
   {
     value.serialize(self)
   }
   fn serialize_unit(self) -> Result<Self::Ok, Self::Error> {
-    self.output += "";
-    Ok(())
+    self.push_value(String::new())
   }
   fn serialize_unit_struct(
     self, name: &'static str,
@@ -126,21 +163,21 @@ impl Serializer for &mut KVString {
     self.serialize_str(variant)
   }
 
-  fn serialize_newtype_struct<T: ?Sized>(
+  fn serialize_newtype_struct<T>(
     self, name: &'static str, value: &T,
   ) -> Result<Self::Ok, Self::Error>
   where
-    T: Serialize,
+    T: ?Sized + Serialize,
   {
     unimplemented!()
   }
 
-  fn serialize_newtype_variant<T: ?Sized>(
+  fn serialize_newtype_variant<T>(
     self, name: &'static str, variant_index: u32, variant: &'static str,
     value: &T,
   ) -> Result<Self::Ok, Self::Error>
   where
-    T: Serialize,
+    T: ?Sized + Serialize,
   {
     unimplemented!()
   }
@@ -148,12 +185,14 @@ impl Serializer for &mut KVString {
   fn serialize_seq(
     self, len: Option<usize>,
   ) -> Result<Self::SerializeSeq, Self::Error> {
-    unimplemented!()
+    self.start_seq();
+    Ok(self)
   }
   fn serialize_tuple(
     self, len: usize,
   ) -> Result<Self::SerializeTuple, Self::Error> {
-    unimplemented!()
+    self.start_seq();
+    Ok(self)
   }
   fn serialize_tuple_struct(
     self, name: &'static str, len: usize,
@@ -184,23 +223,20 @@ impl Serializer for &mut KVString {
   }
 }
 
-impl<'a> ser::SerializeStruct for &mut KVString {
+impl ser::SerializeStruct for &mut KVString {
   type Ok = ();
   type Error = Error;
 
-  // Assume a single flat struct for now
   fn serialize_field<T>(
     &mut self, key: &'static str, value: &T,
   ) -> Result<(), Self::Error>
   where
     T: ?Sized + Serialize,
   {
-    if !self.output.is_empty() {
-      self.output += ", "
-    }
-    self.output += key;
-    self.output += " = ";
-    value.serialize(&mut **self)
+    self.path.push(key);
+    let result = value.serialize(&mut **self);
+    self.path.pop();
+    result
   }
 
   fn end(self) -> Result<(), Self::Error> {
@@ -208,9 +244,41 @@ impl<'a> ser::SerializeStruct for &mut KVString {
   }
 }
 
+impl ser::SerializeSeq for &mut KVString {
+  type Ok = ();
+  type Error = Error;
+
+  fn serialize_element<T>(&mut self, value: &T) -> Result<(), Self::Error>
+  where
+    T: ?Sized + Serialize,
+  {
+    value.serialize(&mut **self)
+  }
+
+  fn end(self) -> Result<(), Self::Error> {
+    self.end_seq()
+  }
+}
+
+impl ser::SerializeTuple for &mut KVString {
+  type Ok = ();
+  type Error = Error;
+
+  fn serialize_element<T>(&mut self, value: &T) -> Result<(), Self::Error>
+  where
+    T: ?Sized + Serialize,
+  {
+    value.serialize(&mut **self)
+  }
+
+  fn end(self) -> Result<(), Self::Error> {
+    self.end_seq()
+  }
+}
+
 #[cfg(test)]
 mod test {
-  use rav1e::prelude::SpeedSettings;
+  use rav1e::prelude::{EncoderConfig, SpeedSettings};
 
   #[test]
   fn serialize_speed_settings() {
@@ -220,4 +288,15 @@ mod test {
       println!("preset {}: {}", preset, out);
     }
   }
+
+  #[test]
+  fn serialize_encoder_config() {
+    let enc = EncoderConfig::default();
+    let pairs = super::to_pairs(&enc).unwrap();
+    assert!(pairs.contains(&("time_base".to_owned(), "[1, 30]".to_owned())));
+    assert!(pairs.contains(&("level_idx".to_owned(), "None".to_owned())));
+    assert!(pairs
+      .iter()
+      .any(|(key, _)| key == "speed_settings.partition.encode_bottomup"));
+  }
 }
@@ -0,0 +1,127 @@
+// Copyright (c) 2017-2022, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+use super::Muxer;
+use crate::error::*;
+use rav1e::prelude::*;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+/// Returns the size of the tiles of a grid covering its image, rounded up
+/// to whole chroma samples.
+///
+/// # Errors
+///
+/// Returns an error if the grid has no tiles, more than 256 rows or columns,
+/// or tiles outside of the image.
+pub fn grid_tile_size(
+  grid: &ImageGrid, chroma_sampling: ChromaSampling,
+) -> Result<(usize, usize), CliError> {
+  if !(1..=256).contains(&grid.rows) || !(1..=256).contains(&grid.columns) {
+    return Err(CliError::new(
+      "A grid must have between 1 and 256 rows and columns",
+    ));
+  }
+  let (ss_x, ss_y) = chroma_sampling.get_decimation().unwrap_or((0, 0));
+  let tile_size = |size: usize, count: usize, ss| {
+    size.div_ceil(count).next_multiple_of(1usize << ss)
+  };
+  let width = tile_size(grid.width, grid.columns, ss_x);
+  let height = tile_size(grid.height, grid.rows, ss_y);
+  if (grid.columns - 1) * width >= grid.width
+    || (grid.rows - 1) * height >= grid.height
+  {
+    return Err(CliError::new(
+      "The grid has tiles outside of the image, use fewer rows or columns",
+    ));
+  }
+  Ok((width, height))
+}
+
+/// Writes the pictures of a still picture encode as an AVIF image: its
+/// single picture, or the tiles of its grid, with their alpha planes.
+pub struct AvifMuxer {
+  output: Box<dyn Write + Send>,
+  grid: Option<ImageGrid>,
+  config: Option<(EncoderConfig, Vec<u8>)>,
+  alpha_av1c: Option<Vec<u8>>,
+  /// The coded pictures, with their coded alpha planes.
+  tiles: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+}
+
+impl AvifMuxer {
+  pub fn open<P: AsRef<Path>>(
+    path: P, grid: Option<ImageGrid>,
+  ) -> Result<Box<dyn Muxer + Send>, CliError> {
+    let output: Box<dyn Write + Send> = match path.as_ref().to_str() {
+      Some("-") => Box::new(io::stdout()),
+      _ => Box::new(BufWriter::new(
+        File::create(path)
+          .map_err(|e| e.context("Cannot open output file"))?,
+      )),
+    };
+    Ok(Box::new(AvifMuxer {
+      output,
+      grid,
+      config: None,
+      alpha_av1c: None,
+      tiles: Vec::new(),
+    }))
+  }
+}
+
+impl Muxer for AvifMuxer {
+  fn write_header(&mut self, config: &EncoderConfig, av1c: &[u8]) {
+    self.config = Some((config.clone(), av1c.to_vec()));
+  }
+
+  fn write_frame(&mut self, _pts: u64, data: &[u8], _frame_type: FrameType) {
+    self.tiles.push((data.to_vec(), None));
+  }
+
+  fn write_alpha_header(&mut self, av1c: &[u8]) {
+    self.alpha_av1c = Some(av1c.to_vec());
+  }
+
+  fn write_alpha_frame(&mut self, data: &[u8]) {
+    let (_, alpha_data) = self.tiles.last_mut().expect("no frame written");
+    *alpha_data = Some(data.to_vec());
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    self.output.flush()
+  }
+
+  fn finish(&mut self) -> io::Result<()> {
+    let (config, av1c) = self.config.as_ref().expect("header not written");
+    if self.tiles.is_empty() {
+      return Err(io::Error::new(io::ErrorKind::InvalidData, "No picture"));
+    }
+    let tiles = self
+      .tiles
+      .iter()
+      .map(|(data, alpha_data)| AvifTile {
+        data,
+        alpha_data: alpha_data.as_deref(),
+      })
+      .collect::<Vec<_>>();
+    let image = avif_image(
+      config,
+      av1c,
+      self.alpha_av1c.as_deref(),
+      &tiles,
+      self.grid.as_ref(),
+    )
+    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    self.output.write_all(&image)?;
+    self.flush()
+  }
+}
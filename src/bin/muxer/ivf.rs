@@ -23,16 +23,13 @@ pub struct IvfMuxer {
 }
 
 impl Muxer for IvfMuxer {
-  fn write_header(
-    &mut self, width: usize, height: usize, framerate_num: usize,
-    framerate_den: usize,
-  ) {
+  fn write_header(&mut self, config: &EncoderConfig, _av1c: &[u8]) {
     write_ivf_header(
       &mut self.output,
-      width,
-      height,
-      framerate_num,
-      framerate_den,
+      config.width,
+      config.height,
+      config.time_base.den as usize,
+      config.time_base.num as usize,
     );
   }
 
@@ -97,4 +94,9 @@ impl IvfMuxer {
     };
     Ok(Box::new(ivf))
   }
+
+  /// Creates a muxer appending frames to an output whose header is written.
+  pub fn append(output: File) -> Box<dyn Muxer + Send> {
+    Box::new(IvfMuxer { output: Box::new(output) })
+  }
 }
@@ -0,0 +1,514 @@
+// Copyright (c) 2017-2022, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+//! Matroska and `WebM` output, following the AV1 Codec Mapping for Matroska.
+
+use super::obu::{
+  is_sync_sample, sequence_header_obu, without_temporal_delimiter,
+};
+use super::Muxer;
+use crate::error::*;
+use rav1e::prelude::*;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const EBML: u32 = 0x1A45_DFA3;
+const EBML_VERSION: u32 = 0x4286;
+const EBML_READ_VERSION: u32 = 0x42F7;
+const EBML_MAX_ID_LENGTH: u32 = 0x42F2;
+const EBML_MAX_SIZE_LENGTH: u32 = 0x42F3;
+const DOC_TYPE: u32 = 0x4282;
+const DOC_TYPE_VERSION: u32 = 0x4287;
+const DOC_TYPE_READ_VERSION: u32 = 0x4285;
+
+const SEGMENT: u32 = 0x1853_8067;
+const SEEK_HEAD: u32 = 0x114D_9B74;
+const SEEK: u32 = 0x4DBB;
+const SEEK_ID: u32 = 0x53AB;
+const SEEK_POSITION: u32 = 0x53AC;
+
+const INFO: u32 = 0x1549_A966;
+const TIMESTAMP_SCALE: u32 = 0x2A_D7B1;
+const MUXING_APP: u32 = 0x4D80;
+const WRITING_APP: u32 = 0x5741;
+const DURATION: u32 = 0x4489;
+
+const TRACKS: u32 = 0x1654_AE6B;
+const TRACK_ENTRY: u32 = 0xAE;
+const TRACK_NUMBER: u32 = 0xD7;
+const TRACK_UID: u32 = 0x73C5;
+const TRACK_TYPE: u32 = 0x83;
+const CODEC_ID: u32 = 0x86;
+const CODEC_PRIVATE: u32 = 0x63A2;
+const DEFAULT_DURATION: u32 = 0x23_E383;
+const VIDEO: u32 = 0xE0;
+const PIXEL_WIDTH: u32 = 0xB0;
+const PIXEL_HEIGHT: u32 = 0xBA;
+const DISPLAY_WIDTH: u32 = 0x54B0;
+const DISPLAY_HEIGHT: u32 = 0x54BA;
+
+const COLOUR: u32 = 0x55B0;
+const MATRIX_COEFFICIENTS: u32 = 0x55B1;
+const BITS_PER_CHANNEL: u32 = 0x55B2;
+const CHROMA_SUBSAMPLING_HORZ: u32 = 0x55B3;
+const CHROMA_SUBSAMPLING_VERT: u32 = 0x55B4;
+const CHROMA_SITING_HORZ: u32 = 0x55B7;
+const CHROMA_SITING_VERT: u32 = 0x55B8;
+const RANGE: u32 = 0x55B9;
+const TRANSFER_CHARACTERISTICS: u32 = 0x55BA;
+const PRIMARIES: u32 = 0x55BB;
+const MAX_CLL: u32 = 0x55BC;
+const MAX_FALL: u32 = 0x55BD;
+const MASTERING_METADATA: u32 = 0x55D0;
+const PRIMARY_R_CHROMATICITY_X: u32 = 0x55D1;
+const LUMINANCE_MAX: u32 = 0x55D9;
+const LUMINANCE_MIN: u32 = 0x55DA;
+
+const CLUSTER: u32 = 0x1F43_B675;
+const TIMESTAMP: u32 = 0xE7;
+const SIMPLE_BLOCK: u32 = 0xA3;
+
+const CUES: u32 = 0x1C53_BB6B;
+const CUE_POINT: u32 = 0xBB;
+const CUE_TIME: u32 = 0xB3;
+const CUE_TRACK_POSITIONS: u32 = 0xB7;
+const CUE_TRACK: u32 = 0xF7;
+const CUE_CLUSTER_POSITION: u32 = 0xF1;
+
+const VOID: u32 = 0xEC;
+
+/// The size of an element whose size is not known when it is written.
+const UNKNOWN_SIZE: u64 = 0x01FF_FFFF_FFFF_FFFF;
+
+/// Timestamps are in milliseconds.
+const TIMESTAMP_SCALE_NS: u64 = 1_000_000;
+
+fn write_id(out: &mut Vec<u8>, id: u32) {
+  let bytes = id.to_be_bytes();
+  out.extend(&bytes[id.leading_zeros() as usize / 8..]);
+}
+
+/// Writes an element data size as a variable size integer of minimal length.
+fn write_size(out: &mut Vec<u8>, size: u64) {
+  let len = (1..8).find(|len| size < (1 << (7 * len)) - 1).unwrap_or(8);
+  let bytes = (size | 1 << (7 * len)).to_be_bytes();
+  out.extend(&bytes[8 - len..]);
+}
+
+fn write_element(
+  out: &mut Vec<u8>, id: u32, content: impl FnOnce(&mut Vec<u8>),
+) {
+  let mut data = Vec::new();
+  content(&mut data);
+  write_binary(out, id, &data);
+}
+
+fn write_binary(out: &mut Vec<u8>, id: u32, data: &[u8]) {
+  write_id(out, id);
+  write_size(out, data.len() as u64);
+  out.extend(data);
+}
+
+fn write_uint(out: &mut Vec<u8>, id: u32, value: u64) {
+  let bytes = value.to_be_bytes();
+  let len = (8 - value.leading_zeros() as usize / 8).max(1);
+  write_binary(out, id, &bytes[8 - len..]);
+}
+
+/// Writes an unsigned integer on 8 bytes, so that it can be patched later.
+fn write_fixed_uint(out: &mut Vec<u8>, id: u32, value: u64) {
+  write_binary(out, id, &value.to_be_bytes());
+}
+
+fn write_float(out: &mut Vec<u8>, id: u32, value: f64) {
+  write_binary(out, id, &value.to_be_bytes());
+}
+
+enum Output {
+  File(BufWriter<File>),
+  Stream(Box<dyn Write + Send>),
+}
+
+impl Write for Output {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    match self {
+      Output::File(file) => file.write(buf),
+      Output::Stream(stream) => stream.write(buf),
+    }
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    match self {
+      Output::File(file) => file.flush(),
+      Output::Stream(stream) => stream.flush(),
+    }
+  }
+}
+
+/// Positions in the output file of the values only known once every frame
+/// is written.
+struct Patches {
+  segment_size: u64,
+  duration: u64,
+  /// Position and size of the seek entry of the cues, voided if there are
+  /// none.
+  cues_seek: (u64, usize),
+}
+
+pub struct MkvMuxer {
+  output: Output,
+  webm: bool,
+  config: Option<(EncoderConfig, Vec<u8>)>,
+  header_written: bool,
+  patches: Option<Patches>,
+  /// Number of bytes written in the segment.
+  position: u64,
+  /// Blocks of the current cluster.
+  cluster: Vec<u8>,
+  cluster_timestamp: u64,
+  cluster_is_sync: bool,
+  /// Timestamps and positions in the segment of the clusters starting with
+  /// a keyframe.
+  cues: Vec<(u64, u64)>,
+  end_timestamp: u64,
+}
+
+impl MkvMuxer {
+  pub fn open<P: AsRef<Path>>(
+    path: P, webm: bool,
+  ) -> Result<Box<dyn Muxer + Send>, CliError> {
+    let output = match path.as_ref().to_str() {
+      Some("-") => Output::Stream(Box::new(io::stdout())),
+      _ => Output::File(BufWriter::new(
+        File::create(path)
+          .map_err(|e| e.context("Cannot open output file"))?,
+      )),
+    };
+    Ok(Box::new(MkvMuxer {
+      output,
+      webm,
+      config: None,
+      header_written: false,
+      patches: None,
+      position: 0,
+      cluster: Vec::new(),
+      cluster_timestamp: 0,
+      cluster_is_sync: false,
+      cues: Vec::new(),
+      end_timestamp: 0,
+    }))
+  }
+
+  fn timestamp(&self, pts: u64) -> u64 {
+    let (enc, _) = self.config.as_ref().expect("header not written");
+    pts * enc.time_base.num * 1000 / enc.time_base.den
+  }
+
+  fn write_tracks(
+    out: &mut Vec<u8>, enc: &EncoderConfig, codec_private: &[u8],
+  ) {
+    write_element(out, TRACKS, |out| {
+      write_element(out, TRACK_ENTRY, |out| {
+        write_uint(out, TRACK_NUMBER, 1);
+        write_uint(out, TRACK_UID, 1);
+        write_uint(out, TRACK_TYPE, 1); // video
+        write_binary(out, CODEC_ID, b"V_AV1");
+        write_binary(out, CODEC_PRIVATE, codec_private);
+        let Rational { num, den } = enc.time_base;
+        write_uint(out, DEFAULT_DURATION, num * 1_000_000_000 / den);
+
+        write_element(out, VIDEO, |out| {
+          write_uint(out, PIXEL_WIDTH, enc.width as u64);
+          write_uint(out, PIXEL_HEIGHT, enc.height as u64);
+          let Rational { num, den } = enc.sample_aspect_ratio;
+          if num != den {
+            write_uint(out, DISPLAY_WIDTH, enc.width as u64 * num / den);
+            write_uint(out, DISPLAY_HEIGHT, enc.height as u64);
+          }
+          write_element(out, COLOUR, |out| Self::write_colour(out, enc));
+        });
+      });
+    });
+  }
+
+  fn write_colour(out: &mut Vec<u8>, enc: &EncoderConfig) {
+    if let Some(color_description) = enc.color_description {
+      write_uint(
+        out,
+        MATRIX_COEFFICIENTS,
+        color_description.matrix_coefficients as u64,
+      );
+    }
+    write_uint(out, BITS_PER_CHANNEL, enc.bit_depth as u64);
+    let subsampling = match enc.chroma_sampling {
+      ChromaSampling::Cs420 => Some((1, 1)),
+      ChromaSampling::Cs422 => Some((1, 0)),
+      ChromaSampling::Cs444 => Some((0, 0)),
+      ChromaSampling::Cs400 => None,
+    };
+    if let Some((horz, vert)) = subsampling {
+      write_uint(out, CHROMA_SUBSAMPLING_HORZ, horz);
+      write_uint(out, CHROMA_SUBSAMPLING_VERT, vert);
+    }
+    if enc.chroma_sampling == ChromaSampling::Cs420 {
+      // 1 is collocated with the first luma sample, 2 is half way.
+      let siting = match enc.chroma_sample_position {
+        ChromaSamplePosition::Unknown => None,
+        ChromaSamplePosition::Vertical => Some((1, 2)),
+        ChromaSamplePosition::Colocated => Some((1, 1)),
+      };
+      if let Some((horz, vert)) = siting {
+        write_uint(out, CHROMA_SITING_HORZ, horz);
+        write_uint(out, CHROMA_SITING_VERT, vert);
+      }
+    }
+    let range = match enc.pixel_range {
+      PixelRange::Limited => 1,
+      PixelRange::Full => 2,
+    };
+    write_uint(out, RANGE, range);
+    if let Some(color_description) = enc.color_description {
+      write_uint(
+        out,
+        TRANSFER_CHARACTERISTICS,
+        color_description.transfer_characteristics as u64,
+      );
+      write_uint(out, PRIMARIES, color_description.color_primaries as u64);
+    }
+
+    if let Some(cll) = enc.content_light {
+      write_uint(out, MAX_CLL, cll.max_content_light_level.into());
+      write_uint(out, MAX_FALL, cll.max_frame_average_light_level.into());
+    }
+    if let Some(mdcv) = enc.mastering_display {
+      write_element(out, MASTERING_METADATA, |out| {
+        let chromaticity = |v: u16| f64::from(v) / 65536.;
+        // The red, green, blue and white point x and y chromaticities have
+        // consecutive IDs.
+        let points =
+          mdcv.primaries.iter().chain(std::iter::once(&mdcv.white_point));
+        for (i, point) in points.enumerate() {
+          let id = PRIMARY_R_CHROMATICITY_X + 2 * i as u32;
+          write_float(out, id, chromaticity(point.x));
+          write_float(out, id + 1, chromaticity(point.y));
+        }
+        write_float(out, LUMINANCE_MAX, f64::from(mdcv.max_luminance) / 256.);
+        write_float(
+          out,
+          LUMINANCE_MIN,
+          f64::from(mdcv.min_luminance) / 16384.,
+        );
+      });
+    }
+  }
+
+  /// Writes the EBML header and the start of the segment, up to the first
+  /// cluster.
+  fn write_segment_header(&mut self, config_obus: &[u8]) -> io::Result<()> {
+    let (enc, av1c) = self.config.as_ref().expect("header not written");
+    let seekable = matches!(self.output, Output::File(_));
+
+    let mut out = Vec::new();
+    write_element(&mut out, EBML, |out| {
+      write_uint(out, EBML_VERSION, 1);
+      write_uint(out, EBML_READ_VERSION, 1);
+      write_uint(out, EBML_MAX_ID_LENGTH, 4);
+      write_uint(out, EBML_MAX_SIZE_LENGTH, 8);
+      let doc_type = if self.webm { "webm" } else { "matroska" };
+      write_binary(out, DOC_TYPE, doc_type.as_bytes());
+      write_uint(out, DOC_TYPE_VERSION, 4);
+      write_uint(out, DOC_TYPE_READ_VERSION, 2);
+    });
+    // The size of the segment is patched once complete, if possible.
+    write_id(&mut out, SEGMENT);
+    let segment_size = out.len() as u64;
+    out.extend(UNKNOWN_SIZE.to_be_bytes());
+    let segment_start = out.len() as u64;
+
+    let mut info = Vec::new();
+    write_element(&mut info, INFO, |out| {
+      write_uint(out, TIMESTAMP_SCALE, TIMESTAMP_SCALE_NS);
+      write_binary(out, MUXING_APP, b"rav1e");
+      write_binary(
+        out,
+        WRITING_APP,
+        format!("rav1e {}", rav1e::version::full()).as_bytes(),
+      );
+      if seekable {
+        // Patched once complete.
+        write_float(out, DURATION, 0.);
+      }
+    });
+    let mut codec_private = av1c.clone();
+    codec_private.extend(config_obus);
+    let mut tracks = Vec::new();
+    Self::write_tracks(&mut tracks, enc, &codec_private);
+
+    let write_seek = |out: &mut Vec<u8>, id: u32, position: u64| {
+      write_element(out, SEEK, |out| {
+        let mut seek_id = Vec::new();
+        write_id(&mut seek_id, id);
+        write_binary(out, SEEK_ID, &seek_id);
+        write_fixed_uint(out, SEEK_POSITION, position);
+      });
+    };
+    let write_seek_head = |out: &mut Vec<u8>, positions: [u64; 3]| {
+      write_element(out, SEEK_HEAD, |out| {
+        let ids = [INFO, TRACKS, CUES];
+        let count = if seekable { 3 } else { 2 };
+        for (id, position) in ids.into_iter().zip(positions).take(count) {
+          write_seek(out, id, position);
+        }
+      });
+    };
+    // The positions are written on a fixed size, so the size of the seek
+    // head does not depend on them.
+    let mut seek_head = Vec::new();
+    write_seek_head(&mut seek_head, [0; 3]);
+    let info_position = seek_head.len() as u64;
+    let tracks_position = info_position + info.len() as u64;
+    seek_head.clear();
+    write_seek_head(&mut seek_head, [info_position, tracks_position, 0]);
+
+    if seekable {
+      // The values to patch end the seek head and the info.
+      let mut cues_seek = Vec::new();
+      write_seek(&mut cues_seek, CUES, 0);
+      self.patches = Some(Patches {
+        segment_size,
+        duration: segment_start + tracks_position - 8,
+        cues_seek: (
+          segment_start + info_position - cues_seek.len() as u64,
+          cues_seek.len(),
+        ),
+      });
+    }
+    out.extend(seek_head);
+    out.extend(info);
+    out.extend(tracks);
+
+    self.position = out.len() as u64 - segment_start;
+    self.header_written = true;
+    self.output.write_all(&out)
+  }
+
+  fn write_cluster(&mut self) -> io::Result<()> {
+    let mut out = Vec::new();
+    write_element(&mut out, CLUSTER, |out| {
+      write_uint(out, TIMESTAMP, self.cluster_timestamp);
+      out.append(&mut self.cluster);
+    });
+    if self.cluster_is_sync {
+      self.cues.push((self.cluster_timestamp, self.position));
+    }
+    self.position += out.len() as u64;
+    self.output.write_all(&out)
+  }
+}
+
+impl Muxer for MkvMuxer {
+  fn write_header(&mut self, config: &EncoderConfig, av1c: &[u8]) {
+    // The segment header is written with the first frame, which carries the
+    // sequence header OBU needed in the codec private data.
+    self.config = Some((config.clone(), av1c.to_vec()));
+  }
+
+  #[profiling::function]
+  fn write_frame(&mut self, pts: u64, data: &[u8], frame_type: FrameType) {
+    if !self.header_written {
+      self
+        .write_segment_header(sequence_header_obu(data).unwrap_or_default())
+        .unwrap();
+    }
+    let (enc, _) = self.config.as_ref().expect("header not written");
+    let sync = is_sync_sample(data, frame_type, enc.still_picture);
+    let timestamp = self.timestamp(pts);
+
+    // A new cluster starts at every keyframe, or when the timestamps of the
+    // blocks relative to the cluster would overflow.
+    if !self.cluster.is_empty()
+      && (sync || timestamp - self.cluster_timestamp > i16::MAX as u64)
+    {
+      self.write_cluster().unwrap();
+    }
+    if self.cluster.is_empty() {
+      self.cluster_timestamp = timestamp;
+      self.cluster_is_sync = sync;
+    }
+
+    let data = without_temporal_delimiter(data);
+    let relative_timestamp = (timestamp - self.cluster_timestamp) as i16;
+    write_id(&mut self.cluster, SIMPLE_BLOCK);
+    write_size(&mut self.cluster, data.len() as u64 + 4);
+    write_size(&mut self.cluster, 1); // track number
+    self.cluster.extend(relative_timestamp.to_be_bytes());
+    self.cluster.push(if sync { 0x80 } else { 0 });
+    self.cluster.extend(data);
+
+    self.end_timestamp = self.end_timestamp.max(self.timestamp(pts + 1));
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    self.output.flush()
+  }
+
+  fn finish(&mut self) -> io::Result<()> {
+    if !self.header_written {
+      self.write_segment_header(&[])?;
+    }
+    if !self.cluster.is_empty() {
+      self.write_cluster()?;
+    }
+
+    let cues_position = self.position;
+    let mut cues = Vec::new();
+    if !self.cues.is_empty() {
+      write_element(&mut cues, CUES, |out| {
+        for &(time, position) in self.cues.iter() {
+          write_element(out, CUE_POINT, |out| {
+            write_uint(out, CUE_TIME, time);
+            write_element(out, CUE_TRACK_POSITIONS, |out| {
+              write_uint(out, CUE_TRACK, 1);
+              write_uint(out, CUE_CLUSTER_POSITION, position);
+            });
+          });
+        }
+      });
+    }
+    self.output.write_all(&cues)?;
+    self.position += cues.len() as u64;
+
+    if let (Some(patches), Output::File(file)) =
+      (self.patches.as_ref(), &mut self.output)
+    {
+      let end = file.stream_position()?;
+      file.seek(SeekFrom::Start(patches.segment_size))?;
+      file.write_all(&(self.position | 1 << 56).to_be_bytes())?;
+      file.seek(SeekFrom::Start(patches.duration))?;
+      file.write_all(&(self.end_timestamp as f64).to_be_bytes())?;
+      let (cues_seek, cues_seek_size) = patches.cues_seek;
+      if cues.is_empty() {
+        // Without cues, their seek entry is replaced by a void element of
+        // the same size.
+        let mut void = Vec::new();
+        write_binary(&mut void, VOID, &vec![0; cues_seek_size - 2]);
+        file.seek(SeekFrom::Start(cues_seek))?;
+        file.write_all(&void)?;
+      } else {
+        // The position ends the seek entry.
+        file.seek(SeekFrom::Start(cues_seek + cues_seek_size as u64 - 8))?;
+        file.write_all(&cues_position.to_be_bytes())?;
+      }
+      file.seek(SeekFrom::Start(end))?;
+    }
+    self.flush()
+  }
+}
@@ -7,55 +7,144 @@
 // Media Patent License 1.0 was not distributed with this source code in the
 // PATENTS file, you can obtain it at www.aomedia.org/license/patent.
 
+mod avif;
+pub use self::avif::grid_tile_size;
+use self::avif::AvifMuxer;
+
 mod ivf;
 use self::ivf::IvfMuxer;
 
+mod mkv;
+use self::mkv::MkvMuxer;
+
+mod mp4;
+use self::mp4::Mp4Muxer;
+
+mod obu;
+use self::obu::ObuMuxer;
+
 mod y4m;
 pub use self::y4m::write_y4m_frame;
 
+use arg_enum_proc_macro::ArgEnum;
 use rav1e::prelude::*;
 
 use std::ffi::OsStr;
+use std::fs::OpenOptions;
 use std::io;
 use std::path::Path;
 
 use crate::error::*;
 
 pub trait Muxer: Send {
-  fn write_header(
-    &mut self, width: usize, height: usize, framerate_num: usize,
-    framerate_den: usize,
-  );
+  /// Writes the header of the container, given the encoder configuration
+  /// and the `av1C` configuration record of the stream.
+  fn write_header(&mut self, config: &EncoderConfig, av1c: &[u8]);
 
   fn write_frame(&mut self, pts: u64, data: &[u8], frame_type: FrameType);
 
+  /// Sets the `av1C` configuration record of the alpha auxiliary stream, for
+  /// the containers storing it.
+  // rav1e-ch rejects alpha coding.
+  #[cfg_attr(feature = "channel-api", allow(dead_code))]
+  fn write_alpha_header(&mut self, _av1c: &[u8]) {}
+
+  /// Writes the alpha auxiliary data of the last frame written, for the
+  /// containers storing it.
+  #[cfg_attr(feature = "channel-api", allow(dead_code))]
+  fn write_alpha_frame(&mut self, _data: &[u8]) {}
+
   fn flush(&mut self) -> io::Result<()>;
+
+  /// Completes the container once every frame is written.
+  fn finish(&mut self) -> io::Result<()> {
+    self.flush()
+  }
+}
+
+/// The container format of the output.
+#[derive(ArgEnum, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Container {
+  Ivf,
+  Mp4,
+  Mkv,
+  Webm,
+  /// Low overhead bitstream format, as specified in section 5.
+  Obu,
+  /// Length delimited bitstream format, as specified in annex B.
+  AnnexB,
+  /// Still image, encoded with `--still-picture`.
+  Avif,
 }
 
+/// Returns the container of the output, if not set matching its extension.
+pub fn output_container<P: AsRef<Path>>(
+  path: P, container: Option<Container>,
+) -> Result<Container, CliError> {
+  match container {
+    Some(container) => Ok(container),
+    // Streams to stdout default to IVF.
+    None if path.as_ref().to_str() == Some("-") => Ok(Container::Ivf),
+    None => {
+      let ext = path
+        .as_ref()
+        .extension()
+        .and_then(OsStr::to_str)
+        .map(str::to_lowercase)
+        .unwrap_or_else(|| "ivf".into());
+
+      match &ext[..] {
+        "ivf" => Ok(Container::Ivf),
+        "mp4" | "m4v" => Ok(Container::Mp4),
+        "mkv" => Ok(Container::Mkv),
+        "webm" => Ok(Container::Webm),
+        "obu" => Ok(Container::Obu),
+        "avif" => Ok(Container::Avif),
+        _ => Err(CliError::Message {
+          msg: format!(
+            "{ext} is not a supported extension, please change to .ivf, .mp4, .mkv, .webm, .obu or .avif, or set --container"
+          ),
+        }),
+      }
+    }
+  }
+}
+
+/// Creates the muxer of the output. The tiles of a `grid` are written as a
+/// single AVIF image.
 pub fn create_muxer<P: AsRef<Path>>(
-  path: P, overwrite: bool,
+  path: P, overwrite: bool, container: Container, fragmented: bool,
+  grid: Option<ImageGrid>,
 ) -> Result<Box<dyn Muxer + Send>, CliError> {
   if !overwrite {
     IvfMuxer::check_file(path.as_ref())?;
   }
 
-  if let Some(path) = path.as_ref().to_str() {
-    if path == "-" {
-      return IvfMuxer::open(path);
-    }
+  match container {
+    Container::Ivf => IvfMuxer::open(path),
+    Container::Mp4 => Mp4Muxer::open(path, fragmented),
+    Container::Mkv => MkvMuxer::open(path, false),
+    Container::Webm => MkvMuxer::open(path, true),
+    Container::Obu => ObuMuxer::open(path, false),
+    Container::AnnexB => ObuMuxer::open(path, true),
+    Container::Avif => AvifMuxer::open(path, grid),
   }
+}
 
-  let ext = path
-    .as_ref()
-    .extension()
-    .and_then(OsStr::to_str)
-    .map(str::to_lowercase)
-    .unwrap_or_else(|| "ivf".into());
-
-  match &ext[..] {
-    "ivf" => IvfMuxer::open(path),
-    _e => {
-      panic!("{ext} is not a supported extension, please change to .ivf");
-    }
+/// Opens the output of an interrupted encode to append the following frames
+/// to it, for the containers written sequentially.
+pub fn append_muxer<P: AsRef<Path>>(
+  path: P, container: Container,
+) -> Result<Box<dyn Muxer + Send>, CliError> {
+  let output = OpenOptions::new()
+    .append(true)
+    .open(path)
+    .map_err(|e| e.context("Cannot open output file"))?;
+
+  match container {
+    Container::Ivf => Ok(IvfMuxer::append(output)),
+    Container::Obu => Ok(ObuMuxer::append(output, false)),
+    Container::AnnexB => Ok(ObuMuxer::append(output, true)),
+    _ => Err(CliError::new("Only IVF and OBU outputs can be appended to")),
   }
 }
@@ -0,0 +1,475 @@
+// Copyright (c) 2017-2022, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+//! MP4 (ISO base media file format) output, following the AV1 Codec ISO
+//! Media File Format Binding.
+
+use super::obu::{
+  is_sync_sample, sequence_header_obu, without_temporal_delimiter,
+};
+use super::Muxer;
+use crate::error::*;
+use rav1e::isobmff::*;
+use rav1e::prelude::*;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const UNITY_MATRIX: [u32; 9] =
+  [0x0001_0000, 0, 0, 0, 0x0001_0000, 0, 0, 0, 0x4000_0000];
+
+/// Appends the `av1C` box of the stream: the configuration record followed
+/// by its sequence header OBU.
+pub fn write_av1c_box(out: &mut Vec<u8>, av1c: &[u8], config_obus: &[u8]) {
+  write_box(out, b"av1C", |out| {
+    out.extend(av1c);
+    out.extend(config_obus);
+  });
+}
+
+/// Appends the `mdcv` and `clli` boxes of the HDR metadata of the stream.
+fn write_hdr_boxes(out: &mut Vec<u8>, enc: &EncoderConfig) {
+  write_mdcv_box(out, enc);
+  write_clli_box(out, enc);
+}
+
+/// Writes a time or duration, on 64 bits in version 1 boxes.
+fn write_time(out: &mut Vec<u8>, version: u8, time: u64) {
+  if version == 1 {
+    out.extend(time.to_be_bytes());
+  } else {
+    out.extend((time as u32).to_be_bytes());
+  }
+}
+
+struct Sample {
+  size: u32,
+  sync: bool,
+}
+
+/// Properties of the video track, known from the header.
+struct Track {
+  enc: EncoderConfig,
+  av1c: Vec<u8>,
+  /// Ticks per second.
+  timescale: u32,
+  /// Duration of every sample, in ticks.
+  sample_duration: u32,
+}
+
+enum Layout {
+  /// A single `mdat`, whose size is patched once complete, followed by the
+  /// `moov` indexing every sample.
+  Flat { output: BufWriter<File>, mdat_start: u64 },
+  /// A `moov` without samples, followed by a `moof` and `mdat` for each
+  /// fragment, starting at every sync sample.
+  Fragmented { output: Box<dyn Write + Send>, sequence_number: u32 },
+}
+
+pub struct Mp4Muxer {
+  layout: Layout,
+  track: Option<Track>,
+  /// The sequence header OBU of the stream, taken from the first sample.
+  config_obus: Option<Vec<u8>>,
+  /// Samples of the file, or of the current fragment.
+  samples: Vec<Sample>,
+  /// Data of the samples of the current fragment.
+  fragment_data: Vec<u8>,
+  /// Number of samples of the previous fragments.
+  fragmented_samples: u64,
+}
+
+impl Mp4Muxer {
+  pub fn open<P: AsRef<Path>>(
+    path: P, fragmented: bool,
+  ) -> Result<Box<dyn Muxer + Send>, CliError> {
+    let layout = if fragmented || path.as_ref().to_str() == Some("-") {
+      Layout::Fragmented {
+        output: match path.as_ref().to_str() {
+          Some("-") => Box::new(io::stdout()),
+          _ => Box::new(BufWriter::new(
+            File::create(path)
+              .map_err(|e| e.context("Cannot open output file"))?,
+          )),
+        },
+        sequence_number: 0,
+      }
+    } else {
+      Layout::Flat {
+        output: BufWriter::new(
+          File::create(path)
+            .map_err(|e| e.context("Cannot open output file"))?,
+        ),
+        mdat_start: 0,
+      }
+    };
+    Ok(Box::new(Mp4Muxer {
+      layout,
+      track: None,
+      config_obus: None,
+      samples: Vec::new(),
+      fragment_data: Vec::new(),
+      fragmented_samples: 0,
+    }))
+  }
+
+  fn write_ftyp(out: &mut Vec<u8>) {
+    write_box(out, b"ftyp", |out| {
+      out.extend(b"isom"); // major_brand
+      out.extend(0x200u32.to_be_bytes()); // minor_version
+      for brand in [b"isom", b"iso6", b"mp41", b"av01"] {
+        out.extend(brand);
+      }
+    });
+  }
+
+  fn write_moov(&self, out: &mut Vec<u8>) {
+    let track = self.track.as_ref().expect("header not written");
+    let enc = &track.enc;
+    let fragmented = matches!(self.layout, Layout::Fragmented { .. });
+    let sample_count = self.samples.len() as u64;
+    let duration = if fragmented {
+      0
+    } else {
+      sample_count * u64::from(track.sample_duration)
+    };
+    let version = u8::from(duration > u64::from(u32::MAX));
+
+    write_box(out, b"moov", |out| {
+      write_full_box(out, b"mvhd", version, 0, |out| {
+        write_time(out, version, 0); // creation_time
+        write_time(out, version, 0); // modification_time
+        out.extend(track.timescale.to_be_bytes());
+        write_time(out, version, duration);
+        out.extend(0x0001_0000u32.to_be_bytes()); // rate
+        out.extend(0x0100u16.to_be_bytes()); // volume
+        out.extend([0; 10]); // reserved
+        for value in UNITY_MATRIX {
+          out.extend(value.to_be_bytes());
+        }
+        out.extend([0; 24]); // pre_defined
+        out.extend(2u32.to_be_bytes()); // next_track_ID
+      });
+
+      write_box(out, b"trak", |out| {
+        // track_enabled, track_in_movie
+        write_full_box(out, b"tkhd", version, 3, |out| {
+          write_time(out, version, 0); // creation_time
+          write_time(out, version, 0); // modification_time
+          out.extend(1u32.to_be_bytes()); // track_ID
+          out.extend([0; 4]); // reserved
+          write_time(out, version, duration);
+          out.extend([0; 8]); // reserved
+          out.extend(0u16.to_be_bytes()); // layer
+          out.extend(0u16.to_be_bytes()); // alternate_group
+          out.extend(0u16.to_be_bytes()); // volume
+          out.extend([0; 2]); // reserved
+          for value in UNITY_MATRIX {
+            out.extend(value.to_be_bytes());
+          }
+          // The presentation size, in 16.16 fixed point.
+          let Rational { num, den } = enc.sample_aspect_ratio;
+          let width = ((enc.width as u64 * num) << 16) / den;
+          out.extend((width as u32).to_be_bytes());
+          out.extend(((enc.height as u32) << 16).to_be_bytes());
+        });
+
+        write_box(out, b"mdia", |out| {
+          write_full_box(out, b"mdhd", version, 0, |out| {
+            write_time(out, version, 0); // creation_time
+            write_time(out, version, 0); // modification_time
+            out.extend(track.timescale.to_be_bytes());
+            write_time(out, version, duration);
+            out.extend(0x55c4u16.to_be_bytes()); // language: und
+            out.extend(0u16.to_be_bytes()); // pre_defined
+          });
+          write_full_box(out, b"hdlr", 0, 0, |out| {
+            out.extend([0; 4]); // pre_defined
+            out.extend(b"vide"); // handler_type
+            out.extend([0; 12]); // reserved
+            out.extend(b"VideoHandler\0");
+          });
+
+          write_box(out, b"minf", |out| {
+            write_full_box(out, b"vmhd", 0, 1, |out| {
+              out.extend([0; 8]); // graphicsmode, opcolor
+            });
+            write_box(out, b"dinf", |out| {
+              write_full_box(out, b"dref", 0, 0, |out| {
+                out.extend(1u32.to_be_bytes()); // entry_count
+                                                // The media data is in the same file.
+                write_full_box(out, b"url ", 0, 1, |_| {});
+              });
+            });
+            write_box(out, b"stbl", |out| self.write_stbl(out, track));
+          });
+        });
+      });
+
+      if fragmented {
+        write_box(out, b"mvex", |out| {
+          write_full_box(out, b"trex", 0, 0, |out| {
+            out.extend(1u32.to_be_bytes()); // track_ID
+            out.extend(1u32.to_be_bytes()); // default_sample_description_index
+            out.extend(track.sample_duration.to_be_bytes());
+            out.extend(0u32.to_be_bytes()); // default_sample_size
+            out.extend(0u32.to_be_bytes()); // default_sample_flags
+          });
+        });
+      }
+    });
+  }
+
+  fn write_stbl(&self, out: &mut Vec<u8>, track: &Track) {
+    let enc = &track.enc;
+    // The samples of a fragmented file are in its fragments.
+    let (samples, mdat_start) = match self.layout {
+      Layout::Flat { mdat_start, .. } => (&self.samples[..], mdat_start),
+      Layout::Fragmented { .. } => (&[][..], 0),
+    };
+
+    write_full_box(out, b"stsd", 0, 0, |out| {
+      out.extend(1u32.to_be_bytes()); // entry_count
+      write_box(out, b"av01", |out| {
+        out.extend([0; 6]); // reserved
+        out.extend(1u16.to_be_bytes()); // data_reference_index
+        out.extend([0; 16]); // pre_defined, reserved
+        out.extend((enc.width as u16).to_be_bytes());
+        out.extend((enc.height as u16).to_be_bytes());
+        out.extend(0x0048_0000u32.to_be_bytes()); // horizresolution
+        out.extend(0x0048_0000u32.to_be_bytes()); // vertresolution
+        out.extend([0; 4]); // reserved
+        out.extend(1u16.to_be_bytes()); // frame_count
+        let mut compressorname = [0; 32];
+        compressorname[0] = 10;
+        compressorname[1..11].copy_from_slice(b"AOM Coding");
+        out.extend(compressorname);
+        out.extend(0x0018u16.to_be_bytes()); // depth
+        out.extend((-1i16).to_be_bytes()); // pre_defined
+
+        write_av1c_box(
+          out,
+          &track.av1c,
+          self.config_obus.as_deref().unwrap_or_default(),
+        );
+        write_colr_box(out, enc);
+        let Rational { num, den } = enc.sample_aspect_ratio;
+        if num != den {
+          write_box(out, b"pasp", |out| {
+            out.extend((num as u32).to_be_bytes());
+            out.extend((den as u32).to_be_bytes());
+          });
+        }
+        write_hdr_boxes(out, enc);
+      });
+    });
+
+    write_full_box(out, b"stts", 0, 0, |out| {
+      if samples.is_empty() {
+        out.extend(0u32.to_be_bytes());
+      } else {
+        out.extend(1u32.to_be_bytes()); // entry_count
+        out.extend((samples.len() as u32).to_be_bytes());
+        out.extend(track.sample_duration.to_be_bytes());
+      }
+    });
+
+    // Every sample is a sync sample when there is no stss.
+    if samples.iter().any(|sample| !sample.sync) {
+      write_full_box(out, b"stss", 0, 0, |out| {
+        let sync_samples = samples
+          .iter()
+          .enumerate()
+          .filter(|(_, sample)| sample.sync)
+          .map(|(i, _)| i as u32 + 1)
+          .collect::<Vec<_>>();
+        out.extend((sync_samples.len() as u32).to_be_bytes());
+        for sample_number in sync_samples {
+          out.extend(sample_number.to_be_bytes());
+        }
+      });
+    }
+
+    // All the samples are in a single chunk.
+    write_full_box(out, b"stsc", 0, 0, |out| {
+      if samples.is_empty() {
+        out.extend(0u32.to_be_bytes());
+      } else {
+        out.extend(1u32.to_be_bytes()); // entry_count
+        out.extend(1u32.to_be_bytes()); // first_chunk
+        out.extend((samples.len() as u32).to_be_bytes());
+        out.extend(1u32.to_be_bytes()); // sample_description_index
+      }
+    });
+    write_full_box(out, b"stsz", 0, 0, |out| {
+      out.extend(0u32.to_be_bytes()); // sample_size
+      out.extend((samples.len() as u32).to_be_bytes());
+      for sample in samples {
+        out.extend(sample.size.to_be_bytes());
+      }
+    });
+    write_full_box(out, b"co64", 0, 0, |out| {
+      if samples.is_empty() {
+        out.extend(0u32.to_be_bytes());
+      } else {
+        out.extend(1u32.to_be_bytes()); // entry_count
+                                        // The mdat has a 64-bit size.
+        out.extend((mdat_start + 16).to_be_bytes());
+      }
+    });
+  }
+
+  /// Writes the samples of the current fragment, preceded by the header of
+  /// the file before the first one.
+  fn write_fragment(&mut self) -> io::Result<()> {
+    let mut out = Vec::new();
+    let Layout::Fragmented { sequence_number, .. } = &mut self.layout else {
+      unreachable!()
+    };
+    *sequence_number += 1;
+    let sequence_number = *sequence_number;
+    if sequence_number == 1 {
+      Self::write_ftyp(&mut out);
+      self.write_moov(&mut out);
+    }
+    if self.samples.is_empty() {
+      return self.write_output(&out);
+    }
+
+    let track = self.track.as_ref().expect("header not written");
+    let moof_start = out.len();
+    let mut data_offset_pos = 0;
+    write_box(&mut out, b"moof", |out| {
+      write_full_box(out, b"mfhd", 0, 0, |out| {
+        out.extend(sequence_number.to_be_bytes());
+      });
+      write_box(out, b"traf", |out| {
+        // default-base-is-moof
+        write_full_box(out, b"tfhd", 0, 0x02_0000, |out| {
+          out.extend(1u32.to_be_bytes()); // track_ID
+        });
+        write_full_box(out, b"tfdt", 1, 0, |out| {
+          let base_media_decode_time =
+            self.fragmented_samples * u64::from(track.sample_duration);
+          out.extend(base_media_decode_time.to_be_bytes());
+        });
+        // data-offset, sample-size and sample-flags present
+        write_full_box(out, b"trun", 0, 0x0601, |out| {
+          out.extend((self.samples.len() as u32).to_be_bytes());
+          data_offset_pos = out.len();
+          out.extend(0u32.to_be_bytes()); // data_offset
+          for sample in self.samples.iter() {
+            out.extend(sample.size.to_be_bytes());
+            // sample_depends_on and sample_is_non_sync_sample
+            let flags = if sample.sync { 0x0200_0000 } else { 0x0101_0000u32 };
+            out.extend(flags.to_be_bytes());
+          }
+        });
+      });
+    });
+    // The samples follow the header of the mdat.
+    let data_offset = (out.len() - moof_start + 8) as u32;
+    out[data_offset_pos..data_offset_pos + 4]
+      .copy_from_slice(&data_offset.to_be_bytes());
+
+    let mdat_size =
+      u32::try_from(self.fragment_data.len() + 8).expect("fragment too large");
+    out.extend(mdat_size.to_be_bytes());
+    out.extend(b"mdat");
+    out.append(&mut self.fragment_data);
+    self.fragmented_samples += self.samples.len() as u64;
+    self.samples.clear();
+    self.write_output(&out)
+  }
+
+  fn write_output(&mut self, data: &[u8]) -> io::Result<()> {
+    match &mut self.layout {
+      Layout::Flat { output, .. } => output.write_all(data),
+      Layout::Fragmented { output, .. } => output.write_all(data),
+    }
+  }
+}
+
+impl Muxer for Mp4Muxer {
+  fn write_header(&mut self, enc: &EncoderConfig, av1c: &[u8]) {
+    let Rational { num, den } = enc.time_base;
+    self.track = Some(Track {
+      enc: enc.clone(),
+      av1c: av1c.to_vec(),
+      // The CLI rejects the time bases which do not fit.
+      timescale: den.try_into().unwrap(),
+      sample_duration: num.try_into().unwrap(),
+    });
+
+    if let Layout::Flat { output, mdat_start } = &mut self.layout {
+      let mut out = Vec::new();
+      Self::write_ftyp(&mut out);
+      *mdat_start = out.len() as u64;
+      // The size of the mdat is only known at the end.
+      out.extend(1u32.to_be_bytes()); // size: largesize follows
+      out.extend(b"mdat");
+      out.extend(0u64.to_be_bytes()); // largesize
+      output.write_all(&out).unwrap();
+    }
+  }
+
+  #[profiling::function]
+  fn write_frame(&mut self, _pts: u64, data: &[u8], frame_type: FrameType) {
+    let still_picture =
+      self.track.as_ref().expect("header not written").enc.still_picture;
+    if self.config_obus.is_none() {
+      self.config_obus = sequence_header_obu(data).map(<[u8]>::to_vec);
+    }
+    let sync = is_sync_sample(data, frame_type, still_picture);
+    let data = without_temporal_delimiter(data);
+    let sample =
+      Sample { size: data.len().try_into().expect("sample too large"), sync };
+
+    if matches!(self.layout, Layout::Fragmented { .. }) {
+      if sample.sync && !self.samples.is_empty() {
+        self.write_fragment().unwrap();
+      }
+      self.fragment_data.extend(data);
+    } else {
+      self.write_output(data).unwrap();
+    }
+    self.samples.push(sample);
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    match &mut self.layout {
+      Layout::Flat { output, .. } => output.flush(),
+      Layout::Fragmented { output, .. } => output.flush(),
+    }
+  }
+
+  fn finish(&mut self) -> io::Result<()> {
+    match self.layout {
+      Layout::Flat { mdat_start, .. } => {
+        let mut moov = Vec::new();
+        self.write_moov(&mut moov);
+        let Layout::Flat { output, .. } = &mut self.layout else {
+          unreachable!()
+        };
+        let mdat_end = output.stream_position()?;
+        output.seek(SeekFrom::Start(mdat_start + 8))?;
+        output.write_all(&(mdat_end - mdat_start).to_be_bytes())?;
+        output.seek(SeekFrom::Start(mdat_end))?;
+        output.write_all(&moov)?;
+      }
+      Layout::Fragmented { sequence_number, .. } => {
+        if sequence_number == 0 || !self.samples.is_empty() {
+          self.write_fragment()?;
+        }
+      }
+    }
+    self.flush()
+  }
+}
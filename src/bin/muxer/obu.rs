@@ -0,0 +1,282 @@
+// Copyright (c) 2017-2022, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+//! Parsing of the OBUs of the temporal units produced by the encoder, as
+//! needed by the containers, and raw OBU output: either the low overhead
+//! bitstream format of section 5 of the specification, or the length
+//! delimited format of its annex B.
+
+use super::Muxer;
+use crate::error::*;
+use rav1e::prelude::*;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+pub const OBU_SEQUENCE_HEADER: u8 = 1;
+pub const OBU_TEMPORAL_DELIMITER: u8 = 2;
+pub const OBU_FRAME_HEADER: u8 = 3;
+pub const OBU_FRAME: u8 = 6;
+
+/// An OBU of a temporal unit, whose `obu_has_size_field` is set.
+pub struct Obu<'a> {
+  pub obu_type: u8,
+  /// The whole OBU, including its header and size.
+  pub data: &'a [u8],
+  /// The header of the OBU, including its extension if any.
+  pub header: &'a [u8],
+  /// The payload of the OBU.
+  pub payload: &'a [u8],
+}
+
+/// Reads a `leb128()` value, returning it with its length in bytes.
+pub fn read_leb128(data: &[u8]) -> Option<(u64, usize)> {
+  let mut value = 0;
+  for (i, &byte) in data.iter().enumerate().take(8) {
+    value |= u64::from(byte & 0x7f) << (i * 7);
+    if byte & 0x80 == 0 {
+      return Some((value, i + 1));
+    }
+  }
+  None
+}
+
+pub fn write_leb128(out: &mut Vec<u8>, mut value: u64) {
+  while value >= 0x80 {
+    out.push((value & 0x7f) as u8 | 0x80);
+    value >>= 7;
+  }
+  out.push(value as u8);
+}
+
+/// Iterates over the OBUs of a temporal unit.
+pub fn obus(mut data: &[u8]) -> impl Iterator<Item = Obu<'_>> {
+  std::iter::from_fn(move || {
+    let &header = data.first()?;
+    let header_len = 1 + usize::from(header & 0x04 != 0);
+    // The encoder always sets obu_has_size_field.
+    debug_assert!(header & 0x02 != 0);
+    let (size, size_len) = read_leb128(data.get(header_len..)?)?;
+    let payload_start = header_len + size_len;
+    let len = payload_start + usize::try_from(size).ok()?;
+    let obu = Obu {
+      obu_type: (header >> 3) & 0xf,
+      data: data.get(..len)?,
+      header: &data[..header_len],
+      payload: &data[payload_start..len],
+    };
+    data = &data[len..];
+    Some(obu)
+  })
+}
+
+/// Returns a temporal unit without its temporal delimiter, which should not
+/// be in the samples of the containers.
+pub fn without_temporal_delimiter(data: &[u8]) -> &[u8] {
+  match obus(data).next() {
+    Some(obu) if obu.obu_type == OBU_TEMPORAL_DELIMITER => {
+      &data[obu.data.len()..]
+    }
+    _ => data,
+  }
+}
+
+/// Returns the sequence header OBU of a temporal unit, if any.
+pub fn sequence_header_obu(data: &[u8]) -> Option<&[u8]> {
+  obus(data)
+    .find(|obu| obu.obu_type == OBU_SEQUENCE_HEADER)
+    .map(|obu| obu.data)
+}
+
+/// Returns whether a temporal unit is a sync sample, i.e. whether its first
+/// frame is a shown key frame. A key frame shown by a `show_existing_frame`
+/// does not start a new coded video sequence.
+pub fn is_sync_sample(
+  data: &[u8], frame_type: FrameType, still_picture: bool,
+) -> bool {
+  if frame_type != FrameType::KEY {
+    return false;
+  }
+  if still_picture {
+    // The reduced still picture header has no show_existing_frame.
+    return true;
+  }
+  obus(data)
+    .find(|obu| matches!(obu.obu_type, OBU_FRAME_HEADER | OBU_FRAME))
+    .and_then(|obu| obu.payload.first().map(|&b| b & 0x80 == 0))
+    .unwrap_or(false)
+}
+
+/// Appends a temporal unit in the annex B format: its OBUs lose their size
+/// fields and are prefixed by their lengths instead, grouped in length
+/// delimited frame units.
+pub fn write_annexb_temporal_unit(out: &mut Vec<u8>, data: &[u8]) {
+  let mut frame_units = Vec::new();
+  let mut frame_unit = Vec::new();
+  let mut has_frame = false;
+  for obu in obus(data) {
+    // The OBUs preceding a frame, such as the temporal delimiter and the
+    // sequence header, are in the frame unit of that frame.
+    let is_frame = matches!(obu.obu_type, OBU_FRAME_HEADER | OBU_FRAME);
+    if is_frame && has_frame {
+      frame_units.push(std::mem::take(&mut frame_unit));
+    }
+    has_frame |= is_frame;
+    write_leb128(
+      &mut frame_unit,
+      (obu.header.len() + obu.payload.len()) as u64,
+    );
+    frame_unit.push(obu.header[0] & !0x02); // obu_has_size_field
+    frame_unit.extend(&obu.header[1..]);
+    frame_unit.extend(obu.payload);
+  }
+  frame_units.push(frame_unit);
+
+  let mut temporal_unit = Vec::new();
+  for frame_unit in frame_units {
+    write_leb128(&mut temporal_unit, frame_unit.len() as u64);
+    temporal_unit.extend(frame_unit);
+  }
+  write_leb128(out, temporal_unit.len() as u64);
+  out.extend(temporal_unit);
+}
+
+pub struct ObuMuxer {
+  output: Box<dyn Write + Send>,
+  annexb: bool,
+}
+
+impl ObuMuxer {
+  pub fn open<P: AsRef<Path>>(
+    path: P, annexb: bool,
+  ) -> Result<Box<dyn Muxer + Send>, CliError> {
+    let output: Box<dyn Write + Send> = match path.as_ref().to_str() {
+      Some("-") => Box::new(io::stdout()),
+      _ => Box::new(BufWriter::new(
+        File::create(path)
+          .map_err(|e| e.context("Cannot open output file"))?,
+      )),
+    };
+    Ok(Box::new(ObuMuxer { output, annexb }))
+  }
+
+  /// Creates a muxer appending temporal units to an output.
+  pub fn append(output: File, annexb: bool) -> Box<dyn Muxer + Send> {
+    Box::new(ObuMuxer { output: Box::new(BufWriter::new(output)), annexb })
+  }
+}
+
+impl Muxer for ObuMuxer {
+  fn write_header(&mut self, _config: &EncoderConfig, _av1c: &[u8]) {}
+
+  #[profiling::function]
+  fn write_frame(&mut self, _pts: u64, data: &[u8], _frame_type: FrameType) {
+    if self.annexb {
+      let mut temporal_unit = Vec::new();
+      write_annexb_temporal_unit(&mut temporal_unit, data);
+      self.output.write_all(&temporal_unit).unwrap();
+    } else {
+      self.output.write_all(data).unwrap();
+    }
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    self.output.flush()
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  /// Parses an annex B temporal unit back into the section 5 format.
+  fn parse_annexb_temporal_unit(data: &[u8]) -> (Vec<u8>, usize) {
+    fn read_sized(data: &[u8]) -> (&[u8], &[u8]) {
+      let (size, len) = read_leb128(data).unwrap();
+      data[len..].split_at(size as usize)
+    }
+    let (mut temporal_unit, rest) = read_sized(data);
+    let mut section5 = Vec::new();
+    while !temporal_unit.is_empty() {
+      let (mut frame_unit, rest) = read_sized(temporal_unit);
+      while !frame_unit.is_empty() {
+        let (obu, rest) = read_sized(frame_unit);
+        assert_eq!(obu[0] & 0x02, 0);
+        let header_len = 1 + usize::from(obu[0] & 0x04 != 0);
+        section5.push(obu[0] | 0x02);
+        section5.extend(&obu[1..header_len]);
+        write_leb128(&mut section5, (obu.len() - header_len) as u64);
+        section5.extend(&obu[header_len..]);
+        frame_unit = rest;
+      }
+      temporal_unit = rest;
+    }
+    (section5, data.len() - rest.len())
+  }
+
+  fn encode_packets(low_latency: bool) -> Vec<Vec<u8>> {
+    let enc = EncoderConfig {
+      width: 64,
+      height: 64,
+      low_latency,
+      min_key_frame_interval: 0,
+      max_key_frame_interval: 4,
+      speed_settings: SpeedSettings::from_preset(10),
+      ..Default::default()
+    };
+    let mut ctx: Context<u8> =
+      Config::new().with_encoder_config(enc).new_context().unwrap();
+    for _ in 0..6 {
+      ctx.send_frame(ctx.new_frame()).unwrap();
+    }
+    ctx.flush();
+
+    let mut packets = Vec::new();
+    loop {
+      match ctx.receive_packet() {
+        Ok(packet) => packets.push(packet.data),
+        Err(EncoderStatus::LimitReached) => break,
+        Err(_) => {}
+      }
+    }
+    packets
+  }
+
+  #[test]
+  fn annexb_roundtrip() {
+    for low_latency in [true, false] {
+      let packets = encode_packets(low_latency);
+      let mut annexb = Vec::new();
+      for packet in packets.iter() {
+        write_annexb_temporal_unit(&mut annexb, packet);
+      }
+
+      let mut data = &annexb[..];
+      for packet in packets.iter() {
+        let (section5, len) = parse_annexb_temporal_unit(data);
+        assert_eq!(&section5, packet);
+        data = &data[len..];
+      }
+      assert!(data.is_empty());
+    }
+  }
+
+  #[test]
+  fn section5_roundtrip() {
+    let packets = encode_packets(false);
+    let section5 = packets.concat();
+    let obus = obus(&section5).collect::<Vec<_>>();
+    assert_eq!(
+      obus.iter().map(|obu| obu.data.len()).sum::<usize>(),
+      section5.len()
+    );
+    assert!(obus.iter().any(|obu| obu.obu_type == OBU_SEQUENCE_HEADER));
+  }
+}
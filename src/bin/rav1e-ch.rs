@@ -16,6 +16,8 @@ mod error;
 #[cfg(feature = "serialize")]
 mod kv;
 mod muxer;
+#[cfg(feature = "serialize")]
+mod settings;
 mod stats;
 
 use crate::common::*;
@@ -24,10 +26,11 @@ use crate::stats::*;
 use rav1e::config::CpuFeatureLevel;
 use rav1e::prelude::*;
 
-use crate::decoder::{Decoder, FrameBuilder, VideoDetails};
+use crate::decoder::y4m::y4m_colorspace;
+use crate::decoder::{Decoder, FrameBuilder, InputDecoder, VideoDetails};
 use crate::muxer::*;
-use std::fs::File;
-use std::io::{Read, Seek, Write};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
 use std::sync::Arc;
 
 impl<T: Pixel> FrameBuilder<T> for FrameSender<T> {
@@ -40,6 +43,9 @@ struct Source<D: Decoder> {
   limit: usize,
   count: usize,
   input: D,
+  /// Format of the frames sent to the encoder, which may drop the chroma
+  /// planes of the input.
+  video_info: VideoDetails,
   #[cfg(all(unix, feature = "signal-hook"))]
   exit_requested: Arc<std::sync::atomic::AtomicBool>,
 }
@@ -47,7 +53,7 @@ struct Source<D: Decoder> {
 impl<D: Decoder> Source<D> {
   cfg_if::cfg_if! {
     if #[cfg(all(unix, feature = "signal-hook"))] {
-      fn new(limit: usize, input: D) -> Self {
+      fn new(limit: usize, input: D, video_info: VideoDetails) -> Self {
         use signal_hook::{flag, consts};
 
         // Make sure double CTRL+C and similar kills
@@ -61,11 +67,11 @@ impl<D: Decoder> Source<D> {
             // first arm and then terminate ‒ all in the first round.
             flag::register(*sig, Arc::clone(&exit_requested)).unwrap();
         }
-        Self { limit, input, count: 0, exit_requested, }
+        Self { limit, input, video_info, count: 0, exit_requested, }
       }
     } else {
-      fn new(limit: usize, input: D) -> Self {
-        Self { limit, input, count: 0, }
+      fn new(limit: usize, input: D, video_info: VideoDetails) -> Self {
+        Self { limit, input, video_info, count: 0, }
       }
     }
   }
@@ -85,8 +91,12 @@ impl<D: Decoder> Source<D> {
     }
     match self.input.read_frame(send_frame, &video_info) {
       Ok(frame) => {
+        let params =
+          video_info.frame_timecode(self.count as u64).map(|timecode| {
+            FrameParameters { timecode: Some(timecode), ..Default::default() }
+          });
         self.count += 1;
-        let _ = send_frame.send(frame);
+        let _ = send_frame.send((frame, params));
         true
       }
       _ => false,
@@ -95,11 +105,11 @@ impl<D: Decoder> Source<D> {
 }
 
 fn do_encode<T: Pixel, D: Decoder>(
-  cfg: Config, verbose: Verboseness, mut progress: ProgressInfo,
-  output: &mut dyn Muxer, mut source: Source<D>, pass1file: Option<File>,
-  pass2file: Option<File>,
+  cfg: Config, enc: &EncoderConfig, verbose: Verboseness,
+  mut progress: ProgressInfo, output: &mut dyn Muxer, mut source: Source<D>,
+  pass1file: Option<File>, pass2file: Option<File>,
   mut y4m_enc: Option<y4m::Encoder<Box<dyn Write + Send>>>,
-  metrics_enabled: MetricsEnabled,
+  metrics_enabled: MetricsEnabled, mut stats_file: Option<StatsWriter>,
 ) -> Result<(), CliError> {
   let ((mut send_frame, receive_packet), (send_rc, receive_rc)) =
     match (pass1file.is_some(), pass2file.is_some()) {
@@ -128,7 +138,9 @@ fn do_encode<T: Pixel, D: Decoder>(
       }
     };
 
-  let y4m_details = source.input.get_video_details();
+  output.write_header(enc, &receive_packet.container_sequence_header());
+
+  let y4m_details = source.video_info;
 
   crossbeam::thread::scope(move |s| -> Result<(), CliError> {
     // Receive pass data
@@ -164,18 +176,7 @@ fn do_encode<T: Pixel, D: Decoder>(
 
               // The last packet of rate control data we get is the summary data.
               // Let's put it at the start of the file.
-              passfile.seek(std::io::SeekFrom::Start(0)).map_err(|e| {
-                e.context("Unable to seek in the two-pass data file.")
-              })?;
-              let len = outbuf.len() as u64;
-
-              passfile.write_all(&len.to_be_bytes()).map_err(|e| {
-                e.context("Unable to write to two-pass data file.")
-              })?;
-
-              passfile.write_all(&outbuf).map_err(|e| {
-                e.context("Unable to write to two-pass data file.")
-              })?;
+              write_pass1_summary(&mut passfile, &outbuf)?;
             }
           }
         }
@@ -246,6 +247,11 @@ fn do_encode<T: Pixel, D: Decoder>(
           y4m_details.chroma_sampling,
           metrics_enabled,
         );
+        if let Some(stats_file) = stats_file.as_mut() {
+          stats_file
+            .write_frame(&summary)
+            .map_err(|e| e.context("Unable to write the stats file"))?;
+        }
 
         if verbose != Verboseness::Quiet {
           progress.add_frame(summary.clone());
@@ -257,6 +263,12 @@ fn do_encode<T: Pixel, D: Decoder>(
           };
         }
       }
+      output.finish().map_err(|e| e.context("Unable to write the output"))?;
+      if let Some(stats_file) = stats_file.as_mut() {
+        stats_file
+          .write_summary()
+          .map_err(|e| e.context("Unable to write the stats file"))?;
+      }
 
       if verbose != Verboseness::Quiet {
         if verbose == Verboseness::Verbose {
@@ -374,36 +386,38 @@ cfg_if::cfg_if! {
 
 fn run() -> Result<(), error::CliError> {
   let mut cli = parse_cli()?;
-  // Maximum frame size by specification + maximum y4m header
-  let limit = y4m::Limits {
-    // Use saturating operations to gracefully handle 32-bit architectures
-    bytes: 64usize
-      .saturating_mul(64)
-      .saturating_mul(4096)
-      .saturating_mul(2304)
-      .saturating_add(1024),
-  };
-  let mut y4m_dec = match y4m::Decoder::new_with_limits(cli.io.input, limit) {
-    Err(e) => {
-      return Err(CliError::new(match e {
-        y4m::Error::ParseError(_) => {
-          "Could not parse input video. Is it a y4m file?"
-        }
-        y4m::Error::IoError(_) => {
-          "Could not read input file. Check that the path is correct and you have read permissions."
-        }
-        y4m::Error::UnknownColorspace => {
-          "Unknown colorspace or unsupported bit depth."
-        }
-        y4m::Error::OutOfMemory => "The video's frame size exceeds the limit.",
-        y4m::Error::EOF => "Unexpected end of input.",
-        y4m::Error::BadInput => "Bad y4m input parameters provided.",
-      }))
-    }
-    Ok(d) => d,
-  };
-  let video_info = y4m_dec.get_video_details();
-  let y4m_enc = cli.io.rec.map(|rec| {
+  if cli.chunks.is_some() {
+    return Err(CliError::new(
+      "Chunked encoding is only supported by rav1e, use --slots instead",
+    ));
+  }
+  #[cfg(feature = "serialize")]
+  if cli.checkpoint.is_some() {
+    return Err(CliError::new("Checkpoints are only supported by rav1e"));
+  }
+  if cli.verify {
+    return Err(CliError::new("Verification is only supported by rav1e"));
+  }
+  if cli.grid.is_some() || cli.enc.alpha_quantizer.is_some() {
+    return Err(CliError::new(
+      "Grids and alpha coding are only supported by rav1e",
+    ));
+  }
+  let mut y4m_dec = cli.io.input;
+  let mut video_info = y4m_dec.get_video_details();
+  let mut rec_colorspace = y4m_dec.rec_colorspace();
+  if cli.monochrome {
+    video_info.chroma_sampling = ChromaSampling::Cs400;
+    video_info.chroma_sample_position = ChromaSamplePosition::Unknown;
+    rec_colorspace =
+      y4m_colorspace(ChromaSampling::Cs400, video_info.bit_depth);
+  }
+  if cli.io.rec.is_some() && rec_colorspace.is_none() {
+    return Err(CliError::new(
+      "Y4M cannot store a 10-bit monochrome reconstruction.",
+    ));
+  }
+  let y4m_enc = cli.io.rec.zip(rec_colorspace).map(|(rec, rec_colorspace)| {
     y4m::encode(
       video_info.width,
       video_info.height,
@@ -412,7 +426,7 @@ fn run() -> Result<(), error::CliError> {
         video_info.time_base.num as usize,
       ),
     )
-    .with_colorspace(y4m_dec.get_colorspace())
+    .with_colorspace(rec_colorspace)
     .with_pixel_aspect(y4m::Ratio {
       num: video_info.sample_aspect_ratio.num as usize,
       den: video_info.sample_aspect_ratio.den as usize,
@@ -488,9 +502,15 @@ fn run() -> Result<(), error::CliError> {
 
   let pass1file = match cli.pass1file_name {
     Some(f) => {
-      let f = File::create(f).map_err(|e| {
-        e.context("Unable to open file for writing two-pass data")
-      })?;
+      let f = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(f)
+        .map_err(|e| {
+          e.context("Unable to open file for writing two-pass data")
+        })?;
       rc = rc.with_emit_data(true);
       Some(f)
     }
@@ -515,20 +535,14 @@ fn run() -> Result<(), error::CliError> {
     }
   }
 
-  cli.io.output.write_header(
-    video_info.width,
-    video_info.height,
-    cli.enc.time_base.den as usize,
-    cli.enc.time_base.num as usize,
-  );
-
   let tiling =
     cfg.tiling_info().map_err(|e| e.context("Invalid configuration"))?;
   if cli.verbose != Verboseness::Quiet {
     info!("CPU Feature Level: {}", CpuFeatureLevel::default());
 
     info!(
-      "Using y4m decoder: {}x{}p @ {}/{} fps, {}, {}-bit",
+      "Using {} decoder: {}x{}p @ {}/{} fps, {}, {}-bit",
+      y4m_dec.name(),
       video_info.width,
       video_info.height,
       video_info.time_base.den,
@@ -555,9 +569,21 @@ fn run() -> Result<(), error::CliError> {
     if cli.limit == 0 { None } else { Some(cli.limit) },
     cli.metrics_enabled,
   );
+  let stats_file = cli
+    .stats_file_name
+    .map(|path| {
+      StatsWriter::create(
+        &path,
+        Rational {
+          num: video_info.time_base.den,
+          den: video_info.time_base.num,
+        },
+      )
+    })
+    .transpose()?;
 
   for _ in 0..cli.skip {
-    match y4m_dec.read_frame() {
+    match y4m_dec.skip_frame() {
       Ok(f) => f,
       Err(_) => {
         return Err(CliError::new("Skipped more frames than in the input"))
@@ -565,11 +591,16 @@ fn run() -> Result<(), error::CliError> {
     };
   }
 
-  let source = Source::new(cli.limit, y4m_dec);
+  // The timecode counts the skipped frames.
+  video_info.timecode = cli.timecode.or(video_info.timecode);
+  video_info.timecode = video_info.frame_timecode(cli.skip as u64);
+
+  let source = Source::new(cli.limit, y4m_dec, video_info);
 
   if video_info.bit_depth == 8 && !cli.force_highbitdepth {
-    do_encode::<u8, y4m::Decoder<Box<dyn Read + Send>>>(
+    do_encode::<u8, InputDecoder>(
       cfg,
+      &cli.enc,
       cli.verbose,
       progress,
       &mut *cli.io.output,
@@ -578,10 +609,12 @@ fn run() -> Result<(), error::CliError> {
       pass2file,
       y4m_enc,
       cli.metrics_enabled,
+      stats_file,
     )?
   } else {
-    do_encode::<u16, y4m::Decoder<Box<dyn Read + Send>>>(
+    do_encode::<u16, InputDecoder>(
       cfg,
+      &cli.enc,
       cli.verbose,
       progress,
       &mut *cli.io.output,
@@ -590,6 +623,7 @@ fn run() -> Result<(), error::CliError> {
       pass2file,
       y4m_enc,
       cli.metrics_enabled,
+      stats_file,
     )?
   }
   if cli.benchmark {
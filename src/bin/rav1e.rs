@@ -10,24 +10,33 @@
 #[macro_use]
 extern crate log;
 
+#[cfg(feature = "serialize")]
+mod checkpoint;
+mod chunks;
 mod common;
 mod decoder;
 mod error;
+mod grid;
 #[cfg(feature = "serialize")]
 mod kv;
 mod muxer;
+#[cfg(feature = "serialize")]
+mod settings;
 mod stats;
+mod verify;
 
 use crate::common::*;
 use crate::error::*;
 use crate::stats::*;
+use crate::verify::Verifier;
 use rav1e::config::CpuFeatureLevel;
 use rav1e::prelude::*;
 
-use crate::decoder::{Decoder, FrameBuilder, VideoDetails};
+use crate::decoder::y4m::y4m_colorspace;
+use crate::decoder::{Decoder, FrameBuilder, InputDecoder, VideoDetails};
 use crate::muxer::*;
-use std::fs::File;
-use std::io::{Read, Seek, Write};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
 use std::process::exit;
 use std::sync::Arc;
 
@@ -37,10 +46,16 @@ impl<T: Pixel> FrameBuilder<T> for Context<T> {
   }
 }
 
+/// A frame read from the input, with its parameters.
+type InputFrame<T> = (Frame<T>, Option<FrameParameters>);
+
 struct Source<D: Decoder> {
   limit: usize,
   count: usize,
   input: D,
+  /// Format of the frames sent to the encoder, which may drop the chroma
+  /// planes of the input.
+  video_info: VideoDetails,
   #[cfg(all(unix, feature = "signal-hook"))]
   exit_requested: Arc<std::sync::atomic::AtomicBool>,
 }
@@ -48,7 +63,7 @@ struct Source<D: Decoder> {
 impl<D: Decoder> Source<D> {
   cfg_if::cfg_if! {
     if #[cfg(all(unix, feature = "signal-hook"))] {
-      fn new(limit: usize, input: D) -> Self {
+      fn new(limit: usize, input: D, video_info: VideoDetails) -> Self {
         use signal_hook::{flag, consts};
 
         // Make sure double CTRL+C and similar kills
@@ -63,46 +78,72 @@ impl<D: Decoder> Source<D> {
             flag::register(*sig, Arc::clone(&exit_requested)).unwrap();
         }
 
-        Self { limit, input, count: 0, exit_requested, }
+        Self { limit, input, video_info, count: 0, exit_requested, }
+      }
+
+      /// Returns whether a signal asked for the encode to stop.
+      fn exit_requested(&self) -> bool {
+        self.exit_requested.load(std::sync::atomic::Ordering::SeqCst)
       }
     } else {
       #[allow(clippy::missing_const_for_fn)]
-      fn new(limit: usize, input: D) -> Self {
-        Self { limit, input, count: 0, }
+      fn new(limit: usize, input: D, video_info: VideoDetails) -> Self {
+        Self { limit, input, video_info, count: 0, }
+      }
+
+      #[allow(clippy::missing_const_for_fn)]
+      fn exit_requested(&self) -> bool {
+        false
       }
     }
   }
 
+  /// Reads the next frame of the input along with its parameters, or returns
+  /// `None` once the input or the frame limit is exhausted.
   #[profiling::function]
-  fn read_frame<T: Pixel>(
-    &mut self, ctx: &mut Context<T>, video_info: VideoDetails,
-  ) -> Result<(), CliError> {
+  fn next_frame<T: Pixel, F: FrameBuilder<T>>(
+    &mut self, ctx: &F,
+  ) -> Result<Option<InputFrame<T>>, CliError> {
     if self.limit != 0 && self.count == self.limit {
-      ctx.flush();
-      return Ok(());
+      return Ok(None);
     }
 
-    #[cfg(all(unix, feature = "signal-hook"))]
-    {
-      if self.exit_requested.load(std::sync::atomic::Ordering::SeqCst) {
-        ctx.flush();
-        return Ok(());
-      }
+    if self.exit_requested() {
+      return Ok(None);
     }
 
+    let video_info = self.video_info;
     match self.input.read_frame(ctx, &video_info) {
       Ok(frame) => {
         match video_info.bit_depth {
           8 | 10 | 12 => {}
           _ => return Err(CliError::new("Unsupported bit depth")),
         }
+        let timecode = video_info.frame_timecode(self.count as u64);
+        let alpha = if video_info.alpha {
+          self.input.read_alpha(ctx).map(AlphaPlane::new)
+        } else {
+          None
+        };
+        let params = (timecode.is_some() || alpha.is_some())
+          .then(|| FrameParameters { timecode, alpha, ..Default::default() });
         self.count += 1;
-        let _ = ctx.send_frame(Some(Arc::new(frame)));
+        Ok(Some((frame, params)))
       }
-      _ => {
-        ctx.flush();
+      _ => Ok(None),
+    }
+  }
+
+  #[profiling::function]
+  fn read_frame<T: Pixel>(
+    &mut self, ctx: &mut Context<T>,
+  ) -> Result<(), CliError> {
+    match self.next_frame(ctx)? {
+      Some((frame, params)) => {
+        let _ = ctx.send_frame((Arc::new(frame), params));
       }
-    };
+      None => ctx.flush(),
+    }
     Ok(())
   }
 }
@@ -114,9 +155,9 @@ fn process_frame<T: Pixel, D: Decoder>(
   ctx: &mut Context<T>, output_file: &mut dyn Muxer, source: &mut Source<D>,
   pass1file: Option<&mut File>, pass2file: Option<&mut File>,
   mut y4m_enc: Option<&mut y4m::Encoder<Box<dyn Write + Send>>>,
-  metrics_cli: MetricsEnabled,
+  verifier: Option<&mut Verifier>, metrics_cli: MetricsEnabled,
 ) -> Result<Option<Vec<FrameSummary>>, CliError> {
-  let y4m_details = source.input.get_video_details();
+  let y4m_details = source.video_info;
   let mut frame_summaries = Vec::new();
   let mut pass1file = pass1file;
   let mut pass2file = pass2file;
@@ -148,11 +189,17 @@ fn process_frame<T: Pixel, D: Decoder>(
         pkt.data.as_ref(),
         pkt.frame_type,
       );
+      if let Some(alpha_data) = &pkt.alpha_data {
+        output_file.write_alpha_frame(alpha_data);
+      }
       if let (Some(ref mut y4m_enc_uw), Some(ref rec)) =
         (y4m_enc.as_mut(), &pkt.rec)
       {
         write_y4m_frame(y4m_enc_uw, rec, y4m_details);
       }
+      if let Some(verifier) = verifier {
+        verifier.verify(pkt.input_frameno, &pkt.data, pkt.rec.as_deref())?;
+      }
       frame_summaries.push(build_frame_summary(
         pkt,
         y4m_details.bit_depth,
@@ -162,7 +209,7 @@ fn process_frame<T: Pixel, D: Decoder>(
       Ok((Some(frame_summaries), true))
     }
     Err(EncoderStatus::NeedMoreData) => {
-      source.read_frame(ctx, y4m_details)?;
+      source.read_frame(ctx)?;
       Ok((Some(frame_summaries), false))
     }
     Err(EncoderStatus::EnoughData) => {
@@ -195,18 +242,7 @@ fn process_frame<T: Pixel, D: Decoder>(
         Some(RcData::Summary(outbuf)) => {
           // The last packet of rate control data we get is the summary data.
           // Let's put it at the start of the file.
-          passfile.rewind().map_err(|e| {
-            e.context("Unable to seek in the two-pass data file.")
-          })?;
-          let len = outbuf.len() as u64;
-
-          passfile.write_all(&len.to_be_bytes()).map_err(|e| {
-            e.context("Unable to write to two-pass data file.")
-          })?;
-
-          passfile.write_all(&outbuf).map_err(|e| {
-            e.context("Unable to write to two-pass data file.")
-          })?;
+          write_pass1_summary(passfile, &outbuf)?;
         }
         None => {}
       }
@@ -217,15 +253,21 @@ fn process_frame<T: Pixel, D: Decoder>(
 }
 
 fn do_encode<T: Pixel, D: Decoder>(
-  cfg: Config, verbose: Verboseness, mut progress: ProgressInfo,
-  output: &mut dyn Muxer, mut source: Source<D>, mut pass1file: Option<File>,
-  mut pass2file: Option<File>,
+  cfg: Config, enc: &EncoderConfig, verbose: Verboseness,
+  mut progress: ProgressInfo, output: &mut dyn Muxer, mut source: Source<D>,
+  mut pass1file: Option<File>, mut pass2file: Option<File>,
   mut y4m_enc: Option<y4m::Encoder<Box<dyn Write + Send>>>,
-  metrics_enabled: MetricsEnabled,
+  mut verifier: Option<Verifier>, metrics_enabled: MetricsEnabled,
+  mut stats_file: Option<StatsWriter>,
 ) -> Result<(), CliError> {
   let mut ctx: Context<T> =
     cfg.new_context().map_err(|e| e.context("Invalid encoder settings"))?;
 
+  output.write_header(enc, &ctx.container_sequence_header());
+  if let Some(av1c) = ctx.alpha_container_sequence_header() {
+    output.write_alpha_header(&av1c);
+  }
+
   // Let's write down a placeholder.
   if let Some(passfile) = pass1file.as_mut() {
     let len = ctx.rc_summary_size();
@@ -247,22 +289,61 @@ fn do_encode<T: Pixel, D: Decoder>(
     pass1file.as_mut(),
     pass2file.as_mut(),
     y4m_enc.as_mut(),
+    verifier.as_mut(),
     metrics_enabled,
   )? {
-    if verbose != Verboseness::Quiet {
-      for frame in frame_info {
-        progress.add_frame(frame.clone());
-        if verbose == Verboseness::Verbose {
-          info!("{} - {}", frame, progress);
-        } else {
-          // Print a one-line progress indicator that overrides itself with every update
-          eprint!("\r{progress}                    ");
-        };
-      }
+    report_frames(
+      frame_info,
+      verbose,
+      &mut progress,
+      stats_file.as_mut(),
+      &mut *output,
+    )?;
+  }
+  finish_encode(verbose, progress, output, stats_file)
+}
 
-      output.flush().unwrap();
+/// Writes the statistics of encoded frames and updates the progress.
+fn report_frames(
+  frames: Vec<FrameSummary>, verbose: Verboseness,
+  progress: &mut ProgressInfo, stats_file: Option<&mut StatsWriter>,
+  output: &mut dyn Muxer,
+) -> Result<(), CliError> {
+  if let Some(stats_file) = stats_file {
+    for frame in frames.iter() {
+      stats_file
+        .write_frame(frame)
+        .map_err(|e| e.context("Unable to write the stats file"))?;
     }
   }
+  if verbose != Verboseness::Quiet {
+    for frame in frames {
+      progress.add_frame(frame.clone());
+      if verbose == Verboseness::Verbose {
+        info!("{} - {}", frame, progress);
+      } else {
+        // Print a one-line progress indicator that overrides itself with every update
+        eprint!("\r{progress}                    ");
+      };
+    }
+
+    output.flush().unwrap();
+  }
+  Ok(())
+}
+
+/// Finishes the output once all frames are written, and prints the summary
+/// of the encode.
+fn finish_encode(
+  verbose: Verboseness, progress: ProgressInfo, output: &mut dyn Muxer,
+  mut stats_file: Option<StatsWriter>,
+) -> Result<(), CliError> {
+  output.finish().map_err(|e| e.context("Unable to write the output"))?;
+  if let Some(stats_file) = stats_file.as_mut() {
+    stats_file
+      .write_summary()
+      .map_err(|e| e.context("Unable to write the stats file"))?;
+  }
   if verbose != Verboseness::Quiet {
     if verbose == Verboseness::Verbose {
       // Clear out the temporary progress indicator
@@ -364,36 +445,21 @@ cfg_if::cfg_if! {
 
 fn run() -> Result<(), error::CliError> {
   let mut cli = parse_cli()?;
-  // Maximum frame size by specification + maximum y4m header
-  let limit = y4m::Limits {
-    // Use saturating operations to gracefully handle 32-bit architectures
-    bytes: 64usize
-      .saturating_mul(64)
-      .saturating_mul(4096)
-      .saturating_mul(2304)
-      .saturating_add(1024),
-  };
-  let mut y4m_dec = match y4m::Decoder::new_with_limits(cli.io.input, limit) {
-    Err(e) => {
-      return Err(CliError::new(match e {
-        y4m::Error::ParseError(_) => {
-          "Could not parse input video. Is it a y4m file?"
-        }
-        y4m::Error::IoError(_) => {
-          "Could not read input file. Check that the path is correct and you have read permissions."
-        }
-        y4m::Error::UnknownColorspace => {
-          "Unknown colorspace or unsupported bit depth."
-        }
-        y4m::Error::OutOfMemory => "The video's frame size exceeds the limit.",
-        y4m::Error::EOF => "Unexpected end of input.",
-        y4m::Error::BadInput => "Bad y4m input parameters provided.",
-      }))
-    }
-    Ok(d) => d,
-  };
-  let video_info = y4m_dec.get_video_details();
-  let y4m_enc = cli.io.rec.map(|rec| {
+  let mut y4m_dec = cli.io.input;
+  let mut video_info = y4m_dec.get_video_details();
+  let mut rec_colorspace = y4m_dec.rec_colorspace();
+  if cli.monochrome {
+    video_info.chroma_sampling = ChromaSampling::Cs400;
+    video_info.chroma_sample_position = ChromaSamplePosition::Unknown;
+    rec_colorspace =
+      y4m_colorspace(ChromaSampling::Cs400, video_info.bit_depth);
+  }
+  if cli.io.rec.is_some() && rec_colorspace.is_none() {
+    return Err(CliError::new(
+      "Y4M cannot store a 10-bit monochrome reconstruction.",
+    ));
+  }
+  let y4m_enc = cli.io.rec.zip(rec_colorspace).map(|(rec, rec_colorspace)| {
     y4m::encode(
       video_info.width,
       video_info.height,
@@ -402,7 +468,7 @@ fn run() -> Result<(), error::CliError> {
         video_info.time_base.num as usize,
       ),
     )
-    .with_colorspace(y4m_dec.get_colorspace())
+    .with_colorspace(rec_colorspace)
     .with_pixel_aspect(y4m::Ratio {
       num: video_info.sample_aspect_ratio.num as usize,
       den: video_info.sample_aspect_ratio.den as usize,
@@ -410,9 +476,14 @@ fn run() -> Result<(), error::CliError> {
     .write_header(rec)
     .unwrap()
   });
+  let verifier = cli.verify.then(|| Verifier::new(video_info)).transpose()?;
 
   cli.enc.width = video_info.width;
   cli.enc.height = video_info.height;
+  // The frames of a grid are its tiles.
+  if let Some((_, tile_size)) = cli.grid {
+    (cli.enc.width, cli.enc.height) = tile_size;
+  }
   cli.enc.sample_aspect_ratio = video_info.sample_aspect_ratio;
   cli.enc.bit_depth = video_info.bit_depth;
   cli.enc.chroma_sampling = video_info.chroma_sampling;
@@ -471,11 +542,23 @@ fn run() -> Result<(), error::CliError> {
     None => None,
   };
 
+  // A resumed encode keeps the pass data written before its checkpoint.
+  #[cfg(feature = "serialize")]
+  let resume = cli.checkpoint.as_ref().is_some_and(|c| c.resume);
+  #[cfg(not(feature = "serialize"))]
+  let resume = false;
+
   let pass1file = match cli.pass1file_name {
     Some(f) => {
-      let f = File::create(f).map_err(|e| {
-        e.context("Unable to open file for writing two-pass data")
-      })?;
+      let f = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(!resume)
+        .open(f)
+        .map_err(|e| {
+          e.context("Unable to open file for writing two-pass data")
+        })?;
       rc = rc.with_emit_data(true);
       Some(f)
     }
@@ -499,20 +582,14 @@ fn run() -> Result<(), error::CliError> {
     }
   }
 
-  cli.io.output.write_header(
-    video_info.width,
-    video_info.height,
-    cli.enc.time_base.den as usize,
-    cli.enc.time_base.num as usize,
-  );
-
   let tiling =
     cfg.tiling_info().map_err(|e| e.context("Invalid configuration"))?;
   if cli.verbose != Verboseness::Quiet {
     info!("CPU Feature Level: {}", CpuFeatureLevel::default());
 
     info!(
-      "Using y4m decoder: {}x{}p @ {}/{} fps, {}, {}-bit",
+      "Using {} decoder: {}x{}p @ {}/{} fps, {}, {}-bit",
+      y4m_dec.name(),
       video_info.width,
       video_info.height,
       video_info.time_base.den,
@@ -536,12 +613,28 @@ fn run() -> Result<(), error::CliError> {
 
   let progress = ProgressInfo::new(
     Rational { num: video_info.time_base.den, den: video_info.time_base.num },
-    if cli.limit == 0 { None } else { Some(cli.limit) },
+    match cli.grid {
+      Some((grid, _)) => Some(grid.rows * grid.columns),
+      None if cli.limit == 0 => None,
+      None => Some(cli.limit),
+    },
     cli.metrics_enabled,
   );
+  let stats_file = cli
+    .stats_file_name
+    .map(|path| {
+      StatsWriter::create(
+        &path,
+        Rational {
+          num: video_info.time_base.den,
+          den: video_info.time_base.num,
+        },
+      )
+    })
+    .transpose()?;
 
   for _ in 0..cli.skip {
-    match y4m_dec.read_frame() {
+    match y4m_dec.skip_frame() {
       Ok(f) => f,
       Err(_) => {
         return Err(CliError::new("Skipped more frames than in the input"))
@@ -549,11 +642,118 @@ fn run() -> Result<(), error::CliError> {
     };
   }
 
-  let source = Source::new(cli.limit, y4m_dec);
+  // The timecode counts the skipped frames.
+  video_info.timecode = cli.timecode.or(video_info.timecode);
+  video_info.timecode = video_info.frame_timecode(cli.skip as u64);
+
+  // The alpha channel is only read when it is coded.
+  video_info.alpha &= cli.enc.alpha_quantizer.is_some();
+  let source = Source::new(cli.limit, y4m_dec, video_info);
+
+  #[cfg(feature = "serialize")]
+  if let Some(options) = cli.checkpoint {
+    if video_info.bit_depth == 8 && !cli.force_highbitdepth {
+      checkpoint::do_encode_resumable::<u8>(
+        cfg,
+        &cli.enc,
+        cli.verbose,
+        progress,
+        &mut *cli.io.output,
+        source,
+        pass1file,
+        pass2file,
+        verifier,
+        cli.metrics_enabled,
+        options,
+      )?
+    } else {
+      checkpoint::do_encode_resumable::<u16>(
+        cfg,
+        &cli.enc,
+        cli.verbose,
+        progress,
+        &mut *cli.io.output,
+        source,
+        pass1file,
+        pass2file,
+        verifier,
+        cli.metrics_enabled,
+        options,
+      )?
+    }
+    if cli.benchmark {
+      print_rusage();
+    }
+    return Ok(());
+  }
 
-  if video_info.bit_depth == 8 && !cli.force_highbitdepth {
-    do_encode::<u8, y4m::Decoder<Box<dyn Read + Send>>>(
+  if let Some((grid, _)) = cli.grid {
+    if video_info.bit_depth == 8 && !cli.force_highbitdepth {
+      grid::do_encode_grid::<u8, InputDecoder>(
+        cfg,
+        &cli.enc,
+        &grid,
+        cli.verbose,
+        progress,
+        &mut *cli.io.output,
+        source,
+        cli.metrics_enabled,
+        stats_file,
+      )?
+    } else {
+      grid::do_encode_grid::<u16, InputDecoder>(
+        cfg,
+        &cli.enc,
+        &grid,
+        cli.verbose,
+        progress,
+        &mut *cli.io.output,
+        source,
+        cli.metrics_enabled,
+        stats_file,
+      )?
+    }
+  } else if let Some((workers, passes)) = cli.chunks {
+    if cli.verbose != Verboseness::Quiet {
+      info!(
+        "Encoding {workers} chunks in parallel, in {passes} pass(es) each"
+      );
+    }
+    if video_info.bit_depth == 8 && !cli.force_highbitdepth {
+      chunks::do_encode_chunks::<u8, InputDecoder>(
+        cfg,
+        &cli.enc,
+        workers,
+        passes,
+        cli.verbose,
+        progress,
+        &mut *cli.io.output,
+        source,
+        y4m_enc,
+        verifier,
+        cli.metrics_enabled,
+        stats_file,
+      )?
+    } else {
+      chunks::do_encode_chunks::<u16, InputDecoder>(
+        cfg,
+        &cli.enc,
+        workers,
+        passes,
+        cli.verbose,
+        progress,
+        &mut *cli.io.output,
+        source,
+        y4m_enc,
+        verifier,
+        cli.metrics_enabled,
+        stats_file,
+      )?
+    }
+  } else if video_info.bit_depth == 8 && !cli.force_highbitdepth {
+    do_encode::<u8, InputDecoder>(
       cfg,
+      &cli.enc,
       cli.verbose,
       progress,
       &mut *cli.io.output,
@@ -561,11 +761,14 @@ fn run() -> Result<(), error::CliError> {
       pass1file,
       pass2file,
       y4m_enc,
+      verifier,
       cli.metrics_enabled,
+      stats_file,
     )?
   } else {
-    do_encode::<u16, y4m::Decoder<Box<dyn Read + Send>>>(
+    do_encode::<u16, InputDecoder>(
       cfg,
+      &cli.enc,
       cli.verbose,
       progress,
       &mut *cli.io.output,
@@ -573,7 +776,9 @@ fn run() -> Result<(), error::CliError> {
       pass1file,
       pass2file,
       y4m_enc,
+      verifier,
       cli.metrics_enabled,
+      stats_file,
     )?
   }
   if cli.benchmark {
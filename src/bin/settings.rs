@@ -0,0 +1,344 @@
+// Copyright (c) 2017-2022, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+//! The settings of the encoder configuration, addressed by keys such as
+//! `speed_settings.partition.encode_bottomup`, which a loaded configuration
+//! file and `--set` give on top of the command line options.
+
+use rav1e::prelude::*;
+use toml::Value;
+
+use crate::decoder::VideoDetails;
+use crate::error::*;
+
+/// The settings of the encoder configuration set by each command line
+/// option, which override the ones of a loaded configuration file when the
+/// option is given.
+pub const OPTION_SETTINGS: &[(&str, &[&str])] = &[
+  ("quantizer", &["quantizer"]),
+  ("min_quantizer", &["min_quantizer"]),
+  // The quantizer is unconstrained by default with a bitrate.
+  ("bitrate", &["bitrate", "quantizer"]),
+  ("speed", &["speed_settings"]),
+  ("scd_speed", &["speed_settings.scene_detection_mode"]),
+  ("min_keyint", &["min_key_frame_interval"]),
+  ("keyint", &["max_key_frame_interval"]),
+  ("switch_frame_interval", &["switch_frame_interval"]),
+  ("open_gop", &["open_gop"]),
+  ("reservoir_frame_delay", &["reservoir_frame_delay"]),
+  ("low_latency", &["low_latency"]),
+  ("drop_frames", &["drop_frames"]),
+  ("no_scene_detection", &["speed_settings.scene_detection_mode"]),
+  ("rdo_lookahead_frames", &["speed_settings.rdo_lookahead_frames"]),
+  ("tune", &["tune", "speed_settings.transform.tx_domain_distortion"]),
+  ("still_picture", &["still_picture"]),
+  ("alpha_quantizer", &["alpha_quantizer"]),
+  ("film_grain_table", &["film_grain_params"]),
+  ("denoise_noise_level", &["denoise_noise_level"]),
+  ("denoise_temporal_radius", &["denoise_temporal_radius"]),
+  ("no_denoise_grain", &["denoise_grain_synthesis"]),
+  ("zones", &["zones"]),
+  ("keyframes", &["forced_keyframes"]),
+  ("tile_rows", &["tile_rows"]),
+  ("tile_cols", &["tile_cols"]),
+  ("tiles", &["tiles"]),
+  ("range", &["pixel_range"]),
+  ("primaries", &["color_description"]),
+  ("transfer", &["color_description"]),
+  ("matrix", &["color_description"]),
+  ("mastering_display", &["mastering_display"]),
+  ("content_light", &["content_light"]),
+  ("level", &["level_idx"]),
+  ("frame_rate", &["time_base"]),
+  ("time_scale", &["time_base"]),
+];
+
+/// A setting given with `--set key=value`.
+pub struct Setting {
+  /// The full key of the setting, with the `speed_settings` prefix which
+  /// may be left out on the command line.
+  pub key: String,
+  /// The value of the setting, or `None` to unset an optional one.
+  value: Option<Value>,
+}
+
+impl Setting {
+  /// Parses a `key=value` argument, where the value is a TOML value or a
+  /// bare string such as `Psychovisual`, and `None` unsets the setting.
+  pub fn parse(arg: &str) -> Result<Self, CliError> {
+    let Some((key, value)) = arg.split_once('=') else {
+      return Err(CliError::new(&format!(
+        "Invalid setting `{arg}`, expected key=value"
+      )));
+    };
+    let (key, value) = (key.trim(), value.trim());
+
+    let keys = setting_keys();
+    let is_setting = |key: &str| {
+      keys.iter().any(|k| {
+        k == key
+          || key.strip_prefix(k.as_str()).is_some_and(|k| k.starts_with('.'))
+          || k.strip_prefix(key).is_some_and(|k| k.starts_with('.'))
+      })
+    };
+    let key = if is_setting(key) {
+      key.to_owned()
+    } else if is_setting(&format!("speed_settings.{key}")) {
+      format!("speed_settings.{key}")
+    } else {
+      return Err(CliError::new(&format!("Unknown setting `{key}`")));
+    };
+
+    let value = (value != "None").then(|| {
+      toml::from_str::<toml::Table>(&format!("value = {value}"))
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| Value::String(value.to_owned()))
+    });
+    Ok(Self { key, value })
+  }
+}
+
+/// Returns the keys of the settings, with their default values.
+pub fn setting_pairs() -> Vec<(String, String)> {
+  crate::kv::to_pairs(&EncoderConfig::default()).unwrap()
+}
+
+fn setting_keys() -> Vec<String> {
+  setting_pairs().into_iter().map(|(key, _)| key).collect()
+}
+
+fn to_tree(enc: &EncoderConfig) -> Result<Value, CliError> {
+  Value::try_from(enc).map_err(|e| e.context("Invalid encoder settings"))
+}
+
+fn get<'a>(tree: &'a Value, key: &str) -> Option<&'a Value> {
+  key.split('.').try_fold(tree, |tree, key| tree.get(key))
+}
+
+/// Sets a value of the tree, or removes it with `None`, adding the tables
+/// holding it if needed.
+fn set(tree: &mut Value, key: &str, value: Option<Value>) -> Option<()> {
+  let (path, key) = key.rsplit_once('.').unwrap_or(("", key));
+  let mut table = tree.as_table_mut()?;
+  for key in path.split('.').filter(|key| !key.is_empty()) {
+    table = table
+      .entry(key)
+      .or_insert_with(|| Value::Table(toml::Table::new()))
+      .as_table_mut()?;
+  }
+  match value {
+    Some(value) => table.insert(key.to_owned(), value),
+    None => table.remove(key),
+  };
+  Some(())
+}
+
+/// Returns the configuration of a loaded file, with the settings of the
+/// command line options which were given, failing if the minimum keyframe
+/// interval of one exceeds the maximum of the other.
+pub fn layer_config(
+  file: &EncoderConfig, cli: &EncoderConfig, given: impl Fn(&str) -> bool,
+) -> Result<EncoderConfig, CliError> {
+  let mut tree = to_tree(file)?;
+  let cli = to_tree(cli)?;
+  for (_, keys) in OPTION_SETTINGS.iter().filter(|(option, _)| given(option)) {
+    for key in keys.iter() {
+      set(&mut tree, key, get(&cli, key).cloned());
+    }
+  }
+  let enc: EncoderConfig =
+    tree.try_into().map_err(|e| e.context("Invalid configuration file"))?;
+  if enc.min_key_frame_interval > enc.max_key_frame_interval {
+    return Err(CliError::new(&format!(
+      "Invalid value for setting `min_key_frame_interval`: {} is greater \
+       than `max_key_frame_interval` {}",
+      enc.min_key_frame_interval, enc.max_key_frame_interval
+    )));
+  }
+  Ok(enc)
+}
+
+/// Applies the settings in order, failing at the first one whose value does
+/// not fit its key.
+pub fn apply_settings(
+  enc: &EncoderConfig, settings: &[Setting],
+) -> Result<EncoderConfig, CliError> {
+  let mut tree = to_tree(enc)?;
+  let mut enc = enc.clone();
+  for setting in settings {
+    let invalid = || {
+      CliError::new(&format!("Invalid value for setting `{}`", setting.key))
+    };
+    set(&mut tree, &setting.key, setting.value.clone()).ok_or_else(invalid)?;
+    enc = tree.clone().try_into().map_err(|e: toml::de::Error| {
+      CliError::new(&format!(
+        "Invalid value for setting `{}`: {}",
+        setting.key,
+        e.message()
+      ))
+    })?;
+  }
+  Ok(enc)
+}
+
+/// Checks that the configuration is valid for the input after each setting,
+/// to report the first one making it invalid. An invalid configuration
+/// without the settings is reported by the encoder instead.
+pub fn validate_settings(
+  enc: &EncoderConfig, settings: &[Setting], details: &VideoDetails,
+) -> Result<(), CliError> {
+  let validate = |enc: EncoderConfig| {
+    Config::new()
+      .with_encoder_config(EncoderConfig {
+        width: details.width,
+        height: details.height,
+        bit_depth: details.bit_depth,
+        chroma_sampling: details.chroma_sampling,
+        ..enc
+      })
+      .validate()
+  };
+  if validate(enc.clone()).is_err() {
+    return Ok(());
+  }
+  for i in 1..=settings.len() {
+    if let Err(e) = validate(apply_settings(enc, &settings[..i])?) {
+      let key = &settings[i - 1].key;
+      return Err(e.context(&format!("Invalid value for setting `{key}`")));
+    }
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::common::CliOptions;
+  use clap::parser::ValueSource;
+  use clap::CommandFactory;
+
+  #[test]
+  fn option_settings_exist() {
+    let matches = CliOptions::command()
+      .try_get_matches_from(["rav1e", "input.y4m", "-o", "output.ivf"])
+      .unwrap();
+    let keys = setting_keys();
+    for (option, settings) in OPTION_SETTINGS {
+      // Panics with an unknown option.
+      assert_ne!(matches.value_source(option), Some(ValueSource::CommandLine));
+      for setting in settings.iter() {
+        assert!(keys.iter().any(
+          |key| key == setting || key.starts_with(&format!("{setting}."))
+        ));
+      }
+    }
+
+    // The options which do not feed `parse_config`, for the input and
+    // output, the threads and debugging, or applied to the layered
+    // configuration.
+    let other_options = [
+      "input",
+      "width",
+      "height",
+      "pix_fmt",
+      "fps",
+      "output",
+      "overwrite",
+      "container",
+      "fragmented",
+      "grid",
+      "checkpoint",
+      "resume",
+      "threads",
+      "slots",
+      "parallel_chunks",
+      "chunk_passes",
+      "first_pass",
+      "second_pass",
+      "limit",
+      "skip",
+      "photon_noise",
+      "monochrome",
+      "high_bitdepth",
+      "set",
+      "timecode",
+      "benchmark",
+      "verbose",
+      "quiet",
+      "psnr",
+      "metrics",
+      "stats_file",
+      "reconstruction",
+      "verify",
+    ];
+    for arg in CliOptions::command().get_arguments() {
+      let id = arg.get_id().as_str();
+      assert!(
+        OPTION_SETTINGS.iter().any(|(option, _)| *option == id)
+          || other_options.contains(&id),
+        "option `{id}` is missing from OPTION_SETTINGS"
+      );
+    }
+  }
+
+  #[test]
+  fn apply_nested_settings() {
+    let settings = ["partition.encode_bottomup=false", "level_idx=31"]
+      .map(|arg| Setting::parse(arg).unwrap());
+    assert_eq!(settings[0].key, "speed_settings.partition.encode_bottomup");
+
+    let mut enc = EncoderConfig::with_speed_preset(0);
+    enc.level_idx = Some(8);
+    let enc = apply_settings(&enc, &settings).unwrap();
+    assert!(!enc.speed_settings.partition.encode_bottomup);
+    assert_eq!(enc.level_idx, Some(31));
+
+    let enc =
+      apply_settings(&enc, &[Setting::parse("level_idx=None").unwrap()])
+        .unwrap();
+    assert_eq!(enc.level_idx, None);
+  }
+
+  #[test]
+  fn invalid_settings() {
+    assert!(Setting::parse("partition.bottomup=false").is_err());
+    assert!(Setting::parse("quantizer").is_err());
+
+    let enc = EncoderConfig::default();
+    let settings = [Setting::parse("cdef=yes").unwrap()];
+    let err = apply_settings(&enc, &settings).err().unwrap().to_string();
+    assert!(err.starts_with("Invalid value for setting `speed_settings.cdef`"));
+  }
+
+  #[test]
+  fn layered_keyframe_intervals() {
+    let file = EncoderConfig {
+      min_key_frame_interval: 12,
+      max_key_frame_interval: 240,
+      ..Default::default()
+    };
+    let cli = EncoderConfig {
+      min_key_frame_interval: 4,
+      max_key_frame_interval: 4,
+      ..Default::default()
+    };
+
+    let enc = layer_config(&file, &cli, |_| true);
+    assert_eq!(enc.unwrap().min_key_frame_interval, 4);
+
+    let err = layer_config(&file, &cli, |option| option == "keyint")
+      .err()
+      .unwrap()
+      .to_string();
+    assert!(
+      err.starts_with("Invalid value for setting `min_key_frame_interval`")
+    );
+  }
+}
@@ -7,10 +7,14 @@
 // Media Patent License 1.0 was not distributed with this source code in the
 // PATENTS file, you can obtain it at www.aomedia.org/license/patent.
 
+use crate::error::*;
 use av_metrics::video::*;
 use rav1e::data::EncoderStats;
 use rav1e::prelude::*;
 use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
 use std::time::Instant;
 
 #[derive(Debug, Clone)]
@@ -19,12 +23,16 @@ pub struct FrameSummary {
   pub size: usize,
   pub input_frameno: u64,
   pub frame_type: FrameType,
+  /// Content transition detected at the frame.
+  pub scene_label: SceneLabel,
   /// Contains metrics such as PSNR, SSIM, etc.
   pub metrics: QualityMetrics,
   /// QP selected for the frame.
   pub qp: u8,
   /// Block-level encoding stats for the frame
   pub enc_stats: EncoderStats,
+  /// Chroma sampling of the frame, telling which planes the metrics cover.
+  pub chroma_sampling: ChromaSampling,
 }
 
 #[profiling::function]
@@ -45,9 +53,11 @@ pub fn build_frame_summary<T: Pixel>(
     size: packets.data.len(),
     input_frameno: packets.input_frameno,
     frame_type: packets.frame_type,
+    scene_label: packets.scene_label,
     metrics: encode_metrics,
     qp: packets.qp,
     enc_stats: packets.enc_stats,
+    chroma_sampling,
   }
 }
 
@@ -55,15 +65,24 @@ impl fmt::Display for FrameSummary {
   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
     write!(
       f,
-      "Input Frame {} - {} - {} bytes{}",
+      "Input Frame {} - {}{} - {} bytes{}",
       self.input_frameno,
       self.frame_type,
+      if self.scene_label == SceneLabel::Normal {
+        String::new()
+      } else {
+        format!(" ({})", self.scene_label)
+      },
       self.size,
       if let Some(psnr) = self.metrics.psnr {
-        format!(
-          " - PSNR: Y: {:.4}  Cb: {:.4}  Cr: {:.4}",
-          psnr.y, psnr.u, psnr.v
-        )
+        if self.chroma_sampling == ChromaSampling::Cs400 {
+          format!(" - PSNR: Y: {:.4}", psnr.y)
+        } else {
+          format!(
+            " - PSNR: Y: {:.4}  Cb: {:.4}  Cr: {:.4}",
+            psnr.y, psnr.u, psnr.v
+          )
+        }
       } else {
         String::new()
       }
@@ -357,10 +376,16 @@ impl ProgressInfo {
     let psnr_v = sum_metric(&self.frame_info, |fi| fi.metrics.psnr.unwrap().v);
     let psnr_avg =
       sum_metric(&self.frame_info, |fi| fi.metrics.psnr.unwrap().avg);
-    info!(
-      "Mean PSNR: Avg: {:.4}  Y: {:.4}  Cb: {:.4}  Cr: {:.4}",
-      psnr_avg, psnr_y, psnr_u, psnr_v
-    );
+    if self.frame_info.first().map(|fi| fi.chroma_sampling)
+      == Some(ChromaSampling::Cs400)
+    {
+      info!("Mean PSNR: Y: {:.4}", psnr_y);
+    } else {
+      info!(
+        "Mean PSNR: Avg: {:.4}  Y: {:.4}  Cb: {:.4}  Cr: {:.4}",
+        psnr_avg, psnr_y, psnr_u, psnr_v
+      );
+    }
   }
   fn print_video_all(&self) {
     info!("----------");
@@ -369,10 +394,13 @@ impl ProgressInfo {
     let ssim = sum_metric(&self.frame_info, |fi| fi.metrics.ssim.unwrap().avg);
     let ms_ssim =
       sum_metric(&self.frame_info, |fi| fi.metrics.ms_ssim.unwrap().avg);
-    let ciede = sum_metric(&self.frame_info, |fi| fi.metrics.ciede.unwrap());
     info!("PSNR HVS: {:.4}", psnr_hvs);
     info!("SSIM: {:.4}  MS SSIM: {:.4}", ssim, ms_ssim);
-    info!("CIEDE2000: {:.4}", ciede);
+    // CIEDE2000 is not computed for monochrome frames.
+    if self.frame_info.iter().all(|fi| fi.metrics.ciede.is_some()) {
+      let ciede = sum_metric(&self.frame_info, |fi| fi.metrics.ciede.unwrap());
+      info!("CIEDE2000: {:.4}", ciede);
+    }
     info!("----------");
   }
 
@@ -710,6 +738,347 @@ fn secs_to_human_time(mut secs: u64) -> String {
   }
 }
 
+/// Names of the block sizes, in the order of their counts in `EncoderStats`.
+const BLOCK_SIZE_NAMES: [&str; BlockSize::BLOCK_SIZES_ALL] = [
+  "4x4", "4x8", "8x4", "8x8", "8x16", "16x8", "16x16", "16x32", "32x16",
+  "32x32", "32x64", "64x32", "64x64", "64x128", "128x64", "128x128", "4x16",
+  "16x4", "8x32", "32x8", "16x64", "64x16",
+];
+
+/// Names of the transform types, in the order of their counts in
+/// `EncoderStats`.
+const TX_TYPE_NAMES: [&str; 16] = [
+  "DCT_DCT",
+  "ADST_DCT",
+  "DCT_ADST",
+  "ADST_ADST",
+  "FLIPADST_DCT",
+  "DCT_FLIPADST",
+  "FLIPADST_FLIPADST",
+  "ADST_FLIPADST",
+  "FLIPADST_ADST",
+  "IDTX",
+  "V_DCT",
+  "H_DCT",
+  "V_ADST",
+  "H_ADST",
+  "V_FLIPADST",
+  "H_FLIPADST",
+];
+
+/// Names of the prediction modes, in the order of their counts in
+/// `EncoderStats`.
+const PREDICTION_MODE_NAMES: [&str; 34] = [
+  "DC_PRED",
+  "V_PRED",
+  "H_PRED",
+  "D45_PRED",
+  "D135_PRED",
+  "D113_PRED",
+  "D157_PRED",
+  "D203_PRED",
+  "D67_PRED",
+  "SMOOTH_PRED",
+  "SMOOTH_V_PRED",
+  "SMOOTH_H_PRED",
+  "PAETH_PRED",
+  "UV_CFL_PRED",
+  "NEARESTMV",
+  "NEAR0MV",
+  "NEAR1MV",
+  "NEAR2MV",
+  "GLOBALMV",
+  "NEWMV",
+  "NEAREST_NEARESTMV",
+  "NEAR_NEAR0MV",
+  "NEAR_NEAR1MV",
+  "NEAR_NEAR2MV",
+  "NEAREST_NEWMV",
+  "NEW_NEARESTMV",
+  "NEAR_NEW0MV",
+  "NEAR_NEW1MV",
+  "NEAR_NEW2MV",
+  "NEW_NEAR0MV",
+  "NEW_NEAR1MV",
+  "NEW_NEAR2MV",
+  "GLOBAL_GLOBALMV",
+  "NEW_NEWMV",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatsFormat {
+  /// A JSON object per line.
+  JsonLines,
+  /// Comma-separated values, after a header line.
+  Csv,
+}
+
+enum StatsValue {
+  Int(u64),
+  Float(f64),
+  Str(String),
+  None,
+}
+
+impl From<Option<f64>> for StatsValue {
+  fn from(value: Option<f64>) -> Self {
+    // PSNR is infinite for identical planes, which JSON cannot represent.
+    value.filter(|v| v.is_finite()).map_or(StatsValue::None, StatsValue::Float)
+  }
+}
+
+/// Writes a record per encoded frame, then a summary record aggregating
+/// them, with the same fields in either format. The histograms count the
+/// pixels of each block size, transform type and prediction mode.
+pub struct StatsWriter {
+  output: BufWriter<File>,
+  format: StatsFormat,
+  frame_rate: Rational,
+  time_started: Instant,
+  frames: u64,
+  bytes: u64,
+  qp_sum: u64,
+  /// The sums of the metrics, with the mean squared errors of the PSNRs.
+  metrics_sum: [Option<f64>; 8],
+  enc_stats: EncoderStats,
+}
+
+impl StatsWriter {
+  /// Creates a stats file, in CSV when its extension is `csv` and in JSON
+  /// Lines otherwise.
+  pub fn create(path: &Path, frame_rate: Rational) -> Result<Self, CliError> {
+    let format = match path.extension().and_then(|e| e.to_str()) {
+      Some(e) if e.eq_ignore_ascii_case("csv") => StatsFormat::Csv,
+      _ => StatsFormat::JsonLines,
+    };
+    let output = BufWriter::new(
+      File::create(path)
+        .map_err(|e| e.context("Cannot create the stats file"))?,
+    );
+    let mut stats = StatsWriter {
+      output,
+      format,
+      frame_rate,
+      time_started: Instant::now(),
+      frames: 0,
+      bytes: 0,
+      qp_sum: 0,
+      metrics_sum: [None; 8],
+      enc_stats: EncoderStats::default(),
+    };
+    if format == StatsFormat::Csv {
+      let names = Self::fields(&FrameRecord::default(), "")
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect::<Vec<_>>();
+      writeln!(stats.output, "{}", names.join(","))
+        .map_err(|e| e.context("Cannot write the stats file"))?;
+    }
+    Ok(stats)
+  }
+
+  pub fn write_frame(&mut self, frame: &FrameSummary) -> io::Result<()> {
+    let metrics = frame_metrics(&frame.metrics);
+    self.frames += 1;
+    self.bytes += frame.size as u64;
+    self.qp_sum += u64::from(frame.qp);
+    for (i, (sum, metric)) in
+      self.metrics_sum.iter_mut().zip(metrics).enumerate()
+    {
+      let metric =
+        if i < PSNR_METRICS { metric.map(psnr_to_mse) } else { metric };
+      *sum = metric.map(|metric| sum.unwrap_or_default() + metric);
+    }
+    self.enc_stats += &frame.enc_stats;
+
+    let record = FrameRecord {
+      frame: Some(self.frames - 1),
+      input_frame: Some(frame.input_frameno),
+      frame_type: Some(format!("{:?}", frame.frame_type)),
+      scene_label: Some(format!("{:?}", frame.scene_label)),
+      qp: Some(f64::from(frame.qp)),
+      bytes: frame.size as u64,
+      time: self.elapsed(),
+      bitrate: None,
+      fps: None,
+      metrics,
+      enc_stats: Some(&frame.enc_stats),
+    };
+    self.write_record(&record, "frame")
+  }
+
+  /// Writes the summary record, with the totals of the sizes and
+  /// histograms, and the means of the QPs and metrics. The PSNRs are those
+  /// of the mean squared errors of all frames, rather than the means of the
+  /// PSNRs of the frames printed in the console summary, which frames
+  /// identical to their source would dominate. Without frames, the bitrate
+  /// and frame rate are null.
+  pub fn write_summary(&mut self) -> io::Result<()> {
+    let frames = self.frames.max(1) as f64;
+    let time = self.elapsed();
+    let seconds = self.frames as f64 * self.frame_rate.den as f64
+      / self.frame_rate.num as f64;
+    let enc_stats = self.enc_stats.clone();
+    let record = FrameRecord {
+      frame: Some(self.frames),
+      input_frame: None,
+      frame_type: None,
+      scene_label: None,
+      qp: Some(self.qp_sum as f64 / frames),
+      bytes: self.bytes,
+      time,
+      bitrate: (self.frames > 0).then(|| self.bytes as f64 * 8. / seconds),
+      fps: (self.frames > 0).then(|| self.frames as f64 / time),
+      metrics: std::array::from_fn(|i| {
+        let mean = self.metrics_sum[i].map(|sum| sum / frames);
+        if i < PSNR_METRICS {
+          mean.map(mse_to_psnr)
+        } else {
+          mean
+        }
+      }),
+      enc_stats: Some(&enc_stats),
+    };
+    self.write_record(&record, "summary")?;
+    self.output.flush()
+  }
+
+  /// Returns the seconds since the start of the encode, to the microsecond.
+  fn elapsed(&self) -> f64 {
+    (self.time_started.elapsed().as_secs_f64() * 1e6).round() / 1e6
+  }
+
+  fn write_record(
+    &mut self, record: &FrameRecord, kind: &str,
+  ) -> io::Result<()> {
+    let fields = Self::fields(record, kind);
+    let format_value = |value: &StatsValue, json: bool| match value {
+      StatsValue::Int(v) => v.to_string(),
+      StatsValue::Float(v) => v.to_string(),
+      StatsValue::Str(v) if json => format!("\"{v}\""),
+      StatsValue::Str(v) => v.clone(),
+      StatsValue::None if json => "null".to_owned(),
+      StatsValue::None => String::new(),
+    };
+    match self.format {
+      StatsFormat::JsonLines => {
+        let fields = fields
+          .iter()
+          .map(|(name, value)| {
+            format!("\"{name}\":{}", format_value(value, true))
+          })
+          .collect::<Vec<_>>();
+        writeln!(self.output, "{{{}}}", fields.join(","))
+      }
+      StatsFormat::Csv => {
+        let fields = fields
+          .iter()
+          .map(|(_, value)| format_value(value, false))
+          .collect::<Vec<_>>();
+        writeln!(self.output, "{}", fields.join(","))
+      }
+    }
+  }
+
+  /// Returns the fields of a record, in their order in the file.
+  fn fields(record: &FrameRecord, kind: &str) -> Vec<(String, StatsValue)> {
+    let int = |v: Option<u64>| v.map_or(StatsValue::None, StatsValue::Int);
+    let string =
+      |v: &Option<String>| v.clone().map_or(StatsValue::None, StatsValue::Str);
+    let mut fields = vec![
+      ("record".to_owned(), StatsValue::Str(kind.to_owned())),
+      ("frame".to_owned(), int(record.frame)),
+      ("input_frame".to_owned(), int(record.input_frame)),
+      ("frame_type".to_owned(), string(&record.frame_type)),
+      ("scene_label".to_owned(), string(&record.scene_label)),
+      ("qp".to_owned(), record.qp.into()),
+      ("bytes".to_owned(), StatsValue::Int(record.bytes)),
+      ("time".to_owned(), StatsValue::Float(record.time)),
+      ("bitrate".to_owned(), record.bitrate.into()),
+      ("fps".to_owned(), record.fps.into()),
+    ];
+    let metric_names = [
+      "psnr_y", "psnr_u", "psnr_v", "psnr", "psnr_hvs", "ssim", "ms_ssim",
+      "ciede",
+    ];
+    for (name, &value) in metric_names.iter().zip(record.metrics.iter()) {
+      fields.push((name.to_string(), value.into()));
+    }
+    let enc_stats = record.enc_stats.cloned().unwrap_or_default();
+    fields.push((
+      "skip".to_owned(),
+      StatsValue::Int(enc_stats.skip_block_count as u64),
+    ));
+    let histograms = [
+      ("block_size", &BLOCK_SIZE_NAMES[..], &enc_stats.block_size_counts[..]),
+      ("tx_type", &TX_TYPE_NAMES[..], &enc_stats.tx_type_counts[..]),
+      (
+        "luma_mode",
+        &PREDICTION_MODE_NAMES[..],
+        &enc_stats.luma_pred_mode_counts[..],
+      ),
+      (
+        "chroma_mode",
+        &PREDICTION_MODE_NAMES[..],
+        &enc_stats.chroma_pred_mode_counts[..],
+      ),
+    ];
+    for (prefix, names, counts) in histograms {
+      for (name, &count) in names.iter().zip(counts) {
+        fields
+          .push((format!("{prefix}_{name}"), StatsValue::Int(count as u64)));
+      }
+    }
+    fields
+  }
+}
+
+/// The fields of a frame or summary record.
+#[derive(Default)]
+struct FrameRecord<'a> {
+  frame: Option<u64>,
+  input_frame: Option<u64>,
+  frame_type: Option<String>,
+  scene_label: Option<String>,
+  qp: Option<f64>,
+  bytes: u64,
+  /// Seconds since the start of the encode.
+  time: f64,
+  bitrate: Option<f64>,
+  fps: Option<f64>,
+  metrics: [Option<f64>; 8],
+  enc_stats: Option<&'a EncoderStats>,
+}
+
+/// The number of PSNRs leading the metrics of the records.
+const PSNR_METRICS: usize = 4;
+
+/// Returns the mean squared error, relative to the squared peak value, of
+/// a PSNR.
+fn psnr_to_mse(psnr: f64) -> f64 {
+  10f64.powf(-psnr / 10.)
+}
+
+/// Returns the PSNR of a mean squared error relative to the squared peak
+/// value.
+fn mse_to_psnr(mse: f64) -> f64 {
+  -10. * mse.log10()
+}
+
+/// Returns the metrics of a frame in the order of the records.
+fn frame_metrics(metrics: &QualityMetrics) -> [Option<f64>; 8] {
+  [
+    metrics.psnr.map(|psnr| psnr.y),
+    metrics.psnr.map(|psnr| psnr.u),
+    metrics.psnr.map(|psnr| psnr.v),
+    metrics.psnr.map(|psnr| psnr.avg),
+    metrics.psnr_hvs.map(|psnr_hvs| psnr_hvs.avg),
+    metrics.ssim.map(|ssim| ssim.avg),
+    metrics.ms_ssim.map(|ms_ssim| ms_ssim.avg),
+    metrics.ciede,
+  ]
+}
+
 #[derive(Debug, Clone, Copy, Default, PartialEq)]
 pub struct QualityMetrics {
   /// Peak Signal-to-Noise Ratio for Y, U, and V planes
@@ -751,6 +1120,36 @@ pub fn calculate_frame_metrics<T: Pixel>(
       ),
       ..Default::default()
     },
+    MetricsEnabled::All if cs == ChromaSampling::Cs400 => {
+      // The metrics below expect chroma planes, so they are computed on the
+      // luma plane standing in for all three. CIEDE2000 is a color
+      // difference and is left out.
+      let (frame1, frame2) = (luma_as_444(frame1), luma_as_444(frame2));
+      let cs = ChromaSampling::Cs444;
+      let luma = |metrics: PlanarMetrics| PlanarMetrics {
+        y: metrics.y,
+        u: metrics.y,
+        v: metrics.y,
+        avg: metrics.y,
+      };
+      QualityMetrics {
+        psnr: Some(luma(
+          psnr::calculate_frame_psnr(&frame1, &frame2, bit_depth, cs).unwrap(),
+        )),
+        psnr_hvs: Some(luma(
+          psnr_hvs::calculate_frame_psnr_hvs(&frame1, &frame2, bit_depth, cs)
+            .unwrap(),
+        )),
+        ssim: Some(luma(
+          ssim::calculate_frame_ssim(&frame1, &frame2, bit_depth, cs).unwrap(),
+        )),
+        ms_ssim: Some(luma(
+          ssim::calculate_frame_msssim(&frame1, &frame2, bit_depth, cs)
+            .unwrap(),
+        )),
+        ..Default::default()
+      }
+    }
     MetricsEnabled::All => {
       let mut metrics = QualityMetrics {
         psnr: Some(
@@ -775,3 +1174,8 @@ pub fn calculate_frame_metrics<T: Pixel>(
     }
   }
 }
+
+fn luma_as_444<T: Pixel>(frame: &Frame<T>) -> Frame<T> {
+  let luma = &frame.planes[0];
+  Frame { planes: [luma.clone(), luma.clone(), luma.clone()] }
+}
@@ -0,0 +1,234 @@
+// Copyright (c) 2017-2022, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+//! Verification of the encoded frames, which are decoded with dav1d and
+//! compared to the reconstruction of the encoder.
+
+#[cfg(feature = "decode_test_dav1d")]
+pub use self::dav1d::Verifier;
+#[cfg(not(feature = "decode_test_dav1d"))]
+pub use self::unsupported::Verifier;
+
+#[cfg(feature = "decode_test_dav1d")]
+mod dav1d {
+  use std::mem::{self, MaybeUninit};
+  use std::{ptr, slice};
+
+  use dav1d_sys::*;
+  use rav1e::prelude::*;
+
+  use crate::decoder::VideoDetails;
+  use crate::error::*;
+
+  /// Decodes the packets of an encode with dav1d, and checks that every
+  /// decoded frame is bit-exact with the reconstruction of the encoder.
+  pub struct Verifier {
+    dec: *mut Dav1dContext,
+    details: VideoDetails,
+  }
+
+  impl Verifier {
+    pub fn new(details: VideoDetails) -> Result<Self, CliError> {
+      // SAFETY: The settings are initialized by dav1d_default_settings().
+      let mut settings = unsafe {
+        let mut settings = MaybeUninit::uninit();
+        dav1d_default_settings(settings.as_mut_ptr());
+        settings.assume_init()
+      };
+      // The reconstruction does not include the film grain, and each frame
+      // is compared as soon as its packet is decoded.
+      settings.apply_grain = 0;
+      settings.n_threads = 1;
+      settings.max_frame_delay = 1;
+
+      let mut dec = ptr::null_mut();
+      // SAFETY: The settings are valid, and dav1d_open() sets the decoder.
+      if unsafe { dav1d_open(&mut dec, &settings) } != 0 {
+        return Err(CliError::new("Cannot create the dav1d decoder"));
+      }
+      Ok(Self { dec, details })
+    }
+
+    /// Decodes the packet of an input frame, and compares the frame it
+    /// shows to its reconstruction, which the packet must have.
+    pub fn verify<T: Pixel>(
+      &mut self, input_frameno: u64, packet: &[u8], rec: Option<&Frame<T>>,
+    ) -> Result<(), CliError> {
+      let fail = |msg: &str| {
+        CliError::new(&format!(
+          "Verification of frame {input_frameno} failed: {msg}"
+        ))
+      };
+      let rec = rec.ok_or_else(|| fail("the packet has no reconstruction"))?;
+
+      let mut data = SafeDav1dData::new(packet);
+      let mut shown = 0;
+      while data.0.sz > 0 {
+        let ret = data.send(self.dec);
+        if ret != 0 && ret != DAV1D_ERR_AGAIN {
+          return Err(fail("dav1d cannot decode the packet"));
+        }
+        loop {
+          let mut pic = SafeDav1dPicture::default();
+          let ret = pic.get(self.dec);
+          if ret == DAV1D_ERR_AGAIN {
+            break;
+          }
+          if ret != 0 {
+            return Err(fail("dav1d cannot decode the packet"));
+          }
+          shown += 1;
+          self.compare(&pic.0, rec).map_err(|msg| fail(&msg))?;
+        }
+      }
+      if shown != 1 {
+        return Err(fail(&format!(
+          "dav1d output {shown} frames instead of 1"
+        )));
+      }
+      Ok(())
+    }
+
+    /// Returns where the decoded picture first differs from the
+    /// reconstruction.
+    fn compare<T: Pixel>(
+      &self, pic: &Dav1dPicture, rec: &Frame<T>,
+    ) -> Result<(), String> {
+      let details = self.details;
+      if pic.p.w as usize != details.width
+        || pic.p.h as usize != details.height
+      {
+        return Err(format!(
+          "the decoded frame is {}x{} instead of {}x{}",
+          pic.p.w, pic.p.h, details.width, details.height
+        ));
+      }
+
+      let planes =
+        if details.chroma_sampling == ChromaSampling::Cs400 { 1 } else { 3 };
+      let (chroma_width, chroma_height) = details
+        .chroma_sampling
+        .get_chroma_dimensions(details.width, details.height);
+      for (pli, plane) in rec.planes[..planes].iter().enumerate() {
+        let (width, height) = if pli == 0 {
+          (details.width, details.height)
+        } else {
+          (chroma_width, chroma_height)
+        };
+        let stride = pic.stride[pli.min(1)] as usize;
+        let rec_lines = plane.data_origin().chunks(plane.cfg.stride);
+        for (y, rec_line) in rec_lines.take(height).enumerate() {
+          // SAFETY: The lines of the picture are `stride` bytes apart, and
+          // hold `width` pixels.
+          let x = unsafe {
+            let line = (pic.data[pli] as *const u8).add(y * stride);
+            if details.bit_depth > 8 {
+              slice::from_raw_parts(line as *const u16, width)
+                .iter()
+                .zip(rec_line)
+                .position(|(&dec, &rec)| dec != u16::cast_from(rec))
+            } else {
+              slice::from_raw_parts(line, width)
+                .iter()
+                .zip(rec_line)
+                .position(|(&dec, &rec)| dec != u8::cast_from(rec))
+            }
+          };
+          if let Some(x) = x {
+            return Err(format!(
+              "the decoded frame differs from the reconstruction in plane \
+               {pli} at ({x}, {y})"
+            ));
+          }
+        }
+      }
+      Ok(())
+    }
+  }
+
+  impl Drop for Verifier {
+    fn drop(&mut self) {
+      // SAFETY: The decoder was created by dav1d_open().
+      unsafe { dav1d_close(&mut self.dec) };
+    }
+  }
+
+  struct SafeDav1dData(Dav1dData);
+
+  impl SafeDav1dData {
+    fn new(packet: &[u8]) -> Self {
+      // SAFETY: dav1d_data_create() allocates `packet.len()` bytes.
+      unsafe {
+        let mut data = Self(mem::zeroed());
+        let ptr = dav1d_data_create(&mut data.0, packet.len());
+        ptr::copy_nonoverlapping(packet.as_ptr(), ptr, packet.len());
+        data
+      }
+    }
+
+    fn send(&mut self, context: *mut Dav1dContext) -> i32 {
+      // SAFETY: The data was created by dav1d_data_create().
+      unsafe { dav1d_send_data(context, &mut self.0) }
+    }
+  }
+
+  impl Drop for SafeDav1dData {
+    fn drop(&mut self) {
+      // SAFETY: The data was created by dav1d_data_create().
+      unsafe { dav1d_data_unref(&mut self.0) };
+    }
+  }
+
+  struct SafeDav1dPicture(Dav1dPicture);
+
+  impl Default for SafeDav1dPicture {
+    fn default() -> Self {
+      // SAFETY: A zeroed picture is an empty one.
+      Self(unsafe { mem::zeroed() })
+    }
+  }
+
+  impl SafeDav1dPicture {
+    fn get(&mut self, context: *mut Dav1dContext) -> i32 {
+      // SAFETY: The picture is empty.
+      unsafe { dav1d_get_picture(context, &mut self.0) }
+    }
+  }
+
+  impl Drop for SafeDav1dPicture {
+    fn drop(&mut self) {
+      // SAFETY: The picture is either empty or returned by dav1d.
+      unsafe { dav1d_picture_unref(&mut self.0) }
+    }
+  }
+}
+
+#[cfg(not(feature = "decode_test_dav1d"))]
+mod unsupported {
+  use rav1e::prelude::*;
+
+  use crate::decoder::VideoDetails;
+  use crate::error::*;
+
+  /// The frames cannot be verified without dav1d, so `--verify` is only
+  /// available with the `decode_test_dav1d` feature.
+  pub enum Verifier {}
+
+  impl Verifier {
+    pub fn new(_details: VideoDetails) -> Result<Self, CliError> {
+      Err(CliError::new("Verification requires the decode_test_dav1d feature"))
+    }
+
+    pub fn verify<T: Pixel>(
+      &mut self, _input_frameno: u64, _packet: &[u8], _rec: Option<&Frame<T>>,
+    ) -> Result<(), CliError> {
+      match *self {}
+    }
+  }
+}
@@ -210,6 +210,10 @@ impl EncContext {
       frame_type_override: frame_type,
       opaque,
       t35_metadata,
+      hdr10plus: None,
+      dolby_vision_rpu: None,
+      timecode: None,
+      alpha: None,
     };
     if let Some(frame) = frame {
       match (self, frame) {
@@ -548,6 +552,9 @@ pub unsafe extern fn rav1e_config_set_time_base(
 /// enum types `RaChromaSampling` and `RaChromaSamplePosition` respectively.
 /// Valid values for `pixel_range` are 0 and 1.
 ///
+/// `RA_CHROMA_SAMPLING_CS400` encodes a monochrome stream: only the luma
+/// plane of the frames is read and `chroma_pos` is not signaled.
+///
 /// Returns a negative value on error or 0.
 #[no_mangle]
 pub unsafe extern fn rav1e_config_set_pixel_format(
@@ -926,8 +933,10 @@ pub unsafe extern fn rav1e_frame_add_t35_metadata(
 /// last call to `rav1e_receive_packet`, if any (i.e., `RA_ENCODER_STATUS_ENCODED`
 /// was returned). It needs to be called once more after
 /// `RA_ENCODER_STATUS_LIMIT_REACHED` is returned, to retrieve the header that
-/// should be written to the front of the stats file (overwriting the
-/// placeholder header that was emitted at the start of encoding).
+/// should be written to the front of the stats file (replacing the
+/// placeholder header that was emitted at the start of encoding). The header
+/// lists the keyframes placed during the encode, so it is usually larger than
+/// the placeholder.
 ///
 /// It is still safe to call this function when `rav1e_receive_packet()` returns any
 /// other error. It will return `NULL` instead of returning a duplicate copy
@@ -978,8 +987,10 @@ pub enum RcDataKind {
 
 /// Return the Rate Control Summary Packet size
 ///
-/// It is useful mainly to preserve space when saving
-/// both Rate Control Summary and Frame Packets in a single file
+/// The summary lists the keyframes placed so far, so its size is only final
+/// once the encoder is flushed. It used to be constant: space reserved for
+/// the summary with the size returned before the end of the encode may have
+/// to be resized.
 #[no_mangle]
 pub unsafe extern fn rav1e_rc_summary_size(ctx: *const Context) -> size_t {
   (*ctx).ctx.rc_summary_size() as size_t + 8
@@ -1292,7 +1303,7 @@ fn rav1e_frame_extract_plane_internal<T: rav1e::Pixel>(
 /// Fill a frame plane
 ///
 /// Currently the frame contains 3 planes, the first is luminance followed by
-/// chrominance.
+/// chrominance. Monochrome frames only need their first plane filled.
 ///
 /// The data is copied and this function has to be called for each plane.
 ///
@@ -1325,7 +1336,7 @@ pub unsafe extern fn rav1e_frame_fill_plane(
 /// extracting the source and reconstruction data from a `RaPacket`.
 ///
 /// Currently the frame contains 3 planes, the first is luminance followed by
-/// chrominance.
+/// chrominance. The chroma planes of monochrome frames are empty.
 ///
 /// The data is copied out of the frame for a single plane.
 ///
@@ -1792,6 +1792,11 @@ impl<'a> ContextWriter<'a> {
 
     let is_inter = pred_mode >= PredictionMode::NEARESTMV;
 
+    // Lossless blocks do not signal their transform type, and are coded as
+    // DCT_DCT ones.
+    let lossless = tx_type == TxType::WHT_WHT;
+    let tx_type = if lossless { TxType::DCT_DCT } else { tx_type };
+
     // Note: Both intra and inter mode uses inter scan order. Surprised?
     let scan: &[u16] = &av1_scan_orders[tx_size as usize][tx_type as usize]
       .scan[..usize::from(eob)];
@@ -1837,7 +1842,7 @@ impl<'a> ContextWriter<'a> {
     let plane_type = usize::from(plane != 0);
 
     // Signal tx_type for luma plane only
-    if plane == 0 {
+    if plane == 0 && !lossless {
       self.write_tx_type(
         w,
         tx_size,
@@ -0,0 +1,461 @@
+// Copyright (c) 2018-2022, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+use std::f32::consts::PI;
+
+use rayon::iter::*;
+
+use crate::api::config::{DiffGenerator, GrainTableSegment};
+use crate::api::Rational;
+use crate::color::ChromaSampling;
+use crate::frame::{Frame, Plane};
+use crate::util::{CastFromPrimitive, Pixel};
+
+/// Size of the blocks filtered in the DCT domain.
+const BLOCK_SIZE: usize = 16;
+/// Distance between the filtered blocks, which overlap by half.
+const BLOCK_STEP: usize = BLOCK_SIZE / 2;
+/// Factor applied to the noise power before subtracting it from the power
+/// of the coefficients. Subtracting the mean power alone leaves much of the
+/// noise, whose power varies widely between coefficients.
+const NOISE_OVERSUBTRACTION: f32 = 2.0;
+/// Largest displacement searched for the matching blocks of the neighboring
+/// frames, in pixels.
+const SEARCH_RANGE: isize = 8;
+/// Margin by which the planes are extended, covering the blocks reaching past
+/// the edges along with the displacements searched around them.
+const PAD: usize = 2 * BLOCK_SIZE;
+
+type Block = [[f32; BLOCK_SIZE]; BLOCK_SIZE];
+
+/// Removes white noise of a known strength from frames, with a Wiener
+/// filter applied to the DCT coefficients of overlapping blocks.
+///
+/// Each block is first blended with the best matching blocks of the
+/// neighboring frames, which lowers the noise left to the Wiener filter
+/// where the content matches.
+pub struct DctDenoiser {
+  /// Orthonormal DCT-II basis, one function per row.
+  basis: Block,
+  /// Sine window, applied both before the transform and after its inverse.
+  window: [f32; BLOCK_SIZE],
+  /// Standard deviation of the noise, on the 8-bit scale.
+  noise_level: f32,
+  /// Number of frames on each side of a frame blended into it.
+  temporal_radius: usize,
+}
+
+impl DctDenoiser {
+  pub fn new(noise_level: u8, temporal_radius: u8) -> Self {
+    let mut basis = [[0.0; BLOCK_SIZE]; BLOCK_SIZE];
+    for (k, row) in basis.iter_mut().enumerate() {
+      let scale =
+        if k == 0 { 1.0 } else { 2.0f32.sqrt() } / (BLOCK_SIZE as f32).sqrt();
+      for (n, value) in row.iter_mut().enumerate() {
+        *value = scale
+          * (PI * (2 * n + 1) as f32 * k as f32 / (2 * BLOCK_SIZE) as f32)
+            .cos();
+      }
+    }
+    let window = std::array::from_fn(|n| {
+      (PI * (n as f32 + 0.5) / BLOCK_SIZE as f32).sin()
+    });
+    DctDenoiser {
+      basis,
+      window,
+      noise_level: f32::from(noise_level),
+      temporal_radius: usize::from(temporal_radius),
+    }
+  }
+
+  /// Number of frames on each side of a frame needed to denoise it.
+  pub(crate) const fn temporal_radius(&self) -> usize {
+    self.temporal_radius
+  }
+
+  /// Returns a denoised copy of the first `planes` planes of
+  /// `frames[current]`, the other frames being its neighbors. The other
+  /// planes are copied as is.
+  pub fn denoise<T: Pixel>(
+    &self, frames: &[&Frame<T>], current: usize, bit_depth: usize,
+    planes: usize,
+  ) -> Frame<T> {
+    let mut denoised = frames[current].clone();
+    for (p, dst) in denoised.planes[..planes].iter_mut().enumerate() {
+      let sources = frames
+        .iter()
+        .map(|frame| PlaneBuffer::new(&frame.planes[p]))
+        .collect::<Vec<_>>();
+      let halves =
+        sources.iter().map(PlaneBuffer::downscaled).collect::<Vec<_>>();
+      self.denoise_plane(&sources, &halves, current, dst, bit_depth);
+    }
+    denoised
+  }
+
+  fn denoise_plane<T: Pixel>(
+    &self, sources: &[PlaneBuffer], halves: &[PlaneBuffer], current: usize,
+    dst: &mut Plane<T>, bit_depth: usize,
+  ) {
+    let src = &sources[current];
+    let width = src.width;
+    let height = src.height;
+    if width == 0 || height == 0 {
+      return;
+    }
+
+    // White noise spreads evenly over the coefficients of the windowed
+    // blocks, its power being scaled by the energy of the window.
+    let window_energy =
+      self.window.iter().map(|w| w * w).sum::<f32>() / BLOCK_SIZE as f32;
+    let sigma = self.noise_level * (1 << (bit_depth - 8)) as f32;
+    let noise_variance = sigma * sigma;
+    let noise_power =
+      NOISE_OVERSUBTRACTION * noise_variance * window_energy * window_energy;
+
+    // Blocks start half a block before the picture, so that every pixel is
+    // covered by as many blocks.
+    let starts = |len: usize| {
+      (0..(len + BLOCK_STEP).div_ceil(BLOCK_STEP))
+        .map(|i| (i * BLOCK_STEP) as isize - BLOCK_STEP as isize)
+    };
+    // The rows of blocks are filtered in parallel, then overlapped in order.
+    let rows = starts(height)
+      .collect::<Vec<_>>()
+      .into_par_iter()
+      .map(|y0| {
+        starts(width)
+          .map(|x0| {
+            let block = self.filter_block(
+              sources,
+              halves,
+              current,
+              x0,
+              y0,
+              noise_variance,
+              noise_power,
+            );
+            (x0, y0, block)
+          })
+          .collect::<Vec<_>>()
+      })
+      .collect::<Vec<_>>();
+
+    let mut sums = vec![0.0f32; width * height];
+    let mut weights = vec![0.0f32; width * height];
+    for (x0, y0, filtered) in rows.into_iter().flatten() {
+      for (i, row) in filtered.iter().enumerate() {
+        let y = y0 + i as isize;
+        if y < 0 || y >= height as isize {
+          continue;
+        }
+        for (j, &value) in row.iter().enumerate() {
+          let x = x0 + j as isize;
+          if x < 0 || x >= width as isize {
+            continue;
+          }
+          let w = self.window[i] * self.window[j];
+          let idx = y as usize * width + x as usize;
+          sums[idx] += value * w;
+          weights[idx] += w * w;
+        }
+      }
+    }
+
+    let max = ((1 << bit_depth) - 1) as f32;
+    let stride = dst.cfg.stride;
+    for ((dst_row, sums), weights) in dst
+      .data_origin_mut()
+      .chunks_mut(stride)
+      .zip(sums.chunks(width))
+      .zip(weights.chunks(width))
+    {
+      for ((pixel, &sum), &weight) in
+        dst_row[..width].iter_mut().zip(sums).zip(weights)
+      {
+        *pixel = T::cast_from((sum / weight).round().clamp(0.0, max) as u16);
+      }
+    }
+  }
+
+  /// Returns the block of `sources[current]` at `(x0, y0)` blended with the
+  /// matching blocks of the other sources and filtered, before the inverse
+  /// window is applied.
+  fn filter_block(
+    &self, sources: &[PlaneBuffer], halves: &[PlaneBuffer], current: usize,
+    x0: isize, y0: isize, noise_variance: f32, noise_power: f32,
+  ) -> Block {
+    let src = &sources[current];
+    let mut block = src.block(x0, y0);
+    let mut weight_sum = 1.0;
+    let mut squared_weight_sum = 1.0;
+    for (k, (reference, reference_half)) in
+      sources.iter().zip(halves.iter()).enumerate()
+    {
+      if k == current {
+        continue;
+      }
+      let (dx, dy) =
+        find_match(src, &halves[current], reference, reference_half, x0, y0);
+      let distortion = src.sse(x0, y0, reference, x0 + dx, y0 + dy)
+        / (BLOCK_SIZE * BLOCK_SIZE) as f32;
+      // Blocks of the same content differ by twice the noise variance on
+      // average. Blocks differing by twice as much are left out.
+      let weight = ((4.0 * noise_variance - distortion)
+        / (2.0 * noise_variance))
+        .clamp(0.0, 1.0);
+      if weight > 0.0 {
+        for (i, row) in block.iter_mut().enumerate() {
+          let matched =
+            reference.row(x0 + dx, y0 + dy + i as isize, BLOCK_SIZE);
+          for (value, &m) in row.iter_mut().zip(matched) {
+            *value += weight * m;
+          }
+        }
+        weight_sum += weight;
+        squared_weight_sum += weight * weight;
+      }
+    }
+    for (i, row) in block.iter_mut().enumerate() {
+      for (j, value) in row.iter_mut().enumerate() {
+        *value *= self.window[i] * self.window[j] / weight_sum;
+      }
+    }
+    // Averaging independent noise lowers its variance.
+    let block_noise_power =
+      noise_power * squared_weight_sum / (weight_sum * weight_sum);
+
+    let mut coeffs = self.forward(&block);
+    for (k, row) in coeffs.iter_mut().enumerate() {
+      for (l, coeff) in row.iter_mut().enumerate() {
+        // Keep the mean of the block.
+        if k == 0 && l == 0 {
+          continue;
+        }
+        let power = *coeff * *coeff;
+        *coeff *= if power > block_noise_power {
+          (power - block_noise_power) / power
+        } else {
+          0.0
+        };
+      }
+    }
+    self.inverse(&coeffs)
+  }
+
+  fn forward(&self, block: &Block) -> Block {
+    let mut rows = [[0.0; BLOCK_SIZE]; BLOCK_SIZE];
+    for (k, row) in rows.iter_mut().enumerate() {
+      for (n, value) in block.iter().enumerate() {
+        for (out, &v) in row.iter_mut().zip(value.iter()) {
+          *out += self.basis[k][n] * v;
+        }
+      }
+    }
+    let mut coeffs = [[0.0; BLOCK_SIZE]; BLOCK_SIZE];
+    for (out_row, row) in coeffs.iter_mut().zip(rows.iter()) {
+      for (out, basis) in out_row.iter_mut().zip(self.basis.iter()) {
+        *out = row.iter().zip(basis.iter()).map(|(a, b)| a * b).sum();
+      }
+    }
+    coeffs
+  }
+
+  fn inverse(&self, coeffs: &Block) -> Block {
+    let mut rows = [[0.0; BLOCK_SIZE]; BLOCK_SIZE];
+    for (n, row) in rows.iter_mut().enumerate() {
+      for (k, coeff_row) in coeffs.iter().enumerate() {
+        for (out, &c) in row.iter_mut().zip(coeff_row.iter()) {
+          *out += self.basis[k][n] * c;
+        }
+      }
+    }
+    let mut block = [[0.0; BLOCK_SIZE]; BLOCK_SIZE];
+    for (out_row, row) in block.iter_mut().zip(rows.iter()) {
+      for (m, out) in out_row.iter_mut().enumerate() {
+        *out = row
+          .iter()
+          .zip(self.basis.iter())
+          .map(|(c, basis)| c * basis[m])
+          .sum();
+      }
+    }
+    block
+  }
+}
+
+/// Pixels of a plane, extending its edges by a margin.
+struct PlaneBuffer {
+  data: Vec<f32>,
+  width: usize,
+  height: usize,
+  stride: usize,
+  /// Width of the margin on each side.
+  pad: usize,
+}
+
+impl PlaneBuffer {
+  fn new<T: Pixel>(plane: &Plane<T>) -> Self {
+    let width = plane.cfg.width;
+    let height = plane.cfg.height;
+    let stride = width + 2 * PAD;
+    let mut data = Vec::with_capacity(stride * (height + 2 * PAD));
+    if width > 0 {
+      for y in 0..height + 2 * PAD {
+        let y = y.saturating_sub(PAD).min(height - 1);
+        let line = &plane.data_origin()[y * plane.cfg.stride..][..width];
+        data.extend((0..stride).map(|x| {
+          f32::from(u16::cast_from(line[x.saturating_sub(PAD).min(width - 1)]))
+        }));
+      }
+    }
+    PlaneBuffer { data, width, height, stride, pad: PAD }
+  }
+
+  /// Returns the plane at half the resolution, with half the margin.
+  fn downscaled(&self) -> Self {
+    let stride = self.stride / 2;
+    let mut data = Vec::with_capacity(self.data.len() / 4);
+    for rows in self.data.chunks_exact(2 * self.stride) {
+      let (top, bottom) = rows.split_at(self.stride);
+      data.extend((0..stride).map(|x| {
+        (top[2 * x] + top[2 * x + 1] + bottom[2 * x] + bottom[2 * x + 1]) / 4.0
+      }));
+    }
+    PlaneBuffer {
+      data,
+      width: self.width / 2,
+      height: self.height / 2,
+      stride,
+      pad: self.pad / 2,
+    }
+  }
+
+  /// Returns the `len` pixels of row `y` starting at column `x`, which may
+  /// lie within the margin.
+  fn row(&self, x: isize, y: isize, len: usize) -> &[f32] {
+    let start = (y + self.pad as isize) as usize * self.stride
+      + (x + self.pad as isize) as usize;
+    &self.data[start..][..len]
+  }
+
+  /// Returns the block whose top-left corner is at `(x0, y0)`.
+  fn block(&self, x0: isize, y0: isize) -> Block {
+    std::array::from_fn(|i| {
+      self.row(x0, y0 + i as isize, BLOCK_SIZE).try_into().unwrap()
+    })
+  }
+
+  /// Returns the sum of absolute differences between the blocks of `size`
+  /// pixels at `(x0, y0)` and at `(x1, y1)` in `other`.
+  fn sad(
+    &self, x0: isize, y0: isize, other: &Self, x1: isize, y1: isize,
+    size: usize,
+  ) -> f32 {
+    (0..size as isize)
+      .map(|i| {
+        self
+          .row(x0, y0 + i, size)
+          .iter()
+          .zip(other.row(x1, y1 + i, size))
+          .map(|(a, b)| (a - b).abs())
+          .sum::<f32>()
+      })
+      .sum()
+  }
+
+  /// Returns the sum of squared differences between the blocks at
+  /// `(x0, y0)` and at `(x1, y1)` in `other`.
+  fn sse(
+    &self, x0: isize, y0: isize, other: &Self, x1: isize, y1: isize,
+  ) -> f32 {
+    (0..BLOCK_SIZE as isize)
+      .map(|i| {
+        self
+          .row(x0, y0 + i, BLOCK_SIZE)
+          .iter()
+          .zip(other.row(x1, y1 + i, BLOCK_SIZE))
+          .map(|(a, b)| (a - b) * (a - b))
+          .sum::<f32>()
+      })
+      .sum()
+  }
+}
+
+/// Returns the displacement of the block of `reference` best matching the
+/// block of `src` at `(x0, y0)`, which must be even.
+///
+/// The whole range is searched on the planes at half the resolution, then
+/// the best displacement is refined at full resolution along with no
+/// displacement at all.
+fn find_match(
+  src: &PlaneBuffer, src_half: &PlaneBuffer, reference: &PlaneBuffer,
+  reference_half: &PlaneBuffer, x0: isize, y0: isize,
+) -> (isize, isize) {
+  let (hx, hy) = (x0 / 2, y0 / 2);
+  let half_range = SEARCH_RANGE / 2;
+  let mut best = (f32::MAX, 0, 0);
+  for dy in -half_range..=half_range {
+    for dx in -half_range..=half_range {
+      let cost =
+        src_half.sad(hx, hy, reference_half, hx + dx, hy + dy, BLOCK_SIZE / 2);
+      if cost < best.0 {
+        best = (cost, dx, dy);
+      }
+    }
+  }
+
+  let sad = |dx: isize, dy: isize| {
+    src.sad(x0, y0, reference, x0 + dx, y0 + dy, BLOCK_SIZE)
+  };
+  let (_, center_x, center_y) = best;
+  let mut best = (sad(0, 0), 0, 0);
+  for dy in 2 * center_y - 1..=2 * center_y + 1 {
+    for dx in 2 * center_x - 1..=2 * center_x + 1 {
+      let cost = sad(dx, dy);
+      if cost < best.0 {
+        best = (cost, dx, dy);
+      }
+    }
+  }
+  (best.1, best.2)
+}
+
+/// Estimates grain synthesis parameters reproducing the noise removed from
+/// each source frame to get the paired denoised frame.
+///
+/// The timestamps of the returned segment are left to the caller.
+pub(crate) fn estimate_film_grain<'a, T: Pixel>(
+  frames: impl IntoIterator<Item = (&'a Frame<T>, &'a Frame<T>)>,
+  bit_depth: usize, chroma_sampling: ChromaSampling, time_base: Rational,
+) -> Option<GrainTableSegment> {
+  let mut generator = DiffGenerator::new(
+    (time_base.den as i64, time_base.num as i64).into(),
+    bit_depth,
+    bit_depth,
+  );
+  let mut frame_count = 0;
+  for (source, denoised) in frames {
+    generator.diff_frame(source, denoised).ok()?;
+    frame_count += 1;
+  }
+  if frame_count == 0 {
+    return None;
+  }
+  // Only the noise model of the last frames is kept, if the noise changed
+  // type on the way.
+  let mut segment = generator.finish().pop()?;
+  if chroma_sampling == ChromaSampling::Cs400 {
+    // The empty chroma planes are still modeled.
+    segment.scaling_points_cb.clear();
+    segment.scaling_points_cr.clear();
+    segment.chroma_scaling_from_luma = false;
+  }
+  Some(segment)
+}
@@ -63,8 +63,12 @@ fn poly2(q: f32, a: f32, b: f32, c: f32, max: i32) -> i32 {
 
 pub static TEMPORAL_DELIMITER: [u8; 2] = [0x12, 0x00];
 
+// I don't know why this is the base unit for a timestamp but it is. 1/10000000 of a second.
+pub(crate) const TIMESTAMP_BASE_UNIT: u64 = 10_000_000;
+
 const MAX_NUM_TEMPORAL_LAYERS: usize = 8;
 const MAX_NUM_SPATIAL_LAYERS: usize = 4;
+
 const MAX_NUM_OPERATING_POINTS: usize =
   MAX_NUM_TEMPORAL_LAYERS * MAX_NUM_SPATIAL_LAYERS;
 
@@ -98,6 +102,16 @@ impl<T: Pixel> ReferenceFramesSet<T> {
   pub fn new() -> Self {
     Self { frames: Default::default(), deblock: Default::default() }
   }
+
+  /// Fills every slot with the frame in slot `idx`, as showing an existing
+  /// key frame does.
+  pub(crate) fn load_key_frame(&mut self, idx: usize) {
+    let frame = self.frames[idx].clone();
+    for slot in self.frames.iter_mut() {
+      slot.clone_from(&frame);
+    }
+    self.deblock = [self.deblock[idx]; REF_FRAMES];
+  }
 }
 
 #[wasm_bindgen]
@@ -320,11 +334,13 @@ impl Sequence {
       decoder_model_info_present_flag: false,
       level_idx,
       tier,
-      film_grain_params_present: config
-        .film_grain_params
-        .as_ref()
-        .map(|entries| !entries.is_empty())
-        .unwrap_or(false),
+      film_grain_params_present: (config.denoise_noise_level > 0
+        && config.denoise_grain_synthesis)
+        || config
+          .film_grain_params
+          .as_ref()
+          .map(|entries| !entries.is_empty())
+          .unwrap_or(false),
       timing_info_present: config.enable_timing_info,
       time_base: config.time_base,
     }
@@ -668,6 +684,8 @@ pub struct FrameInvariants<T: Pixel> {
   pub default_filter: FilterMode,
   pub enable_segmentation: bool,
   pub t35_metadata: Box<[T35]>,
+  /// Timecode of the frame, only set on shown frames.
+  pub timecode: Option<Timecode>,
   /// Target CPU feature level.
   pub cpu_feature_level: crate::cpu_features::CpuFeatureLevel,
 
@@ -940,6 +958,7 @@ impl<T: Pixel> FrameInvariants<T> {
         .transform
         .enable_inter_tx_split,
       t35_metadata: Box::new([]),
+      timecode: None,
       sequence,
       config,
       coded_frame_data: None,
@@ -963,9 +982,9 @@ impl<T: Pixel> FrameInvariants<T> {
   /// a placeholder frame.
   pub(crate) fn new_inter_frame(
     previous_coded_fi: &Self, inter_cfg: &InterConfig,
-    gop_input_frameno_start: u64, output_frameno_in_gop: u64,
-    next_keyframe_input_frameno: u64, error_resilient: bool,
-    t35_metadata: Box<[T35]>,
+    gop_input_frameno_start: u64, gop_order_hint_start: u32,
+    output_frameno_in_gop: u64, next_keyframe_input_frameno: u64,
+    error_resilient: bool, t35_metadata: Box<[T35]>,
   ) -> Option<Self> {
     let input_frameno = inter_cfg
       .get_input_frameno(output_frameno_in_gop, gop_input_frameno_start);
@@ -989,8 +1008,9 @@ impl<T: Pixel> FrameInvariants<T> {
       fi.coded_frame_data.clone_from(&previous_coded_fi.coded_frame_data);
     }
 
-    fi.order_hint =
-      inter_cfg.get_order_hint(output_frameno_in_gop, fi.idx_in_group_output);
+    fi.order_hint = gop_order_hint_start
+      + inter_cfg
+        .get_order_hint(output_frameno_in_gop, fi.idx_in_group_output);
 
     fi.pyramid_level = inter_cfg.get_level(fi.idx_in_group_output);
 
@@ -1117,6 +1137,46 @@ impl<T: Pixel> FrameInvariants<T> {
     Some(fi)
   }
 
+  /// Switches the frame to the settings of the zone of `base` covering it,
+  /// or back to the settings of `base` outside of any zone.
+  pub(crate) fn apply_zone(&mut self, base: &EncoderConfig) {
+    let zone = base.zone_at(self.input_frameno);
+    let quantizer =
+      zone.and_then(|zone| zone.quantizer).unwrap_or(base.quantizer);
+    let tune = zone.and_then(|zone| zone.tune).unwrap_or(base.tune);
+    let speed_settings =
+      zone.and_then(|zone| zone.speed_settings).unwrap_or(base.speed_settings);
+
+    // Only copy the config on change: it also carries the grain seeds.
+    if self.config.quantizer == quantizer
+      && self.config.tune == tune
+      && self.config.speed_settings == speed_settings
+    {
+      return;
+    }
+
+    let config = Arc::make_mut(&mut self.config);
+    config.quantizer = quantizer;
+    config.tune = tune;
+    config.speed_settings = speed_settings;
+
+    self.use_reduced_tx_set = speed_settings.transform.reduced_tx_set;
+    self.use_tx_domain_distortion =
+      tune == Tune::Psnr && speed_settings.transform.tx_domain_distortion;
+    self.use_tx_domain_rate = speed_settings.transform.tx_domain_rate;
+    self.partition_range = speed_settings.partition.partition_range;
+    self.enable_segmentation =
+      speed_settings.segmentation != SegmentationLevel::Disabled;
+    self.enable_inter_txfm_split =
+      speed_settings.transform.enable_inter_tx_split;
+    self.tx_mode_select = if self.intra_only {
+      speed_settings.transform.rdo_tx_decision
+    } else {
+      self.enable_inter_txfm_split
+    };
+    self.base_q_idx = quantizer as u8;
+  }
+
   pub fn is_show_existing_frame(&self) -> bool {
     self.coded_frame_data.is_none()
   }
@@ -1190,10 +1250,42 @@ impl<T: Pixel> FrameInvariants<T> {
       default_filter: self.default_filter,
       enable_segmentation: self.enable_segmentation,
       t35_metadata: self.t35_metadata.clone(),
+      timecode: self.timecode,
       cpu_feature_level: self.cpu_feature_level,
     }
   }
 
+  /// Replaces each reference with the temporally nearest one in the same
+  /// direction, which best predicts a picture changing steadily.
+  pub(crate) fn use_nearest_ref_frames(&mut self) {
+    let Some(coded_data) = self.coded_frame_data.as_ref() else {
+      return;
+    };
+    let dists = self.ref_frames.map(|slot| {
+      coded_data.lookahead_rec_buffer.frames[slot as usize].as_ref().map(
+        |rec| self.sequence.get_relative_dist(rec.order_hint, self.order_hint),
+      )
+    });
+    let nearest = |backward: bool| {
+      self
+        .ref_frames
+        .iter()
+        .zip(dists.iter())
+        .filter_map(|(&slot, &dist)| Some((slot, dist?)))
+        .filter(|&(_, dist)| if backward { dist < 0 } else { dist > 0 })
+        .min_by_key(|&(_, dist)| dist.abs())
+        .map(|(slot, _)| slot)
+    };
+    let (nearest_backward, nearest_forward) = (nearest(true), nearest(false));
+    for (slot, dist) in self.ref_frames.iter_mut().zip(dists) {
+      match dist {
+        Some(dist) if dist < 0 => *slot = nearest_backward.unwrap(),
+        Some(dist) if dist > 0 => *slot = nearest_forward.unwrap(),
+        _ => {}
+      }
+    }
+  }
+
   pub fn set_ref_frame_sign_bias(&mut self) {
     for i in 0..INTER_REFS_PER_FRAME {
       self.ref_frame_sign_bias[i] = if !self.sequence.enable_order_hint {
@@ -1210,7 +1302,7 @@ impl<T: Pixel> FrameInvariants<T> {
   }
 
   pub fn get_frame_subtype(&self) -> usize {
-    if self.frame_type == FrameType::KEY {
+    if self.frame_type.all_intra() {
       FRAME_SUBTYPE_I
     } else {
       FRAME_SUBTYPE_P + (self.pyramid_level as usize)
@@ -1262,6 +1354,15 @@ impl<T: Pixel> FrameInvariants<T> {
     }
   }
 
+  /// Whether the frame is coded losslessly, i.e. `CodedLossless` in the
+  /// spec: with a zero quantizer index and no quantizer deltas.
+  #[inline(always)]
+  pub fn coded_lossless(&self) -> bool {
+    self.base_q_idx == 0
+      && self.dc_delta_q == [0; 3]
+      && self.ac_delta_q == [0; 3]
+  }
+
   #[inline(always)]
   pub fn sb_size_log2(&self) -> usize {
     self.sequence.tiling.sb_size_log2
@@ -1276,9 +1377,6 @@ impl<T: Pixel> FrameInvariants<T> {
   }
 
   pub fn frame_timestamp(&self) -> u64 {
-    // I don't know why this is the base unit for a timestamp but it is. 1/10000000 of a second.
-    const TIMESTAMP_BASE_UNIT: u64 = 10_000_000;
-
     self.input_frameno * TIMESTAMP_BASE_UNIT * self.sequence.time_base.num
       / self.sequence.time_base.den
   }
@@ -1449,6 +1547,12 @@ pub fn encode_tx_block<T: Pixel, W: Writer>(
     tx_size.sqr() <= TxSize::TX_32X32 || tx_type == TxType::DCT_DCT
   );
 
+  // Lossless blocks use the Walsh-Hadamard transform, which is not signaled
+  // and scans the coefficients as DCT_DCT does.
+  let tx_type = if fi.coded_lossless() { TxType::WHT_WHT } else { tx_type };
+  let scan_tx_type =
+    if tx_type == TxType::WHT_WHT { TxType::DCT_DCT } else { tx_type };
+
   let plane_bsize = bsize.subsampled_size(xdec, ydec).unwrap();
 
   debug_assert!(p != 0 || !mode.is_intra() || tx_size.block_size() == plane_bsize || need_recon_pixel,
@@ -1553,7 +1657,7 @@ pub fn encode_tx_block<T: Pixel, W: Writer>(
   // SAFETY: forward_transform initialized coeffs
   let coeffs = unsafe { slice_assume_init_mut(coeffs) };
 
-  let eob = ts.qc.quantize(coeffs, qcoeffs, tx_size, tx_type);
+  let eob = ts.qc.quantize(coeffs, qcoeffs, tx_size, scan_tx_type);
 
   let has_coeff = if need_recon_pixel || rdo_type.needs_coeff_rate() {
     debug_assert!((((fi.w_in_b - frame_bo.0.x) << MI_SIZE_LOG2) >> xdec) >= 4);
@@ -1705,157 +1809,44 @@ pub fn motion_compensate<T: Pixel>(
     let tile_rect = luma_tile_rect.decimated(xdec, ydec);
 
     let area = Area::BlockStartingAt { bo: tile_bo.0 };
-    if p > 0 && bsize < BlockSize::BLOCK_8X8 {
-      let mut some_use_intra = false;
-      if bsize == BlockSize::BLOCK_4X4 || bsize == BlockSize::BLOCK_4X8 {
-        some_use_intra |=
-          cw.bc.blocks[tile_bo.with_offset(-1, 0)].mode.is_intra();
-      };
-      if !some_use_intra && bsize == BlockSize::BLOCK_4X4
-        || bsize == BlockSize::BLOCK_8X4
-      {
-        some_use_intra |=
-          cw.bc.blocks[tile_bo.with_offset(0, -1)].mode.is_intra();
-      };
-      if !some_use_intra && bsize == BlockSize::BLOCK_4X4 {
-        some_use_intra |=
-          cw.bc.blocks[tile_bo.with_offset(-1, -1)].mode.is_intra();
-      };
-
-      if some_use_intra {
-        luma_mode.predict_inter(
-          fi,
-          tile_rect,
-          p,
-          po,
-          &mut rec.subregion_mut(area),
-          plane_bsize.width(),
-          plane_bsize.height(),
-          ref_frames,
-          mvs,
-          compound_buffer,
-        );
-      } else {
-        assert!(u_xdec == 1 && u_ydec == 1);
-        // TODO: these are absolutely only valid for 4:2:0
-        if bsize == BlockSize::BLOCK_4X4 {
-          let mv0 = cw.bc.blocks[tile_bo.with_offset(-1, -1)].mv;
-          let rf0 = cw.bc.blocks[tile_bo.with_offset(-1, -1)].ref_frames;
-          let mv1 = cw.bc.blocks[tile_bo.with_offset(0, -1)].mv;
-          let rf1 = cw.bc.blocks[tile_bo.with_offset(0, -1)].ref_frames;
-          let po1 = PlaneOffset { x: po.x + 2, y: po.y };
-          let area1 = Area::StartingAt { x: po1.x, y: po1.y };
-          let mv2 = cw.bc.blocks[tile_bo.with_offset(-1, 0)].mv;
-          let rf2 = cw.bc.blocks[tile_bo.with_offset(-1, 0)].ref_frames;
-          let po2 = PlaneOffset { x: po.x, y: po.y + 2 };
-          let area2 = Area::StartingAt { x: po2.x, y: po2.y };
-          let po3 = PlaneOffset { x: po.x + 2, y: po.y + 2 };
-          let area3 = Area::StartingAt { x: po3.x, y: po3.y };
-          luma_mode.predict_inter(
-            fi,
-            tile_rect,
-            p,
-            po,
-            &mut rec.subregion_mut(area),
-            2,
-            2,
-            rf0,
-            mv0,
-            compound_buffer,
-          );
-          luma_mode.predict_inter(
-            fi,
-            tile_rect,
-            p,
-            po1,
-            &mut rec.subregion_mut(area1),
-            2,
-            2,
-            rf1,
-            mv1,
-            compound_buffer,
-          );
-          luma_mode.predict_inter(
-            fi,
-            tile_rect,
-            p,
-            po2,
-            &mut rec.subregion_mut(area2),
-            2,
-            2,
-            rf2,
-            mv2,
-            compound_buffer,
-          );
-          luma_mode.predict_inter(
-            fi,
-            tile_rect,
-            p,
-            po3,
-            &mut rec.subregion_mut(area3),
-            2,
-            2,
-            ref_frames,
-            mvs,
-            compound_buffer,
-          );
-        }
-        if bsize == BlockSize::BLOCK_8X4 {
-          let mv1 = cw.bc.blocks[tile_bo.with_offset(0, -1)].mv;
-          let rf1 = cw.bc.blocks[tile_bo.with_offset(0, -1)].ref_frames;
-          luma_mode.predict_inter(
-            fi,
-            tile_rect,
-            p,
-            po,
-            &mut rec.subregion_mut(area),
-            4,
-            2,
-            rf1,
-            mv1,
-            compound_buffer,
-          );
-          let po3 = PlaneOffset { x: po.x, y: po.y + 2 };
-          let area3 = Area::StartingAt { x: po3.x, y: po3.y };
-          luma_mode.predict_inter(
-            fi,
-            tile_rect,
-            p,
-            po3,
-            &mut rec.subregion_mut(area3),
-            4,
-            2,
-            ref_frames,
-            mvs,
-            compound_buffer,
-          );
-        }
-        if bsize == BlockSize::BLOCK_4X8 {
-          let mv2 = cw.bc.blocks[tile_bo.with_offset(-1, 0)].mv;
-          let rf2 = cw.bc.blocks[tile_bo.with_offset(-1, 0)].ref_frames;
-          luma_mode.predict_inter(
-            fi,
-            tile_rect,
-            p,
-            po,
-            &mut rec.subregion_mut(area),
-            2,
-            4,
-            rf2,
-            mv2,
-            compound_buffer,
-          );
-          let po3 = PlaneOffset { x: po.x + 2, y: po.y };
-          let area3 = Area::StartingAt { x: po3.x, y: po3.y };
+    // A chroma block covering several luma blocks is predicted in parts,
+    // each with the motion of the luma block it is co-located with, unless
+    // one of them is intra.
+    let (offset_x, offset_y) = bsize.sub8x8_offset(u_xdec, u_ydec);
+    let some_use_intra = p > 0
+      && bsize.is_sub8x8(u_xdec, u_ydec)
+      && (offset_y..=0).any(|y| {
+        (offset_x..=0).any(|x| {
+          (x, y) != (0, 0)
+            && cw.bc.blocks[tile_bo.with_offset(x, y)].mode.is_intra()
+        })
+      });
+    if p > 0 && bsize.is_sub8x8(u_xdec, u_ydec) && !some_use_intra {
+      let pred_w = if offset_x != 0 { 4 >> xdec } else { plane_bsize.width() };
+      let pred_h =
+        if offset_y != 0 { 4 >> ydec } else { plane_bsize.height() };
+      for (r, y) in (offset_y..=0).enumerate() {
+        for (c, x) in (offset_x..=0).enumerate() {
+          let (rfs, mvs) = if (x, y) == (0, 0) {
+            (ref_frames, mvs)
+          } else {
+            let block = &cw.bc.blocks[tile_bo.with_offset(x, y)];
+            (block.ref_frames, block.mv)
+          };
+          let sub_po = PlaneOffset {
+            x: po.x + (c * pred_w) as isize,
+            y: po.y + (r * pred_h) as isize,
+          };
           luma_mode.predict_inter(
             fi,
             tile_rect,
             p,
-            po3,
-            &mut rec.subregion_mut(area3),
-            2,
-            4,
-            ref_frames,
+            sub_po,
+            &mut rec
+              .subregion_mut(Area::StartingAt { x: sub_po.x, y: sub_po.y }),
+            pred_w,
+            pred_h,
+            rfs,
             mvs,
             compound_buffer,
           );
@@ -2237,9 +2228,6 @@ pub fn encode_block_post_cdef<T: Pixel, W: Writer>(
   }
 }
 
-/// # Panics
-///
-/// - If attempting to encode a lossless block (not yet supported)
 pub fn write_tx_blocks<T: Pixel, W: Writer>(
   fi: &FrameInvariants<T>, ts: &mut TileStateMut<'_, T>,
   cw: &mut ContextWriter, w: &mut W, luma_mode: PredictionMode,
@@ -2252,11 +2240,6 @@ pub fn write_tx_blocks<T: Pixel, W: Writer>(
   let bh = bsize.height_mi() / tx_size.height_mi();
   let qidx = get_qidx(fi, ts, cw, tile_bo);
 
-  // TODO: Lossless is not yet supported.
-  if !skip {
-    assert_ne!(qidx, 0);
-  }
-
   let PlaneConfig { xdec, ydec, .. } = ts.input.planes[1].cfg;
   let mut ac = Aligned::<[MaybeUninit<i16>; 32 * 32]>::uninit_array();
   let mut partition_has_coeff: bool = false;
@@ -2663,14 +2646,7 @@ fn encode_partition_bottomup<T: Pixel, W: Writer>(
   let must_split =
     is_square && (bsize > fi.partition_range.max || !has_cols || !has_rows);
 
-  let can_split = // FIXME: sub-8x8 inter blocks not supported for non-4:2:0 sampling
-    if fi.frame_type.has_inter() &&
-      fi.sequence.chroma_sampling != ChromaSampling::Cs420 &&
-      bsize <= BlockSize::BLOCK_8X8 {
-      false
-    } else {
-      (bsize > fi.partition_range.min && is_square) || must_split
-    };
+  let can_split = (bsize > fi.partition_range.min && is_square) || must_split;
 
   assert!(bsize >= BlockSize::BLOCK_8X8 || !can_split);
 
@@ -2734,15 +2710,24 @@ fn encode_partition_bottomup<T: Pixel, W: Writer>(
     debug_assert!(is_square);
 
     let mut partition_types = ArrayVec::<PartitionType, 3>::new();
-    if bsize
-      <= fi.config.speed_settings.partition.non_square_partition_max_threshold
-      || is_straddle_x
-      || is_straddle_y
+    // Lossless blocks only have 4x4 transforms, which are reached with
+    // square partitions.
+    if !fi.coded_lossless()
+      && (bsize
+        <= fi
+          .config
+          .speed_settings
+          .partition
+          .non_square_partition_max_threshold
+        || is_straddle_x
+        || is_straddle_y)
     {
       if has_cols {
         partition_types.push(PartitionType::PARTITION_HORZ);
       }
-      if !(fi.sequence.chroma_sampling == ChromaSampling::Cs422) && has_rows {
+      // Tall blocks have no 4:2:2 chroma block size (see `Subsampled_Size`
+      // in the spec), so they cannot be coded.
+      if fi.sequence.chroma_sampling != ChromaSampling::Cs422 && has_rows {
         partition_types.push(PartitionType::PARTITION_VERT);
       }
     }
@@ -2936,14 +2921,7 @@ fn encode_partition_topdown<T: Pixel, W: Writer>(
   let must_split =
     is_square && (bsize > fi.partition_range.max || !has_cols || !has_rows);
 
-  let can_split = // FIXME: sub-8x8 inter blocks not supported for non-4:2:0 sampling
-    if fi.frame_type.has_inter() &&
-      fi.sequence.chroma_sampling != ChromaSampling::Cs420 &&
-      bsize <= BlockSize::BLOCK_8X8 {
-      false
-    } else {
-      (bsize > fi.partition_range.min && is_square) || must_split
-    };
+  let can_split = (bsize > fi.partition_range.min && is_square) || must_split;
 
   let mut rdo_output =
     block_output.clone().unwrap_or_else(|| PartitionGroupParameters {
@@ -3263,18 +3241,22 @@ fn encode_tile_group<T: Pixel>(
   /* Frame deblocking operates over a single large tile wrapping the
    * frame rather than the frame itself so that deblocking is
    * available inside RDO when needed */
-  /* TODO: Don't apply if lossless */
-  let levels = fs.apply_tile_state_mut(|ts| {
-    let rec = &mut ts.rec;
-    deblock_filter_optimize(
-      fi,
-      &rec.as_const(),
-      &ts.input.as_tile(),
-      &blocks.as_tile_blocks(),
-      fi.width,
-      fi.height,
-    )
-  });
+  // Lossless frames are not deblocked.
+  let levels = if fi.coded_lossless() {
+    [0; 4]
+  } else {
+    fs.apply_tile_state_mut(|ts| {
+      let rec = &mut ts.rec;
+      deblock_filter_optimize(
+        fi,
+        &rec.as_const(),
+        &ts.input.as_tile(),
+        &blocks.as_tile_blocks(),
+        fi.width,
+        fi.height,
+      )
+    })
+  };
   fs.deblock.levels = levels;
 
   if fs.deblock.levels[0] != 0 || fs.deblock.levels[1] != 0 {
@@ -3584,16 +3566,19 @@ fn encode_tile<'a, T: Pixel>(
   }
 
   if fi.sequence.enable_delayed_loopfilter_rdo {
-    // Solve deblocking for just this tile
-    /* TODO: Don't apply if lossless */
-    let deblock_levels = deblock_filter_optimize(
-      fi,
-      &ts.rec.as_const(),
-      &ts.input_tile,
-      &cw.bc.blocks.as_const(),
-      fi.width,
-      fi.height,
-    );
+    // Solve deblocking for just this tile, which lossless frames skip
+    let deblock_levels = if fi.coded_lossless() {
+      [0; 4]
+    } else {
+      deblock_filter_optimize(
+        fi,
+        &ts.rec.as_const(),
+        &ts.input_tile,
+        &cw.bc.blocks.as_const(),
+        fi.width,
+        fi.height,
+      )
+    };
 
     if deblock_levels[0] != 0 || deblock_levels[1] != 0 {
       // copy reconstruction to a temp frame to restore it later
@@ -3702,6 +3687,13 @@ pub fn encode_show_existing_frame<T: Pixel>(
     write_key_frame_obus(&mut packet, fi, obu_extension).unwrap();
   }
 
+  if let Some(timecode) = fi.timecode.as_ref() {
+    let mut timecode_buf = Vec::new();
+    let mut timecode_bw = BitWriter::endian(&mut timecode_buf, BigEndian);
+    timecode_bw.write_timecode_metadata_obu(timecode).unwrap();
+    packet.write_all(&timecode_buf).unwrap();
+  }
+
   for t35 in fi.t35_metadata.iter() {
     let mut t35_buf = Vec::new();
     let mut t35_bw = BitWriter::endian(&mut t35_buf, BigEndian);
@@ -3783,6 +3775,13 @@ pub fn encode_frame<T: Pixel>(
     write_key_frame_obus(&mut packet, fi, obu_extension).unwrap();
   }
 
+  if let Some(timecode) = fi.timecode.as_ref() {
+    let mut timecode_buf = Vec::new();
+    let mut timecode_bw = BitWriter::endian(&mut timecode_buf, BigEndian);
+    timecode_bw.write_timecode_metadata_obu(timecode).unwrap();
+    packet.write_all(&timecode_buf).unwrap();
+  }
+
   for t35 in fi.t35_metadata.iter() {
     let mut t35_buf = Vec::new();
     let mut t35_bw = BitWriter::endian(&mut t35_buf, BigEndian);
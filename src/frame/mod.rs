@@ -7,9 +7,11 @@
 // Media Patent License 1.0 was not distributed with this source code in the
 // PATENTS file, you can obtain it at www.aomedia.org/license/patent.
 
+use std::any::Any;
+
 use num_derive::FromPrimitive;
 
-use crate::api::{Opaque, T35};
+use crate::api::{DolbyVisionRpu, Hdr10Plus, Opaque, Timecode, T35};
 use crate::context::SB_SIZE;
 use crate::mc::SUBPEL_FILTER_SIZE;
 use crate::util::*;
@@ -44,6 +46,38 @@ pub struct FrameParameters {
   pub opaque: Option<Opaque>,
   /// List of t35 metadata associated with this frame
   pub t35_metadata: Box<[T35]>,
+  /// HDR10+ dynamic metadata of the frame, written in a T.35 metadata OBU
+  /// ahead of the ones of `t35_metadata`. It is boxed to keep the
+  /// parameters small.
+  pub hdr10plus: Option<Box<Hdr10Plus>>,
+  /// Dolby Vision RPU of the frame, written in a T.35 metadata OBU ahead of
+  /// the ones of `t35_metadata`.
+  pub dolby_vision_rpu: Option<DolbyVisionRpu>,
+  /// Timecode of the frame, written in a timecode metadata OBU in the
+  /// temporal unit showing it.
+  pub timecode: Option<Timecode>,
+  /// Alpha plane of the frame, encoded in the alpha auxiliary stream
+  /// enabled by `EncoderConfig::alpha_quantizer`. Frames sent without one
+  /// are fully opaque.
+  pub alpha: Option<AlphaPlane>,
+}
+
+/// Alpha plane of a frame, as allocated by `Context::new_alpha_plane`
+///
+/// Its pixel type must match the one of the encoder context.
+#[derive(Debug)]
+pub struct AlphaPlane(Box<dyn Any + Send + Sync>);
+
+impl AlphaPlane {
+  /// Wrap a plane of alpha values.
+  pub fn new<T: Pixel>(plane: Plane<T>) -> Self {
+    AlphaPlane(Box::new(plane))
+  }
+
+  /// Returns the alpha plane, or `None` if its pixel type is not `T`.
+  pub(crate) fn into_plane<T: Pixel>(self) -> Option<Plane<T>> {
+    self.0.downcast::<Plane<T>>().ok().map(|plane| *plane)
+  }
 }
 
 pub use v_frame::frame::Frame;
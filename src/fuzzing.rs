@@ -228,6 +228,7 @@ impl Arbitrary<'_> for ArbitraryEncoder {
       low_latency: Arbitrary::arbitrary(u)?,
       quantizer: Arbitrary::arbitrary(u)?,
       min_quantizer: Arbitrary::arbitrary(u)?,
+      alpha_quantizer: None,
       bitrate: Arbitrary::arbitrary(u)?,
       tile_cols: u.int_in_range(0..=2)?,
       tile_rows: u.int_in_range(0..=2)?,
@@ -258,6 +259,12 @@ impl Arbitrary<'_> for ArbitraryEncoder {
       switch_frame_interval: u.int_in_range(0..=3)?,
       tune: *u.choose(&[Tune::Psnr, Tune::Psychovisual])?,
       film_grain_params: None,
+      denoise_noise_level: 0,
+      denoise_temporal_radius: 1,
+      denoise_grain_synthesis: true,
+      drop_frames: false,
+      forced_keyframes: None,
+      zones: None,
     };
 
     let frame_count =
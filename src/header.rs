@@ -157,6 +157,9 @@ pub trait UncompressedHeader {
   ) -> io::Result<()>;
   fn write_color_config(&mut self, seq: &Sequence) -> io::Result<()>;
   fn write_t35_metadata_obu(&mut self, t35: &T35) -> io::Result<()>;
+  fn write_timecode_metadata_obu(
+    &mut self, timecode: &Timecode,
+  ) -> io::Result<()>;
   // End of OBU Headers
 
   fn write_max_frame_size<T: Pixel>(
@@ -276,6 +279,43 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
     Ok(())
   }
 
+  fn write_timecode_metadata_obu(
+    &mut self, timecode: &Timecode,
+  ) -> io::Result<()> {
+    // The payload is not byte aligned, so write it first to get its size
+    let mut payload = Vec::new();
+    let mut bw = BitWriter::endian(&mut payload, BigEndian);
+    bw.write_uleb128(ObuMetaType::OBU_META_TIMECODE as u64)?;
+
+    bw.write(5, timecode.counting_type)?;
+    bw.write_bit(timecode.full_timestamp)?;
+    bw.write_bit(timecode.discontinuity)?; // discontinuity_flag
+    bw.write_bit(timecode.drop_frame)?; // cnt_dropped_flag
+    bw.write(9, timecode.frames)?; // n_frames
+    if timecode.full_timestamp {
+      bw.write(6, timecode.seconds)?;
+      bw.write(6, timecode.minutes)?;
+      bw.write(5, timecode.hours)?;
+    } else {
+      bw.write_bit(false)?; // seconds_flag
+    }
+    let time_offset_length = 32 - timecode.time_offset.leading_zeros();
+    bw.write(5, time_offset_length)?;
+    if time_offset_length > 0 {
+      bw.write(time_offset_length, timecode.time_offset)?;
+    }
+
+    // trailing bits
+    bw.write_bit(true)?;
+    bw.byte_align()?;
+
+    self.write_obu_header(ObuType::OBU_METADATA, 0)?;
+    self.write_uleb128(payload.len() as u64)?;
+    self.write_bytes(&payload)?;
+
+    Ok(())
+  }
+
   fn write_sequence_header_obu<T: Pixel>(
     &mut self, fi: &FrameInvariants<T>,
   ) -> io::Result<()> {
@@ -567,7 +607,6 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
     if fi.frame_type == FrameType::KEY {
       if !fi.show_frame {
         // unshown keyframe (forward keyframe)
-        unimplemented!();
         self.write(REF_FRAMES as u32, fi.refresh_frame_flags)?;
       } else {
         assert!(fi.refresh_frame_flags == ALL_REF_FRAMES_MASK);
@@ -735,7 +774,6 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
     }
 
     // quantization
-    assert!(fi.base_q_idx > 0);
     self.write(8, fi.base_q_idx)?; // base_q_idx
     self.write_delta_q(fi.dc_delta_q[0])?;
     if fi.sequence.chroma_sampling != ChromaSampling::Cs400 {
@@ -756,7 +794,9 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
     self.write_segment_data(fi, &fs.segmentation)?;
 
     // delta_q
-    self.write_bit(false)?; // delta_q_present_flag: no delta q
+    if fi.base_q_idx > 0 {
+      self.write_bit(false)?; // delta_q_present_flag: no delta q
+    }
 
     // delta_lf_params in the spec
     self.write_deblock_filter_a(fi, &fs.deblock)?;
@@ -772,7 +812,10 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
     // loop restoration
     self.write_frame_lrf(fi, &fs.restoration)?;
 
-    self.write_bit(fi.tx_mode_select)?; // tx mode
+    // Lossless frames only have 4x4 transforms.
+    if !fi.coded_lossless() {
+      self.write_bit(fi.tx_mode_select)?; // tx mode
+    }
 
     let mut reference_select = false;
     if !fi.intra_only {
@@ -860,22 +903,31 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
           } else {
             false
           };
-        if !(fi.sequence.chroma_sampling == ChromaSampling::Cs400
-          || chroma_scaling_from_luma
-          || (fi.sequence.chroma_sampling == ChromaSampling::Cs420
-            && grain_params.scaling_points_y.is_empty()))
-        {
-          self.write(4, grain_params.scaling_points_cb.len() as u8)?;
-          for point in &grain_params.scaling_points_cb {
-            self.write(8, point[0])?;
-            self.write(8, point[1])?;
-          }
-          self.write(4, grain_params.scaling_points_cr.len() as u8)?;
-          for point in &grain_params.scaling_points_cr {
-            self.write(8, point[0])?;
-            self.write(8, point[1])?;
-          }
-        }
+        // The chroma scaling points are inferred to be empty when they are
+        // not coded, whatever the parameters hold.
+        let (scaling_points_cb, scaling_points_cr) =
+          if fi.sequence.chroma_sampling == ChromaSampling::Cs400
+            || chroma_scaling_from_luma
+            || (fi.sequence.chroma_sampling == ChromaSampling::Cs420
+              && grain_params.scaling_points_y.is_empty())
+          {
+            (&[][..], &[][..])
+          } else {
+            self.write(4, grain_params.scaling_points_cb.len() as u8)?;
+            for point in &grain_params.scaling_points_cb {
+              self.write(8, point[0])?;
+              self.write(8, point[1])?;
+            }
+            self.write(4, grain_params.scaling_points_cr.len() as u8)?;
+            for point in &grain_params.scaling_points_cr {
+              self.write(8, point[0])?;
+              self.write(8, point[1])?;
+            }
+            (
+              &grain_params.scaling_points_cb[..],
+              &grain_params.scaling_points_cr[..],
+            )
+          };
 
         self.write(2, grain_params.scaling_shift - 8)?;
         self.write(2, grain_params.ar_coeff_lag)?;
@@ -893,16 +945,12 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
           num_pos_chroma = num_pos_luma;
         }
 
-        if chroma_scaling_from_luma
-          || !grain_params.scaling_points_cb.is_empty()
-        {
+        if chroma_scaling_from_luma || !scaling_points_cb.is_empty() {
           for i in 0..num_pos_chroma {
             self.write(8, grain_params.ar_coeffs_cb[i] as i16 + 128)?;
           }
         }
-        if chroma_scaling_from_luma
-          || !grain_params.scaling_points_cr.is_empty()
-        {
+        if chroma_scaling_from_luma || !scaling_points_cr.is_empty() {
           for i in 0..num_pos_chroma {
             self.write(8, grain_params.ar_coeffs_cr[i] as i16 + 128)?;
           }
@@ -910,12 +958,12 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
 
         self.write(2, grain_params.ar_coeff_shift - 6)?;
         self.write(2, grain_params.grain_scale_shift)?;
-        if !grain_params.scaling_points_cb.is_empty() {
+        if !scaling_points_cb.is_empty() {
           self.write(8, grain_params.cb_mult)?;
           self.write(8, grain_params.cb_luma_mult)?;
           self.write(9, grain_params.cb_offset)?;
         }
-        if !grain_params.scaling_points_cr.is_empty() {
+        if !scaling_points_cr.is_empty() {
           self.write(8, grain_params.cr_mult)?;
           self.write(8, grain_params.cr_luma_mult)?;
           self.write(9, grain_params.cr_offset)?;
@@ -1035,6 +1083,10 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
   fn write_deblock_filter_b<T: Pixel>(
     &mut self, fi: &FrameInvariants<T>, deblock: &DeblockState,
   ) -> io::Result<()> {
+    if fi.coded_lossless() {
+      // Lossless frames are not deblocked.
+      return Ok(());
+    }
     let planes = if fi.sequence.chroma_sampling == ChromaSampling::Cs400 {
       1
     } else {
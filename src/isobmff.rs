@@ -0,0 +1,99 @@
+// Copyright (c) 2018-2022, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+//! Boxes of the ISO base media file format, shared by the AVIF images and
+//! the MP4 output.
+
+use crate::api::color::*;
+use crate::api::config::EncoderConfig;
+
+/// Appends a box whose content is written by `content`.
+///
+/// # Panics
+///
+/// Panics if the box is larger than 4 GiB.
+pub fn write_box(
+  out: &mut Vec<u8>, box_type: &[u8; 4], content: impl FnOnce(&mut Vec<u8>),
+) {
+  let start = out.len();
+  out.extend([0; 4]);
+  out.extend(box_type);
+  content(out);
+  let size = u32::try_from(out.len() - start).expect("box too large");
+  out[start..start + 4].copy_from_slice(&size.to_be_bytes());
+}
+
+/// Appends a full box, which starts with a version and flags.
+pub fn write_full_box(
+  out: &mut Vec<u8>, box_type: &[u8; 4], version: u8, flags: u32,
+  content: impl FnOnce(&mut Vec<u8>),
+) {
+  write_box(out, box_type, |out| {
+    out.extend((u32::from(version) << 24 | flags).to_be_bytes());
+    content(out);
+  });
+}
+
+/// Appends the `colr` box describing the colors of the stream, if they are
+/// not all unspecified.
+pub fn write_colr_box(out: &mut Vec<u8>, enc: &EncoderConfig) {
+  if enc.color_description.is_none() && enc.pixel_range == PixelRange::Limited
+  {
+    return;
+  }
+  let ColorDescription {
+    color_primaries,
+    transfer_characteristics,
+    matrix_coefficients,
+  } = enc.color_description.unwrap_or(ColorDescription {
+    color_primaries: ColorPrimaries::Unspecified,
+    transfer_characteristics: TransferCharacteristics::Unspecified,
+    matrix_coefficients: MatrixCoefficients::Unspecified,
+  });
+  write_box(out, b"colr", |out| {
+    out.extend(b"nclx");
+    out.extend((color_primaries as u16).to_be_bytes());
+    out.extend((transfer_characteristics as u16).to_be_bytes());
+    out.extend((matrix_coefficients as u16).to_be_bytes());
+    out.push(u8::from(enc.pixel_range == PixelRange::Full) << 7);
+  });
+}
+
+/// Appends the `mdcv` box of the mastering display of the stream, if set.
+pub fn write_mdcv_box(out: &mut Vec<u8>, enc: &EncoderConfig) {
+  let Some(mdcv) = enc.mastering_display else {
+    return;
+  };
+  write_box(out, b"mdcv", |out| {
+    // The chromaticities are in units of 0.00002 in green, blue, red
+    // order, and the luminances in units of 0.0001 cd/m².
+    let chromaticity = |v: u16| (u32::from(v) * 50000 + (1 << 15)) >> 16;
+    for i in [1, 2, 0] {
+      let primary = mdcv.primaries[i];
+      out.extend((chromaticity(primary.x) as u16).to_be_bytes());
+      out.extend((chromaticity(primary.y) as u16).to_be_bytes());
+    }
+    out.extend((chromaticity(mdcv.white_point.x) as u16).to_be_bytes());
+    out.extend((chromaticity(mdcv.white_point.y) as u16).to_be_bytes());
+    let max_luminance = (u64::from(mdcv.max_luminance) * 10000) >> 8;
+    let min_luminance = (u64::from(mdcv.min_luminance) * 10000) >> 14;
+    out.extend((max_luminance as u32).to_be_bytes());
+    out.extend((min_luminance as u32).to_be_bytes());
+  });
+}
+
+/// Appends the `clli` box of the content light level of the stream, if set.
+pub fn write_clli_box(out: &mut Vec<u8>, enc: &EncoderConfig) {
+  if let Some(cll) = enc.content_light {
+    write_box(out, b"clli", |out| {
+      out.extend(cll.max_content_light_level.to_be_bytes());
+      out.extend(cll.max_frame_average_light_level.to_be_bytes());
+    });
+  }
+}
@@ -20,7 +20,7 @@
 //! * DCT, (FLIP-)ADST and identity transforms (up to 64x64, 16x16 and 32x32
 //!   respectively)
 //! * 8-, 10- and 12-bit depth color
-//! * 4:2:0 (full support), 4:2:2 and 4:4:4 (limited) chroma sampling
+//! * 4:0:0 (monochrome), 4:2:0, 4:2:2 and 4:4:4 chroma sampling
 //! * Variable speed settings
 //! * Near real-time encoding at high speed levels
 //!
@@ -96,6 +96,7 @@ mod cdef;
 #[doc(hidden)]
 pub mod context;
 mod deblock;
+mod denoise;
 mod encoder;
 mod entropymode;
 mod levels;
@@ -119,13 +120,15 @@ mod token_cdfs;
 mod api;
 mod frame;
 mod header;
+#[doc(hidden)]
+pub mod isobmff;
 
 /// Commonly used types and traits.
 pub mod prelude {
   pub use crate::api::*;
   pub use crate::encoder::{Sequence, Tune};
   pub use crate::frame::{
-    Frame, FrameParameters, FrameTypeOverride, Plane, PlaneConfig,
+    AlphaPlane, Frame, FrameParameters, FrameTypeOverride, Plane, PlaneConfig,
   };
   pub use crate::partition::BlockSize;
   pub use crate::predict::PredictionMode;
@@ -136,9 +139,9 @@ pub mod prelude {
 /// Basic data structures
 pub mod data {
   pub use crate::api::{
-    ChromaticityPoint, EncoderStatus, FrameType, Packet, Rational,
+    ChromaticityPoint, EncoderStatus, FrameType, Packet, Rational, SceneLabel,
   };
-  pub use crate::frame::{Frame, FrameParameters};
+  pub use crate::frame::{AlphaPlane, Frame, FrameParameters};
   pub use crate::stats::EncoderStats;
   pub use crate::util::{CastFromPrimitive, Pixel, PixelType};
 }
@@ -291,6 +294,9 @@ pub mod bench {
   pub mod context {
     pub use crate::context::*;
   }
+  pub mod denoise {
+    pub use crate::denoise::*;
+  }
   pub mod dist {
     pub use crate::dist::*;
   }
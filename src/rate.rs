@@ -8,15 +8,20 @@
 // PATENTS file, you can obtain it at www.aomedia.org/license/patent.
 
 use std::cmp;
+use std::collections::BTreeSet;
 
 use crate::api::color::ChromaSampling;
-use crate::api::ContextInner;
+use crate::api::{ContextInner, SceneLabel};
 use crate::encoder::TEMPORAL_DELIMITER;
 use crate::quantize::{ac_q, dc_q, select_ac_qi, select_dc_qi};
+use crate::serialize::{Deserialize, Serialize};
 use crate::util::{
   bexp64, bexp_q24, blog64, clamp, q24_to_q57, q57, q57_to_q24, Pixel,
 };
 
+#[cfg(feature = "serialize")]
+use serde_big_array::BigArray;
+
 // The number of frame sub-types for which we track distinct parameters.
 // This does not include FRAME_SUBTYPE_SEF, because we don't need to do any
 //  parameter tracking for Show Existing Frame frames.
@@ -38,12 +43,23 @@ const PASS_2_PLUS_1: i32 = 3;
 // Magic value at the start of the 2-pass stats file
 const TWOPASS_MAGIC: i32 = 0x50324156;
 // Version number for the 2-pass stats file
-const TWOPASS_VERSION: i32 = 1;
+const TWOPASS_VERSION: i32 = 3;
 // 4 byte magic + 4 byte version + 4 byte TU count + 4 byte SEF frame count
-//  + FRAME_NSUBTYPES*(4 byte frame count + 1 byte exp + 8 byte scale_sum)
-pub(crate) const TWOPASS_HEADER_SZ: usize = 16 + FRAME_NSUBTYPES * (4 + 1 + 8);
+//  + FRAME_NSUBTYPES*(4 byte frame count + 1 byte exp + 8 byte scale_sum
+//  + 8 byte bit count) + 4 byte pass number + 8 byte bit target
+//  + 4 byte keyframe count
+pub(crate) const TWOPASS_HEADER_SZ: usize =
+  16 + FRAME_NSUBTYPES * (4 + 1 + 8 + 8) + 4 + 8 + 4;
+// 4 byte input frame number, repeated after the header for each keyframe
+pub(crate) const TWOPASS_KEYFRAME_SZ: usize = 4;
 // 4 byte frame type (show_frame and fti jointly coded) + 4 byte log_scale_q24
-const TWOPASS_PACKET_SZ: usize = 8;
+//  + 4 byte bit count + 4 byte log_target_q_q24 + 4 byte intra cost
+//  + 4 byte inter cost + 4 byte motion magnitude
+pub(crate) const TWOPASS_PACKET_SZ: usize = 28;
+
+// The log of the largest correction of the size of a frame relative to the
+//  previous rate-controlled pass, in Q57 format.
+const NPASS_MAX_LOG_CORRECTION: i64 = q57(1);
 
 const SEF_BITS: i64 = 24;
 
@@ -81,6 +97,28 @@ const DQP_Q57: &[i64; FRAME_NSUBTYPES] = &[
   (2.0 * (33_810_170.0 / 86_043_287.0) * (1i64 << 57) as f64) as i64,
 ];
 
+// Quantizer adjustment of a non-reference flash, about 15 quantizer steps
+//  coarser: nothing is predicted from it, and it is only seen briefly.
+const DQP_FLASH_Q57: i64 =
+  ((33_810_170.0 / 86_043_287.0) * (1i64 << 57) as f64) as i64;
+// Quantizer adjustment of a fade or cross-dissolve, about 7 quantizer steps
+//  finer: without weighted prediction, most of the residual is a brightness
+//  change, which turns into blocking at coarse quantizers.
+const DQP_GRADUAL_Q57: i64 =
+  (-0.5 * (33_810_170.0 / 86_043_287.0) * (1i64 << 57) as f64) as i64;
+
+/// Returns the adjustment (Q57) of the log quantizer of a frame for the
+///  content transition detected at it.
+pub(crate) fn scene_label_log_q_offset(
+  label: SceneLabel, is_reference: bool,
+) -> i64 {
+  match label {
+    SceneLabel::Flash if !is_reference => DQP_FLASH_Q57,
+    label if label.is_gradual() => DQP_GRADUAL_Q57,
+    _ => 0,
+  }
+}
+
 // For 8-bit-depth inter frames, log_q_y is derived from log_target_q with a
 //  linear model:
 //  log_q_y = log_target_q + (log_target_q >> 32) * Q_MODEL_MUL + Q_MODEL_ADD
@@ -119,6 +157,7 @@ const ROUGH_TAN_LOOKUP: &[u16; 18] = &[
 // A digital approximation of a 2nd-order low-pass Bessel follower.
 // We use this for rate control because it has fast reaction time, but is
 //  critically damped.
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct IIRBessel2 {
   c: [i32; 2],
   g: i32,
@@ -207,14 +246,34 @@ impl IIRBessel2 {
   }
 }
 
-#[derive(Copy, Clone)]
-struct RCFrameMetrics {
+// Lookahead statistics of a frame, recorded in the pass data.
+#[derive(
+  Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+pub(crate) struct RCFrameStats {
+  // The mean intra prediction cost (SATD) of the 8x8 blocks of the frame.
+  pub(crate) intra_cost: u32,
+  // The mean inter prediction cost (SAD) of the 8x8 blocks of the frame,
+  //  equal to the intra cost for frames without inter prediction.
+  pub(crate) inter_cost: u32,
+  // The mean motion vector magnitude, in 1/8th pel units.
+  pub(crate) motion: u32,
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct RCFrameMetrics {
   // The log base 2 of the scale factor for this frame in Q24 format.
   log_scale_q24: i32,
   // The frame type from pass 1
   fti: usize,
   // Whether or not the frame was hidden in pass 1
   show_frame: bool,
+  // The number of bits the frame was coded with in the previous pass.
+  bits: i64,
+  // The log of the target quantizer of the previous pass in Q24 format.
+  log_target_q_q24: i32,
+  // The lookahead statistics of the frame.
+  pub(crate) stats: RCFrameStats,
   // TODO: The input frame number corresponding to this frame in the input.
   // input_frameno: u32
   // TODO vfr: PTS
@@ -222,7 +281,14 @@ struct RCFrameMetrics {
 
 impl RCFrameMetrics {
   const fn new() -> RCFrameMetrics {
-    RCFrameMetrics { log_scale_q24: 0, fti: 0, show_frame: false }
+    RCFrameMetrics {
+      log_scale_q24: 0,
+      fti: 0,
+      show_frame: false,
+      bits: 0,
+      log_target_q_q24: 0,
+      stats: RCFrameStats { intra_cost: 0, inter_cost: 0, motion: 0 },
+    }
   }
 }
 
@@ -236,20 +302,33 @@ pub struct RCSummary {
   nframes: [i32; FRAME_NSUBTYPES + 1],
   exp: [u8; FRAME_NSUBTYPES],
   scale_sum: [i64; FRAME_NSUBTYPES],
+  // The number of bits spent on each frame subtype.
+  pub(crate) bits: [i64; FRAME_NSUBTYPES],
   pub(crate) total: i32,
+  // The number of the pass which produced the summary, starting at 1.
+  pub(crate) pass: i32,
+  // The number of bits the pass aimed for, or 0 if it was not rate
+  //  controlled.
+  pub(crate) target_bits: i64,
+  // The number of keyframes following the header.
+  pub(crate) nkeyframes: usize,
+  // The input frame numbers of the keyframes placed in the first pass.
+  pub(crate) keyframes: Vec<u64>,
 }
 
 // Backing storage to deserialize Summary and Per-Frame pass data
 //
 // Can store up to a full header size since it is the largest of the two
 // packet kinds.
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub(crate) struct RCDeserialize {
   // The current byte position in the frame metrics buffer.
-  pass2_buffer_pos: usize,
+  pub(crate) pass2_buffer_pos: usize,
   // In pass 2, this represents the number of bytes that are available in the
   //  input buffer.
-  pass2_buffer_fill: usize,
+  pub(crate) pass2_buffer_fill: usize,
   // Buffer for current frame metrics in pass 2.
+  #[serde(with = "BigArray")]
   pass2_buffer: [u8; TWOPASS_HEADER_SZ],
 }
 
@@ -296,7 +375,7 @@ impl RCDeserialize {
   }
 
   // Read metrics for the next frame.
-  fn parse_metrics(&mut self) -> Result<RCFrameMetrics, String> {
+  pub(crate) fn parse_metrics(&mut self) -> Result<RCFrameMetrics, String> {
     debug_assert!(self.pass2_buffer_fill >= TWOPASS_PACKET_SZ);
     let ft_val = self.unbuffer_val(4);
     let show_frame = (ft_val >> 31) != 0;
@@ -306,7 +385,37 @@ impl RCDeserialize {
       return Err("Invalid frame type".to_string());
     }
     let log_scale_q24 = self.unbuffer_val(4) as i32;
-    Ok(RCFrameMetrics { log_scale_q24, fti, show_frame })
+    let bits = self.unbuffer_val(4);
+    let log_target_q_q24 = self.unbuffer_val(4) as i32;
+    let stats = RCFrameStats {
+      intra_cost: self.unbuffer_val(4) as u32,
+      inter_cost: self.unbuffer_val(4) as u32,
+      motion: self.unbuffer_val(4) as u32,
+    };
+    Ok(RCFrameMetrics {
+      log_scale_q24,
+      fti,
+      show_frame,
+      bits,
+      log_target_q_q24,
+      stats,
+    })
+  }
+
+  // Read the input frame number of the next keyframe.
+  pub(crate) fn parse_keyframe(
+    &mut self, prev_keyframe: Option<u64>, ntus: i32,
+  ) -> Result<u64, String> {
+    debug_assert!(self.pass2_buffer_fill >= TWOPASS_KEYFRAME_SZ);
+    let keyframe = self.unbuffer_val(TWOPASS_KEYFRAME_SZ) as u64;
+    // Keyframes are stored in increasing order, and each input frame is a
+    //  TU.
+    if prev_keyframe.is_some_and(|prev| keyframe <= prev)
+      || keyframe >= ntus as u64
+    {
+      return Err("Invalid keyframe list".to_string());
+    }
+    Ok(keyframe)
   }
 
   // Read the summary header data.
@@ -357,10 +466,34 @@ impl RCDeserialize {
         return Err("Got negative scale sum".to_string());
       }
     }
+
+    for bits in s.bits.iter_mut() {
+      *bits = self.unbuffer_val(8);
+      if *bits < 0 {
+        return Err("Got negative bit count".to_string());
+      }
+    }
+
+    s.pass = self.unbuffer_val(4) as i32;
+    if s.pass < 1 {
+      return Err("Invalid pass number".to_string());
+    }
+    s.target_bits = self.unbuffer_val(8);
+    if s.target_bits < 0 {
+      return Err("Got negative bit target".to_string());
+    }
+
+    s.nkeyframes = self.unbuffer_val(4) as usize;
+    // We can't have more keyframes than TUs.
+    if s.nkeyframes > s.ntus as usize {
+      return Err("More keyframes than TUs".to_string());
+    }
     Ok(s)
   }
 }
 
+// The state is saved in checkpoints, to resume an encode.
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct RCState {
   // The target bit-rate in bits per second.
   target_bitrate: i32,
@@ -384,6 +517,9 @@ pub struct RCState {
   cap_underflow: bool,
   // The log of the first-pass base quantizer.
   pass1_log_base_q: i64,
+  // The number of the current pass, starting at 1.
+  // Pass data of a pass N is read in pass N+1.
+  pass: i32,
   // Two-pass mode state.
   // PASS_SINGLE => 1-pass encoding.
   // PASS_1 => 1st pass of 2-pass encoding.
@@ -430,8 +566,8 @@ pub struct RCState {
   nencoded_frames: i64,
   // The number of Show Existing Frames that have been emitted.
   nsef_frames: i64,
-  // Buffer for current frame metrics in pass 1.
-  pass1_buffer: [u8; TWOPASS_HEADER_SZ],
+  // Buffer for current frame metrics or summary in pass 1.
+  pass1_buffer: Vec<u8>,
   // Whether or not the user has retrieved the pass 1 data for the last frame.
   // For PASS_1 or PASS_2_PLUS_1 encoding, this is set to false after each
   //  frame is encoded, and must be set to true by calling twopass_out() before
@@ -446,9 +582,10 @@ pub struct RCState {
   //  frame, and must be set to true by calling twopass_in() before the next
   //  frame can be encoded.
   pass2_data_ready: bool,
-  // TODO: Add a way to force the next frame to be a keyframe in 2-pass mode.
-  // Right now we are relying on keyframe detection to detect the same
-  //  keyframes.
+  // The keyframes read from the summary in pass 2, to be replayed.
+  first_pass_keyframes: Vec<u64>,
+  // The number of keyframes of the summary still to be read in pass 2.
+  nkeyframes_left: usize,
   // The metrics for the previous frame.
   prev_metrics: RCFrameMetrics,
   // The metrics for the current frame.
@@ -475,6 +612,15 @@ pub struct RCState {
   nframes_left: [i32; FRAME_NSUBTYPES + 1],
   // The sum of the scale values for each frame subtype.
   scale_sum: [i64; FRAME_NSUBTYPES],
+  // The number of bits spent on each frame subtype.
+  bits_sum: [i64; FRAME_NSUBTYPES],
+  // The lookahead statistics of the frame about to be coded.
+  frame_stats: RCFrameStats,
+  // The number of bits the frames yet to be coded used in the previous
+  //  rate-controlled pass, or 0 if their quantizers are not refined.
+  npass_bits_left: i64,
+  // The number of bits left for those frames in this pass.
+  npass_target_left: i64,
   // The number of TUs represented by the current scale sums.
   scale_window_ntus: i32,
   // The frame count of each frame subtype in the current scale window.
@@ -484,6 +630,8 @@ pub struct RCState {
 }
 
 // TODO: Separate qi values for each color plane.
+// The parameters of the lookahead motion search are saved in checkpoints.
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct QuantizerParameters {
   // The full-precision, unmodulated log quantizer upon which our modulated
   //  quantizer indices are based.
@@ -523,6 +671,24 @@ fn chroma_offset(
 }
 
 impl QuantizerParameters {
+  /// Derives the quantizers of a frame of type `fti` directly from a base
+  /// quantizer index, without rate control.
+  pub(crate) fn new_flat(
+    base_qi: u8, bit_depth: usize, chroma_sampling: ChromaSampling,
+    fti: usize, log_isqrt_mean_scale: i64,
+  ) -> QuantizerParameters {
+    let (log_base_q, log_q) =
+      RCState::calc_flat_quantizer(base_qi, bit_depth, fti);
+    QuantizerParameters::new_from_log_q(
+      log_base_q,
+      log_q,
+      bit_depth,
+      chroma_sampling,
+      fti == 0,
+      log_isqrt_mean_scale,
+    )
+  }
+
   fn new_from_log_q(
     log_base_q: i64, log_target_q: i64, bit_depth: usize,
     chroma_sampling: ChromaSampling, is_intra: bool,
@@ -585,32 +751,16 @@ impl RCState {
     frame_width: i32, frame_height: i32, framerate_num: i64,
     framerate_den: i64, target_bitrate: i32, maybe_ac_qi_max: Option<u8>,
     ac_qi_min: u8, max_key_frame_interval: i32,
-    maybe_reservoir_frame_delay: Option<i32>,
+    maybe_reservoir_frame_delay: Option<i32>, drop_frames: bool,
   ) -> RCState {
-    // The default buffer size is set equal to 1.5x the keyframe interval, or 240
-    //  frames; whichever is smaller, with a minimum of 12.
-    // For user set values, we enforce a minimum of 12.
-    // The interval is short enough to allow reaction, but long enough to allow
-    //  looking into the next GOP (avoiding the case where the last frames
-    //  before an I-frame get starved), in most cases.
-    // The 12 frame minimum gives us some chance to distribute bit estimation
-    //  errors in the worst case.
-    let reservoir_frame_delay = maybe_reservoir_frame_delay
-      .unwrap_or_else(|| ((max_key_frame_interval * 3) >> 1).min(240))
-      .max(12);
+    let reservoir_frame_delay = Self::reservoir_frame_delay(
+      max_key_frame_interval,
+      maybe_reservoir_frame_delay,
+    );
     // TODO: What are the limits on these?
     let npixels = (frame_width as i64) * (frame_height as i64);
-    // Insane framerates or frame sizes mean insane bitrates.
-    // Let's not get carried away.
-    // We also subtract 16 bits from each temporal unit to account for the
-    //  temporal delimiter, whose bits are not included in the frame sizes
-    //  reported to update_state().
-    // TODO: Support constraints imposed by levels.
-    let bits_per_tu = clamp(
-      (target_bitrate as i64) * framerate_den / framerate_num,
-      40,
-      0x4000_0000_0000,
-    ) - (TEMPORAL_DELIMITER.len() * 8) as i64;
+    let bits_per_tu =
+      Self::bits_per_tu(target_bitrate, framerate_num, framerate_den);
     let reservoir_max = bits_per_tu * (reservoir_frame_delay as i64);
     // Start with a buffer fullness and fullness target of 50%.
     let reservoir_target = (reservoir_max + 1) >> 1;
@@ -654,10 +804,11 @@ impl RCState {
       reservoir_frame_delay_is_set: maybe_reservoir_frame_delay.is_some(),
       maybe_ac_qi_max,
       ac_qi_min,
-      drop_frames: false,
+      drop_frames,
       cap_overflow: true,
       cap_underflow: false,
       pass1_log_base_q: 0,
+      pass: 1,
       twopass_state: PASS_SINGLE,
       log_npixels: blog64(npixels),
       bits_per_tu,
@@ -679,10 +830,12 @@ impl RCState {
       rate_bias: 0,
       nencoded_frames: 0,
       nsef_frames: 0,
-      pass1_buffer: [0; TWOPASS_HEADER_SZ],
+      pass1_buffer: vec![0; TWOPASS_HEADER_SZ],
       pass1_data_retrieved: true,
       pass1_summary_retrieved: false,
       pass2_data_ready: false,
+      first_pass_keyframes: Vec::new(),
+      nkeyframes_left: 0,
       prev_metrics: RCFrameMetrics::new(),
       cur_metrics: RCFrameMetrics::new(),
       frame_metrics: Vec::new(),
@@ -695,6 +848,10 @@ impl RCState {
       nframes_total_total: 0,
       nframes_left: [0; FRAME_NSUBTYPES + 1],
       scale_sum: [0; FRAME_NSUBTYPES],
+      bits_sum: [0; FRAME_NSUBTYPES],
+      frame_stats: RCFrameStats::default(),
+      npass_bits_left: 0,
+      npass_target_left: 0,
       scale_window_ntus: 0,
       scale_window_nframes: [0; FRAME_NSUBTYPES + 1],
       scale_window_sum: [0; FRAME_NSUBTYPES],
@@ -722,23 +879,24 @@ impl RCState {
   // TODO: Separate quantizers for Cb and Cr.
   #[profiling::function]
   pub(crate) fn select_qi<T: Pixel>(
-    &self, ctx: &ContextInner<T>, output_frameno: u64, fti: usize,
-    maybe_prev_log_base_q: Option<i64>, log_isqrt_mean_scale: i64,
+    &self, ctx: &ContextInner<T>, output_frameno: u64, input_frameno: u64,
+    fti: usize, maybe_prev_log_base_q: Option<i64>, log_isqrt_mean_scale: i64,
   ) -> QuantizerParameters {
+    let zone = ctx.config.zone_at(input_frameno);
+    let maybe_zone_quantizer = zone.and_then(|zone| zone.quantizer);
     // Is rate control active?
-    if self.target_bitrate <= 0 {
+    // A zone with an explicit quantizer bypasses it for its frames.
+    if self.target_bitrate <= 0 || maybe_zone_quantizer.is_some() {
       // Rate control is not active.
       // Derive quantizer directly from frame type.
       let bit_depth = ctx.config.bit_depth;
       let chroma_sampling = ctx.config.chroma_sampling;
-      let (log_base_q, log_q) =
-        Self::calc_flat_quantizer(ctx.config.quantizer as u8, bit_depth, fti);
-      QuantizerParameters::new_from_log_q(
-        log_base_q,
-        log_q,
+      let base_qi = maybe_zone_quantizer.unwrap_or(ctx.config.quantizer);
+      QuantizerParameters::new_flat(
+        base_qi as u8,
         bit_depth,
         chroma_sampling,
-        fti == 0,
+        fti,
         log_isqrt_mean_scale,
       )
     } else {
@@ -962,6 +1120,18 @@ impl RCState {
       let mut log_q = ((log_base_q + (1i64 << 11)) >> 12)
         * (MQP_Q12[fti] as i64)
         + DQP_Q57[fti];
+      // Scale the rate of the frames in a zone with a bitrate multiplier.
+      // With rate = scale*(quantizer**-exp), multiplying the rate by m
+      //  means lowering log_q by log2(m)/exp.
+      if let Some(multiplier) = zone.and_then(|zone| zone.bitrate_multiplier) {
+        let log_multiplier = (multiplier.log2() * (1i64 << 57) as f64) as i64;
+        log_q -= (log_multiplier / (self.exp[fti] as i64)) << 6;
+      }
+      // The quantizer refined from the previous pass already reflects the
+      //  zone of the frame.
+      if let Some(npass_log_q) = self.npass_log_q(fti) {
+        log_q = npass_log_q;
+      }
       // The above allocation looks only at the total rate we'll accumulate
       //  in the next reservoir_frame_delay frames.
       // However, we could overflow the bit reservoir on the very next
@@ -1043,6 +1213,28 @@ impl RCState {
     }
   }
 
+  // Computes the quantizer of the frame about to be coded from its size in
+  //  the previous rate-controlled pass, scaled by the ratio of the bits left
+  //  in this pass to the bits the remaining frames used in that one.
+  fn npass_log_q(&self, fti: usize) -> Option<i64> {
+    let m = &self.cur_metrics;
+    if self.npass_bits_left <= 0 || m.fti != fti || m.bits <= 0 {
+      return None;
+    }
+    let log_ratio = if self.npass_target_left > 0 {
+      (blog64(self.npass_target_left) - blog64(self.npass_bits_left))
+        .clamp(-NPASS_MAX_LOG_CORRECTION, NPASS_MAX_LOG_CORRECTION)
+    } else {
+      -NPASS_MAX_LOG_CORRECTION
+    };
+    // As rate = scale*(quantizer**-exp), multiplying the rate by the ratio
+    //  means lowering log_q by log2(ratio)/exp.
+    Some(
+      q24_to_q57(m.log_target_q_q24)
+        - ((log_ratio / (self.exp[fti] as i64)) << 6),
+    )
+  }
+
   // Computes a quantizer directly from the frame type and base quantizer index,
   // without consideration for rate control.
   fn calc_flat_quantizer(
@@ -1069,6 +1261,81 @@ impl RCState {
     (log_base_q, log_q)
   }
 
+  fn reservoir_frame_delay(
+    max_key_frame_interval: i32, maybe_reservoir_frame_delay: Option<i32>,
+  ) -> i32 {
+    // The default buffer size is set equal to 1.5x the keyframe interval, or 240
+    //  frames; whichever is smaller, with a minimum of 12.
+    // For user set values, we enforce a minimum of 12.
+    // The interval is short enough to allow reaction, but long enough to allow
+    //  looking into the next GOP (avoiding the case where the last frames
+    //  before an I-frame get starved), in most cases.
+    // The 12 frame minimum gives us some chance to distribute bit estimation
+    //  errors in the worst case.
+    maybe_reservoir_frame_delay
+      .unwrap_or_else(|| ((max_key_frame_interval * 3) >> 1).min(240))
+      .max(12)
+  }
+
+  fn bits_per_tu(
+    target_bitrate: i32, framerate_num: i64, framerate_den: i64,
+  ) -> i64 {
+    // Insane framerates or frame sizes mean insane bitrates.
+    // Let's not get carried away.
+    // We also subtract 16 bits from each temporal unit to account for the
+    //  temporal delimiter, whose bits are not included in the frame sizes
+    //  reported to update_state().
+    // TODO: Support constraints imposed by levels.
+    clamp(
+      (target_bitrate as i64) * framerate_den / framerate_num,
+      40,
+      0x4000_0000_0000,
+    ) - (TEMPORAL_DELIMITER.len() * 8) as i64
+  }
+
+  // Changes the rate targets in the middle of a single-pass encode.
+  // The rate model estimates gathered so far are kept, and the reservoir
+  //  keeps its relative fullness.
+  pub(crate) fn reconfigure(
+    &mut self, framerate_num: i64, framerate_den: i64, target_bitrate: i32,
+    maybe_ac_qi_max: Option<u8>, ac_qi_min: u8, max_key_frame_interval: i32,
+    maybe_reservoir_frame_delay: Option<i32>, drop_frames: bool,
+  ) {
+    debug_assert!(self.twopass_state == PASS_SINGLE);
+    let reservoir_frame_delay = Self::reservoir_frame_delay(
+      max_key_frame_interval,
+      maybe_reservoir_frame_delay,
+    );
+    let bits_per_tu =
+      Self::bits_per_tu(target_bitrate, framerate_num, framerate_den);
+    let reservoir_max = bits_per_tu * (reservoir_frame_delay as i64);
+    self.reservoir_fullness = (self.reservoir_fullness as f64
+      * reservoir_max as f64
+      / self.reservoir_max as f64) as i64;
+    self.reservoir_target = (reservoir_max + 1) >> 1;
+    self.reservoir_max = reservoir_max;
+    self.bits_per_tu = bits_per_tu;
+    self.reservoir_frame_delay = reservoir_frame_delay;
+    self.reservoir_frame_delay_is_set = maybe_reservoir_frame_delay.is_some();
+    self.inter_delay_target = reservoir_frame_delay >> 1;
+    self.target_bitrate = target_bitrate;
+    self.maybe_ac_qi_max = maybe_ac_qi_max;
+    self.ac_qi_min = ac_qi_min;
+    self.drop_frames = drop_frames;
+  }
+
+  // Whether pass data is read or written, in which case the rate targets
+  //  cannot change during the encode.
+  pub(crate) const fn is_multipass(&self) -> bool {
+    self.twopass_state != PASS_SINGLE
+  }
+
+  // Whether pass data is written, with the lookahead statistics of each
+  //  frame.
+  pub(crate) const fn emits_pass_data(&self) -> bool {
+    self.twopass_state == PASS_1 || self.twopass_state == PASS_2_PLUS_1
+  }
+
   #[profiling::function]
   pub fn update_state(
     &mut self, bits: i64, fti: usize, show_frame: bool, log_target_q: i64,
@@ -1096,7 +1363,8 @@ impl RCState {
         droppable = false;
       }
       if fti == FRAME_SUBTYPE_SEF {
-        debug_assert!(bits == SEF_BITS);
+        // Showing a keyframe also repeats the sequence header.
+        debug_assert!(bits >= SEF_BITS);
         debug_assert!(show_frame);
         // Please don't make trial encodes of a SEF.
         debug_assert!(!trial);
@@ -1128,6 +1396,13 @@ impl RCState {
         if !trial {
           // Move the current metrics back one frame.
           self.prev_metrics = self.cur_metrics;
+          // Take the frame out of those left to refine.
+          self.npass_bits_left -= if self.prev_metrics.fti == FRAME_SUBTYPE_SEF
+          {
+            SEF_BITS
+          } else {
+            self.prev_metrics.bits
+          };
           // Back out the last frame's statistics from the sliding window.
           let ftj = self.prev_metrics.fti;
           self.nframes_left[ftj] -= 1;
@@ -1161,6 +1436,13 @@ impl RCState {
         self.prev_metrics.log_scale_q24 = log_scale_q24;
         self.prev_metrics.fti = fti;
         self.prev_metrics.show_frame = show_frame;
+        self.prev_metrics.bits = bits;
+        self.prev_metrics.log_target_q_q24 = q57_to_q24(log_target_q);
+        self.prev_metrics.stats = if fti == FRAME_SUBTYPE_SEF {
+          RCFrameStats::default()
+        } else {
+          self.frame_stats
+        };
         self.pass1_data_retrieved = false;
       }
       // Common to all passes:
@@ -1195,7 +1477,8 @@ impl RCState {
         // If this frame busts our budget, it must be dropped.
         if droppable && self.reservoir_fullness + self.bits_per_tu < bits {
           // TODO: Adjust VFR rate based on drop count.
-          bits = 0;
+          // The frame is replaced by a Show Existing Frame.
+          bits = SEF_BITS;
           dropped = true;
         } else {
           // TODO: Update a low-pass filter to estimate the "real" frame rate
@@ -1210,6 +1493,7 @@ impl RCState {
           self.nframes[fti] += 1;
         }
         self.reservoir_fullness -= bits;
+        self.npass_target_left -= bits;
         if show_frame {
           self.reservoir_fullness += self.bits_per_tu;
           // TODO: Properly account for temporal delimiter bits.
@@ -1232,6 +1516,12 @@ impl RCState {
     dropped
   }
 
+  // Record the lookahead statistics of the next frame to be coded, to be
+  //  emitted with its pass data.
+  pub(crate) fn set_frame_stats(&mut self, stats: RCFrameStats) {
+    self.frame_stats = stats;
+  }
+
   pub const fn needs_trial_encode(&self, fti: usize) -> bool {
     self.target_bitrate > 0 && self.nframes[fti] == 0
   }
@@ -1259,10 +1549,12 @@ impl RCState {
   }
 
   pub(crate) fn select_pass1_log_base_q<T: Pixel>(
-    &self, ctx: &ContextInner<T>, output_frameno: u64,
+    &self, ctx: &ContextInner<T>, output_frameno: u64, input_frameno: u64,
   ) -> i64 {
     assert_eq!(self.twopass_state, PASS_SINGLE);
-    self.select_qi(ctx, output_frameno, FRAME_SUBTYPE_I, None, 0).log_base_q
+    self
+      .select_qi(ctx, output_frameno, input_frameno, FRAME_SUBTYPE_I, None, 0)
+      .log_base_q
   }
 
   // Initialize the first pass and emit a placeholder summary
@@ -1297,6 +1589,7 @@ impl RCState {
     let fti = self.prev_metrics.fti;
     if fti < FRAME_NSUBTYPES {
       self.scale_sum[fti] += bexp_q24(self.prev_metrics.log_scale_q24);
+      self.bits_sum[fti] += self.prev_metrics.bits;
     }
     if self.prev_metrics.show_frame {
       self.ntus += 1;
@@ -1314,13 +1607,23 @@ impl RCState {
     );
     cur_pos =
       self.buffer_val(self.prev_metrics.log_scale_q24 as i64, 4, cur_pos);
+    cur_pos = self.buffer_val(self.prev_metrics.bits, 4, cur_pos);
+    cur_pos =
+      self.buffer_val(self.prev_metrics.log_target_q_q24 as i64, 4, cur_pos);
+    let stats = self.prev_metrics.stats;
+    cur_pos = self.buffer_val(stats.intra_cost as i64, 4, cur_pos);
+    cur_pos = self.buffer_val(stats.inter_cost as i64, 4, cur_pos);
+    cur_pos = self.buffer_val(stats.motion as i64, 4, cur_pos);
     debug_assert!(cur_pos == TWOPASS_PACKET_SZ);
     self.pass1_data_retrieved = true;
     Some(&self.pass1_buffer[..cur_pos])
   }
 
   // Summary of the whole encoding process.
-  pub(crate) fn emit_summary(&mut self) -> &[u8] {
+  pub(crate) fn emit_summary(&mut self, keyframes: &BTreeSet<u64>) -> &[u8] {
+    self
+      .pass1_buffer
+      .resize(TWOPASS_HEADER_SZ + keyframes.len() * TWOPASS_KEYFRAME_SZ, 0);
     let mut cur_pos = 0;
     cur_pos = self.buffer_val(TWOPASS_MAGIC as i64, 4, cur_pos);
     cur_pos = self.buffer_val(TWOPASS_VERSION as i64, 4, cur_pos);
@@ -1334,14 +1637,30 @@ impl RCState {
     for fti in 0..FRAME_NSUBTYPES {
       cur_pos = self.buffer_val(self.scale_sum[fti], 8, cur_pos);
     }
+    for fti in 0..FRAME_NSUBTYPES {
+      cur_pos = self.buffer_val(self.bits_sum[fti], 8, cur_pos);
+    }
+    cur_pos = self.buffer_val(self.pass as i64, 4, cur_pos);
+    // Only a rate-controlled pass has a bit target to refine.
+    let target_bits =
+      if self.twopass_state == PASS_2_PLUS_1 && self.target_bitrate > 0 {
+        self.bits_per_tu * (self.ntus as i64)
+      } else {
+        0
+      };
+    cur_pos = self.buffer_val(target_bits, 8, cur_pos);
+    cur_pos = self.buffer_val(keyframes.len() as i64, 4, cur_pos);
     debug_assert!(cur_pos == TWOPASS_HEADER_SZ);
+    for &keyframe in keyframes {
+      cur_pos = self.buffer_val(keyframe as i64, TWOPASS_KEYFRAME_SZ, cur_pos);
+    }
     self.pass1_summary_retrieved = true;
     &self.pass1_buffer[..cur_pos]
   }
 
   // Emit either summary or frame-specific data depending on the previous call
   pub(crate) fn twopass_out(
-    &mut self, done_processing: bool,
+    &mut self, done_processing: bool, keyframes: &BTreeSet<u64>,
   ) -> Option<&[u8]> {
     if !self.pass1_data_retrieved {
       if self.twopass_state != PASS_1 && self.twopass_state != PASS_2_PLUS_1 {
@@ -1350,7 +1669,7 @@ impl RCState {
         self.emit_frame_data()
       }
     } else if done_processing && !self.pass1_summary_retrieved {
-      Some(self.emit_summary())
+      Some(self.emit_summary(keyframes))
     } else {
       // The data for this frame has already been retrieved.
       None
@@ -1384,6 +1703,16 @@ impl RCState {
   }
 
   pub(crate) fn setup_second_pass(&mut self, s: &RCSummary) {
+    self.pass = s.pass + 1;
+    // A rate-controlled pass already distributed its bits sensibly, and each
+    //  frame will take about as many bits again at the same quantizer.
+    // Rather than the scales, refine the quantizer of each frame from its
+    //  size in that pass to spend the bits left over the remaining frames.
+    if s.target_bits > 0 {
+      self.npass_bits_left = s.bits.iter().sum::<i64>()
+        + (s.nframes[FRAME_SUBTYPE_SEF] as i64) * SEF_BITS;
+      self.npass_target_left = self.bits_per_tu * (s.ntus as i64);
+    }
     self.ntus_total = s.ntus;
     self.ntus_left = s.ntus;
     self.nframes_total = s.nframes;
@@ -1415,6 +1744,7 @@ impl RCState {
       let s = self.des.parse_summary()?;
 
       self.setup_second_pass(&s);
+      self.nkeyframes_left = s.nkeyframes;
 
       // Got a valid header.
       // Set up pass 2.
@@ -1443,6 +1773,52 @@ impl RCState {
     TWOPASS_HEADER_SZ + frames_needed * TWOPASS_PACKET_SZ
   }
 
+  // Parse the keyframe list following the summary header
+  //
+  // If no buffer is passed return the amount of data it expects
+  // to consume next.
+  fn twopass_parse_keyframes(
+    &mut self, maybe_buf: Option<&[u8]>, mut consumed: usize,
+  ) -> Result<usize, String> {
+    while self.nkeyframes_left > 0 {
+      if let Some(buf) = maybe_buf {
+        consumed = self.des.buffer_fill(buf, consumed, TWOPASS_KEYFRAME_SZ);
+        if self.des.pass2_buffer_fill < TWOPASS_KEYFRAME_SZ {
+          // Go back for more data.
+          break;
+        }
+        self.des.pass2_buffer_pos = 0;
+        let keyframe = self.des.parse_keyframe(
+          self.first_pass_keyframes.last().copied(),
+          self.ntus_total,
+        )?;
+        self.first_pass_keyframes.push(keyframe);
+        self.nkeyframes_left -= 1;
+        self.des.pass2_buffer_fill = 0;
+      } else {
+        return Ok(
+          self.nkeyframes_left * TWOPASS_KEYFRAME_SZ
+            - self.des.pass2_buffer_fill,
+        );
+      }
+    }
+    Ok(consumed)
+  }
+
+  pub(crate) const fn ntus_total(&self) -> i32 {
+    self.ntus_total
+  }
+
+  // Return the keyframes of the summary read by twopass_in(), once all of
+  //  them have been read.
+  pub(crate) fn take_first_pass_keyframes(&mut self) -> Option<Vec<u64>> {
+    if self.nkeyframes_left == 0 && !self.first_pass_keyframes.is_empty() {
+      Some(std::mem::take(&mut self.first_pass_keyframes))
+    } else {
+      None
+    }
+  }
+
   // Return the number of frame data packets to be parsed before
   // the encoding process can continue.
   pub(crate) fn twopass_in_frames_needed(&self) -> i32 {
@@ -1631,6 +2007,12 @@ impl RCState {
         return Ok(self.twopass_first_packet_size());
       }
     }
+    if self.nkeyframes_left > 0 {
+      consumed = self.twopass_parse_keyframes(maybe_buf, consumed)?;
+      if self.nkeyframes_left > 0 {
+        return Ok(consumed);
+      }
+    }
     if self.nframes_total[FRAME_SUBTYPE_I] > 0 {
       if self.nencoded_frames + self.nsef_frames
         >= self.nframes_total_total as i64
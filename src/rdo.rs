@@ -748,6 +748,7 @@ pub fn rdo_tx_size_type<T: Pixel>(
 
     let do_rdo_tx_type = tx_set > TxSet::TX_SET_DCTONLY
       && fi.config.speed_settings.transform.rdo_tx_decision
+      && !fi.coded_lossless()
       && !is_inter
       && !skip;
 
@@ -942,9 +943,13 @@ fn luma_chroma_mode_rdo<T: Pixel>(
     zero_distortion
   };
 
-  // Don't skip when using intra modes
-  let zero_distortion =
-    if !luma_mode_is_intra { chroma_rdo(true) } else { false };
+  // Don't skip when using intra modes, nor in lossless frames, whose blocks
+  // are only skipped once coded without residual.
+  let zero_distortion = if !luma_mode_is_intra && !fi.coded_lossless() {
+    chroma_rdo(true)
+  } else {
+    false
+  };
   // early skip
   if !zero_distortion {
     chroma_rdo(false);
@@ -0,0 +1,287 @@
+// Copyright (c) 2018-2022, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+#![deny(missing_docs)]
+
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use crate::api::{
+  ChromaSampling, Config, EncoderConfig, EncoderStatus, InvalidConfig,
+  SceneDetectionSpeed, SceneLabel,
+};
+use crate::cpu_features::CpuFeatureLevel;
+use crate::encoder::Sequence;
+use crate::frame::{Frame, FrameAlloc};
+use crate::util::Pixel;
+
+use super::{SceneChangeDetector, ScenecutResult};
+
+/// Settings of a [`SceneDetector`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SceneDetectionConfig {
+  /// Width of the frames in pixels.
+  pub width: usize,
+  /// Height of the frames in pixels.
+  pub height: usize,
+  /// Bit depth of the frames.
+  pub bit_depth: usize,
+  /// Chroma subsampling of the frames.
+  pub chroma_sampling: ChromaSampling,
+  /// Detection method. With `SceneDetectionSpeed::None`, scenes are only cut
+  /// when they reach `max_scene_len`.
+  pub speed: SceneDetectionSpeed,
+  /// Minimum length of a scene, in frames.
+  pub min_scene_len: u64,
+  /// Maximum length of a scene, in frames. A value of 0 means no maximum.
+  pub max_scene_len: u64,
+  /// Number of frames following a frame which are analyzed before deciding
+  /// whether it starts a new scene.
+  pub lookahead_frames: usize,
+}
+
+impl Default for SceneDetectionConfig {
+  fn default() -> Self {
+    let enc = EncoderConfig::default();
+    SceneDetectionConfig {
+      width: enc.width,
+      height: enc.height,
+      bit_depth: enc.bit_depth,
+      chroma_sampling: enc.chroma_sampling,
+      speed: SceneDetectionSpeed::Standard,
+      min_scene_len: enc.min_key_frame_interval,
+      max_scene_len: enc.max_key_frame_interval,
+      lookahead_frames: 5,
+    }
+  }
+}
+
+impl SceneDetectionConfig {
+  /// Validates the configuration.
+  ///
+  /// # Errors
+  ///
+  /// Returns `InvalidConfig` if the frame size or the maximum scene length
+  /// is invalid.
+  pub fn validate(&self) -> Result<(), InvalidConfig> {
+    Config::new().with_encoder_config(self.encoder_config()).validate()
+  }
+
+  /// Returns the encoder settings the detection runs with.
+  fn encoder_config(&self) -> EncoderConfig {
+    let mut enc = EncoderConfig {
+      width: self.width,
+      height: self.height,
+      bit_depth: self.bit_depth,
+      chroma_sampling: self.chroma_sampling,
+      ..Default::default()
+    };
+    enc.set_key_frame_interval(self.min_scene_len, self.max_scene_len);
+    enc.speed_settings.scene_detection_mode = self.speed;
+    enc
+  }
+}
+
+/// Decision taken by a [`SceneDetector`] on a frame.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SceneDecision {
+  /// The number of the input frame.
+  pub input_frameno: u64,
+  /// Whether a new scene starts at the frame.
+  pub scene_cut: bool,
+  /// Content transition detected at the frame.
+  pub label: SceneLabel,
+  /// Metrics the decision was based on. Missing when the decision did not
+  /// depend on the content, e.g. for the first frame or near the end of the
+  /// video.
+  pub score: Option<ScenecutResult>,
+}
+
+/// Splits a video into scenes, independently from an encoder.
+///
+/// Frames are sent in order with [`send_frame`], and a decision on each of
+/// them is received in the same order with [`receive_decision`] once enough
+/// following frames were sent.
+///
+/// # Examples
+///
+/// ```
+/// use rav1e::scenechange::{SceneDetectionConfig, SceneDetector};
+/// use rav1e::EncoderStatus;
+/// use std::sync::Arc;
+///
+/// # fn main() -> Result<(), rav1e::InvalidConfig> {
+/// let config = SceneDetectionConfig { width: 64, height: 64, ..Default::default() };
+/// let mut detector: SceneDetector<u8> = SceneDetector::new(config)?;
+///
+/// let mut scene_cuts = Vec::new();
+/// for _ in 0..10 {
+///   let frame = detector.new_frame();
+///   detector.send_frame(Arc::new(frame)).unwrap();
+///   while let Ok(decision) = detector.receive_decision() {
+///     if decision.scene_cut {
+///       scene_cuts.push(decision.input_frameno);
+///     }
+///   }
+/// }
+/// detector.flush();
+/// loop {
+///   match detector.receive_decision() {
+///     Ok(decision) if decision.scene_cut => {
+///       scene_cuts.push(decision.input_frameno)
+///     }
+///     Ok(_) => {}
+///     Err(EncoderStatus::LimitReached) => break,
+///     Err(e) => panic!("unexpected status {e}"),
+///   }
+/// }
+/// assert_eq!(scene_cuts, [0]);
+/// # Ok(())
+/// # }
+/// ```
+///
+/// [`send_frame`]: #method.send_frame
+/// [`receive_decision`]: #method.receive_decision
+pub struct SceneDetector<T: Pixel> {
+  config: SceneDetectionConfig,
+  detector: SceneChangeDetector<T>,
+  /// Frames starting from the one before the next frame to decide on.
+  frames: VecDeque<Arc<Frame<T>>>,
+  /// The number of the next frame to decide on.
+  next_frameno: u64,
+  /// The number of the frame starting the last scene.
+  last_scene_cut: u64,
+  flushed: bool,
+}
+
+impl<T: Pixel> SceneDetector<T> {
+  /// Creates a scene detector.
+  ///
+  /// # Errors
+  ///
+  /// Returns `InvalidConfig` if the configuration is invalid.
+  ///
+  /// # Panics
+  ///
+  /// Panics if the size of `T` does not match the bit depth.
+  pub fn new(config: SceneDetectionConfig) -> Result<Self, InvalidConfig> {
+    assert!(
+      8 * std::mem::size_of::<T>() >= config.bit_depth,
+      "The Pixel u{} does not match the bit_depth {}",
+      8 * std::mem::size_of::<T>(),
+      config.bit_depth
+    );
+
+    config.validate()?;
+
+    let enc = config.encoder_config();
+    let sequence = Arc::new(Sequence::new(&enc));
+    let detector = SceneChangeDetector::new(
+      enc,
+      CpuFeatureLevel::default(),
+      config.lookahead_frames,
+      sequence,
+    );
+
+    Ok(SceneDetector {
+      config,
+      detector,
+      frames: VecDeque::new(),
+      next_frameno: 0,
+      last_scene_cut: 0,
+      flushed: false,
+    })
+  }
+
+  /// Allocates and returns a new frame.
+  pub fn new_frame(&self) -> Frame<T> {
+    Frame::new(
+      self.config.width,
+      self.config.height,
+      self.config.chroma_sampling,
+    )
+  }
+
+  /// Sends the next frame for analysis.
+  ///
+  /// # Errors
+  ///
+  /// Returns `EncoderStatus::EnoughData` if the detector was flushed.
+  pub fn send_frame(
+    &mut self, frame: Arc<Frame<T>>,
+  ) -> Result<(), EncoderStatus> {
+    if self.flushed {
+      return Err(EncoderStatus::EnoughData);
+    }
+    self.frames.push_back(frame);
+    Ok(())
+  }
+
+  /// Signals the end of the video, so the last frames can be decided on
+  /// without waiting for following ones.
+  pub fn flush(&mut self) {
+    self.flushed = true;
+  }
+
+  /// Returns the decision on the next frame.
+  ///
+  /// # Errors
+  ///
+  /// Returns `EncoderStatus::NeedMoreData` if more frames must be sent
+  /// before deciding on the next frame, and `EncoderStatus::LimitReached`
+  /// once all frames were decided on after a flush.
+  pub fn receive_decision(&mut self) -> Result<SceneDecision, EncoderStatus> {
+    let missing = if self.flushed {
+      EncoderStatus::LimitReached
+    } else {
+      EncoderStatus::NeedMoreData
+    };
+
+    if self.next_frameno == 0 {
+      if self.frames.is_empty() {
+        return Err(missing);
+      }
+      // The first frame always starts a scene.
+      self.next_frameno = 1;
+      return Ok(SceneDecision {
+        input_frameno: 0,
+        scene_cut: true,
+        label: SceneLabel::Normal,
+        score: None,
+      });
+    }
+
+    let frame_set_len = self.config.lookahead_frames + 2;
+    if self.frames.len() < 2
+      || (!self.flushed && self.frames.len() < frame_set_len)
+    {
+      return Err(missing);
+    }
+
+    let input_frameno = self.next_frameno;
+    let frame_set = self.frames.iter().take(frame_set_len).collect::<Vec<_>>();
+    let (scene_cut, score) = self.detector.score_next_frame(
+      &frame_set,
+      input_frameno,
+      self.last_scene_cut,
+    );
+    if scene_cut {
+      self.last_scene_cut = input_frameno;
+    }
+    let label = self.detector.scene_label(input_frameno);
+
+    // The label of the frame is still needed for the next one.
+    self.detector.forget_scene_labels_before(input_frameno);
+    self.detector.intra_costs =
+      self.detector.intra_costs.split_off(&input_frameno);
+    self.frames.pop_front();
+    self.next_frameno += 1;
+
+    Ok(SceneDecision { input_frameno, scene_cut, label, score })
+  }
+}
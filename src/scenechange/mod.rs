@@ -12,17 +12,25 @@ use std::collections::BTreeMap;
 use std::num::NonZeroUsize;
 use std::sync::Arc;
 
-use crate::api::{EncoderConfig, SceneDetectionSpeed};
+use crate::api::{EncoderConfig, SceneDetectionSpeed, SceneLabel};
 use crate::cpu_features::CpuFeatureLevel;
 use crate::encoder::Sequence;
 use crate::frame::*;
 use crate::me::RefMEStats;
+use crate::serialize::{Deserialize, Serialize};
 use crate::util::Pixel;
 
 use self::fast::{detect_scale_factor, FAST_THRESHOLD};
+use self::transition::Thumbnail;
 
+#[cfg(feature = "scenechange")]
+pub use self::detector::*;
+
+#[cfg(feature = "scenechange")]
+mod detector;
 mod fast;
 mod standard;
+mod transition;
 
 /// Experiments have determined this to be an optimal threshold
 const IMP_BLOCK_DIFF_THRESHOLD: f64 = 7.0;
@@ -87,6 +95,20 @@ pub struct SceneChangeDetector<T: Pixel> {
   pub(crate) intra_costs: BTreeMap<u64, Box<[u32]>>,
   /// Temporary buffer used by `estimate_intra_costs`.
   pub(crate) temp_plane: Option<Plane<T>>,
+  /// Luma thumbnails of the frames around the analyzed one.
+  thumbnails: BTreeMap<u64, Thumbnail>,
+  /// Content transitions detected for each input frame.
+  scene_labels: BTreeMap<u64, SceneLabel>,
+}
+
+/// The results of the frames analyzed so far which the detector uses for the
+/// following frames, saved in checkpoints.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct SceneDetectorHistory {
+  deque_offset: usize,
+  score_deque: Vec<ScenecutResult>,
+  thumbnails: Vec<(u64, Thumbnail)>,
+  scene_labels: Vec<(u64, SceneLabel)>,
 }
 
 impl<T: Pixel> SceneChangeDetector<T> {
@@ -111,8 +133,7 @@ impl<T: Pixel> SceneChangeDetector<T> {
     let score_deque = Vec::with_capacity(5 + lookahead_distance);
 
     // Downscaling factor for fast scenedetect (is currently always a power of 2)
-    let factor =
-      scale_func.as_ref().map_or(NonZeroUsize::new(1).unwrap(), |x| x.factor);
+    let factor = scale_func.as_ref().map_or(NonZeroUsize::MIN, |x| x.factor);
 
     let pixels = if speed_mode == SceneDetectionSpeed::Fast {
       fast_idiv(sequence.max_frame_height as usize, factor)
@@ -139,9 +160,47 @@ impl<T: Pixel> SceneChangeDetector<T> {
       sequence,
       intra_costs: BTreeMap::new(),
       temp_plane: None,
+      thumbnails: BTreeMap::new(),
+      scene_labels: BTreeMap::new(),
+    }
+  }
+
+  /// Changes the minimum and maximum keyframe intervals for the frames
+  /// analyzed from now on.
+  pub(crate) fn set_key_frame_intervals(
+    &mut self, min_key_frame_interval: u64, max_key_frame_interval: u64,
+  ) {
+    self.encoder_config.min_key_frame_interval = min_key_frame_interval;
+    self.encoder_config.max_key_frame_interval = max_key_frame_interval;
+  }
+
+  /// Returns the results of the frames analyzed so far which are used for
+  /// the following frames.
+  pub(crate) fn history(&self) -> SceneDetectorHistory {
+    SceneDetectorHistory {
+      deque_offset: self.deque_offset,
+      score_deque: self.score_deque.clone(),
+      thumbnails: self
+        .thumbnails
+        .iter()
+        .map(|(&input_frameno, thumbnail)| (input_frameno, thumbnail.clone()))
+        .collect(),
+      scene_labels: self
+        .scene_labels
+        .iter()
+        .map(|(&input_frameno, &label)| (input_frameno, label))
+        .collect(),
     }
   }
 
+  /// Continues the analysis of a resumed encode from its `history`.
+  pub(crate) fn restore_history(&mut self, history: &SceneDetectorHistory) {
+    self.deque_offset = history.deque_offset;
+    self.score_deque.clone_from(&history.score_deque);
+    self.thumbnails = history.thumbnails.iter().cloned().collect();
+    self.scene_labels = history.scene_labels.iter().copied().collect();
+  }
+
   /// Runs keyframe detection on the next frame in the lookahead queue.
   ///
   /// This function requires that a subset of input frames
@@ -155,25 +214,36 @@ impl<T: Pixel> SceneChangeDetector<T> {
     &mut self, frame_set: &[&Arc<Frame<T>>], input_frameno: u64,
     previous_keyframe: u64,
   ) -> bool {
+    self.score_next_frame(frame_set, input_frameno, previous_keyframe).0
+  }
+
+  /// Runs keyframe detection like `analyze_next_frame`, also returning the
+  /// metrics the decision was based on, if any were computed.
+  pub(crate) fn score_next_frame(
+    &mut self, frame_set: &[&Arc<Frame<T>>], input_frameno: u64,
+    previous_keyframe: u64,
+  ) -> (bool, Option<ScenecutResult>) {
     // Use score deque for adaptive threshold for scene cut
     // Declare score_deque offset based on lookahead  for scene change scores
 
     // Find the distance to the previous keyframe.
     let distance = input_frameno - previous_keyframe;
 
+    let label = self.label_next_frame(frame_set, input_frameno);
+
     if frame_set.len() <= self.lookahead_offset {
       // Don't insert keyframes in the last few frames of the video
       // This is basically a scene flash and a waste of bits
-      return false;
+      return (false, None);
     }
 
     if self.encoder_config.speed_settings.scene_detection_mode
       == SceneDetectionSpeed::None
     {
       if let Some(true) = self.handle_min_max_intervals(distance) {
-        return true;
+        return (true, None);
       };
-      return false;
+      return (false, None);
     }
 
     // Initialization of score deque
@@ -206,16 +276,25 @@ impl<T: Pixel> SceneChangeDetector<T> {
 
     // Adaptive scenecut check
     let (scenecut, score) = self.adaptive_scenecut();
+    // Neither a flash, nor the frame it returns to, nor a gradual transition
+    // starts a new scene.
+    let scenecut = scenecut
+      && label == SceneLabel::Normal
+      && self.scene_label(input_frameno - 1) != SceneLabel::Flash;
+    if scenecut {
+      self.scene_labels.insert(input_frameno, SceneLabel::SceneCut);
+    }
     let scenecut = self.handle_min_max_intervals(distance).unwrap_or(scenecut);
     debug!(
-      "[SC-Detect] Frame {}: Raw={:5.1}  ImpBl={:5.1}  Bwd={:5.1}  Fwd={:5.1}  Th={:.1}  {}",
+      "[SC-Detect] Frame {}: Raw={:5.1}  ImpBl={:5.1}  Bwd={:5.1}  Fwd={:5.1}  Th={:.1}  {}  {}",
       input_frameno,
       score.inter_cost,
       score.imp_block_cost,
       score.backward_adjusted_cost,
       score.forward_adjusted_cost,
       score.threshold,
-      if scenecut { "Scenecut" } else { "No cut" }
+      if scenecut { "Scenecut" } else { "No cut" },
+      self.scene_label(input_frameno)
     );
 
     // Keep score deque of 5 backward frames
@@ -224,7 +303,7 @@ impl<T: Pixel> SceneChangeDetector<T> {
       self.score_deque.pop();
     }
 
-    scenecut
+    (scenecut, Some(score))
   }
 
   fn handle_min_max_intervals(&mut self, distance: u64) -> Option<bool> {
@@ -373,11 +452,19 @@ impl<T: Pixel> SceneChangeDetector<T> {
   }
 }
 
-#[derive(Debug, Clone, Copy)]
-struct ScenecutResult {
-  inter_cost: f64,
-  imp_block_cost: f64,
-  backward_adjusted_cost: f64,
-  forward_adjusted_cost: f64,
-  threshold: f64,
+/// Metrics computed by the scene change detection between a frame and the
+/// one before it.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ScenecutResult {
+  /// Cost of coding the frame from the previous one, or the mean absolute
+  /// difference between them in fast mode.
+  pub inter_cost: f64,
+  /// Difference between the frames weighted by the importance of the blocks.
+  pub imp_block_cost: f64,
+  /// `inter_cost` minus the highest cost of the preceding frames.
+  pub backward_adjusted_cost: f64,
+  /// `inter_cost` minus the highest cost of the following frames.
+  pub forward_adjusted_cost: f64,
+  /// Threshold the adjusted costs are compared to.
+  pub threshold: f64,
 }
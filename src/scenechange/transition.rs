@@ -0,0 +1,269 @@
+// Copyright (c) 2018-2022, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+use std::sync::Arc;
+
+use crate::api::{SceneDetectionSpeed, SceneLabel};
+use crate::frame::{Frame, Plane};
+use crate::serialize::{Deserialize, Serialize};
+use v_frame::pixel::{CastFromPrimitive, Pixel};
+
+use super::SceneChangeDetector;
+
+/// Number of cells per dimension of the luma thumbnails.
+const THUMBNAIL_SIZE: usize = 16;
+/// Distance between the luma samples kept with a thumbnail.
+const SAMPLE_STEP: usize = 4;
+/// Minimum mean difference to the previous frame, on the 8-bit scale,
+/// for a frame to start a flash.
+const FLASH_THRESHOLD: f32 = 20.0;
+/// Maximum length of a flash, in frames.
+const MAX_FLASH_LEN: u64 = 2;
+/// Number of consecutive frame differences a gradual transition must span.
+const GRADUAL_WINDOW: u64 = 3;
+/// Range of the mean difference between two frames of a gradual transition,
+/// on the 8-bit scale.
+const GRADUAL_MIN_STEP: f32 = 0.5;
+const GRADUAL_MAX_STEP: f32 = 16.0;
+/// Maximum relative distance of the frames of a cross-dissolve to the blend
+/// of its ends. Motion shows as a large distance, since it does not
+/// move pixel values along a straight line.
+const GRADUAL_MAX_NONLINEARITY: f32 = 0.25;
+/// Maximum contrast ratio between the ends of a fade.
+const FADE_MAX_CONTRAST_RATIO: f32 = 0.85;
+/// Contrast, on the 8-bit scale, below which a picture counts as flat.
+const FLAT_DEVIATION: f32 = 1.0;
+/// Minimum correlation between the change over a fade and the picture
+/// fading, which it scales.
+const FADE_MIN_CORRELATION: f32 = 0.8;
+/// Minimum mean difference between the ends of a cross-dissolve, on the
+/// 8-bit scale.
+const DISSOLVE_MIN_DIFF: f32 = 4.0;
+
+/// A coarse grid of mean luma values of a frame, along with sparse samples.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(super) struct Thumbnail {
+  cells: Box<[f32]>,
+  mean: f32,
+  deviation: f32,
+  samples: Box<[u16]>,
+}
+
+impl Thumbnail {
+  fn new<T: Pixel>(plane: &Plane<T>, bit_depth: usize) -> Self {
+    let width = plane.cfg.width;
+    let height = plane.cfg.height;
+    let cols = THUMBNAIL_SIZE.min(width);
+    let rows = THUMBNAIL_SIZE.min(height);
+    let col_of_x: Box<[usize]> =
+      (0..width).map(|x| x * cols / width).collect();
+
+    let mut sums = vec![0u64; cols * rows];
+    let mut counts = vec![0u64; cols * rows];
+    let mut samples = Vec::new();
+    for (y, row) in plane.rows_iter().enumerate() {
+      let cells = y * rows / height * cols;
+      for (&pixel, &col) in row.iter().zip(col_of_x.iter()) {
+        sums[cells + col] += u64::from(u16::cast_from(pixel));
+        counts[cells + col] += 1;
+      }
+      if y % SAMPLE_STEP == 0 {
+        samples.extend(
+          row[..width].iter().step_by(SAMPLE_STEP).map(|&p| u16::cast_from(p)),
+        );
+      }
+    }
+
+    // Bring the values to the 8-bit scale, so thresholds apply to any depth.
+    let scale = (1 << (bit_depth - 8)) as f32;
+    let cells: Box<[f32]> = sums
+      .iter()
+      .zip(counts.iter())
+      .map(|(&sum, &count)| sum as f32 / count as f32 / scale)
+      .collect();
+    let (mean, deviation) = mean_and_deviation(&cells);
+
+    Thumbnail { cells, mean, deviation, samples: samples.into_boxed_slice() }
+  }
+
+  /// Mean absolute difference to `other`.
+  fn diff(&self, other: &Thumbnail) -> f32 {
+    self
+      .cells
+      .iter()
+      .zip(other.cells.iter())
+      .map(|(a, b)| (a - b).abs())
+      .sum::<f32>()
+      / self.cells.len() as f32
+  }
+
+  /// Correlation of the cells with the change from `self` to `other`.
+  fn correlation_with_change(&self, other: &Thumbnail) -> f32 {
+    let change: Box<[f32]> =
+      self.cells.iter().zip(other.cells.iter()).map(|(a, b)| b - a).collect();
+    let (change_mean, change_deviation) = mean_and_deviation(&change);
+    if self.deviation < FLAT_DEVIATION || change_deviation == 0.0 {
+      return 0.0;
+    }
+    let covariance = self
+      .cells
+      .iter()
+      .zip(change.iter())
+      .map(|(a, b)| (a - self.mean) * (b - change_mean))
+      .sum::<f32>()
+      / self.cells.len() as f32;
+    covariance / (self.deviation * change_deviation)
+  }
+
+  /// Relative distance of the samples of `self` to their projection on the
+  /// line going through those of `first` and `last`.
+  fn nonlinearity(&self, first: &Thumbnail, last: &Thumbnail) -> f32 {
+    let (mut step2, mut dot, mut span2) = (0.0f64, 0.0f64, 0.0f64);
+    for ((&a, &b), &c) in
+      first.samples.iter().zip(self.samples.iter()).zip(last.samples.iter())
+    {
+      let step = f64::from(b) - f64::from(a);
+      let span = f64::from(c) - f64::from(a);
+      step2 += step * step;
+      dot += step * span;
+      span2 += span * span;
+    }
+    if step2 == 0.0 {
+      return 0.0;
+    }
+    if span2 == 0.0 {
+      return 1.0;
+    }
+    ((step2 - dot * dot / span2).max(0.0) / step2).sqrt() as f32
+  }
+}
+
+fn mean_and_deviation(values: &[f32]) -> (f32, f32) {
+  let mean = values.iter().sum::<f32>() / values.len() as f32;
+  let variance = values.iter().map(|&v| (v - mean) * (v - mean)).sum::<f32>()
+    / values.len() as f32;
+  (mean, variance.sqrt())
+}
+
+impl<T: Pixel> SceneChangeDetector<T> {
+  /// Labels the content transition at `input_frameno`, without deciding
+  /// whether it starts a new scene.
+  ///
+  /// `frame_set` follows the same convention as in `analyze_next_frame`.
+  pub(crate) fn label_next_frame(
+    &mut self, frame_set: &[&Arc<Frame<T>>], input_frameno: u64,
+  ) -> SceneLabel {
+    if self.encoder_config.speed_settings.scene_detection_mode
+      == SceneDetectionSpeed::None
+    {
+      return SceneLabel::Normal;
+    }
+
+    for (frameno, frame) in (input_frameno - 1..).zip(frame_set.iter()) {
+      self
+        .thumbnails
+        .entry(frameno)
+        .or_insert_with(|| Thumbnail::new(&frame.planes[0], self.bit_depth));
+    }
+    // A gradual transition looks back at most this far.
+    self.thumbnails =
+      self.thumbnails.split_off(&input_frameno.saturating_sub(GRADUAL_WINDOW));
+
+    // Frames following the start of a flash are labeled with it.
+    let label =
+      self.scene_labels.get(&input_frameno).copied().unwrap_or_else(|| {
+        self
+          .detect_flash(input_frameno)
+          .or_else(|| self.detect_gradual(input_frameno))
+          .unwrap_or_default()
+      });
+    self.scene_labels.insert(input_frameno, label);
+    label
+  }
+
+  /// Returns the detected content transition at `input_frameno`.
+  pub(crate) fn scene_label(&self, input_frameno: u64) -> SceneLabel {
+    self.scene_labels.get(&input_frameno).copied().unwrap_or_default()
+  }
+
+  /// Drops the labels of the frames before `input_frameno`.
+  pub(crate) fn forget_scene_labels_before(&mut self, input_frameno: u64) {
+    self.scene_labels = self.scene_labels.split_off(&input_frameno);
+  }
+
+  /// Detects a flash starting at `input_frameno`: a short run of frames very
+  /// different from the one before, after which the scene comes back.
+  fn detect_flash(&mut self, input_frameno: u64) -> Option<SceneLabel> {
+    let prev = self.thumbnails.get(&(input_frameno - 1))?;
+    let jump = prev.diff(self.thumbnails.get(&input_frameno)?);
+    if jump < FLASH_THRESHOLD {
+      return None;
+    }
+
+    for len in 1..=MAX_FLASH_LEN {
+      let back = prev.diff(self.thumbnails.get(&(input_frameno + len))?);
+      if back * 4.0 < jump {
+        for frameno in input_frameno + 1..input_frameno + len {
+          self.scene_labels.insert(frameno, SceneLabel::Flash);
+        }
+        return Some(SceneLabel::Flash);
+      }
+      if back < jump / 2.0 {
+        return None;
+      }
+    }
+    None
+  }
+
+  /// Detects a fade or cross-dissolve around `input_frameno`, by looking
+  /// for a window of frames containing it which blend steadily from one
+  /// picture to another.
+  fn detect_gradual(&self, input_frameno: u64) -> Option<SceneLabel> {
+    let first_start = input_frameno.saturating_sub(GRADUAL_WINDOW - 1).max(1);
+    (first_start..=input_frameno).find_map(|start| {
+      let frames = (start - 1..start + GRADUAL_WINDOW)
+        .map(|frameno| self.thumbnails.get(&frameno))
+        .collect::<Option<Vec<_>>>()?;
+      Self::classify_gradual(&frames)
+    })
+  }
+
+  fn classify_gradual(frames: &[&Thumbnail]) -> Option<SceneLabel> {
+    let steps_in_range = frames.windows(2).all(|pair| {
+      let step = pair[0].diff(pair[1]);
+      (GRADUAL_MIN_STEP..=GRADUAL_MAX_STEP).contains(&step)
+    });
+    if !steps_in_range {
+      return None;
+    }
+    let first = frames[0];
+    let last = frames[frames.len() - 1];
+    if last.deviation < first.deviation * FADE_MAX_CONTRAST_RATIO
+      && (last.deviation < FLAT_DEVIATION
+        || first.correlation_with_change(last) <= -FADE_MIN_CORRELATION)
+    {
+      Some(SceneLabel::FadeOut)
+    } else if first.deviation < last.deviation * FADE_MAX_CONTRAST_RATIO
+      && (first.deviation < FLAT_DEVIATION
+        || -last.correlation_with_change(first) >= FADE_MIN_CORRELATION)
+    {
+      Some(SceneLabel::FadeIn)
+    } else if first.diff(last) >= DISSOLVE_MIN_DIFF
+      // Unlike a fade, which scales the contrast, a cross-dissolve is only
+      // told apart from motion by its frames being blends of its ends.
+      && frames[1..frames.len() - 1].iter().all(|frame| {
+        frame.nonlinearity(first, last) < GRADUAL_MAX_NONLINEARITY
+      })
+    {
+      Some(SceneLabel::CrossDissolve)
+    } else {
+      None
+    }
+  }
+}
@@ -13,6 +13,8 @@
 use crate::color::ChromaSampling;
 
 use crate::api::config::GrainTableSegment;
+use crate::api::FrameType;
+use crate::frame::{AlphaPlane, FrameParameters};
 use crate::util::Pixel;
 use crate::*;
 
@@ -451,6 +453,50 @@ fn keyframes(decoder: &str) {
   );
 }
 
+#[cfg_attr(feature = "decode_test", interpolate_test(aom, "aom"))]
+#[cfg_attr(feature = "decode_test_dav1d", interpolate_test(dav1d, "dav1d"))]
+fn open_gop_keyframes(decoder: &str) {
+  let limit = 14;
+  let w = 64;
+  let h = 80;
+
+  let mut enc = EncoderConfig::with_speed_preset(10);
+  enc.width = w;
+  enc.height = h;
+  enc.min_key_frame_interval = 0;
+  enc.max_key_frame_interval = 8;
+  enc.open_gop = true;
+  let cfg = Config::new().with_encoder_config(enc).with_threads(2);
+  let mut ctx: Context<u8> = cfg.new_context().unwrap();
+
+  // Two scenes of a still picture, the second one starting at frame 5.
+  for i in 0..limit {
+    let mut ra = ChaChaRng::from_seed([(i >= 5) as u8; 32]);
+    let mut input = ctx.new_frame();
+    fill_frame(&mut ra, &mut input);
+    ctx.send_frame(input).unwrap();
+  }
+  ctx.flush();
+
+  let frame_types = decode_packets(decoder, &mut ctx, w, h)
+    .into_iter()
+    .map(|(frame_type, _)| frame_type)
+    .collect::<Vec<_>>();
+  assert_eq!(frame_types.len(), limit);
+  // The scene change within the keyframe interval is an intra-only frame.
+  assert_eq!(frame_types[5], FrameType::INTRA_ONLY);
+  // The keyframe at the end of the interval is coded ahead of the frames
+  // before it, which may reference it, and shown last.
+  assert_eq!(frame_types[13], FrameType::KEY);
+  assert_eq!(
+    frame_types
+      .iter()
+      .filter(|&&frame_type| frame_type == FrameType::KEY)
+      .count(),
+    2
+  );
+}
+
 #[cfg_attr(feature = "decode_test", interpolate_test(aom, "aom"))]
 #[cfg_attr(feature = "decode_test_dav1d", interpolate_test(dav1d, "dav1d"))]
 fn reordering(decoder: &str) {
@@ -619,6 +665,32 @@ fn switch_frame(decoder: &str) {
   );
 }
 
+#[cfg_attr(feature = "decode_test", interpolate_test(aom, "aom"))]
+#[cfg_attr(feature = "decode_test_dav1d", interpolate_test(dav1d, "dav1d"))]
+fn dropped_frames(decoder: &str) {
+  let limit = 10;
+  let w = 64;
+  let h = 80;
+
+  let mut enc = EncoderConfig::with_speed_preset(10);
+  enc.width = w;
+  enc.height = h;
+  enc.low_latency = true;
+  enc.bitrate = 1000;
+  enc.drop_frames = true;
+  let cfg = Config::new().with_encoder_config(enc).with_threads(2);
+  let mut ctx: Context<u8> = cfg.new_context().unwrap();
+
+  let mut ra = ChaChaRng::from_seed([0; 32]);
+  read_frame_batch(&mut ctx, &mut ra, limit);
+
+  let packets = decode_packets(decoder, &mut ctx, w, h);
+  assert_eq!(packets.len(), limit);
+  // Frames of noise bust the budget, so some are replaced by the previous
+  // frame shown again.
+  assert!(packets.iter().any(|&(_, dropped)| dropped));
+}
+
 #[cfg_attr(feature = "decode_test", interpolate_test(aom, "aom"))]
 #[cfg_attr(feature = "decode_test_dav1d", interpolate_test(dav1d, "dav1d"))]
 #[ignore]
@@ -733,38 +805,60 @@ macro_rules! test_high_bit_depth {
 
 test_high_bit_depth! {10, 12}
 
-fn chroma_sampling(decoder: &str, cs: ChromaSampling) {
+fn chroma_sampling(decoder: &str, cs: ChromaSampling, depth: usize) {
   let quantizer = 100;
   let limit = 3; // Include inter frames
   let speed = 0; // Test as many tools as possible
   let w = 64;
   let h = 80;
 
-  let mut dec = get_decoder::<u8>(decoder, w as usize, h as usize);
-  dec.encode_decode(
-    true, w, h, speed, quantizer, limit, 8, cs, 15, 15, 0, true, false, 0, 0,
-    0, false, None,
-  );
+  if depth == 8 {
+    let mut dec = get_decoder::<u8>(decoder, w as usize, h as usize);
+    dec.encode_decode(
+      true, w, h, speed, quantizer, limit, depth, cs, 15, 15, 0, true, false,
+      0, 0, 0, false, None,
+    );
+  } else {
+    let mut dec = get_decoder::<u16>(decoder, w as usize, h as usize);
+    dec.encode_decode(
+      true, w, h, speed, quantizer, limit, depth, cs, 15, 15, 0, true, false,
+      0, 0, 0, false, None,
+    );
+  }
 }
 
 macro_rules! test_chroma_sampling {
   ($(($S:expr, $I:expr)),+) => {
+    $(
+      test_chroma_sampling!(@depths $S, $I, 8, 10, 12);
+    )*
+  };
+  (@depths $S:expr, $I:expr, $($B:expr),+) => {
     $(
       paste::item!{
         #[cfg_attr(feature = "decode_test", interpolate_test(aom, "aom"))]
         #[cfg_attr(feature = "decode_test_dav1d", interpolate_test(dav1d, "dav1d"))]
         #[ignore]
-        fn [<chroma_sampling_ $S>](decoder: &str) {
-          chroma_sampling(decoder, $I);
+        fn [<chroma_sampling_ $S _ $B>](decoder: &str) {
+          chroma_sampling(decoder, $I, $B);
         }
       }
     )*
-  }
+  };
 }
 
 test_chroma_sampling! {(400, ChromaSampling::Cs400), (420, ChromaSampling::Cs420),
 (422, ChromaSampling::Cs422), (444, ChromaSampling::Cs444)}
 
+// Inter blocks 4 pixels wide predict their 4:2:2 chroma in parts, with the
+// motion of each co-located luma block, and transform size RDO is enabled at
+// speed 0.
+#[cfg_attr(feature = "decode_test", interpolate_test(aom, "aom"))]
+#[cfg_attr(feature = "decode_test_dav1d", interpolate_test(dav1d, "dav1d"))]
+fn chroma_sampling_422_10_sub8x8_inter(decoder: &str) {
+  chroma_sampling(decoder, ChromaSampling::Cs422, 10);
+}
+
 #[cfg_attr(feature = "decode_test", interpolate_test(aom, "aom"))]
 #[cfg_attr(feature = "decode_test_dav1d", interpolate_test(dav1d, "dav1d"))]
 fn tile_encoding_with_stretched_restoration_units(decoder: &str) {
@@ -831,6 +925,41 @@ fn still_picture_mode(decoder: &str) {
   );
 }
 
+// Decodes the packets of an encode, comparing the decoded frames with the
+// reconstructed ones, and returns the frame type and whether the frame was
+// dropped for each packet.
+fn decode_packets(
+  decoder: &str, ctx: &mut Context<u8>, w: usize, h: usize,
+) -> Vec<(FrameType, bool)> {
+  let mut dec = get_decoder::<u8>(decoder, w, h);
+  let mut rec_fifo = VecDeque::new();
+  let mut packets = Vec::new();
+  loop {
+    match ctx.receive_packet() {
+      Ok(pkt) => {
+        if let Some(pkt_rec) = pkt.rec {
+          rec_fifo.push_back((*pkt_rec).clone());
+        }
+        let res = dec.decode_packet(
+          &pkt.data,
+          &mut rec_fifo,
+          w,
+          h,
+          ChromaSampling::Cs420,
+          8,
+          true,
+        );
+        assert!(!matches!(res, DecodeResult::Corrupted(_)));
+        packets.push((pkt.frame_type, pkt.dropped));
+      }
+      Err(EncoderStatus::Encoded) => {}
+      Err(EncoderStatus::LimitReached) => break,
+      Err(e) => panic!("Unexpected status {e:?}"),
+    }
+  }
+  packets
+}
+
 pub(crate) fn get_decoder<T: Pixel>(
   decoder: &str, w: usize, h: usize,
 ) -> Box<dyn TestDecoder<T>> {
@@ -983,6 +1112,18 @@ fn film_grain_table_luma_only(decoder: &str) {
 #[cfg_attr(feature = "decode_test_dav1d", interpolate_test(dav1d, "dav1d"))]
 #[ignore]
 fn film_grain_table_chroma(decoder: &str) {
+  film_grain_table_with_chroma_points(decoder, ChromaSampling::Cs420);
+}
+
+// Monochrome streams do not code the chroma scaling points of the table.
+#[cfg_attr(feature = "decode_test", interpolate_test(aom, "aom"))]
+#[cfg_attr(feature = "decode_test_dav1d", interpolate_test(dav1d, "dav1d"))]
+#[ignore]
+fn film_grain_table_monochrome(decoder: &str) {
+  film_grain_table_with_chroma_points(decoder, ChromaSampling::Cs400);
+}
+
+fn film_grain_table_with_chroma_points(decoder: &str, cs: ChromaSampling) {
   let quantizer = 100;
   let limit = 5; // Include inter frames
   let speed = 10;
@@ -998,7 +1139,7 @@ fn film_grain_table_chroma(decoder: &str) {
     quantizer,
     limit,
     8,
-    Default::default(),
+    cs,
     15,
     15,
     0,
@@ -1070,3 +1211,100 @@ fn film_grain_table_chroma(decoder: &str) {
     }]),
   );
 }
+
+#[cfg_attr(feature = "decode_test", interpolate_test(aom, "aom"))]
+#[cfg_attr(feature = "decode_test_dav1d", interpolate_test(dav1d, "dav1d"))]
+#[ignore]
+fn alpha_stream(decoder: &str) {
+  encode_decode_alpha(decoder, 50);
+}
+
+#[cfg_attr(feature = "decode_test", interpolate_test(aom, "aom"))]
+#[cfg_attr(feature = "decode_test_dav1d", interpolate_test(dav1d, "dav1d"))]
+#[ignore]
+fn lossless_alpha_stream(decoder: &str) {
+  encode_decode_alpha(decoder, 0);
+}
+
+fn encode_decode_alpha(decoder: &str, alpha_quantizer: usize) {
+  let limit = 10;
+  let w = 64;
+  let h = 80;
+
+  let mut enc = EncoderConfig::with_speed_preset(10);
+  enc.width = w;
+  enc.height = h;
+  enc.min_key_frame_interval = 4;
+  enc.max_key_frame_interval = 4;
+  enc.alpha_quantizer = Some(alpha_quantizer);
+  let cfg = Config::new().with_encoder_config(enc).with_threads(2);
+  let mut ctx: Context<u8> = cfg.new_context().unwrap();
+
+  let mut ra = ChaChaRng::from_seed([0; 32]);
+  let mut alphas = Vec::new();
+  for i in 0..limit {
+    let mut input = ctx.new_frame();
+    fill_frame(&mut ra, &mut input);
+    let mut alpha = ctx.new_alpha_plane();
+    for pixel in alpha.data.iter_mut() {
+      *pixel = ra.gen();
+    }
+    // Frames sent without an alpha plane are fully opaque.
+    let alpha = (i % 3 != 2).then_some(alpha);
+    let params = FrameParameters {
+      alpha: alpha.clone().map(AlphaPlane::new),
+      ..Default::default()
+    };
+    alphas.push(alpha);
+    ctx.send_frame((input, params)).unwrap();
+  }
+  ctx.flush();
+
+  let mut dec = get_decoder::<u8>(decoder, w, h);
+  let mut alpha_dec = get_decoder::<u8>(decoder, w, h);
+  let mut rec_fifo = VecDeque::new();
+  let mut alpha_rec_fifo = VecDeque::new();
+  let mut packets = 0;
+  loop {
+    match ctx.receive_packet() {
+      Ok(pkt) => {
+        let alpha_rec = pkt.alpha_rec.unwrap();
+        if alpha_quantizer == 0 {
+          // Lossless alpha planes are reconstructed exactly.
+          let rec_rows = alpha_rec.planes[0].rows_iter().take(h);
+          match &alphas[pkt.input_frameno as usize] {
+            Some(alpha) => {
+              for (rec, input) in rec_rows.zip(alpha.rows_iter()) {
+                assert_eq!(rec[..w], input[..w]);
+              }
+            }
+            None => {
+              for rec in rec_rows {
+                assert!(rec[..w].iter().all(|&v| v == 255));
+              }
+            }
+          }
+        }
+        rec_fifo.push_back((*pkt.rec.unwrap()).clone());
+        alpha_rec_fifo.push_back((*alpha_rec).clone());
+        for (dec, data, rec_fifo, cs) in [
+          (&mut dec, &pkt.data, &mut rec_fifo, ChromaSampling::Cs420),
+          (
+            &mut alpha_dec,
+            pkt.alpha_data.as_ref().unwrap(),
+            &mut alpha_rec_fifo,
+            ChromaSampling::Cs400,
+          ),
+        ] {
+          let res = dec.decode_packet(data, rec_fifo, w, h, cs, 8, true);
+          assert!(!matches!(res, DecodeResult::Corrupted(_)));
+        }
+        packets += 1;
+      }
+      Err(EncoderStatus::Encoded) => {}
+      Err(EncoderStatus::LimitReached) => break,
+      Err(e) => panic!("Unexpected status {e:?}"),
+    }
+  }
+  assert_eq!(packets, limit);
+}
@@ -166,4 +166,756 @@ mod binary {
       .assert()
       .success();
   }
+
+  fn top_level_boxes(path: &Path) -> Vec<String> {
+    let data = std::fs::read(path).unwrap();
+    let mut boxes = Vec::new();
+    let mut offset = 0;
+    while offset < data.len() {
+      let header = &data[offset..offset + 16.min(data.len() - offset)];
+      let size = match u32::from_be_bytes(header[..4].try_into().unwrap()) {
+        1 => u64::from_be_bytes(header[8..16].try_into().unwrap()) as usize,
+        size => size as usize,
+      };
+      boxes.push(String::from_utf8_lossy(&header[4..8]).into_owned());
+      assert!(size >= 8 && offset + size <= data.len());
+      offset += size;
+    }
+    boxes
+  }
+
+  /// Returns the type and content of the boxes of `data`.
+  fn mp4_boxes(mut data: &[u8]) -> Vec<(String, &[u8])> {
+    let mut boxes = Vec::new();
+    while !data.is_empty() {
+      let (size, header_size) =
+        match u32::from_be_bytes(data[..4].try_into().unwrap()) {
+          1 => {
+            (u64::from_be_bytes(data[8..16].try_into().unwrap()) as usize, 16)
+          }
+          size => (size as usize, 8),
+        };
+      let box_type = String::from_utf8_lossy(&data[4..8]).into_owned();
+      boxes.push((box_type, &data[header_size..size]));
+      data = &data[size..];
+    }
+    boxes
+  }
+
+  /// Returns the content of the first box of type `box_type` in `data`.
+  fn mp4_box<'a>(data: &'a [u8], box_type: &str) -> &'a [u8] {
+    mp4_boxes(data)
+      .into_iter()
+      .find(|(t, _)| t == box_type)
+      .unwrap_or_else(|| panic!("missing {box_type} box"))
+      .1
+  }
+
+  fn be_u32(data: &[u8]) -> u32 {
+    u32::from_be_bytes(data[..4].try_into().unwrap())
+  }
+
+  /// Returns the packets of an IVF file, without their temporal delimiter.
+  fn ivf_packets(path: &Path) -> Vec<Vec<u8>> {
+    let data = std::fs::read(path).unwrap();
+    let mut packets = Vec::new();
+    let mut offset = 32;
+    while offset < data.len() {
+      let size =
+        u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+      let packet = &data[offset + 12..offset + 12 + size as usize];
+      packets.push(packet.strip_prefix(&[0x12, 0]).unwrap_or(packet).to_vec());
+      offset += 12 + size as usize;
+    }
+    packets
+  }
+
+  /// Encodes the test input with the same settings to IVF and to MP4, and
+  /// returns the packets of the former and the data of the latter.
+  fn encode_mp4(fragmented: bool) -> (Vec<Vec<u8>>, Vec<u8>) {
+    let ivf = get_tempfile_path("ivf");
+    let mp4 = get_tempfile_path("mp4");
+    for outfile in [&ivf, &mp4] {
+      let mut cmd = get_common_cmd(outfile, false);
+      if fragmented {
+        cmd.arg("--fragmented");
+      }
+      cmd
+        .args(["--keyint", "2"])
+        .arg("-")
+        .write_stdin(get_y4m_input())
+        .assert()
+        .success();
+    }
+    (ivf_packets(&ivf), std::fs::read(&mp4).unwrap())
+  }
+
+  /// Checks that the `av1C` box of the `moov` holds the sequence header OBU
+  /// starting the first packet.
+  fn check_av1c(moov: &[u8], packets: &[Vec<u8>]) {
+    let stbl = ["trak", "mdia", "minf", "stbl"]
+      .into_iter()
+      .fold(moov, |data, box_type| mp4_box(data, box_type));
+    // The stsd entry count, then the visual sample entry fields.
+    let av01 = mp4_box(&mp4_box(stbl, "stsd")[8..], "av01");
+    let av1c = mp4_box(&av01[78..], "av1C");
+    assert_eq!(av1c[0], 0x81);
+    let sequence_header = &av1c[4..];
+    assert_eq!(sequence_header[0] >> 3 & 0xf, 1);
+    assert!(packets[0].starts_with(sequence_header));
+  }
+
+  #[test]
+  fn mp4_output() {
+    let (packets, data) = encode_mp4(false);
+
+    let boxes = mp4_boxes(&data);
+    let names: Vec<_> = boxes.iter().map(|(t, _)| t.as_str()).collect();
+    assert_eq!(names, ["ftyp", "mdat", "moov"]);
+    let moov = boxes[2].1;
+    check_av1c(moov, &packets);
+
+    let stbl = ["trak", "mdia", "minf", "stbl"]
+      .into_iter()
+      .fold(moov, |data, box_type| mp4_box(data, box_type));
+    let stsz = mp4_box(stbl, "stsz");
+    assert_eq!(be_u32(&stsz[8..]) as usize, packets.len());
+    let sizes: Vec<_> =
+      stsz[12..].chunks(4).map(|size| be_u32(size) as usize).collect();
+    assert_eq!(sizes, packets.iter().map(Vec::len).collect::<Vec<_>>());
+
+    // All the samples are in a single chunk.
+    let co64 = mp4_box(stbl, "co64");
+    assert_eq!(be_u32(&co64[4..]), 1);
+    let offset = u64::from_be_bytes(co64[8..16].try_into().unwrap()) as usize;
+    assert_eq!(&data[offset..offset + boxes[1].1.len()], boxes[1].1);
+    assert_eq!(boxes[1].1, packets.concat());
+  }
+
+  #[test]
+  fn fragmented_mp4_output() {
+    let (packets, data) = encode_mp4(true);
+
+    let boxes = mp4_boxes(&data);
+    let names: Vec<_> = boxes.iter().map(|(t, _)| t.as_str()).collect();
+    assert_eq!(names[..2], ["ftyp", "moov"]);
+    assert!(names.len() > 4);
+    assert!(names[2..].chunks(2).all(|fragment| fragment == ["moof", "mdat"]));
+    check_av1c(boxes[1].1, &packets);
+
+    let mut samples = Vec::new();
+    let mut offset = boxes[0].1.len() + boxes[1].1.len() + 16;
+    for fragment in boxes[2..].chunks(2) {
+      let (moof, mdat) = (fragment[0].1, fragment[1].1);
+      let trun = mp4_box(mp4_box(moof, "traf"), "trun");
+      let sample_count = be_u32(&trun[4..]) as usize;
+      // The data offset is relative to the start of the moof.
+      let mut sample_offset = offset + be_u32(&trun[8..]) as usize;
+      assert_eq!(sample_offset, offset + moof.len() + 16);
+      for sample in trun[12..].chunks(8).take(sample_count) {
+        let size = be_u32(sample) as usize;
+        samples.push(&data[sample_offset..sample_offset + size]);
+        sample_offset += size;
+      }
+      assert_eq!(sample_offset, offset + moof.len() + mdat.len() + 16);
+      offset = sample_offset;
+    }
+    assert_eq!(samples, packets);
+  }
+
+  #[test]
+  fn mp4_output_with_large_time_base() {
+    let outfile = get_tempfile_path("mp4");
+
+    let assert = get_common_cmd(&outfile, false)
+      .args(["--frame-rate", "30", "--time-scale", "4294967296"])
+      .arg("-")
+      .write_stdin(get_y4m_input())
+      .assert()
+      .failure();
+    let stderr = String::from_utf8_lossy(&assert.get_output().stderr);
+    assert!(stderr.contains("time base of at most 32 bits"), "{stderr}");
+  }
+
+  #[test]
+  fn unsupported_output_extension() {
+    let outfile = get_tempfile_path("mov");
+
+    let assert = get_common_cmd(&outfile, false)
+      .arg("-")
+      .write_stdin(get_y4m_input())
+      .assert()
+      .failure();
+    let stderr = String::from_utf8_lossy(&assert.get_output().stderr);
+    assert!(stderr.contains("mov is not a supported extension"), "{stderr}");
+  }
+
+  #[test]
+  fn webm_output() {
+    let outfile = get_tempfile_path("webm");
+
+    get_common_cmd(&outfile, false)
+      .arg("-")
+      .write_stdin(get_y4m_input())
+      .assert()
+      .success();
+
+    let data = std::fs::read(&outfile).unwrap();
+    assert_eq!(data[..4], [0x1a, 0x45, 0xdf, 0xa3]);
+    assert!(data.windows(4).any(|doc_type| doc_type == b"webm"));
+    // The cues end the file.
+    assert!(data.windows(4).any(|id| id == [0x1c, 0x53, 0xbb, 0x6b]));
+  }
+
+  #[test]
+  fn webm_output_without_frames() {
+    let outfile = get_tempfile_path("webm");
+    let input = get_y4m_input();
+    let header_len = input.iter().position(|&b| b == b'\n').unwrap() + 1;
+
+    get_common_cmd(&outfile, false)
+      .arg("-")
+      .write_stdin(&input[..header_len])
+      .assert()
+      .success();
+
+    // Neither the cues nor their seek entry are written.
+    let data = std::fs::read(&outfile).unwrap();
+    assert!(!data.windows(4).any(|id| id == [0x1c, 0x53, 0xbb, 0x6b]));
+    assert!(data.windows(4).any(|id| id == [0x15, 0x49, 0xa9, 0x66]));
+  }
+
+  #[test]
+  fn webm_stdout_output() {
+    let output = get_rav1e_command(false)
+      .args(["--bitrate", "1000", "--container", "webm", "-o", "-", "-"])
+      .write_stdin(get_y4m_input())
+      .assert()
+      .success();
+
+    let data = &output.get_output().stdout;
+    assert_eq!(data[..4], [0x1a, 0x45, 0xdf, 0xa3]);
+    assert!(data.windows(5).any(|codec_id| codec_id == b"V_AV1"));
+  }
+
+  #[test]
+  fn avif_output() {
+    let outfile = get_tempfile_path("avif");
+
+    get_rav1e_command(false)
+      .arg("--still-picture")
+      .arg("-o")
+      .arg(&outfile)
+      .arg("-")
+      .write_stdin(get_y4m_input())
+      .assert()
+      .success();
+
+    let data = std::fs::read(&outfile).unwrap();
+    assert_eq!(&data[4..12], b"ftypavif");
+    assert_eq!(top_level_boxes(&outfile), ["ftyp", "meta", "mdat"]);
+
+    get_rav1e_command(false)
+      .arg("-o")
+      .arg(&outfile)
+      .arg("-y")
+      .arg("-")
+      .write_stdin(get_y4m_input())
+      .assert()
+      .failure();
+  }
+
+  #[test]
+  fn avif_grid_output() {
+    let outfile = get_tempfile_path("avif");
+
+    get_rav1e_command(false)
+      .args(["--still-picture", "--grid", "2x1", "-o"])
+      .arg(&outfile)
+      .arg("-")
+      .write_stdin(get_y4m_input())
+      .assert()
+      .success();
+
+    let data = std::fs::read(&outfile).unwrap();
+    let meta = &mp4_box(&data, "meta")[4..];
+    let infos = mp4_boxes(&mp4_box(meta, "iinf")[6..]);
+    let item_types: Vec<_> =
+      infos.iter().map(|(_, infe)| &infe[8..12]).collect();
+    assert_eq!(item_types, [&b"grid"[..], b"av01", b"av01"]);
+    assert!(mp4_boxes(&mp4_box(meta, "iref")[4..])
+      .iter()
+      .any(|(ref_type, _)| ref_type == "dimg"));
+
+    get_rav1e_command(false)
+      .args(["--still-picture", "--grid", "257x1", "-o"])
+      .arg(&outfile)
+      .arg("-y")
+      .arg("-")
+      .write_stdin(get_y4m_input())
+      .assert()
+      .failure();
+  }
+
+  /// Returns an uncompressed RGBA PNG image.
+  fn rgba_png(
+    width: u32, height: u32, pixels: impl Iterator<Item = [u8; 4]>,
+  ) -> Vec<u8> {
+    fn crc32(data: &[u8]) -> u32 {
+      !data.iter().fold(!0, |crc, &byte| {
+        (0..8).fold(crc ^ u32::from(byte), |crc, _| {
+          (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg())
+        })
+      })
+    }
+    let chunk = |png: &mut Vec<u8>, chunk_type: &[u8; 4], data: &[u8]| {
+      png.extend((data.len() as u32).to_be_bytes());
+      let start = png.len();
+      png.extend(chunk_type);
+      png.extend(data);
+      let crc = crc32(&png[start..]);
+      png.extend(crc.to_be_bytes());
+    };
+
+    // Each row starts with its filter type, and is stored in its own block.
+    let pixels = pixels.flatten().collect::<Vec<u8>>();
+    let mut raw = Vec::new();
+    for row in pixels.chunks(width as usize * 4) {
+      raw.push(0);
+      raw.extend(row);
+    }
+    let mut zlib = vec![0x78, 0x01];
+    for (i, row) in raw.chunks(width as usize * 4 + 1).enumerate() {
+      zlib.push(u8::from(i + 1 == height as usize));
+      zlib.extend((row.len() as u16).to_le_bytes());
+      zlib.extend((!(row.len() as u16)).to_le_bytes());
+      zlib.extend(row);
+    }
+    let (a, b) = raw.iter().fold((1u32, 0u32), |(a, b), &byte| {
+      let a = (a + u32::from(byte)) % 65521;
+      (a, (b + a) % 65521)
+    });
+    zlib.extend((b << 16 | a).to_be_bytes());
+
+    let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+    let mut header = Vec::new();
+    header.extend(width.to_be_bytes());
+    header.extend(height.to_be_bytes());
+    header.extend([8, 6, 0, 0, 0]); // 8-bit RGBA
+    chunk(&mut png, b"IHDR", &header);
+    chunk(&mut png, b"IDAT", &zlib);
+    chunk(&mut png, b"IEND", &[]);
+    png
+  }
+
+  #[test]
+  fn avif_alpha_output() {
+    let image_path = get_tempfile_path("png");
+    let pixels = (0..32 * 16).map(|i| [255, 0, 0, (i % 256) as u8]);
+    std::fs::write(&image_path, rgba_png(32, 16, pixels)).unwrap();
+    let outfile = get_tempfile_path("avif");
+
+    get_rav1e_command(false)
+      .args(["--still-picture", "--alpha-quantizer", "0", "-o"])
+      .arg(&outfile)
+      .arg(&image_path)
+      .assert()
+      .success();
+
+    let data = std::fs::read(&outfile).unwrap();
+    let meta = &mp4_box(&data, "meta")[4..];
+    let infos = mp4_boxes(&mp4_box(meta, "iinf")[6..]);
+    assert_eq!(infos.len(), 2);
+    assert!(mp4_boxes(&mp4_box(meta, "iref")[4..])
+      .iter()
+      .any(|(ref_type, _)| ref_type == "auxl"));
+
+    get_rav1e_command(false)
+      .args(["--alpha-quantizer", "0", "-o"])
+      .arg(get_tempfile_path("ivf"))
+      .arg(&image_path)
+      .assert()
+      .failure();
+  }
+
+  /// Returns the frames of the small Y4M input without their headers.
+  fn get_raw_input() -> Vec<u8> {
+    let y4m = get_y4m_input();
+    let frame_size = 64 * 64 * 3 / 2;
+    let mut frames = Vec::new();
+    let mut lines = y4m.splitn(2, |&b| b == b'\n');
+    let mut data = lines.nth(1).unwrap();
+    while !data.is_empty() {
+      let header_len = data.iter().position(|&b| b == b'\n').unwrap() + 1;
+      frames.extend(&data[header_len..][..frame_size]);
+      data = &data[header_len + frame_size..];
+    }
+    frames
+  }
+
+  #[test]
+  fn raw_yuv_input() {
+    let outfile = get_tempfile_path("ivf");
+    let y4m_recfile = get_tempfile_path("y4m");
+    let raw_recfile = get_tempfile_path("y4m");
+
+    get_common_cmd(&outfile, false)
+      .arg("--reconstruction")
+      .arg(&y4m_recfile)
+      .arg("-")
+      .write_stdin(get_y4m_input())
+      .assert()
+      .success();
+    get_common_cmd(&outfile, false)
+      .args(["--width", "64", "--height", "64", "--fps", "25"])
+      .arg("--reconstruction")
+      .arg(&raw_recfile)
+      .arg("-")
+      .write_stdin(get_raw_input())
+      .assert()
+      .success();
+
+    // Only the pixel aspect ratio of the raw input is unknown.
+    let frames = |path| {
+      let rec = std::fs::read(path).unwrap();
+      let header_len = rec.iter().position(|&b| b == b'\n').unwrap();
+      rec[header_len..].to_vec()
+    };
+    assert_eq!(frames(&y4m_recfile), frames(&raw_recfile));
+  }
+
+  #[test]
+  fn image_sequence_input() {
+    let image_path = get_tempfile_path("ppm");
+    let pattern = image_path.to_str().unwrap().replace(".ppm", "%02d.ppm");
+    for (i, color) in
+      [[255, 0, 0], [0, 255, 0], [0, 0, 255]].iter().enumerate()
+    {
+      let mut ppm = b"P6\n32 16\n255\n".to_vec();
+      ppm.extend(color.repeat(32 * 16));
+      std::fs::write(pattern.replace("%02d", &format!("{:02}", i + 1)), ppm)
+        .unwrap();
+    }
+    let outfile = get_tempfile_path("ivf");
+    let recfile = get_tempfile_path("y4m");
+
+    get_common_cmd(&outfile, false)
+      .arg("--reconstruction")
+      .arg(&recfile)
+      .arg(&pattern)
+      .assert()
+      .success();
+
+    let rec = std::fs::read(&recfile).unwrap();
+    assert!(rec.starts_with(b"YUV4MPEG2 W32 H16 F30:1 C420"));
+    assert_eq!(rec.windows(5).filter(|&frame| frame == b"FRAME").count(), 3);
+  }
+
+  #[test]
+  fn chunked_encoding() {
+    let encode = |parallel_chunks: &str| {
+      let outfile = get_tempfile_path("ivf");
+      get_rav1e_command(false)
+        .args(["--quantizer", "100", "--keyint", "2", "--min-keyint", "1"])
+        .args(["--parallel-chunks", parallel_chunks])
+        .arg("-o")
+        .arg(&outfile)
+        .arg("-")
+        .write_stdin(get_y4m_input())
+        .assert()
+        .success();
+      std::fs::read(&outfile).unwrap()
+    };
+
+    // The chunks start at the keyframes a single encoder would place, so
+    // they are coded the same way.
+    assert_eq!(encode("2"), encode("0"));
+  }
+
+  #[test]
+  fn single_worker_chunked_encoding() {
+    // Scenes of flat frames around the frames of the input, with cuts the
+    // detector finds.
+    let y4m = get_y4m_input();
+    let header_len = y4m.iter().position(|&b| b == b'\n').unwrap() + 1;
+    let mut input = y4m[..header_len].to_vec();
+    let frame_size = 64 * 64 * 3 / 2;
+    let raw = get_raw_input();
+    let scenes = [vec![16; frame_size]]
+      .into_iter()
+      .chain(raw.chunks(frame_size).map(<[u8]>::to_vec))
+      .chain([vec![235; frame_size]]);
+    for (i, frame) in scenes.enumerate() {
+      let count = if i == 0 || i == 6 { 8 } else { 1 };
+      for _ in 0..count {
+        input.extend(b"FRAME\n");
+        input.extend(&frame);
+      }
+    }
+    let encode = |parallel_chunks: &str| {
+      let outfile = get_tempfile_path("ivf");
+      get_rav1e_command(false)
+        .args(["--quantizer", "100", "--parallel-chunks", parallel_chunks])
+        .arg("-o")
+        .arg(&outfile)
+        .arg("-")
+        .write_stdin(input.clone())
+        .timeout(std::time::Duration::from_secs(120))
+        .assert()
+        .success();
+      std::fs::read(&outfile).unwrap()
+    };
+
+    // The worker is given the next chunk once the previous one is over.
+    assert_eq!(encode("1"), encode("0"));
+  }
+
+  #[test]
+  fn chunked_two_pass_encoding() {
+    let outfile = get_tempfile_path("ivf");
+    let recfile = get_tempfile_path("y4m");
+
+    get_common_cmd(&outfile, false)
+      .args(["--keyint", "2", "--min-keyint", "1"])
+      .args(["--parallel-chunks", "2", "--chunk-passes", "2"])
+      .arg("--reconstruction")
+      .arg(&recfile)
+      .arg("-")
+      .write_stdin(get_y4m_input())
+      .assert()
+      .success();
+
+    let rec = std::fs::read(&recfile).unwrap();
+    assert_eq!(rec.windows(6).filter(|w| w == b"FRAME\n").count(), 5);
+  }
+
+  #[cfg(feature = "serialize")]
+  #[test]
+  fn checkpointed_encoding() {
+    // The frames of the input repeated, so an encode has keyframes past
+    // the lookahead of the first frames.
+    let y4m = get_y4m_input();
+    let header_len = y4m.iter().position(|&b| b == b'\n').unwrap() + 1;
+    let mut input = y4m[..header_len].to_vec();
+    for frame in get_raw_input().chunks(64 * 64 * 3 / 2).cycle().take(30) {
+      input.extend(b"FRAME\n");
+      input.extend(frame);
+    }
+    let checkpoint = get_tempfile_path("toml");
+    let encode = |outfile: &Path,
+                  checkpoint: Option<&Path>,
+                  limit: Option<&str>,
+                  resume: bool| {
+      let mut cmd = get_common_cmd(outfile, false);
+      cmd.args(["--keyint", "4", "--min-keyint", "1"]);
+      cmd.args(["--rdo-lookahead-frames", "1"]);
+      if let Some(checkpoint) = checkpoint {
+        cmd.arg("--checkpoint").arg(checkpoint);
+      }
+      if let Some(limit) = limit {
+        cmd.args(["--limit", limit]);
+      }
+      if resume {
+        cmd.arg("--resume");
+      }
+      cmd.arg("-").write_stdin(input.clone()).assert()
+    };
+    let full = get_tempfile_path("ivf");
+    encode(&full, None, None, false).success();
+    let full = std::fs::read(full).unwrap();
+
+    // Saving checkpoints does not change the output, and the last one is
+    // removed once the encode is done.
+    let outfile = get_tempfile_path("ivf");
+    encode(&outfile, Some(&checkpoint), None, false).success();
+    assert_eq!(std::fs::read(&outfile).unwrap(), full);
+    assert!(!checkpoint.exists());
+
+    // An encode stopped by the frame limit keeps its checkpoint, and is
+    // resumed to the same output as an uninterrupted one.
+    encode(&outfile, Some(&checkpoint), Some("20"), false).success();
+    assert!(checkpoint.exists());
+    let stopped = std::fs::read(&outfile).unwrap();
+    encode(&outfile, Some(&checkpoint), None, true).success();
+    assert!(!checkpoint.exists());
+    let resumed = std::fs::read(&outfile).unwrap();
+    assert!(stopped.len() < resumed.len());
+    assert_eq!(resumed, full);
+
+    // There is no checkpoint left to resume from.
+    encode(&outfile, Some(&checkpoint), None, true).failure();
+  }
+
+  #[cfg(feature = "serialize")]
+  #[test]
+  fn encoder_settings() {
+    let outfile = get_tempfile_path("ivf");
+    let config = get_tempfile_path("toml");
+    let layered = get_tempfile_path("toml");
+
+    get_common_cmd(&outfile, false)
+      .args(["--quantizer", "150"])
+      .args(["--set", "partition.encode_bottomup=false"])
+      .args(["--set", "speed_settings.transform.rdo_tx_decision=true"])
+      .arg("-")
+      .arg("advanced")
+      .arg("--save-config")
+      .arg(&config)
+      .write_stdin(get_y4m_input())
+      .assert()
+      .success();
+    let saved = std::fs::read_to_string(&config).unwrap();
+    assert!(saved.contains("quantizer = 150"));
+    assert!(saved.contains("encode_bottomup = false"));
+    assert!(saved.contains("rdo_tx_decision = true"));
+
+    // The options given on the command line override the loaded ones.
+    get_common_cmd(&outfile, false)
+      .args(["--quantizer", "80"])
+      .arg("-")
+      .arg("advanced")
+      .arg("--load-config")
+      .arg(&config)
+      .arg("--save-config")
+      .arg(&layered)
+      .write_stdin(get_y4m_input())
+      .assert()
+      .success();
+    let saved = std::fs::read_to_string(&layered).unwrap();
+    assert!(saved.contains("quantizer = 80"));
+    assert!(saved.contains("encode_bottomup = false"));
+
+    // Invalid settings are reported with their key.
+    for (setting, key) in [
+      ("partition.bottomup=false", "`partition.bottomup`"),
+      ("cdef=yes", "`speed_settings.cdef`"),
+      ("tile_cols=3", "`tile_cols`"),
+    ] {
+      let assert = get_common_cmd(&outfile, false)
+        .args(["--set", setting])
+        .arg("-")
+        .write_stdin(get_y4m_input())
+        .assert()
+        .failure();
+      let stderr = String::from_utf8_lossy(&assert.get_output().stderr);
+      assert!(stderr.contains(key), "{stderr}");
+    }
+  }
+
+  #[cfg(feature = "decode_test_dav1d")]
+  #[interpolate_test(low_bitdepth, false)]
+  #[interpolate_test(high_bitdepth, true)]
+  fn verified_encoding(high_bitdepth: bool) {
+    let outfile = get_tempfile_path("ivf");
+
+    get_common_cmd(&outfile, high_bitdepth)
+      .args(["--keyint", "2", "--min-keyint", "1", "--photon-noise", "8"])
+      .arg("--verify")
+      .arg("-")
+      .write_stdin(get_y4m_input())
+      .assert()
+      .success();
+  }
+
+  #[interpolate_test(json_lines, "jsonl")]
+  #[interpolate_test(csv, "csv")]
+  fn stats_file_output(extension: &str) {
+    let outfile = get_tempfile_path("ivf");
+    let statsfile = get_tempfile_path(extension);
+
+    get_common_cmd(&outfile, false)
+      .arg("--psnr")
+      .arg("--stats-file")
+      .arg(&statsfile)
+      .arg("-")
+      .write_stdin(get_y4m_input())
+      .assert()
+      .success();
+
+    let stats = std::fs::read_to_string(&statsfile).unwrap();
+    let lines = stats.lines().collect::<Vec<_>>();
+    if extension == "csv" {
+      assert!(lines[0].starts_with("record,frame,input_frame,frame_type,"));
+      // A header, the five frames and the summary.
+      assert_eq!(lines.len(), 7);
+      assert!(lines[1..6].iter().all(|line| line.starts_with("frame,")));
+      assert!(lines[6].starts_with("summary,5,"));
+    } else {
+      assert_eq!(lines.len(), 6);
+      assert!(lines[..5]
+        .iter()
+        .all(|line| line.starts_with("{\"record\":\"frame\",")));
+      assert!(lines[5].starts_with("{\"record\":\"summary\",\"frame\":5,"));
+      assert!(lines.iter().all(|line| line.contains("\"psnr_y\":")));
+    }
+  }
+
+  #[test]
+  fn stats_summary_without_frames() {
+    let y4m = get_y4m_input();
+    let header_len = y4m.iter().position(|&b| b == b'\n').unwrap() + 1;
+    let outfile = get_tempfile_path("ivf");
+    let statsfile = get_tempfile_path("jsonl");
+
+    get_rav1e_command(false)
+      .args(["--psnr", "--stats-file"])
+      .arg(&statsfile)
+      .arg("-o")
+      .arg(&outfile)
+      .arg("-")
+      .write_stdin(&y4m[..header_len])
+      .assert()
+      .success();
+
+    let stats = std::fs::read_to_string(&statsfile).unwrap();
+    let lines = stats.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 1);
+    assert!(lines[0].starts_with("{\"record\":\"summary\",\"frame\":0,"));
+    assert!(lines[0].contains("\"bitrate\":null,\"fps\":null,"));
+    assert!(!lines[0].contains("NaN") && !lines[0].contains("inf"));
+  }
+
+  #[test]
+  fn stats_summary_with_identical_frame() {
+    // A flat gray frame is predicted exactly, so it has no error.
+    let y4m = get_y4m_input();
+    let header_len = y4m.iter().position(|&b| b == b'\n').unwrap() + 1;
+    let mut input = y4m[..header_len].to_vec();
+    for (i, frame) in get_raw_input().chunks(64 * 64 * 3 / 2).enumerate() {
+      input.extend(b"FRAME\n");
+      if i == 0 {
+        input.extend(vec![128; frame.len()]);
+      } else {
+        input.extend(frame);
+      }
+    }
+    let outfile = get_tempfile_path("ivf");
+    let statsfile = get_tempfile_path("jsonl");
+
+    get_rav1e_command(false)
+      .args(["--quantizer", "100", "--psnr", "--stats-file"])
+      .arg(&statsfile)
+      .arg("-o")
+      .arg(&outfile)
+      .arg("-")
+      .write_stdin(input)
+      .assert()
+      .success();
+
+    let stats = std::fs::read_to_string(&statsfile).unwrap();
+    let psnr_y = stats
+      .lines()
+      .map(|line| {
+        let value = line.split("\"psnr_y\":").nth(1).unwrap();
+        value[..value.find(',').unwrap()].parse::<f64>().unwrap()
+      })
+      .collect::<Vec<_>>();
+    let (summary, frames) = psnr_y.split_last().unwrap();
+    assert_eq!(frames.len(), 5);
+    assert_eq!(frames[0], 100.);
+    // The PSNR of the mean squared error of the frames.
+    let mse = frames.iter().map(|psnr| 10f64.powf(-psnr / 10.)).sum::<f64>()
+      / frames.len() as f64;
+    assert!((summary - -10. * mse.log10()).abs() < 1e-6);
+  }
 }